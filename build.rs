@@ -0,0 +1,28 @@
+use std::{env, fs, path::Path};
+
+/// Exposes the resolved `mongodb` crate version as `MONGODB_DRIVER_VERSION`,
+/// which `/readyz` reports. The driver itself does not make it public. Also
+/// generates the gRPC service from `proto/`.
+fn main() {
+    let lock = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock.display());
+
+    let version = fs::read_to_string(&lock)
+        .ok()
+        .and_then(|lock| {
+            let mut lines = lock.lines();
+            lines.find(|line| *line == "name = \"mongodb\"")?;
+            let version = lines.next()?.strip_prefix("version = \"")?;
+            Some(version.trim_end_matches('"').to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=MONGODB_DRIVER_VERSION={}", version);
+
+    // A vendored protoc, with the well-known types, so none needs installing
+    env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    let well_known = protoc_bin_vendored::include_path().unwrap();
+    tonic_build::configure()
+        .compile_protos(&["proto/restaurants.proto"], &[Path::new("proto"), &well_known])
+        .unwrap();
+}
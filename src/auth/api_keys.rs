@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::stream::TryStreamExt;
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use rand::{RngCore, rngs::OsRng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{Grants, Operation, Principal};
+use crate::{config::AuthConfig, db::mongodb::is_duplicate_key, error::AppError, request::RequestContext};
+
+/// Collection holding the hashed keys, next to the restaurants.
+pub const COLLECTION: &str = "api_keys";
+
+/// Every key starts with this, so leaked keys are easy to search for.
+const KEY_PREFIX: &str = "rk_";
+
+/// Characters of a key kept in clear, to tell keys apart in listings.
+const DISPLAY_PREFIX_LEN: usize = 11;
+
+/// Once the cache holds this many keys, expired entries are dropped.
+const MAX_CACHED_KEYS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "restaurants:read")]
+    RestaurantsRead,
+    #[serde(rename = "restaurants:write")]
+    RestaurantsWrite,
+    /// Grants every other scope as well.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Scope::RestaurantsRead => "restaurants:read",
+            Scope::RestaurantsWrite => "restaurants:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn allows(self, operation: Operation) -> bool {
+        match self {
+            Scope::RestaurantsRead => operation == Operation::Read,
+            // Like the `editor` role, deleting is left to `admin`
+            Scope::RestaurantsWrite => operation == Operation::Write,
+            Scope::Admin => true,
+        }
+    }
+}
+
+/// A document of the `api_keys` collection. Only the SHA-256 of the key is
+/// stored; keys are 256 random bits, so a plain hash is enough to make a
+/// leaked collection useless.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyDoc {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub name: String,
+    /// The first characters of the key.
+    pub prefix: String,
+    /// Hex SHA-256 of the whole key.
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: bson::DateTime,
+    pub revoked_at: Option<bson::DateTime>,
+}
+
+/// Validates API keys against the `api_keys` collection. Keys that were
+/// found are cached for `auth.api_key_cache_secs`, so a revoked key keeps
+/// working for at most that long. Cheap to clone; clones share the cache.
+#[derive(Clone)]
+pub struct ApiKeys {
+    collection: Collection<ApiKeyDoc>,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, (Instant, Principal)>>>,
+}
+
+impl ApiKeys {
+    pub fn new(db: &Database, config: &AuthConfig) -> Self {
+        Self {
+            collection: db.collection(COLLECTION),
+            cache_ttl: Duration::from_secs(config.api_key_cache_secs),
+            cache: Arc::default(),
+        }
+    }
+
+    /// The active key `key` hashes to, if any.
+    pub async fn lookup(&self, request: &RequestContext, key: &str) -> Result<Option<Principal>, AppError> {
+        if let Some(principal) = self.cached(key) {
+            return Ok(Some(principal));
+        }
+        let key_hash = hash_key(key);
+
+        let filter = doc! { "key_hash": &key_hash, "revoked_at": null };
+        let Some(found) = self.collection.find_one(filter).comment(request.comment()).await? else {
+            return Ok(None);
+        };
+        let principal = Principal {
+            subject: format!("apikey:{}", found.name),
+            grants: Grants::Scopes(found.scopes),
+        };
+
+        let mut cache = self.cache();
+        if cache.len() >= MAX_CACHED_KEYS {
+            let ttl = self.cache_ttl;
+            cache.retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+        }
+        cache.insert(key_hash, (Instant::now(), principal.clone()));
+        Ok(Some(principal))
+    }
+
+    /// The principal of `key` if it was found within the cache lifetime,
+    /// without reaching MongoDB.
+    pub fn cached(&self, key: &str) -> Option<Principal> {
+        let (cached_at, principal) = self.cache().get(&hash_key(key))?.clone();
+        (cached_at.elapsed() < self.cache_ttl).then_some(principal)
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Instant, Principal)>> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stores a new key and returns it together with its document. The key
+    /// itself cannot be recovered later.
+    pub async fn mint(&self, name: &str, scopes: Vec<Scope>) -> Result<(String, ApiKeyDoc), AppError> {
+        if name.trim().is_empty() {
+            return Err(AppError::BadRequest("the key name must not be empty".to_string()));
+        }
+        if scopes.is_empty() {
+            return Err(AppError::BadRequest("a key needs at least one scope".to_string()));
+        }
+        // Active names are unique through a partial index, so a revoked
+        // key's name can be given to a new one and two concurrent mints of
+        // the same name cannot both succeed.
+        let indexes = [
+            IndexModel::builder()
+                .keys(doc! { "key_hash": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "name": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .unique(true)
+                        .partial_filter_expression(doc! { "revoked_at": { "$type": "null" } })
+                        .build(),
+                )
+                .build(),
+        ];
+        self.collection.create_indexes(indexes).await?;
+
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let key = format!("{}{}", KEY_PREFIX, hex::encode(secret));
+        let api_key = ApiKeyDoc {
+            id: ObjectId::new(),
+            name: name.to_string(),
+            prefix: key[..DISPLAY_PREFIX_LEN].to_string(),
+            key_hash: hash_key(&key),
+            scopes,
+            created_at: bson::DateTime::now(),
+            revoked_at: None,
+        };
+        match self.collection.insert_one(&api_key).await {
+            Ok(_) => Ok((key, api_key)),
+            Err(e) if is_duplicate_key(&e) => {
+                Err(AppError::BadRequest(format!("an active key named {:?} already exists", name)))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Revokes the active key with this hex ID or name; returns how many were revoked.
+    pub async fn revoke(&self, id_or_name: &str) -> Result<u64, AppError> {
+        let selector = match ObjectId::parse_str(id_or_name) {
+            Ok(id) => doc! { "_id": id },
+            Err(_) => doc! { "name": id_or_name },
+        };
+        let filter = doc! { "$and": [selector, { "revoked_at": null }] };
+        let update = doc! { "$set": { "revoked_at": bson::DateTime::now() } };
+        let result = self.collection.update_many(filter, update).await?;
+        Ok(result.modified_count)
+    }
+
+    /// Every key, revoked ones included, oldest first.
+    pub async fn list(&self) -> Result<Vec<ApiKeyDoc>, AppError> {
+        let cursor = self.collection.find(doc! {}).sort(doc! { "created_at": 1 }).await?;
+        Ok(cursor.try_collect().await?)
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Credentials, frameworks::testing};
+
+    #[test]
+    fn keys_are_stored_as_their_sha256() {
+        assert_eq!(hash_key("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(hash_key("rk_a"), hash_key("rk_b"));
+    }
+
+    #[test]
+    fn scopes_allow_their_operations_only() {
+        let allowed = |scope: Scope| {
+            [Operation::Read, Operation::Write, Operation::Delete, Operation::Admin].map(|operation| scope.allows(operation))
+        };
+        assert_eq!(allowed(Scope::RestaurantsRead), [true, false, false, false]);
+        assert_eq!(allowed(Scope::RestaurantsWrite), [false, true, false, false]);
+        assert_eq!(allowed(Scope::Admin), [true, true, true, true]);
+    }
+
+    /// Keys found before are answered from the cache until it expires; the
+    /// operations they may perform are still checked.
+    #[tokio::test]
+    async fn cached_keys_authenticate_until_they_expire() {
+        let config = AuthConfig { enabled: true, ..AuthConfig::default() };
+        let auth = testing::authenticator(&config).await;
+        let principal = |name: &str| Principal { subject: format!("apikey:{}", name), grants: Grants::Scopes(vec![Scope::RestaurantsRead]) };
+        let api_keys = auth.api_keys();
+        api_keys.cache().insert(hash_key("rk_reader"), (Instant::now(), principal("reader")));
+        let stale = Instant::now().checked_sub(Duration::from_secs(config.api_key_cache_secs + 1)).unwrap();
+        api_keys.cache().insert(hash_key("rk_stale"), (stale, principal("stale")));
+
+        assert_eq!(api_keys.cached("rk_reader").map(|principal| principal.subject).as_deref(), Some("apikey:reader"));
+        assert!(api_keys.cached("rk_wrong").is_none());
+        assert!(api_keys.cached("rk_stale").is_none());
+
+        let request = RequestContext::cli("test");
+        let credentials = |key: &'static str| Credentials::from_headers(move |name| (name == "x-api-key").then_some(key));
+        let reader = auth.authorize_operation(&request, Operation::Read, &credentials("rk_reader")).await.unwrap();
+        assert_eq!(reader.map(|principal| principal.subject).as_deref(), Some("apikey:reader"));
+        let missing_scope = auth.authorize_operation(&request, Operation::Write, &credentials("rk_reader")).await;
+        assert!(matches!(missing_scope, Err(AppError::Forbidden(_))));
+        assert_eq!(auth.identify(&credentials("rk_wrong")), None);
+        let missing = auth.authorize_operation(&request, Operation::Read, &Credentials::default()).await;
+        assert!(matches!(missing, Err(AppError::Unauthorized(_))));
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs;
+
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, JwkSet},
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{Grants, Operation, Principal};
+use crate::{config::AuthConfig, error::AppError};
+
+/// Roles of the SSO, read from the claim named by `auth.jwt_roles_claim`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub fn allows(self, operation: Operation) -> bool {
+        match self {
+            Role::Viewer => operation == Operation::Read,
+            Role::Editor => matches!(operation, Operation::Read | Operation::Write),
+            Role::Admin => true,
+        }
+    }
+}
+
+/// Verifies bearer tokens: HS256 against `auth.jwt_secret`, RS256 against
+/// the keys of `auth.jwks_file`. A token is only checked with the kind of
+/// key its `alg` calls for, so an RSA public key can never be used as an
+/// HMAC secret.
+pub struct JwtVerifier {
+    secret: Option<DecodingKey>,
+    /// RSA keys of the JWKS, by `kid`.
+    rsa_keys: Vec<(Option<String>, DecodingKey)>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    roles_claim: String,
+}
+
+impl JwtVerifier {
+    /// `None` when neither a secret nor a JWKS file is configured.
+    pub fn from_config(config: &AuthConfig) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if config.jwt_secret.is_none() && config.jwks_file.is_none() {
+            return Ok(None);
+        }
+
+        let mut rsa_keys = Vec::new();
+        if let Some(path) = &config.jwks_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("could not read auth.jwks_file {}: {}", path.display(), e))?;
+            let jwks: JwkSet = serde_json::from_str(&contents)
+                .map_err(|e| format!("auth.jwks_file {} is not a JWKS: {}", path.display(), e))?;
+            for jwk in &jwks.keys {
+                if let AlgorithmParameters::RSA(rsa) = &jwk.algorithm {
+                    let key = DecodingKey::from_rsa_components(&rsa.n, &rsa.e)?;
+                    rsa_keys.push((jwk.common.key_id.clone(), key));
+                }
+            }
+            if rsa_keys.is_empty() {
+                return Err(format!("auth.jwks_file {} holds no RSA keys", path.display()).into());
+            }
+        }
+
+        Ok(Some(Self {
+            secret: config.jwt_secret.as_ref().map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            rsa_keys,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            roles_claim: config.jwt_roles_claim.clone(),
+        }))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Principal, AppError> {
+        let invalid = |reason: &str| AppError::Unauthorized(format!("invalid bearer token: {}", reason));
+
+        let header = decode_header(token).map_err(|_| invalid("malformed"))?;
+        let key = match header.alg {
+            Algorithm::HS256 => self.secret.as_ref(),
+            Algorithm::RS256 => match &header.kid {
+                Some(kid) => self.rsa_keys.iter().find(|(id, _)| id.as_deref() == Some(kid.as_str())),
+                None if self.rsa_keys.len() == 1 => self.rsa_keys.first(),
+                None => None,
+            }
+            .map(|(_, key)| key),
+            _ => None,
+        }
+        .ok_or_else(|| invalid("no key for its alg and kid"))?;
+
+        // `iss` and `aud` are only checked when present, so they are made required when configured
+        let mut validation = Validation::new(header.alg);
+        let mut required = vec!["exp", "sub"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
+
+        let claims = decode::<Value>(token, key, &validation)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => invalid("expired"),
+                ErrorKind::ImmatureSignature => invalid("not valid yet"),
+                ErrorKind::InvalidIssuer => invalid("wrong issuer"),
+                ErrorKind::InvalidAudience => invalid("wrong audience"),
+                ErrorKind::InvalidSignature => invalid("bad signature"),
+                ErrorKind::MissingRequiredClaim(claim) => invalid(&format!("no `{}` claim", claim)),
+                _ => invalid("malformed"),
+            })?
+            .claims;
+
+        let subject = claims["sub"].as_str().ok_or_else(|| invalid("`sub` is not a string"))?;
+        Ok(Principal {
+            subject: subject.to_string(),
+            grants: Grants::Roles(self.roles(&claims)),
+        })
+    }
+
+    /// The known roles in the roles claim, which may be nested
+    /// (`realm_access.roles`) and either an array or a single string.
+    fn roles(&self, claims: &Value) -> Vec<Role> {
+        let claim = self.roles_claim.split('.').fold(claims, |value, key| &value[key]);
+        let names: Vec<&Value> = match claim {
+            Value::Array(names) => names.iter().collect(),
+            name => vec![name],
+        };
+        names
+            .into_iter()
+            .filter_map(|name| Role::deserialize(name).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+    use crate::frameworks::{testing, Framework};
+
+    const SECRET: &str = "test-secret";
+    const ISSUER: &str = "https://sso.example.com";
+    const AUDIENCE: &str = "restaurants";
+    const RSA_PRIVATE_KEY: &[u8] = include_bytes!("testdata/rsa_private.pem");
+    const RSA_PUBLIC_KEY: &[u8] = include_bytes!("testdata/rsa_public.pem");
+
+    /// Accepts HS256 with `SECRET` and RS256 with the `test-key` of the JWKS.
+    fn verifier(configure: impl FnOnce(&mut AuthConfig)) -> JwtVerifier {
+        let mut config = AuthConfig {
+            jwt_secret: Some(SECRET.to_string()),
+            jwks_file: Some(PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src/auth/testdata/jwks.json"))),
+            jwt_issuer: Some(ISSUER.to_string()),
+            jwt_audience: Some(AUDIENCE.to_string()),
+            ..AuthConfig::default()
+        };
+        configure(&mut config);
+        JwtVerifier::from_config(&config).unwrap().unwrap()
+    }
+
+    fn claims() -> Value {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        json!({ "sub": "alice", "exp": now + 3600, "iss": ISSUER, "aud": AUDIENCE, "roles": ["editor", "auditor"] })
+    }
+
+    fn sign(alg: Algorithm, kid: Option<&str>, key: &EncodingKey, claims: &Value) -> String {
+        let header = Header { kid: kid.map(str::to_string), ..Header::new(alg) };
+        encode(&header, claims, key).unwrap()
+    }
+
+    fn hs256(claims: &Value) -> String {
+        sign(Algorithm::HS256, None, &EncodingKey::from_secret(SECRET.as_bytes()), claims)
+    }
+
+    fn rs256(kid: Option<&str>, claims: &Value) -> String {
+        sign(Algorithm::RS256, kid, &EncodingKey::from_rsa_pem(RSA_PRIVATE_KEY).unwrap(), claims)
+    }
+
+    /// Why `token` is refused.
+    fn rejection(verifier: &JwtVerifier, token: &str) -> String {
+        match verifier.verify(token) {
+            Err(AppError::Unauthorized(reason)) => reason,
+            other => panic!("expected a 401, got {:?}", other.map(|principal| principal.subject)),
+        }
+    }
+
+    #[test]
+    fn tokens_are_checked_with_the_key_their_alg_and_kid_select() {
+        let verifier = verifier(|_| {});
+        assert_eq!(verifier.verify(&hs256(&claims())).unwrap().subject, "alice");
+        assert_eq!(verifier.verify(&rs256(Some("test-key"), &claims())).unwrap().subject, "alice");
+        // The only RSA key is used for tokens without a `kid`
+        assert!(verifier.verify(&rs256(None, &claims())).is_ok());
+
+        assert!(rejection(&verifier, &rs256(Some("rotated-key"), &claims())).ends_with("no key for its alg and kid"));
+        let wrong_secret = sign(Algorithm::HS256, None, &EncodingKey::from_secret(b"guessed"), &claims());
+        assert!(rejection(&verifier, &wrong_secret).ends_with("bad signature"));
+        let hs384 = sign(Algorithm::HS384, None, &EncodingKey::from_secret(SECRET.as_bytes()), &claims());
+        assert!(rejection(&verifier, &hs384).ends_with("no key for its alg and kid"));
+        // The RSA public key is never tried as an HMAC secret
+        let confused = sign(Algorithm::HS256, None, &EncodingKey::from_secret(RSA_PUBLIC_KEY), &claims());
+        assert!(rejection(&verifier, &confused).ends_with("bad signature"));
+        let rsa_only = self::verifier(|config| config.jwt_secret = None);
+        assert!(rejection(&rsa_only, &confused).ends_with("no key for its alg and kid"));
+        assert!(rejection(&verifier, "not.a.token").ends_with("malformed"));
+    }
+
+    #[test]
+    fn expiry_and_the_configured_claims_are_required() {
+        let verifier = verifier(|_| {});
+        let with = |key: &str, value: Value| {
+            let mut claims = claims();
+            claims[key] = value;
+            hs256(&claims)
+        };
+        let without = |key: &str| {
+            let mut claims = claims();
+            claims.as_object_mut().unwrap().remove(key);
+            hs256(&claims)
+        };
+        assert!(rejection(&verifier, &with("exp", json!(1_600_000_000))).ends_with("expired"));
+        assert!(rejection(&verifier, &without("exp")).ends_with("no `exp` claim"));
+        assert!(rejection(&verifier, &without("sub")).ends_with("no `sub` claim"));
+        assert!(rejection(&verifier, &with("aud", json!("billing"))).ends_with("wrong audience"));
+        assert!(rejection(&verifier, &without("aud")).ends_with("no `aud` claim"));
+        assert!(rejection(&verifier, &with("iss", json!("https://evil.example.com"))).ends_with("wrong issuer"));
+        assert!(rejection(&verifier, &without("iss")).ends_with("no `iss` claim"));
+
+        // Without an issuer or audience configured, neither is needed
+        let lenient = self::verifier(|config| {
+            config.jwt_issuer = None;
+            config.jwt_audience = None;
+        });
+        let mut claims = claims();
+        claims.as_object_mut().unwrap().retain(|key, _| key == "sub" || key == "exp");
+        assert!(lenient.verify(&hs256(&claims)).is_ok());
+    }
+
+    #[test]
+    fn roles_are_read_from_the_configured_claim() {
+        let roles = |verifier: &JwtVerifier, claims: &Value| match verifier.verify(&hs256(claims)).unwrap().grants {
+            Grants::Roles(roles) => roles,
+            Grants::Scopes(_) => panic!("tokens grant roles"),
+        };
+        // Unknown roles are ignored
+        assert_eq!(roles(&verifier(|_| {}), &claims()), [Role::Editor]);
+
+        let nested = verifier(|config| config.jwt_roles_claim = "realm_access.roles".to_string());
+        let mut claims = claims();
+        claims["realm_access"] = json!({ "roles": "admin" });
+        assert_eq!(roles(&nested, &claims), [Role::Admin]);
+        claims.as_object_mut().unwrap().remove("realm_access");
+        assert!(roles(&nested, &claims).is_empty());
+
+        let allowed = |role: Role| {
+            [Operation::Read, Operation::Write, Operation::Delete, Operation::Admin].map(|operation| role.allows(operation))
+        };
+        assert_eq!(allowed(Role::Viewer), [true, false, false, false]);
+        assert_eq!(allowed(Role::Editor), [true, true, false, false]);
+        assert_eq!(allowed(Role::Admin), [true, true, true, true]);
+    }
+
+    /// A viewer may read but not delete, on every framework, and neither
+    /// check needs MongoDB with an HS256 token.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_forbids_viewers_to_delete() {
+        let mut config = testing::config();
+        config.auth.enabled = true;
+        config.auth.jwt_secret = Some(SECRET.to_string());
+        let mut claims = claims();
+        claims["roles"] = json!(["viewer"]);
+        let authorization = format!("Bearer {}", hs256(&claims));
+        let failures = testing::with_every_framework(config, |config| async move {
+            let mut failures = Vec::new();
+            let headers = [("Authorization", authorization.as_str())];
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                let delete = testing::send(addr, "DELETE", "/api/restaurants/5eb3d668b31de5d588f42a7e", &headers).await;
+                check("answers DELETE with 403", delete.status == 403);
+                // Past authorization, the ID is refused
+                let get = testing::send(addr, "GET", "/api/restaurants/not-an-object-id", &headers).await;
+                check("lets the viewer read", get.status == 400);
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "role checks differ:\n{}", failures.join("\n"));
+    }
+}
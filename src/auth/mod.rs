@@ -0,0 +1,260 @@
+mod api_keys;
+mod jwt;
+
+use std::fmt;
+use std::sync::Arc;
+
+pub use api_keys::{ApiKeys, Scope};
+pub use jwt::{JwtVerifier, Role};
+
+use mongodb::Database;
+
+use crate::{config::AuthConfig, error::AppError, request::RequestContext};
+
+/// Header carrying an API key.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// What a request does to the API, which decides the scope or role it needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    /// Creating or updating restaurants.
+    Write,
+    Delete,
+    /// Admin routes and bulk operations.
+    Admin,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Read => "read restaurants",
+            Operation::Write => "create or update restaurants",
+            Operation::Delete => "delete restaurants",
+            Operation::Admin => "use admin routes",
+        })
+    }
+}
+
+/// The operation a request performs: reads, writes and deletes of `/api`,
+/// anything under `/admin`, and reads for `/graphql`, whose mutations check
+/// their own operation. Health, metrics, the API documentation and GraphiQL
+/// stay open; the GraphQL web socket authenticates in its `connection_init`.
+pub fn required_operation(method: &str, path: &str) -> Option<Operation> {
+    let is_under = |prefix: &str| {
+        path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    if is_under("/admin") {
+        Some(Operation::Admin)
+    } else if path == "/graphql" {
+        (method != "OPTIONS").then_some(Operation::Read)
+    } else if is_under("/api") {
+        match method {
+            "GET" | "HEAD" => Some(Operation::Read),
+            // CORS preflights never carry credentials
+            "OPTIONS" => None,
+            "DELETE" => Some(Operation::Delete),
+            _ => Some(Operation::Write),
+        }
+    } else {
+        None
+    }
+}
+
+/// Who a request was authenticated as.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    /// The token's `sub`, or `apikey:<name>` for API keys. Recorded on writes.
+    pub subject: String,
+    pub grants: Grants,
+}
+
+#[derive(Debug, Clone)]
+pub enum Grants {
+    /// Scopes of an API key.
+    Scopes(Vec<Scope>),
+    /// Roles claimed by a bearer token.
+    Roles(Vec<Role>),
+}
+
+impl Principal {
+    pub fn allows(&self, operation: Operation) -> bool {
+        match &self.grants {
+            Grants::Scopes(scopes) => scopes.iter().any(|scope| scope.allows(operation)),
+            Grants::Roles(roles) => roles.iter().any(|role| role.allows(operation)),
+        }
+    }
+}
+
+/// Credentials sent with a request, copied out of its headers.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    api_key: Option<String>,
+    bearer: Option<String>,
+}
+
+impl Credentials {
+    /// `header` looks up a request header by its lower case name.
+    pub fn from_headers<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Self {
+        let bearer = header("authorization").and_then(|value| {
+            let (scheme, token) = value.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+        });
+        Self {
+            api_key: header(API_KEY_HEADER).map(str::to_string),
+            bearer,
+        }
+    }
+}
+
+/// Authenticates requests with a bearer token or an API key and checks
+/// that they may perform the operation of their route. Cheap to clone.
+#[derive(Clone)]
+pub struct Authenticator {
+    enabled: bool,
+    api_keys: ApiKeys,
+    jwt: Option<Arc<JwtVerifier>>,
+}
+
+impl Authenticator {
+    /// Fails if the JWKS file cannot be read or holds unusable keys.
+    pub fn new(db: &Database, config: &AuthConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            enabled: config.enabled,
+            api_keys: ApiKeys::new(db, config),
+            jwt: JwtVerifier::from_config(config)?.map(Arc::new),
+        })
+    }
+
+    pub fn api_keys(&self) -> &ApiKeys {
+        &self.api_keys
+    }
+
+    /// `WWW-Authenticate` value sent with 401 responses.
+    pub fn challenge(&self) -> &'static str {
+        if self.jwt.is_some() {
+            "Bearer, ApiKey header=\"X-API-Key\""
+        } else {
+            "ApiKey header=\"X-API-Key\""
+        }
+    }
+
+    /// The subject the credentials authenticate as, when that is known
+    /// without reaching MongoDB: from a valid bearer token, or an API key
+    /// found recently. `None` when auth is disabled.
+    pub fn identify(&self, credentials: &Credentials) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let principal = match (&credentials.bearer, &credentials.api_key, &self.jwt) {
+            (Some(token), _, Some(jwt)) => jwt.verify(token).ok()?,
+            (None, Some(key), _) => self.api_keys.cached(key)?,
+            _ => return None,
+        };
+        Some(principal.subject)
+    }
+
+    /// Checks the credentials sent with a request against the operation of
+    /// its route, and records the subject on the request. Returns `None`
+    /// when auth is disabled or the route is open.
+    pub async fn authorize(
+        &self,
+        request: &RequestContext,
+        method: &str,
+        path: &str,
+        credentials: &Credentials,
+    ) -> Result<Option<Principal>, AppError> {
+        match required_operation(method, path) {
+            Some(operation) => self.authorize_operation(request, operation, credentials).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the credentials for one operation, for requests that perform
+    /// several kinds, such as GraphQL mutations. Returns `None` when auth is
+    /// disabled.
+    pub async fn authorize_operation(
+        &self,
+        request: &RequestContext,
+        operation: Operation,
+        credentials: &Credentials,
+    ) -> Result<Option<Principal>, AppError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let principal = match (&credentials.bearer, &credentials.api_key, &self.jwt) {
+            (Some(token), _, Some(jwt)) => jwt.verify(token)?,
+            (Some(_), _, None) => {
+                return Err(AppError::Unauthorized("bearer tokens are not accepted".to_string()));
+            }
+            (None, Some(key), _) => self
+                .api_keys
+                .lookup(request, key)
+                .await?
+                .ok_or_else(|| AppError::Unauthorized("invalid or revoked API key".to_string()))?,
+            (None, None, _) => return Err(AppError::Unauthorized("missing credentials".to_string())),
+        };
+
+        request.set_subject(&principal.subject);
+        if !principal.allows(operation) {
+            return Err(AppError::Forbidden(format!("not allowed to {}", operation)));
+        }
+        Ok(Some(principal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frameworks::{testing, Framework};
+
+    #[test]
+    fn routes_require_the_operation_they_perform() {
+        let cases = [
+            ("GET", "/api/restaurants", Some(Operation::Read)),
+            ("HEAD", "/api/restaurants/5eb3d668b31de5d588f42a7e", Some(Operation::Read)),
+            ("POST", "/api/restaurants", Some(Operation::Write)),
+            ("PUT", "/api/restaurants/5eb3d668b31de5d588f42a7e", Some(Operation::Write)),
+            ("DELETE", "/api/restaurants/5eb3d668b31de5d588f42a7e", Some(Operation::Delete)),
+            ("OPTIONS", "/api/restaurants", None),
+            ("GET", "/admin/restaurants/scan", Some(Operation::Admin)),
+            ("POST", "/graphql", Some(Operation::Read)),
+            ("OPTIONS", "/graphql", None),
+            ("GET", "/apikeys", None),
+            ("GET", "/healthz", None),
+            ("GET", "/docs", None),
+        ];
+        for (method, path, operation) in cases {
+            assert_eq!(required_operation(method, path), operation, "{} {}", method, path);
+        }
+    }
+
+    /// Requests without credentials are refused before they reach MongoDB,
+    /// with a challenge naming the API key header; open routes stay open.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_challenges_requests_without_credentials() {
+        let mut config = testing::config();
+        config.auth.enabled = true;
+        let failures = testing::with_every_framework(config, |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                for (method, path) in [("GET", "/api/restaurants"), ("DELETE", "/api/restaurants/5eb3d668b31de5d588f42a7e")] {
+                    let response = testing::send(addr, method, path, &[]).await;
+                    check(&format!("{} {} answers 401", method, path), response.status == 401);
+                    let challenge = response.header("www-authenticate");
+                    check(&format!("{} {} sends the API key challenge", method, path), challenge == Some("ApiKey header=\"X-API-Key\""));
+                }
+                check("/healthz stays open", testing::send(addr, "GET", "/healthz", &[]).await.status == 200);
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "authentication differs:\n{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,367 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+
+use crate::{config::CompressionConfig, error::AppError, request::Headers};
+
+/// Content codings offered to clients, most preferred first when the
+/// client's `Accept-Encoding` weighs them the same.
+const ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// The token of `Accept-Encoding` and `Content-Encoding`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn parse(token: &str) -> Option<Self> {
+        ENCODINGS.into_iter().find(|encoding| token.eq_ignore_ascii_case(encoding.name())).or_else(|| {
+            token.eq_ignore_ascii_case("x-gzip").then_some(Encoding::Gzip)
+        })
+    }
+
+    /// Levels favour speed, as responses are compressed on every request.
+    fn encode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                brotli::BrotliCompress(&mut &body[..], &mut compressed, &brotli_params())?;
+                Ok(compressed)
+            }
+            Encoding::Zstd => zstd::encode_all(body, ZSTD_LEVEL),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn decoder<'a>(self, body: &'a [u8]) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+            Encoding::Zstd => Box::new(zstd::Decoder::with_buffer(body)?),
+            Encoding::Gzip => Box::new(GzDecoder::new(body)),
+        })
+    }
+}
+
+/// Compresses a body of unknown length as it is sent, flushing after every
+/// chunk so the client can decode each one as soon as it arrives.
+enum StreamEncoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl StreamEncoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Brotli => {
+                StreamEncoder::Brotli(Box::new(brotli::CompressorWriter::with_params(Vec::new(), 4096, &brotli_params())))
+            }
+            Encoding::Zstd => StreamEncoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+            Encoding::Gzip => StreamEncoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+        })
+    }
+
+    /// Compresses and flushes `chunk`, returning what is ready to send.
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        fn write_flushed(writer: &mut impl Write, chunk: &[u8]) -> io::Result<()> {
+            writer.write_all(chunk)?;
+            writer.flush()
+        }
+        let output = match self {
+            StreamEncoder::Brotli(writer) => {
+                write_flushed(writer.as_mut(), chunk)?;
+                writer.get_mut()
+            }
+            StreamEncoder::Zstd(writer) => {
+                write_flushed(writer, chunk)?;
+                writer.get_mut()
+            }
+            StreamEncoder::Gzip(writer) => {
+                write_flushed(writer, chunk)?;
+                writer.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    /// Ends the compressed stream, returning its last bytes.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Brotli(writer) => Ok(writer.into_inner()),
+            StreamEncoder::Zstd(writer) => writer.finish(),
+            StreamEncoder::Gzip(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Compresses a streamed response body with `encoding`, as `Compression::stream_encoding`
+/// chose it. Dropping the result drops `body`.
+pub fn compress_stream<B: AsRef<[u8]>>(
+    encoding: Encoding,
+    body: impl Stream<Item = io::Result<B>>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let state = StreamEncoder::new(encoding).map(|encoder| (Box::pin(body), encoder));
+    stream::unfold(Some(state), |state| async move {
+        let (mut body, mut encoder) = match state? {
+            Ok(state) => state,
+            Err(e) => return Some((Err(e), None)),
+        };
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                let compressed = encoder.encode(chunk.as_ref());
+                Some((compressed.map(Bytes::from), Some(Ok((body, encoder)))))
+            }
+            Some(Err(e)) => Some((Err(e), None)),
+            None => Some((encoder.finish().map(Bytes::from), None)),
+        }
+    })
+    .try_filter(|chunk| future::ready(!chunk.is_empty()))
+}
+
+/// Response compression and bulk import decompression, the same in every
+/// framework. Cheap to clone.
+#[derive(Clone)]
+pub struct Compression {
+    config: Arc<CompressionConfig>,
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self { config: Arc::new(config.clone()) }
+    }
+
+    /// Compresses a response body with the best encoding the request's
+    /// `Accept-Encoding` allows. Returns the headers to add, and the new body
+    /// if it was compressed: bodies under `min_size_bytes` and other than
+    /// text or the formats of the API are left alone. `header` looks up a
+    /// request header by its lower case name.
+    pub fn compress<'a>(
+        &self,
+        header: impl Fn(&str) -> Option<&'a str>,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> (Headers, Option<Vec<u8>>) {
+        let (mut headers, encoding) = self.choose(header, content_type);
+        let Some(encoding) = encoding.filter(|_| body.len() >= self.config.min_size_bytes) else {
+            return (headers, None);
+        };
+        match encoding.encode(body) {
+            Ok(compressed) if compressed.len() < body.len() => {
+                headers.push(("content-encoding", encoding.name().to_string()));
+                (headers, Some(compressed))
+            }
+            _ => (headers, None),
+        }
+    }
+
+    /// Like `compress`, for a streamed body of unknown length: the headers to
+    /// add, and the encoding to pass it through `compress_stream` with, if
+    /// any. `min_size_bytes` does not apply.
+    pub fn stream_encoding<'a>(
+        &self,
+        header: impl Fn(&str) -> Option<&'a str>,
+        content_type: Option<&str>,
+    ) -> (Headers, Option<Encoding>) {
+        let (mut headers, encoding) = self.choose(header, content_type);
+        if let Some(encoding) = encoding {
+            headers.push(("content-encoding", encoding.name().to_string()));
+        }
+        (headers, encoding)
+    }
+
+    /// The encoding for a response of `content_type`, and the `Vary` header
+    /// when the response depends on `Accept-Encoding` at all.
+    fn choose<'a>(&self, header: impl Fn(&str) -> Option<&'a str>, content_type: Option<&str>) -> (Headers, Option<Encoding>) {
+        if !self.config.enabled || !content_type.is_some_and(is_compressible) {
+            return (Vec::new(), None);
+        }
+        // Caches must not hand a compressed response to a client that cannot read it
+        (vec![("vary", "Accept-Encoding".to_string())], negotiate(header("accept-encoding")))
+    }
+
+    /// Upper bound of a bulk import body as received.
+    pub fn max_import_bytes(&self) -> usize {
+        self.config.max_import_bytes
+    }
+
+    /// The 413 for a bulk import body over `max_import_bytes` as received.
+    pub fn import_too_large(&self) -> AppError {
+        AppError::PayloadTooLarge(format!("body exceeds {} bytes", self.config.max_import_bytes))
+    }
+
+    /// Decodes a bulk import body sent with `content_encoding`. Fails with
+    /// 415 for unknown encodings and 413 when it inflates past
+    /// `max_import_bytes`.
+    pub fn decompress(&self, content_encoding: Option<&str>, body: &[u8]) -> Result<Vec<u8>, AppError> {
+        let token = content_encoding.map(str::trim).unwrap_or_default();
+        if token.is_empty() || token.eq_ignore_ascii_case("identity") {
+            return Ok(body.to_vec());
+        }
+        let encoding = Encoding::parse(token).ok_or_else(|| {
+            AppError::UnsupportedMediaType(format!("content encoding {} is not supported, use gzip, br or zstd", token))
+        })?;
+        let invalid = |e: io::Error| AppError::BadRequest(format!("body is not valid {}: {}", encoding.name(), e));
+
+        let limit = self.config.max_import_bytes;
+        let mut decoded = Vec::new();
+        encoding
+            .decoder(body)
+            .map_err(invalid)?
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(invalid)?;
+        if decoded.len() > limit {
+            return Err(AppError::PayloadTooLarge(format!("decompressed body exceeds {} bytes", limit)));
+        }
+        Ok(decoded)
+    }
+}
+
+/// Text, and the formats restaurants are negotiated in: their field names
+/// repeat in every document, so even BSON and MessagePack shrink well.
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/x-ndjson" | "application/bson" | "application/msgpack"
+        )
+}
+
+fn brotli_params() -> brotli::enc::BrotliEncoderParams {
+    brotli::enc::BrotliEncoderParams { quality: 4, ..Default::default() }
+}
+
+/// The acceptable encoding with the highest `q`, if any.
+fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let mut weights: [Option<f32>; ENCODINGS.len()] = [None; ENCODINGS.len()];
+    let mut wildcard = None;
+    for item in accept_encoding?.split(',') {
+        let mut parts = item.split(';');
+        let token = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if token == "*" {
+            wildcard = Some(q);
+        } else if let Some(encoding) = Encoding::parse(token) {
+            let index = ENCODINGS.iter().position(|e| *e == encoding).unwrap_or_default();
+            weights[index] = Some(q);
+        }
+    }
+    ENCODINGS
+        .into_iter()
+        .zip(weights)
+        .filter_map(|(encoding, q)| q.or(wildcard).map(|q| (encoding, q)))
+        .filter(|(_, q)| *q > 0.0)
+        // `max_by` keeps the last of equal weights, so reverse for the preferred one
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frameworks::{testing, Framework};
+
+    #[test]
+    fn negotiation_follows_weights_then_preference() {
+        assert_eq!(negotiate(Some("gzip, deflate, br, zstd")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(Some("gzip;q=1.0, br;q=0.5")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("*;q=0.1, zstd")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(Some("br;q=0, *")), Some(Encoding::Zstd));
+        assert_eq!(negotiate(Some("identity, deflate")), None);
+        assert_eq!(negotiate(None), None);
+    }
+
+    #[test]
+    fn every_encoding_round_trips_within_the_limit() {
+        let compression = Compression::new(&CompressionConfig { min_size_bytes: 16, max_import_bytes: 4096, ..Default::default() });
+        let body = br#"[{"name": "Morris Park Bake Shop", "cuisine": "Bakery"}]"#.repeat(20);
+        for encoding in ENCODINGS {
+            let (headers, compressed) =
+                compression.compress(|_| Some(encoding.name()), Some("application/json"), &body);
+            assert!(headers.contains(&("content-encoding", encoding.name().to_string())));
+            let decoded = compression.decompress(Some(encoding.name()), &compressed.unwrap()).unwrap();
+            assert_eq!(decoded, body);
+        }
+
+        let bomb = Encoding::Gzip.encode(&vec![b' '; 8192]).unwrap();
+        assert_eq!(compression.decompress(Some("gzip"), &bomb).unwrap_err().status(), 413);
+        assert_eq!(compression.decompress(Some("deflate"), &bomb).unwrap_err().status(), 415);
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_compressed_chunk_by_chunk() {
+        let compression = Compression::new(&CompressionConfig::default());
+        let chunks: Vec<Vec<u8>> = (0..20).map(|i| format!("{{\"name\": \"Restaurant {}\"}}\n", i).into_bytes()).collect();
+        for encoding in ENCODINGS {
+            let (headers, chosen) = compression.stream_encoding(|_| Some(encoding.name()), Some("application/x-ndjson"));
+            assert_eq!(chosen, Some(encoding));
+            assert!(headers.contains(&("content-encoding", encoding.name().to_string())));
+
+            let body = stream::iter(chunks.clone().into_iter().map(Ok));
+            let compressed: Vec<Bytes> = compress_stream(encoding, body).try_collect().await.unwrap();
+            // Every chunk is flushed as it arrives, the end of the stream follows
+            assert_eq!(compressed.len(), chunks.len() + 1);
+            let decoded = compression.decompress(Some(encoding.name()), &compressed.concat()).unwrap();
+            assert_eq!(decoded, chunks.concat());
+        }
+        let (headers, chosen) = compression.stream_encoding(|_| Some("gzip"), Some("image/png"));
+        assert!(headers.is_empty() && chosen.is_none());
+    }
+
+    /// Every framework must negotiate the same encoding, skip small bodies
+    /// and reject import bodies it cannot decode before touching MongoDB.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_compresses_the_same_way() {
+        let failures = testing::with_every_framework(testing::config(), |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+
+                for (accept, expected) in [("gzip", Some("gzip")), ("gzip;q=0.5, br", Some("br")), ("zstd", Some("zstd")), ("identity", None)] {
+                    let response = testing::send(addr, "GET", "/openapi.json", &[("Accept-Encoding", accept)]).await;
+                    check(&format!("Accept-Encoding: {} gives {:?}", accept, expected), response.header("content-encoding") == expected);
+                    check("large responses vary by Accept-Encoding", response.header("vary") == Some("Accept-Encoding"));
+                }
+                let small = testing::send(addr, "GET", "/healthz", &[("Accept-Encoding", "gzip")]).await;
+                check("small responses are not compressed", small.header("content-encoding").is_none());
+
+                let import = testing::send(addr, "POST", "/admin/restaurants/import", &[("Content-Encoding", "deflate")]).await;
+                check("imports in another encoding answer 415", import.status == 415);
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "compression differs:\n{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,782 @@
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use bson::{doc, oid::ObjectId, raw::{RawBsonRef, RawDocument}, Bson, DateTime, RawDocumentBuf};
+use bytes::Bytes;
+use futures::{future, stream::{self, BoxStream}, Stream, StreamExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    db::mongodb::MongoRepo,
+    error::AppError,
+    models::{
+        lenient::{DocumentWarning, Lenient},
+        restaurant::RestaurantResponse,
+    },
+    request::{self, RequestContext},
+};
+
+/// Documents joined into one chunk of a streamed body at most.
+const CHUNK_DOCUMENTS: usize = 64;
+
+/// Media types restaurants are answered in, most preferred first when the
+/// client's `Accept` weighs them the same.
+pub const MEDIA_TYPES: [MediaType; 5] = [
+    MediaType::Json,
+    MediaType::Bson,
+    MediaType::MessagePack,
+    MediaType::Ndjson,
+    MediaType::Csv,
+];
+
+/// Media types lenient lists are answered in, the ones that can carry the
+/// warnings next to the restaurants.
+const LENIENT_MEDIA_TYPES: [MediaType; 2] = [MediaType::Json, MediaType::MessagePack];
+
+/// Columns of `text/csv` responses: the address is flattened and the grades
+/// are summarized.
+const CSV_COLUMNS: [&str; 15] = [
+    "_id",
+    "restaurant_id",
+    "name",
+    "borough",
+    "cuisine",
+    "building",
+    "street",
+    "zipcode",
+    "longitude",
+    "latitude",
+    "grade_count",
+    "latest_grade",
+    "latest_score",
+    "latest_date",
+    "average_score",
+];
+
+/// A response body sent as it is encoded. Frameworks drop it when the client
+/// goes away, and with it the cursor behind it, which the driver then kills
+/// on the server.
+pub type BodyStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// The format of a request body, from its `Content-Type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFormat {
+    Json,
+    Bson,
+    MessagePack,
+}
+
+impl BodyFormat {
+    /// The format of a body sent with `content_type`, parameters such as
+    /// `charset` aside. Fails with 415 for anything else.
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Self, AppError> {
+        let essence = essence(content_type.unwrap_or_default());
+        match MediaType::parse(&essence) {
+            Some(MediaType::Json) => Ok(BodyFormat::Json),
+            Some(MediaType::Bson) => Ok(BodyFormat::Bson),
+            Some(MediaType::MessagePack) => Ok(BodyFormat::MessagePack),
+            _ if essence.is_empty() => Err(AppError::UnsupportedMediaType(
+                "Content-Type application/json, application/bson or application/msgpack is required".to_string(),
+            )),
+            _ => Err(AppError::UnsupportedMediaType(format!(
+                "content type {} is not supported, use application/json, application/bson or application/msgpack",
+                essence
+            ))),
+        }
+    }
+}
+
+/// A media type restaurants are answered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Json,
+    Bson,
+    MessagePack,
+    Ndjson,
+    Csv,
+}
+
+impl MediaType {
+    /// The `Content-Type` of responses of this type.
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            MediaType::Json => "application/json",
+            MediaType::Bson => "application/bson",
+            MediaType::MessagePack => "application/msgpack",
+            MediaType::Ndjson => "application/x-ndjson",
+            MediaType::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    /// A media type essence, in lower case, including the names MessagePack
+    /// and NDJSON went by before they were registered.
+    fn parse(essence: &str) -> Option<Self> {
+        match essence {
+            "application/json" => Some(MediaType::Json),
+            "application/bson" => Some(MediaType::Bson),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(MediaType::MessagePack)
+            }
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => Some(MediaType::Ndjson),
+            "text/csv" => Some(MediaType::Csv),
+            _ => None,
+        }
+    }
+
+    /// The acceptable type of `offered` with the highest `q`, the first
+    /// offered when there is no `Accept`. A type's `q` comes from the most specific range that
+    /// matches it, so `*/*;q=0.1, text/csv` prefers CSV. Fails with 406 when
+    /// no type is acceptable.
+    fn negotiate(accept: Option<&str>, offered: &[MediaType]) -> Result<Self, AppError> {
+        let accept = accept.map(str::trim).unwrap_or_default();
+        if accept.is_empty() {
+            return Ok(offered[0]);
+        }
+        // (specificity, q) of the best matching range, per type
+        let mut weights: Vec<Option<(u8, f32)>> = vec![None; offered.len()];
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let range = essence(parts.next().unwrap_or_default());
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let exact = MediaType::parse(&range);
+            for (&format, weight) in offered.iter().zip(weights.iter_mut()) {
+                let specificity = if exact == Some(format) {
+                    3
+                } else if range.strip_suffix("/*").is_some_and(|kind| format.content_type().starts_with(&format!("{}/", kind))) {
+                    2
+                } else if range == "*/*" {
+                    1
+                } else {
+                    continue;
+                };
+                if weight.is_none_or(|(best, _)| specificity > best) {
+                    *weight = Some((specificity, q));
+                }
+            }
+        }
+        offered
+            .iter()
+            .copied()
+            .zip(weights)
+            .filter_map(|(format, weight)| weight.map(|(_, q)| (format, q)))
+            .filter(|(_, q)| *q > 0.0)
+            // `max_by` keeps the last of equal weights, so reverse for the preferred one
+            .rev()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(format, _)| format)
+            .ok_or_else(|| {
+                let offered: Vec<_> = offered.iter().map(|format| essence(format.content_type())).collect();
+                AppError::NotAcceptable(format!("{} is not acceptable, use one of {}", accept, offered.join(", ")))
+            })
+    }
+}
+
+/// The format of a restaurant response: the media type negotiated from
+/// `Accept`, and how JSON, NDJSON and MessagePack write the bson types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseFormat {
+    pub media: MediaType,
+    /// Canonical Extended JSON, asked for with `?format=extended`, rather
+    /// than IDs as hex strings and dates in RFC 3339. BSON and CSV responses
+    /// are the same either way.
+    pub extended: bool,
+    /// Lists decoded leniently, asked for with `?lenient=true`: documents
+    /// that do not match the model are answered with the fields that do, or
+    /// skipped, and reported in `warnings` next to the restaurants.
+    pub lenient: bool,
+}
+
+impl From<MediaType> for ResponseFormat {
+    fn from(media: MediaType) -> Self {
+        ResponseFormat { media, extended: false, lenient: false }
+    }
+}
+
+impl ResponseFormat {
+    /// The format of the response to a request with `accept` and the query
+    /// string `query`. Fails with 406 when no media type is acceptable, or
+    /// none that lenient lists are answered in, and with 400 when `format`
+    /// is anything but `extended` or `lenient` anything but a boolean.
+    pub fn negotiate(accept: Option<&str>, query: Option<&str>) -> Result<Self, AppError> {
+        let lenient = request::query_param(query, "lenient");
+        let offered: &[MediaType] = if lenient == Some("true") { &LENIENT_MEDIA_TYPES } else { &MEDIA_TYPES };
+        let media = MediaType::negotiate(accept, offered)?;
+        let extended = match request::query_param(query, "format") {
+            None => false,
+            Some("extended") => true,
+            Some(format) => return Err(AppError::BadRequest(format!("format must be extended, got {:?}", format))),
+        };
+        let lenient = match lenient {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(lenient) => return Err(AppError::BadRequest(format!("lenient must be true or false, got {:?}", lenient))),
+        };
+        Ok(ResponseFormat { media, extended, lenient })
+    }
+
+    /// The `Content-Type` of responses in this format.
+    pub const fn media_type(self) -> &'static str {
+        self.media.content_type()
+    }
+
+    /// Encodes one restaurant: a JSON or MessagePack object, a BSON
+    /// document, one NDJSON line, or a CSV header and row.
+    pub fn encode_one(self, restaurant: &RestaurantResponse) -> Result<Vec<u8>, AppError> {
+        match self.media {
+            MediaType::Json => serde_json::to_vec(&self.to_json(restaurant)?).map_err(encoding_error),
+            MediaType::Bson => bson::to_vec(restaurant).map_err(encoding_error),
+            MediaType::MessagePack => to_msgpack(&self.to_json(restaurant)?),
+            MediaType::Ndjson | MediaType::Csv => self.encode_many(std::slice::from_ref(restaurant)),
+        }
+    }
+
+    /// Encodes a list of restaurants: a JSON or MessagePack array, BSON
+    /// documents one after the other, one NDJSON line each, or a CSV header
+    /// and a row each.
+    pub fn encode_many(self, restaurants: &[RestaurantResponse]) -> Result<Vec<u8>, AppError> {
+        let to_json = || restaurants.iter().map(|restaurant| self.to_json(restaurant)).collect::<Result<Vec<_>, _>>();
+        match self.media {
+            MediaType::Json => serde_json::to_vec(&to_json()?).map_err(encoding_error),
+            MediaType::MessagePack => to_msgpack(&to_json()?),
+            MediaType::Bson => {
+                let mut body = Vec::new();
+                for restaurant in restaurants {
+                    body.extend(bson::to_vec(restaurant).map_err(encoding_error)?);
+                }
+                Ok(body)
+            }
+            MediaType::Ndjson => {
+                let mut body = Vec::new();
+                for restaurant in restaurants {
+                    serde_json::to_writer(&mut body, &self.to_json(restaurant)?).map_err(encoding_error)?;
+                    body.push(b'\n');
+                }
+                Ok(body)
+            }
+            MediaType::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                writer.write_record(CSV_COLUMNS).map_err(encoding_error)?;
+                for restaurant in restaurants {
+                    writer.write_record(csv_record(restaurant)).map_err(encoding_error)?;
+                }
+                writer.into_inner().map_err(encoding_error)
+            }
+        }
+    }
+
+    /// Encodes restaurants like `encode_many`, a chunk at a time as they
+    /// arrive. A MessagePack array starts with its length, so that format is
+    /// collected first and sent in one chunk.
+    pub fn encode_stream(self, restaurants: BoxStream<'static, Result<RestaurantResponse, AppError>>) -> BodyStream {
+        match self.media {
+            MediaType::Json => {
+                chunked(restaurants.map(move |restaurant| self.encode_one(&restaurant?)), b"[", b",", b"]")
+            }
+            MediaType::Bson | MediaType::Ndjson => {
+                chunked(restaurants.map(move |restaurant| self.encode_one(&restaurant?)), b"", b"", b"")
+            }
+            MediaType::Csv => {
+                let rows = restaurants.map(|restaurant| csv_line(csv_record(&restaurant?)));
+                chunked(stream::once(future::ready(csv_line(CSV_COLUMNS))).chain(rows), b"", b"", b"")
+            }
+            MediaType::MessagePack => {
+                let all = stream::once(async move { self.encode_many(&restaurants.try_collect::<Vec<_>>().await?) });
+                chunked(all, b"", b"", b"")
+            }
+        }
+    }
+
+    /// Encodes a lenient list, a JSON or MessagePack object with the
+    /// `restaurants` that could be read and the `warnings` about the
+    /// documents that did not match. JSON restaurants are streamed like
+    /// `encode_stream`, and the warnings gathered on the way sent after them.
+    pub fn encode_lenient_stream(self, documents: BoxStream<'static, Result<Lenient, AppError>>) -> BodyStream {
+        let warnings = Arc::new(Mutex::new(Vec::<DocumentWarning>::new()));
+        let found = warnings.clone();
+        let restaurants = documents.try_filter_map(move |document| {
+            found.lock().unwrap_or_else(PoisonError::into_inner).extend(document.warning);
+            future::ready(Ok(document.restaurant.map(RestaurantResponse::from)))
+        });
+        let take_warnings = move || std::mem::take(&mut *warnings.lock().unwrap_or_else(PoisonError::into_inner));
+        match self.media {
+            MediaType::MessagePack => {
+                let all = stream::once(async move {
+                    let restaurants = restaurants.and_then(|restaurant| future::ready(self.to_json(&restaurant))).try_collect::<Vec<_>>().await?;
+                    to_msgpack(&serde_json::json!({ "restaurants": restaurants, "warnings": take_warnings() }))
+                });
+                chunked(all, b"", b"", b"")
+            }
+            _ => {
+                let restaurants = restaurants.map(move |restaurant| self.encode_one(&restaurant?));
+                let warnings = stream::once(async move {
+                    let mut chunk = b",\"warnings\":".to_vec();
+                    serde_json::to_writer(&mut chunk, &take_warnings()).map_err(io::Error::other)?;
+                    chunk.push(b'}');
+                    Ok(Bytes::from(chunk))
+                });
+                chunked(restaurants, b"{\"restaurants\":[", b",", b"]").chain(warnings).boxed()
+            }
+        }
+    }
+
+    /// `restaurant` as JSON, NDJSON and MessagePack write it.
+    fn to_json(self, restaurant: &RestaurantResponse) -> Result<Value, AppError> {
+        let document = bson::to_bson(restaurant).map_err(encoding_error)?;
+        Ok(if self.extended { document.into_canonical_extjson() } else { api_json(document) })
+    }
+}
+
+/// `value` as the API writes it to JSON: IDs as hex strings, dates in
+/// RFC 3339 and the other bson types as relaxed Extended JSON.
+fn api_json(value: Bson) -> Value {
+    match value {
+        Bson::ObjectId(id) => Value::String(id.to_hex()),
+        Bson::DateTime(date) => Value::String(rfc3339(date)),
+        Bson::Document(document) => Value::Object(document.into_iter().map(|(key, value)| (key, api_json(value))).collect()),
+        Bson::Array(values) => Value::Array(values.into_iter().map(api_json).collect()),
+        value => value.into_relaxed_extjson(),
+    }
+}
+
+/// Joins encoded documents into chunks between `open` and `close`, each
+/// chunk with the documents the cursor already holds so none waits on
+/// MongoDB for the next batch.
+fn chunked(
+    documents: impl Stream<Item = Result<Vec<u8>, AppError>> + Send + 'static,
+    open: &'static [u8],
+    separator: &'static [u8],
+    close: &'static [u8],
+) -> BodyStream {
+    let mut first = true;
+    let chunks = documents.ready_chunks(CHUNK_DOCUMENTS).map(move |documents| {
+        let mut chunk = Vec::new();
+        for document in documents {
+            if !std::mem::take(&mut first) {
+                chunk.extend_from_slice(separator);
+            }
+            chunk.extend(document?);
+        }
+        Ok(Bytes::from(chunk))
+    });
+    stream::once(future::ready(Ok(Bytes::from_static(open))))
+        .chain(chunks)
+        .chain(stream::once(future::ready(Ok(Bytes::from_static(close)))))
+        .try_filter(|chunk| future::ready(!chunk.is_empty()))
+        .map_err(|e: AppError| {
+            warn!(error = %e, "response body cut short");
+            io::Error::other(e)
+        })
+        .boxed()
+}
+
+/// The body of `GET /api/restaurants/{id}` in `format`. BSON is the
+/// document byte for byte as the driver read it, never decoded.
+pub async fn read_restaurant(
+    repo: &MongoRepo,
+    request: &RequestContext,
+    id: ObjectId,
+    format: ResponseFormat,
+) -> Result<Vec<u8>, AppError> {
+    match format.media {
+        MediaType::Bson => Ok(repo.get_restaurant_raw(request, id).await?.into_bytes()),
+        _ => format.encode_one(&repo.get_restaurant_by_id(request, id).await?.into()),
+    }
+}
+
+/// The body of `GET /api/restaurants` in `format`, streamed from the cursor:
+/// only the batch the driver holds is in memory, and the next batch is not
+/// fetched until the client has taken this one. BSON is the documents byte
+/// for byte as the driver read them. Lenient lists are decoded leniently,
+/// see `encode_lenient_stream`. Fails before anything is sent when the
+/// query does; later errors cut the body short.
+pub async fn stream_restaurants(
+    repo: &MongoRepo,
+    request: &RequestContext,
+    limit: i64,
+    format: ResponseFormat,
+) -> Result<BodyStream, AppError> {
+    Ok(match format.media {
+        _ if format.lenient => format.encode_lenient_stream(repo.stream_restaurants_lenient(request, limit).await?),
+        MediaType::Bson => {
+            let documents = repo.stream_restaurants_raw(request, limit).await?;
+            chunked(documents.map_ok(RawDocumentBuf::into_bytes), b"", b"", b"")
+        }
+        _ => {
+            let restaurants = repo.stream_restaurants(request, doc! {}, limit).await?;
+            format.encode_stream(restaurants.map_ok(RestaurantResponse::from).boxed())
+        }
+    })
+}
+
+/// Decodes a BSON body, one document. Fails with 400 when it is not BSON
+/// and with 422 when it nests deeper than `max_depth` or does not have the
+/// shape of `T`.
+pub fn from_bson<T: DeserializeOwned>(body: &[u8], max_depth: usize) -> Result<T, AppError> {
+    let document = RawDocument::from_bytes(body).map_err(invalid_bson)?;
+    check_bson_depth(document, max_depth)?;
+    bson::from_slice(body).map_err(|e| AppError::Unprocessable(e.to_string()))
+}
+
+/// Decodes a body of BSON documents one after the other, each like
+/// `from_bson`.
+pub fn from_bson_sequence<T: DeserializeOwned>(mut body: &[u8], max_depth: usize) -> Result<Vec<T>, AppError> {
+    let mut decoded = Vec::new();
+    while !body.is_empty() {
+        let length = body
+            .get(..4)
+            .map(|prefix| i32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]))
+            .and_then(|length| usize::try_from(length).ok())
+            .filter(|length| *length <= body.len())
+            .ok_or_else(|| AppError::BadRequest("body is not valid BSON: document length incorrect".to_string()))?;
+        let (document, rest) = body.split_at(length);
+        decoded.push(from_bson(document, max_depth)?);
+        body = rest;
+    }
+    Ok(decoded)
+}
+
+/// Decodes a MessagePack body, read the way JSON is: the bson types as
+/// strings or in their Extended JSON form. Fails like `from_bson`.
+pub fn from_msgpack<T: DeserializeOwned>(body: &[u8], max_depth: usize) -> Result<T, AppError> {
+    let mut deserializer = rmp_serde::Deserializer::from_read_ref(body).with_human_readable();
+    // The top level counts as one, like JSON's outermost brackets
+    deserializer.set_max_depth(max_depth + 1);
+    T::deserialize(&mut deserializer).map_err(|e| match e {
+        rmp_serde::decode::Error::DepthLimitExceeded => {
+            AppError::Unprocessable(format!("body nests deeper than {} levels", max_depth))
+        }
+        rmp_serde::decode::Error::InvalidMarkerRead(_)
+        | rmp_serde::decode::Error::InvalidDataRead(_)
+        | rmp_serde::decode::Error::Utf8Error(_) => AppError::BadRequest(format!("body is not valid MessagePack: {}", e)),
+        _ => AppError::Unprocessable(e.to_string()),
+    })
+}
+
+/// Fails with 422 when documents and arrays in `document` nest deeper than
+/// `max`, and with 400 when an element is malformed. Walked with a stack of
+/// its own so a hostile body never reaches the parser's recursion.
+fn check_bson_depth(document: &RawDocument, max: usize) -> Result<(), AppError> {
+    type Elements<'a> = Box<dyn Iterator<Item = bson::raw::Result<RawBsonRef<'a>>> + 'a>;
+    fn elements(document: &RawDocument) -> Elements<'_> {
+        Box::new(document.iter().map(|element| element.map(|(_, value)| value)))
+    }
+    let mut stack = vec![elements(document)];
+    while let Some(level) = stack.last_mut() {
+        let nested: Elements<'_> = match level.next() {
+            None => {
+                stack.pop();
+                continue;
+            }
+            Some(Err(e)) => return Err(invalid_bson(e)),
+            Some(Ok(RawBsonRef::Document(document))) => elements(document),
+            Some(Ok(RawBsonRef::Array(array))) => Box::new(array.into_iter()),
+            Some(Ok(_)) => continue,
+        };
+        if stack.len() == max {
+            return Err(AppError::Unprocessable(format!("body nests deeper than {} levels", max)));
+        }
+        stack.push(nested);
+    }
+    Ok(())
+}
+
+fn invalid_bson(e: bson::raw::Error) -> AppError {
+    AppError::BadRequest(format!("body is not valid BSON: {}", e))
+}
+
+/// MessagePack written the way JSON is, so both have the same fields.
+fn to_msgpack<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, AppError> {
+    let mut body = Vec::new();
+    value
+        .serialize(&mut rmp_serde::Serializer::new(&mut body).with_struct_map().with_human_readable())
+        .map_err(encoding_error)?;
+    Ok(body)
+}
+
+fn encoding_error(e: impl std::fmt::Display) -> AppError {
+    AppError::Encoding(e.to_string())
+}
+
+/// A media type or range without its parameters, in lower case.
+fn essence(value: &str) -> String {
+    value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+fn csv_record(restaurant: &RestaurantResponse) -> [String; CSV_COLUMNS.len()] {
+    let address = restaurant.address.clone().unwrap_or_default();
+    let coord = |index: usize| address.coord.get(index).map(f64::to_string).unwrap_or_default();
+    let latest = restaurant.grades.iter().max_by_key(|grade| grade.date);
+    let average = (!restaurant.grades.is_empty()).then(|| {
+        let total: i64 = restaurant.grades.iter().map(|grade| i64::from(grade.score)).sum();
+        format!("{:.1}", total as f64 / restaurant.grades.len() as f64)
+    });
+    [
+        restaurant.id.map(|id| id.to_hex()).unwrap_or_default(),
+        restaurant.restaurant_id.clone(),
+        restaurant.name.clone(),
+        restaurant.borough.clone(),
+        restaurant.cuisine.clone(),
+        address.building.clone(),
+        address.street.clone(),
+        address.zipcode.clone(),
+        coord(0),
+        coord(1),
+        restaurant.grades.len().to_string(),
+        latest.map(|grade| grade.grade.clone()).unwrap_or_default(),
+        latest.map(|grade| grade.score.to_string()).unwrap_or_default(),
+        latest.map(|grade| rfc3339(grade.date)).unwrap_or_default(),
+        average.unwrap_or_default(),
+    ]
+}
+
+/// One CSV record and its line break.
+fn csv_line<T: AsRef<[u8]>>(record: impl IntoIterator<Item = T>) -> Result<Vec<u8>, AppError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(record).map_err(encoding_error)?;
+    writer.into_inner().map_err(encoding_error)
+}
+
+fn rfc3339(date: DateTime) -> String {
+    date.try_to_rfc3339_string().unwrap_or_else(|_| date.timestamp_millis().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        frameworks::{testing, Framework},
+        error::FieldError,
+        models::restaurant::{Address, Grade, RestaurantDoc},
+    };
+
+    #[test]
+    fn negotiation_follows_specificity_then_weights() {
+        let negotiate = |accept| ResponseFormat::negotiate(accept, None).unwrap().media;
+        assert_eq!(negotiate(None), MediaType::Json);
+        assert_eq!(negotiate(Some("*/*")), MediaType::Json);
+        assert_eq!(negotiate(Some("application/bson")), MediaType::Bson);
+        assert_eq!(negotiate(Some("application/x-msgpack")), MediaType::MessagePack);
+        assert_eq!(negotiate(Some("text/*")), MediaType::Csv);
+        assert_eq!(negotiate(Some("*/*;q=0.1, text/csv")), MediaType::Csv);
+        assert_eq!(negotiate(Some("application/json;q=0.5, application/x-ndjson")), MediaType::Ndjson);
+        assert_eq!(negotiate(Some("application/*, application/json;q=0")), MediaType::Bson);
+        for accept in ["image/png", "text/html", "application/json;q=0", "*/*;q=0"] {
+            assert_eq!(ResponseFormat::negotiate(Some(accept), None).unwrap_err().status(), 406, "{}", accept);
+        }
+        assert!(ResponseFormat::negotiate(None, Some("limit=5&format=extended")).unwrap().extended);
+        assert!(!ResponseFormat::negotiate(None, Some("limit=5")).unwrap().extended);
+        assert_eq!(ResponseFormat::negotiate(None, Some("format=relaxed")).unwrap_err().status(), 400);
+    }
+
+    #[test]
+    fn only_json_bson_and_msgpack_bodies_are_accepted() {
+        assert_eq!(BodyFormat::from_content_type(Some("application/bson")).unwrap(), BodyFormat::Bson);
+        assert_eq!(BodyFormat::from_content_type(Some("Application/MsgPack")).unwrap(), BodyFormat::MessagePack);
+        assert_eq!(BodyFormat::from_content_type(Some("application/json; charset=utf-8")).unwrap(), BodyFormat::Json);
+        for content_type in [None, Some("text/csv"), Some("application/x-ndjson"), Some("application/xml")] {
+            assert_eq!(BodyFormat::from_content_type(content_type).unwrap_err().status(), 415);
+        }
+    }
+
+    #[test]
+    fn binary_bodies_are_decoded_within_the_depth_limit() {
+        let restaurant = doc! { "name": "Nordic Delicacies", "address": { "coord": [-73.9, 40.7] } };
+        let bson = bson::to_vec(&restaurant).unwrap();
+        let decoded: RestaurantDoc = from_bson(&bson, 3).unwrap();
+        assert_eq!(decoded.address.unwrap().coord, vec![-73.9, 40.7]);
+        assert_eq!(from_bson::<RestaurantDoc>(&bson, 2).unwrap_err().status(), 422);
+        assert_eq!(from_bson::<RestaurantDoc>(&bson[..bson.len() - 1], 3).unwrap_err().status(), 400);
+        assert_eq!(from_bson::<RestaurantDoc>(&bson::to_vec(&doc! { "name": 5 }).unwrap(), 3).unwrap_err().status(), 422);
+
+        let sequence = [bson.clone(), bson.clone()].concat();
+        assert_eq!(from_bson_sequence::<RestaurantDoc>(&sequence, 3).unwrap().len(), 2);
+        assert_eq!(from_bson_sequence::<RestaurantDoc>(&sequence[..sequence.len() - 2], 3).unwrap_err().status(), 400);
+
+        let msgpack = to_msgpack(&restaurant).unwrap();
+        let decoded: RestaurantDoc = from_msgpack(&msgpack, 3).unwrap();
+        assert_eq!(decoded.name, "Nordic Delicacies");
+        assert_eq!(from_msgpack::<RestaurantDoc>(&msgpack, 2).unwrap_err().status(), 422);
+        assert_eq!(from_msgpack::<RestaurantDoc>(&msgpack[..msgpack.len() - 1], 3).unwrap_err().status(), 400);
+        assert_eq!(from_msgpack::<RestaurantDoc>(&to_msgpack(&doc! { "name": 5 }).unwrap(), 3).unwrap_err().status(), 422);
+    }
+
+    #[test]
+    fn encoded_restaurants_read_back_the_same() {
+        let restaurant = RestaurantResponse {
+            id: Some(ObjectId::parse_str("5eb3d668b31de5d588f42a7e").unwrap()),
+            name: "Morris Park Bake Shop".to_string(),
+            borough: "Bronx".to_string(),
+            address: Some(Address { coord: vec![-73.856077, 40.848447], zipcode: "10462".to_string(), ..Default::default() }),
+            grades: vec![
+                Grade { date: DateTime::from_millis(1_393_804_800_000), grade: "A".to_string(), score: 2 },
+                Grade { date: DateTime::from_millis(1_367_366_400_000), grade: "B".to_string(), score: 7 },
+            ],
+            ..Default::default()
+        };
+
+        let msgpack: RestaurantDoc = from_msgpack(&ResponseFormat::from(MediaType::MessagePack).encode_one(&restaurant).unwrap(), 8).unwrap();
+        assert_eq!(msgpack.id, restaurant.id);
+        assert_eq!(msgpack.grades[1].date, restaurant.grades[1].date);
+        let bson: Vec<RestaurantDoc> =
+            from_bson_sequence(&ResponseFormat::from(MediaType::Bson).encode_many(&[restaurant.clone(), restaurant.clone()]).unwrap(), 8).unwrap();
+        assert_eq!(bson[1].id, restaurant.id);
+
+        let ndjson = ResponseFormat::from(MediaType::Ndjson).encode_many(&[restaurant.clone(), restaurant.clone()]).unwrap();
+        assert_eq!(ndjson.iter().filter(|byte| **byte == b'\n').count(), 2);
+
+        let csv = String::from_utf8(ResponseFormat::from(MediaType::Csv).encode_one(&restaurant).unwrap()).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next().unwrap(), CSV_COLUMNS.join(","));
+        assert_eq!(
+            lines.next().unwrap(),
+            "5eb3d668b31de5d588f42a7e,,Morris Park Bake Shop,Bronx,,,,10462,-73.856077,40.848447,2,A,2,2014-03-03T00:00:00Z,4.5"
+        );
+    }
+
+    #[test]
+    fn ids_and_dates_are_strings_unless_extended_json_is_asked_for() {
+        let restaurant = restaurant(1);
+        let json: Value = serde_json::from_slice(&ResponseFormat::from(MediaType::Json).encode_one(&restaurant).unwrap()).unwrap();
+        assert_eq!(json["_id"], "000000000000000000000001");
+        assert_eq!(json["grades"][0]["date"], "2014-03-03T00:00:00Z");
+        assert_eq!(json["grades"][0]["score"], 2);
+
+        let extended = ResponseFormat { extended: true, ..ResponseFormat::from(MediaType::Json) };
+        let extended: Value = serde_json::from_slice(&extended.encode_one(&restaurant).unwrap()).unwrap();
+        assert_eq!(extended["_id"], json!({ "$oid": "000000000000000000000001" }));
+        assert_eq!(extended["grades"][0]["date"], json!({ "$date": { "$numberLong": "1393804800000" } }));
+        assert_eq!(extended["grades"][0]["score"], json!({ "$numberInt": "2" }));
+
+        let canonical = json!({ "_id": extended["_id"], "grades": [{ "date": extended["grades"][0]["date"], "grade": "A", "score": 2 }] });
+        let relaxed = json!({ "_id": extended["_id"], "grades": [{ "date": { "$date": "2014-03-03T00:00:00Z" }, "grade": "A", "score": 2 }] });
+        for body in [json, canonical, relaxed] {
+            let decoded: RestaurantDoc = serde_json::from_value(body).unwrap();
+            assert_eq!(decoded.id, restaurant.id);
+            assert_eq!(decoded.grades[0].date, restaurant.grades[0].date);
+        }
+        for body in [json!({ "_id": "not-an-object-id" }), json!({ "_id": 5 }), json!({ "grades": [{ "date": "yesterday", "grade": "A", "score": 2 }] })] {
+            assert!(serde_json::from_value::<RestaurantDoc>(body.clone()).is_err(), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_lists_match_the_buffered_ones() {
+        let all: Vec<_> = (0..150).map(restaurant).collect();
+        for (media, extended) in MEDIA_TYPES.into_iter().flat_map(|media| [(media, false), (media, true)]) {
+            let format = ResponseFormat { extended, ..ResponseFormat::from(media) };
+            let chunks: Vec<_> = format.encode_stream(restaurants(150)).try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), format.encode_many(&all).unwrap(), "{:?}", format);
+        }
+        assert_eq!(ResponseFormat::from(MediaType::Json).encode_stream(restaurants(0)).try_collect::<Vec<_>>().await.unwrap().concat(), b"[]");
+
+        let failing = stream::iter([Ok(restaurant(0)), Err(AppError::NotFound)]).boxed();
+        assert!(ResponseFormat::from(MediaType::Ndjson).encode_stream(failing).try_collect::<Vec<_>>().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lenient_lists_carry_warnings_after_the_restaurants() {
+        let warning = DocumentWarning {
+            id: Some("5eb3d668b31de5d588f42a7e".to_string()),
+            skipped: false,
+            errors: vec![FieldError { field: "grades[0].score".to_string(), message: "invalid type".to_string() }],
+        };
+        let stored = |index| RestaurantDoc { id: restaurant(index).id, name: "Dirty".to_string(), ..Default::default() };
+        let documents = || {
+            stream::iter([
+                Ok(Lenient { restaurant: Some(stored(0)), warning: None }),
+                Ok(Lenient { restaurant: Some(stored(1)), warning: Some(warning.clone()) }),
+                Ok(Lenient { restaurant: None, warning: Some(DocumentWarning { id: None, skipped: true, ..warning.clone() }) }),
+            ])
+            .boxed()
+        };
+        let lenient = |media| ResponseFormat { lenient: true, ..ResponseFormat::from(media) };
+        let json: Value = serde_json::from_slice(
+            &lenient(MediaType::Json).encode_lenient_stream(documents()).try_collect::<Vec<_>>().await.unwrap().concat(),
+        )
+        .unwrap();
+        assert_eq!(json["restaurants"].as_array().unwrap().len(), 2);
+        assert_eq!(json["restaurants"][1]["_id"], "000000000000000000000001");
+        assert_eq!(json["warnings"][0]["errors"][0]["field"], "grades[0].score");
+        assert_eq!(json["warnings"][1]["skipped"], true);
+        let msgpack: Value = from_msgpack(
+            &lenient(MediaType::MessagePack).encode_lenient_stream(documents()).try_collect::<Vec<_>>().await.unwrap().concat(),
+            8,
+        )
+        .unwrap();
+        assert_eq!(msgpack, json);
+
+        let none = lenient(MediaType::Json).encode_lenient_stream(stream::empty().boxed());
+        assert_eq!(none.try_collect::<Vec<_>>().await.unwrap().concat(), br#"{"restaurants":[],"warnings":[]}"#);
+    }
+
+    fn restaurant(index: usize) -> RestaurantResponse {
+        let mut id = [0; 12];
+        id[4..].copy_from_slice(&(index as u64).to_be_bytes());
+        RestaurantResponse {
+            id: Some(ObjectId::from_bytes(id)),
+            restaurant_id: format!("{:08}", index),
+            name: format!("Restaurant {}", index),
+            borough: "Brooklyn".to_string(),
+            cuisine: "Bakery".to_string(),
+            address: Some(Address { coord: vec![-73.9, 40.7], street: "Flatbush Avenue".to_string(), ..Default::default() }),
+            grades: vec![Grade { date: DateTime::from_millis(1_393_804_800_000), grade: "A".to_string(), score: 2 }],
+            ..Default::default()
+        }
+    }
+
+    /// `count` restaurants, made up as they are read.
+    fn restaurants(count: usize) -> BoxStream<'static, Result<RestaurantResponse, AppError>> {
+        stream::iter((0..count).map(|index| Ok(restaurant(index)))).boxed()
+    }
+
+    /// Every framework must negotiate formats and reject bodies in other
+    /// formats the same way, before touching MongoDB.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_negotiates_the_same_way() {
+        let mut config = testing::config();
+        config.limits.max_json_depth = 2;
+        let deep = bson::to_vec(&doc! { "address": { "coord": [1.0] } }).unwrap();
+        let wrong_shape = to_msgpack(&doc! { "name": 5 }).unwrap();
+        let failures = testing::with_every_framework(config, |config| async move {
+            let id = "/api/restaurants/5eb3d668b31de5d588f42a7e";
+            let cases = [
+                ("GET", "/api/restaurants", "image/png", "application/json", b"".as_slice(), 406),
+                ("GET", "/api/restaurants?limit=0", "*/*", "application/json", b"".as_slice(), 400),
+                ("GET", "/api/restaurants?format=relaxed", "*/*", "application/json", b"".as_slice(), 400),
+                ("GET", "/api/restaurants?lenient=true", "text/csv", "application/json", b"".as_slice(), 406),
+                ("GET", "/api/restaurants?lenient=yes", "*/*", "application/json", b"".as_slice(), 400),
+                ("GET", "/api/restaurants/5eb3d668b31de5d588f42a7e?format=", "*/*", "application/json", b"".as_slice(), 400),
+                ("GET", "/api/restaurants/not-an-object-id", "text/html", "application/json", b"".as_slice(), 406),
+                ("GET", "/api/restaurants/not-an-object-id", "text/csv", "application/json", b"".as_slice(), 400),
+                ("POST", "/api/restaurants", "application/x-ndjson;q=0", "application/json", b"{}".as_slice(), 406),
+                ("POST", "/api/restaurants", "application/bson", "application/xml", b"<restaurant/>".as_slice(), 415),
+                ("POST", "/api/restaurants", "application/msgpack", "application/bson", b"\x05\x00\x00".as_slice(), 400),
+                ("POST", "/api/restaurants", "application/msgpack", "application/msgpack", wrong_shape.as_slice(), 422),
+                ("PUT", id, "*/*", "application/bson", deep.as_slice(), 422),
+            ];
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                for (method, path, accept, content_type, body, status) in cases {
+                    let headers = [("Accept", accept), ("Content-Type", content_type)];
+                    let response = testing::send_body(addr, method, path, &headers, body).await;
+                    if response.status != status {
+                        failures.push(format!("{}: {} {} with {} gave {}, not {}", framework, method, path, accept, response.status, status));
+                    } else if status == 406 && response.header("content-type") != Some("application/problem+json") {
+                        failures.push(format!("{}: 406 is not problem details", framework));
+                    }
+                }
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "content negotiation differs:\n{}", failures.join("\n"));
+    }
+}
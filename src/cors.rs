@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use crate::{config::CorsConfig, error::AppError, request::Headers};
+
+/// Response headers scripts on an allowed origin may read, besides the
+/// CORS-safelisted ones.
+const EXPOSED_HEADERS: &str =
+    "x-request-id, ratelimit-limit, ratelimit-remaining, ratelimit-reset, ratelimit-policy, retry-after, www-authenticate";
+
+/// The `[cors]` policy, applied by every framework before routing so that
+/// preflights are answered the same way on any path. Cheap to clone.
+#[derive(Clone)]
+pub struct CorsPolicy {
+    config: Arc<CorsConfig>,
+}
+
+impl CorsPolicy {
+    pub fn new(config: &CorsConfig) -> Self {
+        Self { config: Arc::new(config.clone()) }
+    }
+
+    /// An `OPTIONS` request with `Origin` and `Access-Control-Request-Method`,
+    /// when CORS is enabled. `header` looks up a request header by its lower
+    /// case name.
+    pub fn is_preflight<'a>(&self, method: &str, header: impl Fn(&str) -> Option<&'a str>) -> bool {
+        self.config.enabled
+            && method == "OPTIONS"
+            && header("origin").is_some()
+            && header("access-control-request-method").is_some()
+    }
+
+    /// Answers a preflight: the headers of its `204`, or a `403` when the
+    /// origin, method or one of the headers is not allowed. `None` for other
+    /// requests, which go on to routing.
+    pub fn preflight<'a>(&self, method: &str, header: impl Fn(&str) -> Option<&'a str>) -> Option<Result<Headers, AppError>> {
+        if !self.is_preflight(method, &header) {
+            return None;
+        }
+        let origin = header("origin").unwrap_or_default();
+        let requested_method = header("access-control-request-method").unwrap_or_default();
+        let requested_headers = header("access-control-request-headers").unwrap_or_default();
+        let rejected = |reason: String| Some(Err(AppError::Forbidden(format!("CORS preflight rejected: {}", reason))));
+
+        let Some(allowed_origin) = self.allowed_origin(origin) else {
+            return rejected(format!("origin {} is not allowed", origin));
+        };
+        if !self.config.allowed_methods.iter().any(|m| m == requested_method) {
+            return rejected(format!("method {} is not allowed", requested_method));
+        }
+        let unlisted = requested_headers
+            .split(',')
+            .map(str::trim)
+            .find(|name| !name.is_empty() && !self.config.allowed_headers.iter().any(|h| h.eq_ignore_ascii_case(name)));
+        if let Some(name) = unlisted {
+            return rejected(format!("header {} is not allowed", name));
+        }
+
+        let mut headers = vec![("access-control-allow-origin", allowed_origin)];
+        if self.config.allow_credentials {
+            headers.push(("access-control-allow-credentials", "true".to_string()));
+        }
+        headers.extend([
+            ("access-control-allow-methods", self.config.allowed_methods.join(", ")),
+            ("access-control-allow-headers", self.config.allowed_headers.join(", ")),
+            ("access-control-max-age", self.config.max_age_secs.to_string()),
+            ("vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers".to_string()),
+        ]);
+        Some(Ok(headers))
+    }
+
+    /// Headers for the response to any request that is not a preflight.
+    pub fn response_headers<'a>(&self, header: impl Fn(&str) -> Option<&'a str>) -> Headers {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+        let mut headers = Vec::new();
+        if let Some(allowed_origin) = header("origin").and_then(|origin| self.allowed_origin(origin)) {
+            headers.push(("access-control-allow-origin", allowed_origin));
+            if self.config.allow_credentials {
+                headers.push(("access-control-allow-credentials", "true".to_string()));
+            }
+            headers.push(("access-control-expose-headers", EXPOSED_HEADERS.to_string()));
+        }
+        // Responses differ by origin unless every origin is allowed
+        if !self.allows_any_origin() {
+            headers.push(("vary", "Origin".to_string()));
+        }
+        headers
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.config.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    /// The `Access-Control-Allow-Origin` value for `origin`, if it is allowed.
+    /// `*` is only sent back when credentials are not allowed, which the
+    /// configuration validation ensures.
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.allows_any_origin() {
+            Some("*".to_string())
+        } else {
+            self.config
+                .allowed_origins
+                .iter()
+                .any(|o| o.eq_ignore_ascii_case(origin))
+                .then(|| origin.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frameworks::{testing, Framework};
+
+    const ORIGIN: &str = "https://app.example.com";
+
+    fn enabled(origins: &[&str], allow_credentials: bool) -> CorsConfig {
+        CorsConfig {
+            enabled: true,
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        }
+    }
+
+    #[test]
+    fn any_origin_is_answered_with_a_wildcard() {
+        let policy = CorsPolicy::new(&enabled(&["*"], false));
+        let headers = policy.response_headers(|name| (name == "origin").then_some("https://other.example"));
+        assert!(headers.contains(&("access-control-allow-origin", "*".to_string())));
+        assert!(!headers.iter().any(|(name, _)| *name == "vary"));
+    }
+
+    /// Every framework must answer preflights and tag responses the same way,
+    /// on API routes, on error responses and on the operational routes.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_applies_the_same_cors_policy() {
+        let mut config = testing::config();
+        config.cors = enabled(&[ORIGIN], true);
+        config.cors.max_age_secs = 120;
+
+        let failures = testing::with_every_framework(config, |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+
+                for path in ["/api/restaurants", "/api/restaurants/not-an-object-id", "/healthz"] {
+                    let allowed = testing::send(addr, "OPTIONS", path, &[
+                        ("Origin", ORIGIN),
+                        ("Access-Control-Request-Method", "PUT"),
+                        ("Access-Control-Request-Headers", "Content-Type, X-API-Key"),
+                    ])
+                    .await;
+                    check(&format!("preflight of {} answers 204", path), allowed.status == 204);
+                    check("preflight echoes the origin", allowed.header("access-control-allow-origin") == Some(ORIGIN));
+                    check("preflight allows credentials", allowed.header("access-control-allow-credentials") == Some("true"));
+                    check(
+                        "preflight lists the methods",
+                        allowed.header("access-control-allow-methods") == Some("GET, POST, PUT, DELETE"),
+                    );
+                    check(
+                        "preflight lists the headers",
+                        allowed.header("access-control-allow-headers") == Some("content-type, authorization, x-api-key"),
+                    );
+                    check("preflight sends max-age", allowed.header("access-control-max-age") == Some("120"));
+                    check("preflight varies by origin", allowed.header("vary").is_some_and(|v| v.contains("Origin")));
+                }
+
+                let rejections = [
+                    ("origin", [("Origin", "https://evil.example"), ("Access-Control-Request-Method", "GET")]),
+                    ("method", [("Origin", ORIGIN), ("Access-Control-Request-Method", "PATCH")]),
+                ];
+                for (what, headers) in rejections {
+                    let rejected = testing::send(addr, "OPTIONS", "/api/restaurants", &headers).await;
+                    check(&format!("preflight with another {} answers 403", what), rejected.status == 403);
+                    check(
+                        &format!("preflight with another {} has no allow-origin", what),
+                        rejected.header("access-control-allow-origin").is_none(),
+                    );
+                }
+                let rejected = testing::send(addr, "OPTIONS", "/api/restaurants", &[
+                    ("Origin", ORIGIN),
+                    ("Access-Control-Request-Method", "GET"),
+                    ("Access-Control-Request-Headers", "x-custom"),
+                ])
+                .await;
+                check("preflight with another header answers 403", rejected.status == 403);
+
+                // 200 from the health check, 400 from a handler
+                for (path, status) in [("/healthz", 200), ("/api/restaurants/not-an-object-id", 400)] {
+                    let allowed = testing::send(addr, "GET", path, &[("Origin", ORIGIN)]).await;
+                    check(&format!("GET {} answers {}", path, status), allowed.status == status);
+                    check(
+                        &format!("GET {} echoes the origin", path),
+                        allowed.header("access-control-allow-origin") == Some(ORIGIN),
+                    );
+                    check(
+                        &format!("GET {} allows credentials", path),
+                        allowed.header("access-control-allow-credentials") == Some("true"),
+                    );
+                    check(
+                        &format!("GET {} exposes the request ID", path),
+                        allowed.header("access-control-expose-headers").is_some_and(|v| v.contains("x-request-id")),
+                    );
+
+                    let other = testing::send(addr, "GET", path, &[("Origin", "https://evil.example")]).await;
+                    check(&format!("GET {} from another origin still answers", path), other.status == status);
+                    check(
+                        &format!("GET {} from another origin has no allow-origin", path),
+                        other.header("access-control-allow-origin").is_none(),
+                    );
+                    check(&format!("GET {} varies by origin", path), other.header("vary") == Some("Origin"));
+                }
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "CORS behavior differs:\n{}", failures.join("\n"));
+    }
+}
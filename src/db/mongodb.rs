@@ -1,59 +1,314 @@
-use mongodb::{Database, Collection, bson::{doc, Document, oid::ObjectId}};
-use futures::stream::TryStreamExt;
-use crate::{models::restaurant::Restaurant, error::AppError};
+use std::future::Future;
+use std::time::Duration;
+use mongodb::{
+    Client, Database, Collection,
+    bson::{doc, DateTime, Document, RawDocumentBuf, oid::ObjectId},
+    change_stream::event::OperationType,
+    error::{ErrorKind, WriteFailure},
+    options::{ClientOptions, FullDocumentType},
+};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use tracing::{Instrument, field, info, info_span};
+use crate::{
+    models::restaurant::{ChangeKind, ImportSummary, RestaurantChange, RestaurantDoc, UpdateRestaurantRequest},
+    models::lenient::{self, Lenient, ScanReport},
+    models::validation::{Validate, Violations},
+    error::AppError,
+    config::MongoConfig,
+    health::TopologyWatcher,
+    metrics::Metrics,
+    request::RequestContext,
+};
 
+/// Builds the one `Client` (and connection pool) shared by every framework.
+/// No connection is made until the first operation, see `health::wait_for_mongodb`.
+pub async fn connect(config: &MongoConfig, topology: &TopologyWatcher, metrics: &Metrics) -> Result<Client, AppError> {
+    let mut options = ClientOptions::parse(&config.uri).await?;
+    options.min_pool_size = Some(config.min_pool_size);
+    options.max_pool_size = Some(config.max_pool_size);
+    options.connect_timeout = Some(Duration::from_secs(config.connect_timeout_secs));
+    options.server_selection_timeout = Some(Duration::from_secs(config.server_selection_timeout_secs));
+    options.sdam_event_handler = Some(topology.event_handler());
+    options.command_event_handler = Some(metrics.command_event_handler());
+    options.cmap_event_handler = Some(metrics.cmap_event_handler());
+    Ok(Client::with_options(options)?)
+}
+
+/// True when a write failed on a unique index (error code 11000).
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000)
+}
+
+/// Every operation takes the `RequestContext` it runs for and sends its
+/// request ID as the command `comment`, which shows up in the profiler,
+/// `currentOp` and the slow query log.
 pub struct MongoRepo {
-    collection: Collection<Restaurant>,
+    collection: Collection<RestaurantDoc>,
+    /// The same collection, read without decoding, for `application/bson`.
+    raw: Collection<RawDocumentBuf>,
 }
 
 impl MongoRepo {
-    pub fn new(db: &Database) -> Self {
+    pub fn new(db: &Database, collection: &str) -> Self {
         Self {
-            collection: db.collection("restaurants"),
+            collection: db.collection(collection),
+            raw: db.collection(collection),
         }
     }
 
-    pub async fn create_restaurant(&self, restaurant: Restaurant) -> Result<Restaurant, AppError> {
-        let result = self.collection.insert_one(restaurant).await?;
-        let filter = doc! { "_id": result.inserted_id };
-        let created_restaurant = self.collection.find_one(filter).await?
-            .ok_or(AppError::NotFound)?;
-        Ok(created_restaurant)
-    }    pub async fn get_restaurants(&self, limit: i64) -> Result<Vec<Restaurant>, AppError> {
-        let mut cursor = self.collection.find(doc! {}).await?;
-        let mut restaurants = Vec::new();
-        while let Some(restaurant) = cursor.try_next().await? {
-            restaurants.push(restaurant);
-            if restaurants.len() >= limit as usize {
-                break;
+    /// Runs one repository call in a child span of the request, with the
+    /// OpenTelemetry database attributes. `NotFound` is an expected outcome
+    /// and does not mark the span as failed.
+    async fn traced<T>(
+        &self,
+        request: &RequestContext,
+        operation: &'static str,
+        call: impl Future<Output = Result<T, AppError>>,
+    ) -> Result<T, AppError> {
+        let namespace = self.collection.namespace();
+        let span = info_span!(
+            parent: &request.span,
+            "mongodb",
+            otel.name = %format_args!("{} {}", operation, namespace.coll),
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            db.system = "mongodb",
+            db.operation = operation,
+            db.name = %namespace.db,
+            db.mongodb.collection = %namespace.coll,
+            error.message = field::Empty,
+        );
+        let result = call.instrument(span.clone()).await;
+        if let Err(e) = &result {
+            if !matches!(e, AppError::NotFound) {
+                span.record("otel.status_code", "ERROR");
+                span.record("error.message", e.to_string());
             }
         }
-        Ok(restaurants)
-    }pub async fn get_restaurant_by_id(&self, id: ObjectId) -> Result<Restaurant, AppError> {
-        let filter = doc! { "_id": id };
-        let restaurant = self.collection.find_one(filter).await?
-            .ok_or(AppError::NotFound)?;
-        Ok(restaurant)
+        result
     }
 
-    pub async fn update_restaurant(&self, id: ObjectId, update: Document) -> Result<Restaurant, AppError> {
-        let filter = doc! { "_id": id };
-        let update_doc = doc! { "$set": update };
-        
-        let result = self.collection.update_one(filter.clone(), update_doc).await?;
-        if result.modified_count == 0 {
-            return Err(AppError::NotFound);
+    pub async fn create_restaurant(&self, request: &RequestContext, mut restaurant: RestaurantDoc) -> Result<RestaurantDoc, AppError> {
+        restaurant.validate()?;
+        // Attribution comes from the request, never from the body
+        restaurant.created_by = request.subject().map(str::to_string);
+        restaurant.updated_by = None;
+        self.traced(request, "insert", async {
+            let result = self.collection.insert_one(restaurant).comment(request.comment()).await?;
+            let filter = doc! { "_id": result.inserted_id };
+            let created_restaurant = self.collection.find_one(filter).comment(request.comment()).await?
+                .ok_or(AppError::NotFound)?;
+            Ok(created_restaurant)
+        }).await
+    }    pub async fn get_restaurants(&self, request: &RequestContext, limit: i64) -> Result<Vec<RestaurantDoc>, AppError> {
+        self.traced(request, "find", async {
+            let mut cursor = self.collection.find(doc! {}).comment(request.comment()).await?;
+            let mut restaurants = Vec::new();
+            while let Some(restaurant) = cursor.try_next().await? {
+                restaurants.push(restaurant);
+                if restaurants.len() >= limit as usize {
+                    break;
+                }
+            }
+            Ok(restaurants)
+        }).await
+    }
+
+    /// A page of the restaurants matching `filter` in `_id` order, starting
+    /// after `after`, so pages stay stable while documents are added.
+    pub async fn find_restaurants(
+        &self,
+        request: &RequestContext,
+        mut filter: Document,
+        after: Option<ObjectId>,
+        limit: i64,
+    ) -> Result<Vec<RestaurantDoc>, AppError> {
+        if let Some(after) = after {
+            filter.insert("_id", doc! { "$gt": after });
         }
+        self.traced(request, "find", async {
+            let cursor = self.collection
+                .find(filter)
+                .sort(doc! { "_id": 1 })
+                .limit(limit)
+                .comment(request.comment())
+                .await?;
+            Ok(cursor.try_collect().await?)
+        }).await
+    }
+
+    /// The first `limit` restaurants matching `filter`, streamed from the
+    /// cursor instead of collected.
+    pub async fn stream_restaurants(
+        &self,
+        request: &RequestContext,
+        filter: Document,
+        limit: i64,
+    ) -> Result<BoxStream<'static, Result<RestaurantDoc, AppError>>, AppError> {
+        let cursor = self.traced(request, "find", async {
+            Ok(self.collection.find(filter).limit(limit).comment(request.comment()).await?)
+        }).await?;
+        Ok(cursor.map_err(AppError::from).boxed())
+    }
+
+    /// Like `stream_restaurants` without a filter, but the documents exactly
+    /// as the driver read them.
+    pub async fn stream_restaurants_raw(
+        &self,
+        request: &RequestContext,
+        limit: i64,
+    ) -> Result<BoxStream<'static, Result<RawDocumentBuf, AppError>>, AppError> {
+        let cursor = self.traced(request, "find", async {
+            Ok(self.raw.find(doc! {}).limit(limit).comment(request.comment()).await?)
+        }).await?;
+        Ok(cursor.map_err(AppError::from).boxed())
+    }
+
+    /// Like `stream_restaurants_raw`, but each document decoded as far as it
+    /// matches the model instead of failing the stream.
+    pub async fn stream_restaurants_lenient(
+        &self,
+        request: &RequestContext,
+        limit: i64,
+    ) -> Result<BoxStream<'static, Result<Lenient, AppError>>, AppError> {
+        let documents = self.stream_restaurants_raw(request, limit).await?;
+        Ok(documents.map_ok(|document| lenient::decode(&document)).boxed())
+    }
+
+    /// Reads the whole collection for the documents that do not match the
+    /// model, see `ScanReport`.
+    pub async fn scan_restaurants(&self, request: &RequestContext) -> Result<ScanReport, AppError> {
+        self.traced(request, "find", async {
+            let mut cursor = self.raw.find(doc! {}).comment(request.comment()).await?;
+            let mut report = ScanReport::default();
+            while let Some(document) = cursor.try_next().await? {
+                report.scan(&document);
+            }
+            info!(scanned = report.scanned, mismatched = report.mismatched.len(), "restaurants scanned");
+            Ok(report)
+        }).await
+    }
+
+    /// `size` documents picked at random with `$sample`, as stored.
+    pub async fn sample_restaurants(
+        &self,
+        request: &RequestContext,
+        size: u32,
+    ) -> Result<BoxStream<'static, Result<Document, AppError>>, AppError> {
+        let cursor = self.traced(request, "aggregate", async {
+            Ok(self.collection.aggregate([doc! { "$sample": { "size": size } }]).comment(request.comment()).await?)
+        }).await?;
+        Ok(cursor.map_err(AppError::from).boxed())
+    }
 
-        self.get_restaurant_by_id(id).await
+    /// The restaurants with any of `ids`, in no particular order; unknown
+    /// IDs are left out.
+    pub async fn get_restaurants_by_ids(&self, request: &RequestContext, ids: &[ObjectId]) -> Result<Vec<RestaurantDoc>, AppError> {
+        self.traced(request, "find", async {
+            let cursor = self.collection.find(doc! { "_id": { "$in": ids } }).comment(request.comment()).await?;
+            Ok(cursor.try_collect().await?)
+        }).await
     }
 
-    pub async fn delete_restaurant(&self, id: ObjectId) -> Result<(), AppError> {
-        let filter = doc! { "_id": id };
-        let result = self.collection.delete_one(filter).await?;
-        if result.deleted_count == 0 {
-            return Err(AppError::NotFound);
+    /// Changes to the collection from now on. Change streams need a replica
+    /// set or a sharded cluster; a standalone server fails here.
+    pub async fn watch(&self, request: &RequestContext) -> Result<BoxStream<'static, Result<RestaurantChange, AppError>>, AppError> {
+        let stream = self.traced(request, "aggregate", async {
+            Ok(self.collection
+                .watch()
+                .full_document(FullDocumentType::UpdateLookup)
+                .comment(request.comment())
+                .await?)
+        }).await?;
+        Ok(stream.filter_map(|event| async move {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let kind = match event.operation_type {
+                OperationType::Insert => ChangeKind::Created,
+                OperationType::Update | OperationType::Replace => ChangeKind::Updated,
+                OperationType::Delete => ChangeKind::Deleted,
+                // Collection level events; `invalidate` also ends the stream
+                _ => return None,
+            };
+            let id = event.document_key.as_ref()?.get_object_id("_id").ok()?;
+            Some(Ok(RestaurantChange { kind, id, restaurant: event.full_document }))
+        }).boxed())
+    }
+
+    pub async fn get_restaurant_by_id(&self, request: &RequestContext, id: ObjectId) -> Result<RestaurantDoc, AppError> {
+        self.traced(request, "find", async {
+            let filter = doc! { "_id": id };
+            let restaurant = self.collection.find_one(filter).comment(request.comment()).await?
+                .ok_or(AppError::NotFound)?;
+            Ok(restaurant)
+        }).await
+    }
+
+    /// Like `get_restaurant_by_id`, but the document exactly as the driver
+    /// read it.
+    pub async fn get_restaurant_raw(&self, request: &RequestContext, id: ObjectId) -> Result<RawDocumentBuf, AppError> {
+        self.traced(request, "find", async {
+            self.raw.find_one(doc! { "_id": id }).comment(request.comment()).await?.ok_or(AppError::NotFound)
+        }).await
+    }
+
+    /// Inserts many restaurants at once, attributed like single creates.
+    pub async fn import_restaurants(&self, request: &RequestContext, mut restaurants: Vec<RestaurantDoc>) -> Result<ImportSummary, AppError> {
+        if restaurants.is_empty() {
+            return Err(AppError::BadRequest("nothing to import".to_string()));
+        }
+        let mut violations = Violations::default();
+        for (index, restaurant) in restaurants.iter().enumerate() {
+            restaurant.check(&format!("[{}]", index), &mut violations);
+        }
+        violations.into_result()?;
+        for restaurant in &mut restaurants {
+            restaurant.created_by = request.subject().map(str::to_string);
+            restaurant.updated_by = None;
         }
-        Ok(())
+        self.traced(request, "insert", async {
+            let result = self.collection.insert_many(restaurants).comment(request.comment()).await?;
+            info!(inserted = result.inserted_ids.len(), subject = request.subject(), "restaurants imported");
+            Ok(ImportSummary { inserted: result.inserted_ids.len() as u64 })
+        }).await
+    }
+
+    pub async fn update_restaurant(&self, request: &RequestContext, id: ObjectId, update: UpdateRestaurantRequest) -> Result<RestaurantDoc, AppError> {
+        self.traced(request, "update", async {
+            if update.is_empty() {
+                return Err(AppError::Unprocessable("the update sets no fields".to_string()));
+            }
+            update.validate()?;
+            let mut update = update.to_document().map_err(|e| AppError::Encoding(e.to_string()))?;
+            if let Some(subject) = request.subject() {
+                update.insert("updated_by", subject);
+            }
+            update.insert("updated_at", DateTime::now());
+
+            // Written by this update, so no longer one the timestamps migration may undo
+            let filter = doc! { "_id": id };
+            let update_doc = doc! { "$set": update, "$pull": { "timestamps_added": "updated_at" } };
+        
+            let result = self.collection.update_one(filter.clone(), update_doc).comment(request.comment()).await?;
+            if result.modified_count == 0 {
+                return Err(AppError::NotFound);
+            }
+
+            self.get_restaurant_by_id(request, id).await
+        }).await
+    }
+
+    pub async fn delete_restaurant(&self, request: &RequestContext, id: ObjectId) -> Result<(), AppError> {
+        self.traced(request, "delete", async {
+            let filter = doc! { "_id": id };
+            let result = self.collection.delete_one(filter).comment(request.comment()).await?;
+            if result.deleted_count == 0 {
+                return Err(AppError::NotFound);
+            }
+            info!(%id, subject = request.subject(), "restaurant deleted");
+            Ok(())
+        }).await
     }
 }
\ No newline at end of file
@@ -1,4 +1,6 @@
+use serde::Serialize;
 use thiserror::Error;
+use utoipa::ToSchema;
 
 #[derive(Error, Debug)]
 pub enum AppError {
@@ -13,10 +15,167 @@ pub enum AppError {
     
     #[error("Handler error: {0}")]
     HandlerError(#[from] tokio::task::JoinError),
+
+    #[error("Could not encode the response: {0}")]
+    Encoding(String),
     
     #[error("Not found")]
     NotFound,
     
     #[error("Bad request: {0}")]
     BadRequest(String),
-}
\ No newline at end of file
+
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Not acceptable: {0}")]
+    NotAcceptable(String),
+
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Unprocessable body: {0}")]
+    Unprocessable(String),
+
+    #[error("Invalid restaurant: {}", summary(.0))]
+    Invalid(Vec<FieldError>),
+
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+}
+
+impl AppError {
+    /// HTTP status code this error is answered with.
+    pub fn status(&self) -> u16 {
+        match self {
+            AppError::NotFound => 404,
+            AppError::InvalidObjectId(_) | AppError::Serialization(_) | AppError::BadRequest(_) => 400,
+            AppError::Unauthorized(_) => 401,
+            AppError::Forbidden(_) => 403,
+            AppError::NotAcceptable(_) => 406,
+            AppError::PayloadTooLarge(_) => 413,
+            AppError::UnsupportedMediaType(_) => 415,
+            AppError::Unprocessable(_) | AppError::Invalid(_) => 422,
+            AppError::TooManyRequests(_) => 429,
+            AppError::Unavailable(_) => 503,
+            AppError::MongoDB(_) | AppError::HandlerError(_) | AppError::Encoding(_) => 500,
+        }
+    }
+
+    /// gRPC status code this error is answered with.
+    pub fn grpc_code(&self) -> tonic::Code {
+        match self {
+            AppError::NotFound => tonic::Code::NotFound,
+            AppError::InvalidObjectId(_)
+            | AppError::Serialization(_)
+            | AppError::BadRequest(_)
+            | AppError::NotAcceptable(_)
+            | AppError::UnsupportedMediaType(_)
+            | AppError::Unprocessable(_)
+            | AppError::Invalid(_) => tonic::Code::InvalidArgument,
+            AppError::Unauthorized(_) => tonic::Code::Unauthenticated,
+            AppError::Forbidden(_) => tonic::Code::PermissionDenied,
+            AppError::PayloadTooLarge(_) | AppError::TooManyRequests(_) => tonic::Code::ResourceExhausted,
+            AppError::Unavailable(_) => tonic::Code::Unavailable,
+            AppError::MongoDB(_) | AppError::HandlerError(_) | AppError::Encoding(_) => tonic::Code::Internal,
+        }
+    }
+
+    /// `Content-Type` of the error response: rejected request bodies and
+    /// unacceptable `Accept` headers are answered with RFC 9457 problem
+    /// details.
+    pub fn content_type(&self) -> &'static str {
+        match self.problem_title() {
+            Some(_) => PROBLEM_JSON,
+            None => "application/json",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let title = self.problem_title();
+        let detail = match self {
+            AppError::NotAcceptable(detail)
+            | AppError::PayloadTooLarge(detail)
+            | AppError::UnsupportedMediaType(detail)
+            | AppError::Unprocessable(detail) => Some(detail.clone()),
+            AppError::Invalid(errors) => Some(summary(errors)),
+            _ => None,
+        };
+        ErrorBody {
+            error: self.to_string(),
+            kind: title.map(|_| "about:blank"),
+            title,
+            status: title.map(|_| self.status()),
+            detail,
+            errors: match self {
+                AppError::Invalid(errors) => Some(errors.clone()),
+                _ => None,
+            },
+        }
+    }
+
+    fn problem_title(&self) -> Option<&'static str> {
+        match self {
+            AppError::NotAcceptable(_) => Some("Not Acceptable"),
+            AppError::PayloadTooLarge(_) => Some("Payload Too Large"),
+            AppError::UnsupportedMediaType(_) => Some("Unsupported Media Type"),
+            AppError::Unprocessable(_) | AppError::Invalid(_) => Some("Unprocessable Entity"),
+            _ => None,
+        }
+    }
+}
+
+impl From<AppError> for tonic::Status {
+    fn from(e: AppError) -> Self {
+        tonic::Status::new(e.grpc_code(), e.to_string())
+    }
+}
+
+/// Media type of problem details responses.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// JSON body of every error response of the API. On 406, 413, 415 and 422 it
+/// also has the RFC 9457 problem details members.
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Human readable description of what went wrong.
+    pub error: String,
+    /// Always `about:blank`: the status says what the problem is.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub kind: Option<&'static str>,
+    /// The reason phrase of `status`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub title: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// What was wrong with the request body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Every rule the restaurant breaks, when it is invalid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
+}
+
+/// A rule a field of a restaurant breaks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct FieldError {
+    /// Path of the field, such as `address.zipcode` or `grades[1].score`.
+    pub field: String,
+    pub message: String,
+}
+
+fn summary(errors: &[FieldError]) -> String {
+    let errors: Vec<_> = errors.iter().map(|e| format!("{} {}", e.field, e.message)).collect();
+    errors.join("; ")
+}
@@ -1,22 +1,86 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Responder};
-use mongodb::Database;
+use actix_web::{
+    web, App, HttpMessage, HttpRequest, HttpServer, HttpResponse, Responder,
+    body::{self, BodySize, BodyStream, BoxBody, EitherBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{StatusCode, header::{self, HeaderMap, HeaderName, HeaderValue}},
+    middleware::{from_fn, Next},
+};
+use actix_ws::{CloseCode, CloseReason, Message};
+use async_graphql::{http::WsMessage, BatchRequest};
 use bson::oid::ObjectId;
-use serde_json::Value;
+use futures::{stream, StreamExt, TryStreamExt};
+use actix_tls::accept::rustls_0_21::TlsStream;
+use actix_web::rt::net::TcpStream;
+use serde::de::DeserializeOwned;
+use std::any::Any;
+use std::io;
+use std::pin::pin;
+use tracing::{Instrument, info};
 
 use crate::{
+    auth::Credentials,
+    compression,
+    content::{self, BodyFormat, ResponseFormat},
     db::mongodb::MongoRepo,
-    models::restaurant::Restaurant,
+    models::restaurant::{CreateRestaurantRequest, RestaurantDoc, UpdateRestaurantRequest},
     error::AppError,
+    frameworks::{Framework, ServerContext},
+    graphql::{self, GraphQl},
+    health,
+    limits::{self, BodyLimits, BodyRoute},
+    metrics,
+    openapi,
+    ratelimit::{Admitted, Throttled},
+    request::{Headers, RequestContext, REQUEST_ID_HEADER},
+    tls::ClientCertificate,
 };
 
-pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
-    let repo = web::Data::new(MongoRepo::new(&db));
+pub async fn start(ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+    let repo = web::Data::new(ctx.repo());
+    let graphql = web::Data::new(GraphQl::new(repo.clone().into_inner(), ctx.auth.clone()));
+    let server_ctx = web::Data::new(ctx.clone());
+    let addr = ctx.config.server.actix;
     
-    println!("Starting Actix Web server at http://127.0.0.1:8080");
+    info!("Starting Actix Web server at {}://{}", ctx.scheme(), addr);
     
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
+        let metrics = server_ctx.metrics.clone();
         App::new()
             .app_data(repo.clone())
+            .app_data(graphql.clone())
+            .app_data(server_ctx.clone())
+            // Registered first so they run inside the request context below, in
+            // reverse order: compression, CORS, the rate limit, authentication
+            .wrap(from_fn(require_auth))
+            .wrap(from_fn(rate_limit))
+            .wrap(from_fn(cors))
+            .wrap(from_fn(compress))
+            .wrap_fn(move |req, srv| {
+                let metrics = metrics.clone();
+                let request = RequestContext::begin(
+                    Framework::Actix.name(),
+                    req.method().as_str(),
+                    req.path(),
+                    |name| req.headers().get(name).and_then(|v| v.to_str().ok()),
+                );
+                req.extensions_mut().insert(request.clone());
+                let span = request.span.clone();
+                let response = span.in_scope(|| srv.call(req));
+                async move {
+                    let mut response = response.await?;
+                    if let Ok(value) = HeaderValue::from_str(&request.request_id) {
+                        response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                    }
+                    request.finish(&metrics, response.status().as_u16());
+                    Ok(response)
+                }
+                .instrument(span)
+            })
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+            .route("/metrics", web::get().to(metrics_handler))
+            .route("/openapi.json", web::get().to(openapi_json))
+            .route("/docs", web::get().to(docs))
             .service(
                 web::scope("/api")
                     .route("/restaurants", web::post().to(create_restaurant))
@@ -25,81 +89,485 @@ pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
                     .route("/restaurants/{id}", web::put().to(update_restaurant))
                     .route("/restaurants/{id}", web::delete().to(delete_restaurant))
             )
+            .route("/admin/restaurants/import", web::post().to(import_restaurants))
+            .route("/admin/restaurants/scan", web::get().to(scan_restaurants))
+            .route(graphql::ENDPOINT, web::post().to(graphql_handler))
+            .route(graphql::WS_ENDPOINT, web::get().to(graphql_ws))
+            .route(graphql::GRAPHIQL, web::get().to(graphiql))
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await?;
+    .on_connect(|conn: &dyn Any, data| {
+        if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+            data.insert(ClientCertificate(stream.get_ref().1.peer_certificates().is_some()));
+        }
+    });
+    let server = match &ctx.tls {
+        // Actix adds the h2 and http/1.1 ALPN protocols itself
+        Some(tls) => server.bind_rustls_021(addr, tls.server_config(&[]))?,
+        None => server.bind(addr)?,
+    };
+    let server = server
+        .disable_signals()
+        .shutdown_timeout(ctx.config.shutdown.drain_timeout_secs)
+        .run();
+
+    // Signals are handled once for every framework, see `frameworks::serve`
+    let handle = server.handle();
+    tokio::spawn(async move {
+        ctx.shutdown.cancelled().await;
+        handle.stop(true).await;
+    });
+
+    server.await?;
     
     Ok(())
 }
 
+/// Compresses responses as the request's `Accept-Encoding` allows.
+async fn compress(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody, BoxBody>>, actix_web::Error> {
+    let ctx = req.app_data::<web::Data<ServerContext>>().expect("ServerContext is app data").clone();
+    let accept_encoding = req.headers().get(header::ACCEPT_ENCODING).cloned();
+    let header = |name: &str| (name == "accept-encoding").then(|| accept_encoding.as_ref()?.to_str().ok()).flatten();
+    let response = next.call(req).await?;
+    // Web sockets stream until closed, so their body is never buffered
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(response.map_into_left_body());
+    }
+    let content_type = response.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string);
+
+    // Bodies of unknown length are streamed, and compressed as they go
+    if let BodySize::Stream = response.response().body().size() {
+        let (headers, encoding) = ctx.compression.stream_encoding(header, content_type.as_deref());
+        let Some(encoding) = encoding else {
+            let mut response = response.map_into_left_body();
+            append_headers(response.headers_mut(), headers);
+            return Ok(response);
+        };
+        let (req, response) = response.into_parts();
+        let (mut response, body) = response.into_parts();
+        append_headers(response.headers_mut(), headers);
+        let mut body = Box::pin(body);
+        let chunks = stream::poll_fn(move |cx| body.as_mut().poll_next(cx))
+            .map_err(|e| io::Error::other(e.into().to_string()));
+        let body = BodyStream::new(compression::compress_stream(encoding, chunks));
+        return Ok(ServiceResponse::new(req, response.set_body(BoxBody::new(body))).map_into_right_body());
+    }
+
+    let (req, response) = response.into_parts();
+    let (mut response, body) = response.into_parts();
+    let body = body::to_bytes(body).await.map_err(|e| actix_web::error::ErrorInternalServerError(e.into()))?;
+    let (headers, compressed) = ctx.compression.compress(header, content_type.as_deref(), &body);
+    append_headers(response.headers_mut(), headers);
+    let body = compressed.map_or(body, Into::into);
+    Ok(ServiceResponse::new(req, response.set_body(BoxBody::new(body))).map_into_right_body())
+}
+
+fn append_headers(map: &mut HeaderMap, headers: Headers) {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            map.append(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+/// Answers CORS preflights and adds the CORS headers to other responses.
+async fn cors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let ctx = req.app_data::<web::Data<ServerContext>>().expect("ServerContext is app data").clone();
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let (mut response, headers) = match ctx.cors.preflight(req.method().as_str(), header) {
+        Some(Ok(headers)) => (req.into_response(HttpResponse::NoContent().finish()).map_into_right_body(), headers),
+        Some(Err(e)) => (req.into_response(error_response(e)).map_into_right_body(), Vec::new()),
+        None => {
+            let headers = ctx.cors.response_headers(header);
+            (next.call(req).await?.map_into_left_body(), headers)
+        }
+    };
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().append(HeaderName::from_static(name), value);
+        }
+    }
+    Ok(response)
+}
+
+/// Applies the client's token bucket and the in-flight cap, adding the
+/// `RateLimit-*` headers to the response.
+async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let ctx = req.app_data::<web::Data<ServerContext>>().expect("ServerContext is app data").clone();
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let admission = ctx.rate_limiter.admit(req.method().as_str(), req.path(), peer, |name| {
+        req.headers().get(name).and_then(|v| v.to_str().ok())
+    });
+    match admission {
+        Ok(admitted) => {
+            let headers = admitted.headers().clone();
+            let mut response = hold(next.call(req).await?, admitted);
+            insert_headers(response.headers_mut(), headers);
+            Ok(response.map_into_left_body())
+        }
+        Err(throttled) => Ok(req.into_response(throttled_response(throttled)).map_into_right_body()),
+    }
+}
+
+fn throttled_response(throttled: Throttled) -> HttpResponse {
+    let mut response = error_response(throttled.error);
+    insert_headers(response.headers_mut(), throttled.headers);
+    response
+}
+
+fn insert_headers(map: &mut HeaderMap, headers: Headers) {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            map.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+/// Keeps the in-flight slot of a streamed response until its body is done.
+fn hold(response: ServiceResponse<impl MessageBody + 'static>, admitted: Admitted) -> ServiceResponse<BoxBody> {
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS || !matches!(response.response().body().size(), BodySize::Stream) {
+        return response.map_into_boxed_body();
+    }
+    response.map_body(|_, body| {
+        let mut body = Box::pin(body);
+        let chunks = stream::poll_fn(move |cx| body.as_mut().poll_next(cx))
+            .map_err(|e| io::Error::other(e.into().to_string()));
+        BoxBody::new(BodyStream::new(admitted.hold(chunks)))
+    })
+}
+
+/// Rejects requests without a bearer token or API key allowed to use their
+/// route, and with mutual TLS, admin requests without a client certificate.
+async fn require_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let ctx = req.app_data::<web::Data<ServerContext>>().expect("ServerContext is app data").clone();
+    let request = req.extensions().get::<RequestContext>().cloned().expect("request context is set first");
+    let client = req.conn_data::<ClientCertificate>().copied().unwrap_or(ClientCertificate(false));
+    let credentials = Credentials::from_headers(|name| req.headers().get(name).and_then(|v| v.to_str().ok()));
+    let authorized = match ctx.check_client_certificate(req.method().as_str(), req.path(), client) {
+        Ok(()) => ctx.auth.authorize(&request, req.method().as_str(), req.path(), &credentials).await,
+        Err(e) => Err(e),
+    };
+    match authorized {
+        Ok(_) => next.call(req).await.map(ServiceResponse::map_into_left_body),
+        Err(e) => {
+            let mut response = error_response(e);
+            if response.status() == StatusCode::UNAUTHORIZED {
+                response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static(ctx.auth.challenge()));
+            }
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(health::liveness())
+}
+
+async fn readyz(ctx: web::Data<ServerContext>) -> impl Responder {
+    let readiness = ctx.readiness().await;
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+async fn metrics_handler(ctx: web::Data<ServerContext>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(ctx.metrics.render())
+}
+
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(openapi::spec_json())
+}
+
+async fn docs() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(openapi::SWAGGER_UI_HTML)
+}
+
+fn error_response(e: AppError) -> HttpResponse {
+    let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    HttpResponse::build(status).content_type(e.content_type()).json(e.body())
+}
+
+/// Reads the body of `route` in the format its `Content-Type` names, within
+/// the route's size limit. Used instead of `web::Json` so every framework
+/// rejects the same bodies the same way.
+async fn decode_body<T: DeserializeOwned>(
+    limits: &BodyLimits,
+    route: BodyRoute,
+    req: &HttpRequest,
+    payload: web::Payload,
+) -> Result<T, AppError> {
+    let format = limits::body_format(route, req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()))?;
+    let body = match payload.to_bytes_limited(limits.max_bytes(route)).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return Err(AppError::BadRequest(e.to_string())),
+        Err(_) => return Err(limits.too_large(route)),
+    };
+    limits.decode(format, &body)
+}
+
+/// The format to answer with, negotiated from `Accept` and `?format=`.
+fn response_format(req: &HttpRequest) -> Result<ResponseFormat, AppError> {
+    ResponseFormat::negotiate(req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()), Some(req.query_string()))
+}
+
+/// A restaurant response, already encoded in `format`.
+fn negotiated(status: StatusCode, format: ResponseFormat, body: Result<Vec<u8>, AppError>) -> HttpResponse {
+    match body {
+        Ok(body) => HttpResponse::build(status)
+            .content_type(format.media_type())
+            .insert_header((header::VARY, "Accept"))
+            .body(body),
+        Err(e) => error_response(e),
+    }
+}
+
 async fn create_restaurant(
     repo: web::Data<MongoRepo>,
-    restaurant: web::Json<Restaurant>,
+    ctx: web::Data<ServerContext>,
+    request: web::ReqData<RequestContext>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> impl Responder {
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let restaurant = match decode_body::<CreateRestaurantRequest>(&ctx.limits, BodyRoute::Create, &req, payload).await {
+        Ok(restaurant) => restaurant,
+        Err(e) => return error_response(e),
+    };
+
+    let created = repo.create_restaurant(&request, restaurant.into()).await;
+    negotiated(StatusCode::CREATED, format, created.and_then(|created| format.encode_one(&created.into())))
+}
+
+async fn import_restaurants(
+    repo: web::Data<MongoRepo>,
+    ctx: web::Data<ServerContext>,
+    request: web::ReqData<RequestContext>,
+    req: HttpRequest,
+    payload: web::Payload,
 ) -> impl Responder {
-    match repo.create_restaurant(restaurant.into_inner()).await {
-        Ok(created) => HttpResponse::Created().json(created),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    let format = match BodyFormat::from_content_type(req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok())) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    // The raw payload: actix's own extractors would decompress it without a limit
+    let compression = &ctx.compression;
+    let body = match payload.to_bytes_limited(compression.max_import_bytes()).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return error_response(AppError::BadRequest(e.to_string())),
+        Err(_) => return error_response(compression.import_too_large()),
+    };
+    let content_encoding = req.headers().get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok());
+    let restaurants = match compression
+        .decompress(content_encoding, &body)
+        .and_then(|body| ctx.limits.decode_many::<CreateRestaurantRequest>(format, &body))
+    {
+        Ok(restaurants) => restaurants,
+        Err(e) => return error_response(e),
+    };
+
+    match repo.import_restaurants(&request, restaurants.into_iter().map(RestaurantDoc::from).collect()).await {
+        Ok(summary) => HttpResponse::Created().json(summary),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn scan_restaurants(repo: web::Data<MongoRepo>, request: web::ReqData<RequestContext>) -> impl Responder {
+    match repo.scan_restaurants(&request).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => error_response(e),
     }
 }
 
-async fn list_restaurants(repo: web::Data<MongoRepo>) -> impl Responder {
-    match repo.get_restaurants(10).await {
-        Ok(restaurants) => HttpResponse::Ok().json(restaurants),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+async fn list_restaurants(
+    repo: web::Data<MongoRepo>,
+    ctx: web::Data<ServerContext>,
+    request: web::ReqData<RequestContext>,
+    req: HttpRequest,
+) -> impl Responder {
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let limit = match ctx.limits.list_limit(Some(req.query_string())) {
+        Ok(limit) => limit,
+        Err(e) => return error_response(e),
+    };
+
+    match content::stream_restaurants(&repo, &request, limit, format).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(format.media_type())
+            .insert_header((header::VARY, "Accept"))
+            .streaming(body),
+        Err(e) => error_response(e),
     }
 }
 
 async fn get_restaurant(
     repo: web::Data<MongoRepo>,
+    request: web::ReqData<RequestContext>,
     id: web::Path<String>,
+    req: HttpRequest,
 ) -> impl Responder {
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
     let object_id = match ObjectId::parse_str(&*id) {
         Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid ID format"),
+        Err(e) => return error_response(e.into()),
     };
 
-    match repo.get_restaurant_by_id(object_id).await {
-        Ok(restaurant) => HttpResponse::Ok().json(restaurant),
-        Err(AppError::NotFound) => HttpResponse::NotFound().finish(),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
+    negotiated(StatusCode::OK, format, content::read_restaurant(&repo, &request, object_id, format).await)
 }
 
 async fn update_restaurant(
     repo: web::Data<MongoRepo>,
+    ctx: web::Data<ServerContext>,
+    request: web::ReqData<RequestContext>,
     id: web::Path<String>,
-    update: web::Json<Value>,
+    req: HttpRequest,
+    payload: web::Payload,
 ) -> impl Responder {
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
     let object_id = match ObjectId::parse_str(&*id) {
         Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid ID format"),
+        Err(e) => return error_response(e.into()),
     };
 
-    let update_doc = match bson::to_document(&update) {
-        Ok(doc) => doc,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid update document"),
+    let update = match decode_body::<UpdateRestaurantRequest>(&ctx.limits, BodyRoute::Update, &req, payload).await {
+        Ok(update) => update,
+        Err(e) => return error_response(e),
     };
 
-    match repo.update_restaurant(object_id, update_doc).await {
-        Ok(updated) => HttpResponse::Ok().json(updated),
-        Err(AppError::NotFound) => HttpResponse::NotFound().finish(),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
+    let updated = repo.update_restaurant(&request, object_id, update).await;
+    negotiated(StatusCode::OK, format, updated.and_then(|updated| format.encode_one(&updated.into())))
 }
 
 async fn delete_restaurant(
     repo: web::Data<MongoRepo>,
+    request: web::ReqData<RequestContext>,
     id: web::Path<String>,
 ) -> impl Responder {
     let object_id = match ObjectId::parse_str(&*id) {
         Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid ID format"),
+        Err(e) => return error_response(e.into()),
     };
 
-    match repo.delete_restaurant(object_id).await {
+    match repo.delete_restaurant(&request, object_id).await {
         Ok(_) => HttpResponse::NoContent().finish(),
-        Err(AppError::NotFound) => HttpResponse::NotFound().finish(),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => error_response(e),
     }
-}
\ No newline at end of file
+}
+async fn graphql_handler(
+    graphql: web::Data<GraphQl>,
+    ctx: web::Data<ServerContext>,
+    request: web::ReqData<RequestContext>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> impl Responder {
+    let batch = match decode_body::<BatchRequest>(&ctx.limits, BodyRoute::GraphQL, &req, payload).await {
+        Ok(batch) => batch,
+        Err(e) => return error_response(e),
+    };
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let credentials = Credentials::from_headers(header);
+    let budget = ctx.rate_limiter.budget(req.peer_addr().map(|addr| addr.ip()), header);
+    HttpResponse::Ok().json(graphql.execute(&request, credentials, budget, batch).await)
+}
+
+async fn graphql_ws(
+    graphql: web::Data<GraphQl>,
+    ctx: web::Data<ServerContext>,
+    request: web::ReqData<RequestContext>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let protocol = req.headers().get(header::SEC_WEBSOCKET_PROTOCOL).and_then(|v| v.to_str().ok());
+    let protocol = match graphql::negotiate_protocol(protocol) {
+        Ok(protocol) => protocol,
+        Err(e) => return Ok(error_response(e)),
+    };
+    // The socket holds a slot of `max_in_flight` for as long as it is open
+    let slot = match ctx.rate_limiter.reserve() {
+        Ok(slot) => slot,
+        Err(throttled) => return Ok(throttled_response(throttled)),
+    };
+    let (mut response, mut session, mut incoming) = actix_ws::handle(&req, payload)?;
+    response
+        .headers_mut()
+        .insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol.sec_websocket_protocol()));
+
+    // actix-ws leaves pings to the application, so frames are read here and
+    // only text handed on to GraphQL
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    let request = request.into_inner();
+    actix_web::rt::spawn(async move {
+        let _slot = slot;
+        let mut outgoing = pin!(graphql.websocket(request, protocol, receiver));
+        loop {
+            tokio::select! {
+                message = incoming.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        let _ = sender.unbounded_send(text.into_bytes());
+                    }
+                    Some(Ok(Message::Binary(bytes))) => {
+                        let _ = sender.unbounded_send(bytes);
+                    }
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                message = outgoing.next() => match message {
+                    Some(WsMessage::Text(text)) => {
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(WsMessage::Close(code, reason)) => {
+                        let reason = CloseReason { code: CloseCode::from(code), description: Some(reason) };
+                        let _ = session.close(Some(reason)).await;
+                        return;
+                    }
+                    None => break,
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+    Ok(response)
+}
+
+async fn graphiql() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(graphql::graphiql_html())
+}
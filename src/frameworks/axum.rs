@@ -1,23 +1,49 @@
 use axum::{
     routing::{get, post, put, delete},
-    Router, Json, extract::{State, Path},
-    response::IntoResponse,
-    http::StatusCode,
+    Router, Json, Extension, body::{self, Body, HttpBody},
+    extract::{ConnectInfo, State, Path, Request, ws::{CloseFrame, Message, WebSocketUpgrade}},
+    response::{Html, IntoResponse, Response},
+    http::{StatusCode, header, HeaderMap, HeaderName, HeaderValue, Uri},
+    middleware::{self, Next},
 };
-use mongodb::Database;
+use async_graphql::{http::WsMessage, BatchRequest};
 use bson::oid::ObjectId;
-use serde_json::Value;
-use std::sync::Arc;
+use futures::{future, SinkExt, StreamExt, TryStreamExt};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use serde::de::DeserializeOwned;
+use std::io;
 use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{Instrument, debug, info, warn};
 
 use crate::{
+    auth::Credentials,
     db::mongodb::MongoRepo,
-    models::restaurant::Restaurant,
+    compression::{self, Compression},
+    content::{self, BodyFormat, ResponseFormat},
+    models::restaurant::{CreateRestaurantRequest, RestaurantDoc, UpdateRestaurantRequest},
     error::AppError,
+    frameworks::{Framework, ServerContext},
+    graphql::{self, GraphQl},
+    health,
+    limits::{self, BodyLimits, BodyRoute},
+    metrics::{self, Metrics},
+    openapi,
+    ratelimit::{Admitted, RateLimiter, Throttled},
+    request::{Headers, RequestContext, REQUEST_ID_HEADER},
+    tls::{self, ClientCertificate},
 };
 
-pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
-    let repo = Arc::new(MongoRepo::new(&db));
+pub async fn start(ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+    let repo = Arc::new(ctx.repo());
     
     let app = Router::new()
         .route("/api/restaurants", post(create_restaurant))
@@ -25,86 +51,490 @@ pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
         .route("/api/restaurants/:id", get(get_restaurant))
         .route("/api/restaurants/:id", put(update_restaurant))
         .route("/api/restaurants/:id", delete(delete_restaurant))
-        .with_state(repo);
+        .route(
+            "/admin/restaurants/import",
+            post(import_restaurants).layer(Extension(ctx.compression.clone())),
+        )
+        .route("/admin/restaurants/scan", get(scan_restaurants))
+        .layer(Extension(ctx.limits.clone()))
+        .with_state(repo.clone())
+        .merge(
+            Router::new()
+                .route(graphql::ENDPOINT, post(graphql_handler))
+                .route(graphql::WS_ENDPOINT, get(graphql_ws))
+                .route(graphql::GRAPHIQL, get(graphiql))
+                .layer(Extension(ctx.limits.clone()))
+                .layer(Extension(ctx.rate_limiter.clone()))
+                .with_state(GraphQl::new(repo, ctx.auth.clone())),
+        )
+        .merge(
+            Router::new()
+                .route("/healthz", get(healthz))
+                .route("/readyz", get(readyz))
+                .route("/metrics", get(metrics_handler))
+                .route("/openapi.json", get(openapi_json))
+                .route("/docs", get(docs))
+                .with_state(ctx.clone()),
+        )
+        .layer(middleware::from_fn_with_state(ctx.clone(), require_auth))
+        .layer(middleware::from_fn_with_state(ctx.clone(), rate_limit))
+        .layer(middleware::from_fn_with_state(ctx.clone(), cors))
+        .layer(middleware::from_fn_with_state(ctx.clone(), compress))
+        .layer(middleware::from_fn_with_state(ctx.metrics.clone(), track_request));
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 8081));
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    println!("Starting Axum server at http://{}", addr);
+    let addr = ctx.config.server.axum;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Starting Axum server at {}://{}", ctx.scheme(), addr);
     
-    axum::serve(listener, app).await?;
+    if ctx.tls.is_some() {
+        serve_tls(listener, app, &ctx).await;
+    } else {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(ctx.shutdown.cancelled_owned())
+            .await?;
+    }
     
     Ok(())
 }
 
+/// `axum::serve` only takes plain TCP, so TLS connections are accepted here
+/// and handed to hyper, which speaks HTTP/2 or HTTP/1.1 as ALPN decided.
+/// Shuts down like `axum::serve`: no new connections, then waits for the
+/// open ones to finish their requests. Also serves gRPC.
+pub(super) async fn serve_tls(listener: TcpListener, app: Router, ctx: &ServerContext) {
+    let tls = ctx.tls.as_ref().expect("TLS is configured");
+    let acceptor = TlsAcceptor::from(Arc::new(tls.server_config(tls::ALPN_H2)));
+    let graceful = GracefulShutdown::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "Could not accept a TLS connection");
+                    continue;
+                }
+            },
+            _ = ctx.shutdown.cancelled() => break,
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => return debug!(%peer, error = %e, "TLS handshake failed"),
+            };
+            let client = ClientCertificate(stream.get_ref().1.peer_certificates().is_some());
+            let service = hyper::service::service_fn(move |mut req: hyper::Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(peer));
+                req.extensions_mut().insert(client);
+                app.clone().oneshot(req)
+            });
+            let builder = auto::Builder::new(TokioExecutor::new());
+            if let Err(e) = watcher.watch(builder.serve_connection(TokioIo::new(stream), service)).await {
+                debug!(%peer, error = %e, "Connection closed with an error");
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
+
+async fn track_request(State(metrics): State<Metrics>, mut req: Request, next: Next) -> Response {
+    let request = RequestContext::begin(
+        Framework::Axum.name(),
+        req.method().as_str(),
+        req.uri().path(),
+        |name| req.headers().get(name).and_then(|v| v.to_str().ok()),
+    );
+    req.extensions_mut().insert(request.clone());
+
+    let mut response = next.run(req).instrument(request.span.clone()).await;
+    if let Ok(value) = HeaderValue::from_str(&request.request_id) {
+        response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    request.finish(&metrics, response.status().as_u16());
+    response
+}
+
+/// Compresses responses as the request's `Accept-Encoding` allows.
+async fn compress(State(ctx): State<ServerContext>, req: Request, next: Next) -> Response {
+    let accept_encoding = req.headers().get(header::ACCEPT_ENCODING).cloned();
+    let header = |name: &str| (name == "accept-encoding").then(|| accept_encoding.as_ref()?.to_str().ok()).flatten();
+    let (mut parts, body) = next.run(req).await.into_parts();
+    let content_type = parts.headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string);
+
+    // Bodies of unknown length are streamed, and compressed as they go
+    if body.size_hint().exact().is_none() {
+        let (headers, encoding) = ctx.compression.stream_encoding(header, content_type.as_deref());
+        append_headers(&mut parts.headers, headers);
+        let body = match encoding {
+            Some(encoding) => Body::from_stream(compression::compress_stream(
+                encoding,
+                body.into_data_stream().map_err(io::Error::other),
+            )),
+            None => body,
+        };
+        return Response::from_parts(parts, body);
+    }
+
+    let body = match body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let (headers, compressed) = ctx.compression.compress(header, content_type.as_deref(), &body);
+    append_headers(&mut parts.headers, headers);
+    match compressed {
+        Some(compressed) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        None => Response::from_parts(parts, Body::from(body)),
+    }
+}
+
+fn append_headers(map: &mut HeaderMap, headers: Headers) {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            map.append(name, value);
+        }
+    }
+}
+
+/// Answers CORS preflights and adds the CORS headers to other responses.
+async fn cors(State(ctx): State<ServerContext>, req: Request, next: Next) -> Response {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let (mut response, headers) = match ctx.cors.preflight(req.method().as_str(), header) {
+        Some(Ok(headers)) => (StatusCode::NO_CONTENT.into_response(), headers),
+        Some(Err(e)) => (error_response(e), Vec::new()),
+        None => {
+            let headers = ctx.cors.response_headers(header);
+            (next.run(req).await, headers)
+        }
+    };
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response
+}
+
+/// Applies the client's token bucket and the in-flight cap, adding the
+/// `RateLimit-*` headers to the response.
+async fn rate_limit(State(ctx): State<ServerContext>, req: Request, next: Next) -> Response {
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let admission = ctx.rate_limiter.admit(req.method().as_str(), req.uri().path(), peer, |name| {
+        req.headers().get(name).and_then(|v| v.to_str().ok())
+    });
+    match admission {
+        Ok(admitted) => {
+            let headers = admitted.headers().clone();
+            with_headers(hold(next.run(req).await, admitted), headers)
+        }
+        Err(throttled) => throttled_response(throttled),
+    }
+}
+
+fn throttled_response(throttled: Throttled) -> Response {
+    with_headers(error_response(throttled.error), throttled.headers)
+}
+
+fn with_headers(mut response: Response, headers: Headers) -> Response {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+/// Keeps the in-flight slot of a streamed response until its body is done.
+fn hold(response: Response, admitted: Admitted) -> Response {
+    let (parts, body) = response.into_parts();
+    if body.size_hint().exact().is_some() {
+        return Response::from_parts(parts, body);
+    }
+    Response::from_parts(parts, Body::from_stream(admitted.hold(body.into_data_stream())))
+}
+
+/// Rejects requests without a bearer token or API key allowed to use their
+/// route, and with mutual TLS, admin requests without a client certificate.
+async fn require_auth(
+    State(ctx): State<ServerContext>,
+    Extension(request): Extension<RequestContext>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let client = req.extensions().get::<ClientCertificate>().copied().unwrap_or(ClientCertificate(false));
+    let credentials = Credentials::from_headers(|name| req.headers().get(name).and_then(|v| v.to_str().ok()));
+    let authorized = match ctx.check_client_certificate(req.method().as_str(), req.uri().path(), client) {
+        Ok(()) => ctx.auth.authorize(&request, req.method().as_str(), req.uri().path(), &credentials).await,
+        Err(e) => Err(e),
+    };
+    match authorized {
+        Ok(_) => next.run(req).await,
+        Err(e) => {
+            let mut response = error_response(e);
+            if response.status() == StatusCode::UNAUTHORIZED {
+                response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static(ctx.auth.challenge()));
+            }
+            response
+        }
+    }
+}
+
+async fn healthz() -> impl IntoResponse {
+    Json(health::liveness())
+}
+
+async fn readyz(State(ctx): State<ServerContext>) -> impl IntoResponse {
+    let readiness = ctx.readiness().await;
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+async fn metrics_handler(State(ctx): State<ServerContext>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], ctx.metrics.render())
+}
+
+async fn openapi_json() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], openapi::spec_json())
+}
+
+async fn docs() -> impl IntoResponse {
+    Html(openapi::SWAGGER_UI_HTML)
+}
+
+fn error_response(e: AppError) -> Response {
+    let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, [(header::CONTENT_TYPE, e.content_type())], Json(e.body())).into_response()
+}
+
+/// Reads the body of `route` in the format its `Content-Type` names, within
+/// the route's size limit. Used instead of `Json` so every framework
+/// rejects the same bodies the same way.
+async fn decode_body<T: DeserializeOwned>(limits: &BodyLimits, route: BodyRoute, req: Request) -> Result<T, AppError> {
+    let format = limits::body_format(route, req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()))?;
+    let body = body::to_bytes(req.into_body(), limits.max_bytes(route)).await.map_err(|_| limits.too_large(route))?;
+    limits.decode(format, &body)
+}
+
+/// The format to answer with, negotiated from `Accept` and `?format=`.
+fn response_format(headers: &HeaderMap, uri: &Uri) -> Result<ResponseFormat, AppError> {
+    ResponseFormat::negotiate(headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()), uri.query())
+}
+
+/// A restaurant response, already encoded in `format`.
+fn negotiated(status: StatusCode, format: ResponseFormat, body: Result<Vec<u8>, AppError>) -> Response {
+    match body {
+        Ok(body) => (status, [(header::CONTENT_TYPE, format.media_type()), (header::VARY, "Accept")], body).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
 async fn create_restaurant(
     State(repo): State<Arc<MongoRepo>>,
-    Json(restaurant): Json<Restaurant>,
+    Extension(request): Extension<RequestContext>,
+    Extension(limits): Extension<BodyLimits>,
+    req: Request,
 ) -> impl IntoResponse {
-    match repo.create_restaurant(restaurant).await {
-        Ok(created) => (StatusCode::CREATED, Json(created)).into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    let format = match response_format(req.headers(), req.uri()) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let restaurant = match decode_body::<CreateRestaurantRequest>(&limits, BodyRoute::Create, req).await {
+        Ok(restaurant) => restaurant,
+        Err(e) => return error_response(e),
+    };
+
+    let created = repo.create_restaurant(&request, restaurant.into()).await;
+    negotiated(StatusCode::CREATED, format, created.and_then(|created| format.encode_one(&created.into())))
+}
+
+async fn import_restaurants(
+    State(repo): State<Arc<MongoRepo>>,
+    Extension(request): Extension<RequestContext>,
+    Extension(compression): Extension<Compression>,
+    Extension(limits): Extension<BodyLimits>,
+    req: Request,
+) -> impl IntoResponse {
+    let format = match BodyFormat::from_content_type(req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok())) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let content_encoding = req.headers().get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let body = match body::to_bytes(req.into_body(), compression.max_import_bytes()).await {
+        Ok(body) => body,
+        Err(_) => return error_response(compression.import_too_large()),
+    };
+    let restaurants = match compression
+        .decompress(content_encoding.as_deref(), &body)
+        .and_then(|body| limits.decode_many::<CreateRestaurantRequest>(format, &body))
+    {
+        Ok(restaurants) => restaurants,
+        Err(e) => return error_response(e),
+    };
+
+    match repo.import_restaurants(&request, restaurants.into_iter().map(RestaurantDoc::from).collect()).await {
+        Ok(summary) => (StatusCode::CREATED, Json(summary)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn scan_restaurants(State(repo): State<Arc<MongoRepo>>, Extension(request): Extension<RequestContext>) -> impl IntoResponse {
+    match repo.scan_restaurants(&request).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => error_response(e),
     }
 }
 
 async fn list_restaurants(
     State(repo): State<Arc<MongoRepo>>,
+    Extension(request): Extension<RequestContext>,
+    Extension(limits): Extension<BodyLimits>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    match repo.get_restaurants(10).await {
-        Ok(restaurants) => (StatusCode::OK, Json(restaurants)).into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    let format = match response_format(&headers, &uri) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let limit = match limits.list_limit(uri.query()) {
+        Ok(limit) => limit,
+        Err(e) => return error_response(e),
+    };
+
+    match content::stream_restaurants(&repo, &request, limit, format).await {
+        Ok(body) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, format.media_type()), (header::VARY, "Accept")],
+            Body::from_stream(body),
+        )
+            .into_response(),
+        Err(e) => error_response(e),
     }
 }
 
 async fn get_restaurant(
     State(repo): State<Arc<MongoRepo>>,
+    Extension(request): Extension<RequestContext>,
     Path(id): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
+    let format = match response_format(&headers, &uri) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
     let object_id = match ObjectId::parse_str(&id) {
         Ok(id) => id,
-        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid ID format").into_response(),
+        Err(e) => return error_response(e.into()),
     };
 
-    match repo.get_restaurant_by_id(object_id).await {
-        Ok(restaurant) => (StatusCode::OK, Json(restaurant)).into_response(),
-        Err(AppError::NotFound) => StatusCode::NOT_FOUND.into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-    }
+    negotiated(StatusCode::OK, format, content::read_restaurant(&repo, &request, object_id, format).await)
 }
 
 async fn update_restaurant(
     State(repo): State<Arc<MongoRepo>>,
+    Extension(request): Extension<RequestContext>,
+    Extension(limits): Extension<BodyLimits>,
     Path(id): Path<String>,
-    Json(update): Json<Value>,
+    req: Request,
 ) -> impl IntoResponse {
+    let format = match response_format(req.headers(), req.uri()) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
     let object_id = match ObjectId::parse_str(&id) {
         Ok(id) => id,
-        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid ID format").into_response(),
+        Err(e) => return error_response(e.into()),
     };
 
-    let update_doc = match bson::to_document(&update) {
-        Ok(doc) => doc,
-        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid update document").into_response(),
+    let update = match decode_body::<UpdateRestaurantRequest>(&limits, BodyRoute::Update, req).await {
+        Ok(update) => update,
+        Err(e) => return error_response(e),
     };
 
-    match repo.update_restaurant(object_id, update_doc).await {
-        Ok(updated) => (StatusCode::OK, Json(updated)).into_response(),
-        Err(AppError::NotFound) => StatusCode::NOT_FOUND.into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
-    }
+    let updated = repo.update_restaurant(&request, object_id, update).await;
+    negotiated(StatusCode::OK, format, updated.and_then(|updated| format.encode_one(&updated.into())))
 }
 
 async fn delete_restaurant(
     State(repo): State<Arc<MongoRepo>>,
+    Extension(request): Extension<RequestContext>,
     Path(id): Path<String>,
 ) -> impl IntoResponse {
     let object_id = match ObjectId::parse_str(&id) {
         Ok(id) => id,
-        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid ID format").into_response(),
+        Err(e) => return error_response(e.into()),
     };
 
-    match repo.delete_restaurant(object_id).await {
+    match repo.delete_restaurant(&request, object_id).await {
         Ok(_) => StatusCode::NO_CONTENT.into_response(),
-        Err(AppError::NotFound) => StatusCode::NOT_FOUND.into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => error_response(e),
     }
-}
\ No newline at end of file
+}
+async fn graphql_handler(
+    State(graphql): State<GraphQl>,
+    Extension(request): Extension<RequestContext>,
+    Extension(limits): Extension<BodyLimits>,
+    Extension(limiter): Extension<RateLimiter>,
+    req: Request,
+) -> impl IntoResponse {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let credentials = Credentials::from_headers(header);
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+    let budget = limiter.budget(peer, header);
+    match decode_body::<BatchRequest>(&limits, BodyRoute::GraphQL, req).await {
+        Ok(batch) => Json(graphql.execute(&request, credentials, budget, batch).await).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn graphql_ws(
+    State(graphql): State<GraphQl>,
+    Extension(request): Extension<RequestContext>,
+    Extension(limiter): Extension<RateLimiter>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let protocol = headers.get(header::SEC_WEBSOCKET_PROTOCOL).and_then(|v| v.to_str().ok());
+    let protocol = match graphql::negotiate_protocol(protocol) {
+        Ok(protocol) => protocol,
+        Err(e) => return error_response(e),
+    };
+    // The socket holds a slot of `max_in_flight` for as long as it is open
+    let slot = match limiter.reserve() {
+        Ok(slot) => slot,
+        Err(throttled) => return throttled_response(throttled),
+    };
+    ws.protocols([protocol.sec_websocket_protocol()]).on_upgrade(move |socket| async move {
+        let _slot = slot;
+        let (mut sink, stream) = socket.split();
+        let incoming = stream.take_while(|message| future::ready(message.is_ok())).filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Text(text)) => Some(text.into_bytes()),
+                Ok(Message::Binary(bytes)) => Some(bytes),
+                _ => None,
+            })
+        });
+        let mut outgoing = pin!(graphql.websocket(request, protocol, Box::pin(incoming)));
+        while let Some(message) = outgoing.next().await {
+            let message = match message {
+                WsMessage::Text(text) => Message::Text(text),
+                WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame { code, reason: reason.into() })),
+            };
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+async fn graphiql() -> impl IntoResponse {
+    Html(graphql::graphiql_html())
+}
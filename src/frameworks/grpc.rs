@@ -0,0 +1,399 @@
+// tonic returns `Status`, a large error, by value throughout its API
+#![allow(clippy::result_large_err)]
+
+use axum::extract::ConnectInfo;
+use bson::{oid::ObjectId, DateTime, Document};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use prost_types::{FieldMask, Timestamp};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tonic::{metadata::MetadataMap, service::Routes, Request, Response, Status};
+use tracing::{Instrument, info};
+
+use crate::{
+    auth::{self, Credentials},
+    db::mongodb::MongoRepo,
+    error::AppError,
+    frameworks::{Framework, ServerContext},
+    metrics::Metrics,
+    models::restaurant::{Address, ChangeKind, Grade, RestaurantChange, RestaurantDoc, UpdateRestaurantRequest},
+    ratelimit::Admitted,
+    request::RequestContext,
+};
+use proto::restaurant_service_server::{RestaurantService, RestaurantServiceServer};
+
+pub mod proto {
+    tonic::include_proto!("restaurants.v1");
+}
+
+/// Restaurants streamed by `ListRestaurants` when the request sets no limit,
+/// as many as `GET /api/restaurants` returns.
+const DEFAULT_LIST_LIMIT: u32 = 10;
+
+/// Fully qualified method paths, used as the `route` label of gRPC metrics.
+pub const ROUTES: &[&str] = &[
+    CREATE.path,
+    GET.path,
+    LIST.path,
+    UPDATE.path,
+    DELETE.path,
+    WATCH.path,
+];
+
+/// An RPC and the REST method it mirrors, whose rate limit class and auth
+/// operation it shares.
+#[derive(Clone, Copy)]
+struct Rpc {
+    path: &'static str,
+    method: &'static str,
+}
+
+const CREATE: Rpc = Rpc { path: "/restaurants.v1.RestaurantService/CreateRestaurant", method: "POST" };
+const GET: Rpc = Rpc { path: "/restaurants.v1.RestaurantService/GetRestaurant", method: "GET" };
+const LIST: Rpc = Rpc { path: "/restaurants.v1.RestaurantService/ListRestaurants", method: "GET" };
+const UPDATE: Rpc = Rpc { path: "/restaurants.v1.RestaurantService/UpdateRestaurant", method: "PUT" };
+const DELETE: Rpc = Rpc { path: "/restaurants.v1.RestaurantService/DeleteRestaurant", method: "DELETE" };
+const WATCH: Rpc = Rpc { path: "/restaurants.v1.RestaurantService/WatchRestaurants", method: "GET" };
+
+/// Route the RPCs are limited and authorized as.
+const API_ROUTE: &str = "/api/restaurants";
+
+pub async fn start(ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+    let service = RestaurantServiceServer::new(RestaurantGrpc { repo: Arc::new(ctx.repo()), ctx: ctx.clone() });
+    let app = Routes::new(service).prepare().into_axum_router();
+
+    let addr = ctx.config.server.grpc;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Starting gRPC server at {}://{}", ctx.scheme(), addr);
+
+    // tonic's router is an axum one, served over HTTP/2 the way axum is
+    if ctx.tls.is_some() {
+        super::axum::serve_tls(listener, app, &ctx).await;
+    } else {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(ctx.shutdown.clone().cancelled_owned())
+            .await?;
+    }
+
+    Ok(())
+}
+
+struct RestaurantGrpc {
+    repo: Arc<MongoRepo>,
+    ctx: ServerContext,
+}
+
+/// An RPC being served, recorded in the metrics once it finishes. Holds its
+/// slot of `rate_limit.max_in_flight` until dropped.
+struct Call {
+    request: RequestContext,
+    metrics: Metrics,
+    admitted: Admitted,
+}
+
+impl Call {
+    /// Ends the call, recording the status the REST route would have
+    /// answered with.
+    fn finish<T>(self, result: Result<T, AppError>) -> Result<Response<T>, Status> {
+        self.request.finish(&self.metrics, result.as_ref().map_or_else(AppError::status, |_| 200));
+        result.map(Response::new).map_err(Status::from)
+    }
+
+    /// Ends the call with a stream, which keeps the in-flight slot until it
+    /// is done.
+    fn finish_streaming<S>(self, result: Result<S, AppError>) -> Result<Response<BoxStream<'static, S::Item>>, Status>
+    where
+        S: Stream + Send + 'static,
+    {
+        self.request.finish(&self.metrics, result.as_ref().map_or_else(AppError::status, |_| 200));
+        let stream = result?;
+        Ok(Response::new(self.admitted.hold(stream).boxed()))
+    }
+}
+
+impl RestaurantGrpc {
+    /// Starts an RPC the way the HTTP middleware starts a request: request
+    /// context, the client's token bucket and auth, all from the metadata.
+    async fn begin<T>(&self, rpc: Rpc, request: &Request<T>) -> Result<Call, Status> {
+        let metadata = request.metadata();
+        let header = |name: &str| metadata.get(name).and_then(|v| v.to_str().ok());
+        let context = RequestContext::begin(Framework::Grpc.name(), "POST", rpc.path, header);
+        let fail = |e: AppError, metadata: MetadataMap| {
+            context.finish(&self.ctx.metrics, e.status());
+            let status = Status::from(e);
+            Status::with_metadata(status.code(), status.message(), metadata)
+        };
+
+        let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip());
+        let admitted = match self.ctx.rate_limiter.admit(rpc.method, API_ROUTE, peer, header) {
+            Ok(admitted) => admitted,
+            Err(throttled) => {
+                let mut metadata = MetadataMap::new();
+                for (name, value) in throttled.headers {
+                    if let Ok(value) = value.parse() {
+                        metadata.insert(name, value);
+                    }
+                }
+                return Err(fail(throttled.error, metadata));
+            }
+        };
+
+        let operation = auth::required_operation(rpc.method, API_ROUTE).expect("API routes need an operation");
+        let credentials = Credentials::from_headers(header);
+        let authorized = self.ctx.auth.authorize_operation(&context, operation, &credentials).instrument(context.span.clone()).await;
+        if let Err(e) = authorized {
+            return Err(fail(e, MetadataMap::new()));
+        }
+        Ok(Call { request: context, metrics: self.ctx.metrics.clone(), admitted })
+    }
+}
+
+#[tonic::async_trait]
+impl RestaurantService for RestaurantGrpc {
+    type ListRestaurantsStream = BoxStream<'static, Result<proto::Restaurant, Status>>;
+    type WatchRestaurantsStream = BoxStream<'static, Result<proto::RestaurantChange, Status>>;
+
+    async fn create_restaurant(
+        &self,
+        request: Request<proto::CreateRestaurantRequest>,
+    ) -> Result<Response<proto::Restaurant>, Status> {
+        let call = self.begin(CREATE, &request).await?;
+        let result = async {
+            let restaurant = request.into_inner().restaurant.unwrap_or_default().try_into()?;
+            self.repo.create_restaurant(&call.request, restaurant).await
+        };
+        let result = result.instrument(call.request.span.clone()).await;
+        call.finish(result.map(proto::Restaurant::from))
+    }
+
+    async fn get_restaurant(
+        &self,
+        request: Request<proto::GetRestaurantRequest>,
+    ) -> Result<Response<proto::Restaurant>, Status> {
+        let call = self.begin(GET, &request).await?;
+        let result = async {
+            let id = ObjectId::parse_str(&request.get_ref().id)?;
+            self.repo.get_restaurant_by_id(&call.request, id).await
+        };
+        let result = result.instrument(call.request.span.clone()).await;
+        call.finish(result.map(proto::Restaurant::from))
+    }
+
+    async fn list_restaurants(
+        &self,
+        request: Request<proto::ListRestaurantsRequest>,
+    ) -> Result<Response<Self::ListRestaurantsStream>, Status> {
+        let call = self.begin(LIST, &request).await?;
+        let list = request.into_inner();
+        let mut filter = Document::new();
+        for (key, value) in [("borough", list.borough), ("cuisine", list.cuisine)] {
+            if !value.is_empty() {
+                filter.insert(key, value);
+            }
+        }
+        let limit = if list.limit == 0 { DEFAULT_LIST_LIMIT } else { list.limit };
+        let result = self
+            .repo
+            .stream_restaurants(&call.request, filter, i64::from(limit))
+            .instrument(call.request.span.clone())
+            .await;
+        call.finish_streaming(result.map(|restaurants| {
+            restaurants.map(|restaurant| restaurant.map(proto::Restaurant::from).map_err(Status::from))
+        }))
+    }
+
+    async fn update_restaurant(
+        &self,
+        request: Request<proto::UpdateRestaurantRequest>,
+    ) -> Result<Response<proto::Restaurant>, Status> {
+        let call = self.begin(UPDATE, &request).await?;
+        let result = async {
+            let update = request.into_inner();
+            let id = ObjectId::parse_str(&update.id)?;
+            let update = update_request(update.restaurant.unwrap_or_default(), update.update_mask)?;
+            self.repo.update_restaurant(&call.request, id, update).await
+        };
+        let result = result.instrument(call.request.span.clone()).await;
+        call.finish(result.map(proto::Restaurant::from))
+    }
+
+    async fn delete_restaurant(&self, request: Request<proto::DeleteRestaurantRequest>) -> Result<Response<()>, Status> {
+        let call = self.begin(DELETE, &request).await?;
+        let result = async {
+            let id = ObjectId::parse_str(&request.get_ref().id)?;
+            self.repo.delete_restaurant(&call.request, id).await
+        };
+        let result = result.instrument(call.request.span.clone()).await;
+        call.finish(result)
+    }
+
+    async fn watch_restaurants(
+        &self,
+        request: Request<proto::WatchRestaurantsRequest>,
+    ) -> Result<Response<Self::WatchRestaurantsStream>, Status> {
+        let call = self.begin(WATCH, &request).await?;
+        let result = self.repo.watch(&call.request).instrument(call.request.span.clone()).await;
+        // Ends with the server rather than holding up its shutdown
+        let shutdown = self.ctx.shutdown.clone().cancelled_owned();
+        call.finish(result.map(|changes| {
+            changes
+                .map(|change| change.map(proto::RestaurantChange::from).map_err(Status::from))
+                .take_until(shutdown)
+                .boxed()
+        }))
+    }
+}
+
+/// The `$set` of `UpdateRestaurant`: the fields of `restaurant` named by
+/// `update_mask`.
+fn update_request(restaurant: proto::Restaurant, update_mask: Option<FieldMask>) -> Result<UpdateRestaurantRequest, AppError> {
+    let paths = update_mask.map(|mask| mask.paths).unwrap_or_default();
+    if paths.is_empty() {
+        return Err(AppError::BadRequest("update_mask names no fields".to_string()));
+    }
+    let restaurant = RestaurantDoc::try_from(restaurant)?;
+    let mut update = UpdateRestaurantRequest::default();
+    for path in paths {
+        match path.as_str() {
+            "name" => update.name = Some(restaurant.name.clone()),
+            "borough" => update.borough = Some(restaurant.borough.clone()),
+            "cuisine" => update.cuisine = Some(restaurant.cuisine.clone()),
+            "restaurant_id" => update.restaurant_id = Some(restaurant.restaurant_id.clone()),
+            "address" => update.address = Some(restaurant.address.clone()),
+            "grades" => update.grades = Some(restaurant.grades.clone()),
+            other => return Err(AppError::BadRequest(format!("{} cannot be updated", other))),
+        }
+    }
+    Ok(update)
+}
+
+fn timestamp(date: DateTime) -> Timestamp {
+    let millis = date.timestamp_millis();
+    Timestamp { seconds: millis.div_euclid(1000), nanos: (millis.rem_euclid(1000) * 1_000_000) as i32 }
+}
+
+impl From<RestaurantDoc> for proto::Restaurant {
+    fn from(restaurant: RestaurantDoc) -> Self {
+        proto::Restaurant {
+            id: restaurant.id.map(|id| id.to_hex()).unwrap_or_default(),
+            name: restaurant.name,
+            borough: restaurant.borough,
+            cuisine: restaurant.cuisine,
+            restaurant_id: restaurant.restaurant_id,
+            address: restaurant.address.map(|address| proto::Address {
+                building: address.building,
+                coord: address.coord,
+                street: address.street,
+                zipcode: address.zipcode,
+            }),
+            grades: restaurant
+                .grades
+                .into_iter()
+                .map(|grade| proto::Grade { date: Some(timestamp(grade.date)), grade: grade.grade, score: grade.score })
+                .collect(),
+            created_by: restaurant.created_by,
+            updated_by: restaurant.updated_by,
+        }
+    }
+}
+
+/// A restaurant sent by a client. Its ID and attribution are the server's
+/// to set, so they are left out.
+impl TryFrom<proto::Restaurant> for RestaurantDoc {
+    type Error = AppError;
+
+    fn try_from(restaurant: proto::Restaurant) -> Result<Self, AppError> {
+        let grades = restaurant
+            .grades
+            .into_iter()
+            .map(|grade| {
+                let date = grade.date.ok_or_else(|| AppError::BadRequest("every grade needs a date".to_string()))?;
+                let millis = date
+                    .seconds
+                    .checked_mul(1000)
+                    .and_then(|millis| millis.checked_add(i64::from(date.nanos) / 1_000_000))
+                    .ok_or_else(|| AppError::BadRequest(format!("grade date of {} seconds is out of range", date.seconds)))?;
+                Ok(Grade { date: DateTime::from_millis(millis), grade: grade.grade, score: grade.score })
+            })
+            .collect::<Result<_, AppError>>()?;
+        Ok(RestaurantDoc {
+            name: restaurant.name,
+            borough: restaurant.borough,
+            cuisine: restaurant.cuisine,
+            restaurant_id: restaurant.restaurant_id,
+            address: restaurant.address.map(|address| Address {
+                building: address.building,
+                coord: address.coord,
+                street: address.street,
+                zipcode: address.zipcode,
+            }),
+            grades,
+            ..Default::default()
+        })
+    }
+}
+
+impl From<RestaurantChange> for proto::RestaurantChange {
+    fn from(change: RestaurantChange) -> Self {
+        let kind = match change.kind {
+            ChangeKind::Created => proto::restaurant_change::Kind::Created,
+            ChangeKind::Updated => proto::restaurant_change::Kind::Updated,
+            ChangeKind::Deleted => proto::restaurant_change::Kind::Deleted,
+        };
+        proto::RestaurantChange {
+            kind: kind.into(),
+            id: change.id.to_hex(),
+            restaurant: change.restaurant.map(proto::Restaurant::from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frameworks::testing;
+    use proto::restaurant_service_client::RestaurantServiceClient;
+
+    #[test]
+    fn update_mask_picks_the_fields_to_set() {
+        let restaurant = proto::Restaurant {
+            name: "Nordic Delicacies".to_string(),
+            cuisine: "Scandinavian".to_string(),
+            grades: vec![proto::Grade { date: Some(Timestamp { seconds: -1, nanos: 500_000_000 }), grade: "A".to_string(), score: 2 }],
+            ..Default::default()
+        };
+        let mask = |paths: &[&str]| Some(FieldMask { paths: paths.iter().map(|path| path.to_string()).collect() });
+
+        let update = update_request(restaurant.clone(), mask(&["name", "grades"])).unwrap().to_document().unwrap();
+        assert_eq!(update.keys().collect::<Vec<_>>(), ["name", "grades"]);
+        let date = update.get_array("grades").unwrap()[0].as_document().unwrap().get_datetime("date").unwrap();
+        assert_eq!(timestamp(*date), Timestamp { seconds: -1, nanos: 500_000_000 });
+
+        assert_eq!(update_request(restaurant.clone(), None).unwrap_err().status(), 400);
+        assert_eq!(update_request(restaurant, mask(&["created_by"])).unwrap_err().status(), 400);
+
+        let far = proto::Restaurant {
+            grades: vec![proto::Grade { date: Some(Timestamp { seconds: i64::MAX, nanos: 0 }), grade: "A".to_string(), score: 2 }],
+            ..Default::default()
+        };
+        assert_eq!(RestaurantDoc::try_from(far).unwrap_err().status(), 400);
+    }
+
+    /// Bad requests are turned away with the status code their `AppError`
+    /// maps to, before touching MongoDB.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejected_calls_map_to_grpc_codes() {
+        let codes = testing::with_every_framework(testing::config(), |config| async move {
+            let addr = testing::addr(&config, Framework::Grpc);
+            let mut client = RestaurantServiceClient::connect(format!("http://{}", addr)).await.unwrap();
+            let get = client.get_restaurant(proto::GetRestaurantRequest { id: "x".to_string() }).await;
+            let update = client
+                .update_restaurant(proto::UpdateRestaurantRequest { id: "5eb3d668b31de5d588f42a7e".to_string(), ..Default::default() })
+                .await;
+            [get.unwrap_err().code(), update.unwrap_err().code()]
+        })
+        .await;
+        assert_eq!(codes, [tonic::Code::InvalidArgument; 2]);
+    }
+}
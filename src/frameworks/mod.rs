@@ -3,4 +3,280 @@ pub mod actix;
 pub mod axum;
 pub mod rocket;
 pub mod warp;
-pub mod tide;
\ No newline at end of file
+pub mod tide;
+pub mod grpc;
+#[cfg(test)]
+pub mod testing;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::future::join_all;
+use mongodb::Database;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    auth::Authenticator,
+    compression::Compression,
+    config::Config,
+    cors::CorsPolicy,
+    db::mongodb::MongoRepo,
+    health::{self, Readiness, TopologyWatcher},
+    limits::BodyLimits,
+    metrics::Metrics,
+    ratelimit::RateLimiter,
+    error::AppError,
+    tls::{ClientCertificate, Tls},
+};
+
+/// What every framework needs to serve the API. Clones share the same
+/// connection pool, configuration and shutdown signal.
+#[derive(Clone)]
+pub struct ServerContext {
+    pub db: Database,
+    pub config: Arc<Config>,
+    pub topology: TopologyWatcher,
+    pub metrics: Metrics,
+    pub auth: Authenticator,
+    pub cors: CorsPolicy,
+    pub compression: Compression,
+    pub limits: BodyLimits,
+    pub rate_limiter: RateLimiter,
+    /// `None` when the servers speak plain HTTP.
+    pub tls: Option<Tls>,
+    pub shutdown: CancellationToken,
+}
+
+impl ServerContext {
+    pub fn repo(&self) -> MongoRepo {
+        MongoRepo::new(&self.db, &self.config.mongodb.collection)
+    }
+
+    /// The `/readyz` report; not ready once shutdown has started.
+    pub async fn readiness(&self) -> Readiness {
+        health::readiness(&self.db, &self.topology, &self.config.health, self.shutdown.is_cancelled()).await
+    }
+
+    /// `https` or `http`, for log messages.
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() { "https" } else { "http" }
+    }
+
+    /// With mutual TLS, admin routes need a client certificate.
+    pub fn check_client_certificate(&self, method: &str, path: &str, client: ClientCertificate) -> Result<(), AppError> {
+        match &self.tls {
+            Some(tls) => tls.check_client_certificate(method, path, client),
+            None => Ok(()),
+        }
+    }
+
+    /// How long in-flight requests get to finish once shutdown starts.
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.shutdown.drain_timeout_secs)
+    }
+}
+
+/// The frameworks that can be served, each on its own port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framework {
+    Actix,
+    Axum,
+    Rocket,
+    Warp,
+    Tide,
+    Grpc,
+}
+
+impl Framework {
+    pub const ALL: [Framework; 6] = [
+        Framework::Actix,
+        Framework::Axum,
+        Framework::Rocket,
+        Framework::Warp,
+        Framework::Tide,
+        Framework::Grpc,
+    ];
+
+    /// The frameworks serving the REST API, whose behavior tests compare.
+    #[cfg(test)]
+    pub const HTTP: [Framework; 5] = [
+        Framework::Actix,
+        Framework::Axum,
+        Framework::Rocket,
+        Framework::Warp,
+        Framework::Tide,
+    ];
+
+    async fn start(self, ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Framework::Actix => actix::start(ctx).await,
+            Framework::Axum => axum::start(ctx).await,
+            Framework::Rocket => rocket::start(ctx).await,
+            Framework::Warp => warp::start(ctx).await,
+            Framework::Tide => tide::start(ctx).await,
+            Framework::Grpc => grpc::start(ctx).await,
+        }
+    }
+}
+
+impl Framework {
+    /// Lower case name, as used on the command line and in metric labels.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Framework::Actix => "actix",
+            Framework::Axum => "axum",
+            Framework::Rocket => "rocket",
+            Framework::Warp => "warp",
+            Framework::Tide => "tide",
+            Framework::Grpc => "grpc",
+        }
+    }
+}
+
+impl fmt::Display for Framework {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Runs the given frameworks side by side until SIGINT/SIGTERM, or until any
+/// of them stops. Every server shares the connection pool behind `ctx.db` and
+/// is told to shut down through `ctx.shutdown`, so they always go down together:
+/// each stops accepting connections, in-flight requests get
+/// `shutdown.drain_timeout_secs` to finish, and then the client is shut down.
+pub async fn serve(ctx: ServerContext, frameworks: &[Framework]) -> Result<(), Box<dyn std::error::Error>> {
+    let signal = ctx.shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("Shutdown requested, draining in-flight requests");
+        signal.cancel();
+    });
+    if let Some(tls) = &ctx.tls {
+        tokio::spawn(tls.clone().watch(ctx.shutdown.clone()));
+    }
+    if ctx.config.rate_limit.enabled {
+        tokio::spawn(ctx.rate_limiter.clone().prune(ctx.shutdown.clone()));
+    }
+
+    // The servers are polled from this task rather than spawned, since their
+    // boxed errors are not `Send`. Each one still hands its connections off
+    // to its own executor (actix workers, async-std for tide, tokio for the rest).
+    let servers = join_all(frameworks.iter().map(|&framework| {
+        let ctx = ctx.clone();
+        async move {
+            let result = framework.start(ctx.clone()).await;
+            ctx.shutdown.cancel();
+            result.map_err(|e| format!("{} server failed: {}", framework, e))
+        }
+    }));
+
+    let drain_timeout = ctx.drain_timeout();
+    let deadline = async {
+        ctx.shutdown.cancelled().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    let results = tokio::select! {
+        results = servers => Some(results),
+        _ = deadline => None,
+    };
+
+    let client = ctx.db.client().clone();
+    match results {
+        Some(results) => {
+            client.shutdown().await;
+            for result in results {
+                result?;
+            }
+        }
+        None => {
+            tracing::warn!("Requests still running after {:?}, shutting down anyway", drain_timeout);
+            client.shutdown().immediate(true).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one (e.g. a Kubernetes pod being stopped).
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    /// Once shutdown starts, every framework must stop taking connections but
+    /// still answer the requests it is serving.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_drains_in_flight_requests_on_shutdown() {
+        let mut config = testing::config();
+        // Long enough that the servers stop because they drained, not because time ran out
+        config.shutdown.drain_timeout_secs = 10;
+
+        let (failures, config) = testing::with_every_framework_and_shutdown(config, |config, shutdown| async move {
+            // Uploads whose body only ends after shutdown has started; the
+            // body is malformed, so they end in a 400 without MongoDB. Rocket
+            // routes a request once it has the first 14 bytes of its body.
+            let (first, rest) = ("{\"name\": \"Hanging", "\"]");
+            let mut uploads = Vec::new();
+            for framework in Framework::HTTP {
+                let mut upload = TcpStream::connect(testing::addr(&config, framework)).await.unwrap();
+                let head = format!(
+                    "POST /api/restaurants HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    first.len() + rest.len(),
+                    first,
+                );
+                upload.write_all(head.as_bytes()).await.unwrap();
+                uploads.push((framework, upload));
+            }
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            shutdown.cancel();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+
+            let mut failures = Vec::new();
+            for (framework, mut upload) in uploads {
+                let mut response = Vec::new();
+                let finished = upload.write_all(rest.as_bytes()).await.is_ok() && upload.read_to_end(&mut response).await.is_ok();
+                if !finished || !response.starts_with(b"HTTP/1.1 400") {
+                    failures.push(format!("{}: the in-flight upload got {:?}", framework, String::from_utf8_lossy(&response)));
+                }
+            }
+            (failures, config)
+        })
+        .await;
+
+        let mut failures = failures;
+        for framework in Framework::HTTP {
+            if TcpStream::connect(testing::addr(&config, framework)).await.is_ok() {
+                failures.push(format!("{}: still accepts connections after shutting down", framework));
+            }
+        }
+        assert!(failures.is_empty(), "shutdown differs:\n{}", failures.join("\n"));
+    }
+}
@@ -1,15 +1,13 @@
-use mongodb::Database;
-use bson::{oid::ObjectId, Document};
-use serde_json::Value;
+use bson::oid::ObjectId;
 use std::io::{self, Write};
 
 use crate::{
     db::mongodb::MongoRepo,
-    models::restaurant::Restaurant,
+    models::restaurant::{CreateRestaurantRequest, UpdateRestaurantRequest},
+    request::RequestContext,
 };
 
-pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
-    let repo = MongoRepo::new(&db);
+pub async fn start(repo: MongoRepo) -> Result<(), Box<dyn std::error::Error>> {
     
     loop {
         println!("\nAvailable operations:");
@@ -45,8 +43,8 @@ async fn create_restaurant(repo: &MongoRepo) -> Result<(), Box<dyn std::error::E
     let mut input = String::new();
     io::stdin().read_line(&mut input)?;
     
-    let restaurant: Restaurant = serde_json::from_str(&input)?;
-    match repo.create_restaurant(restaurant).await {
+    let restaurant: CreateRestaurantRequest = serde_json::from_str(&input)?;
+    match repo.create_restaurant(&RequestContext::cli("create_restaurant"), restaurant.into()).await {
         Ok(created) => println!("Created restaurant: {:?}", created),
         Err(e) => println!("Error creating restaurant: {}", e),
     }
@@ -55,7 +53,7 @@ async fn create_restaurant(repo: &MongoRepo) -> Result<(), Box<dyn std::error::E
 }
 
 async fn list_restaurants(repo: &MongoRepo) -> Result<(), Box<dyn std::error::Error>> {
-    match repo.get_restaurants(10).await {
+    match repo.get_restaurants(&RequestContext::cli("list_restaurants"), 10).await {
         Ok(restaurants) => {
             for restaurant in restaurants {
                 println!("{:?}", restaurant);
@@ -75,7 +73,7 @@ async fn get_restaurant_by_id(repo: &MongoRepo) -> Result<(), Box<dyn std::error
     io::stdin().read_line(&mut input)?;
     
     let id = ObjectId::parse_str(input.trim())?;
-    match repo.get_restaurant_by_id(id).await {
+    match repo.get_restaurant_by_id(&RequestContext::cli("get_restaurant_by_id"), id).await {
         Ok(restaurant) => println!("{:?}", restaurant),
         Err(e) => println!("Error fetching restaurant: {}", e),
     }
@@ -95,10 +93,9 @@ async fn update_restaurant(repo: &MongoRepo) -> Result<(), Box<dyn std::error::E
     io::stdin().read_line(&mut update_input)?;
     
     let id = ObjectId::parse_str(id_input.trim())?;
-    let update: Value = serde_json::from_str(&update_input)?;
-    let update_doc: Document = bson::to_document(&update)?;
+    let update: UpdateRestaurantRequest = serde_json::from_str(&update_input)?;
     
-    match repo.update_restaurant(id, update_doc).await {
+    match repo.update_restaurant(&RequestContext::cli("update_restaurant"), id, update).await {
         Ok(updated) => println!("Updated restaurant: {:?}", updated),
         Err(e) => println!("Error updating restaurant: {}", e),
     }
@@ -114,7 +111,7 @@ async fn delete_restaurant(repo: &MongoRepo) -> Result<(), Box<dyn std::error::E
     io::stdin().read_line(&mut input)?;
     
     let id = ObjectId::parse_str(input.trim())?;
-    match repo.delete_restaurant(id).await {
+    match repo.delete_restaurant(&RequestContext::cli("delete_restaurant"), id).await {
         Ok(_) => println!("Restaurant deleted successfully"),
         Err(e) => println!("Error deleting restaurant: {}", e),
     }
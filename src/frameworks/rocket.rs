@@ -2,93 +2,666 @@ use rocket::{
     self,
     serde::json::Json,
     State,
-    response::status::Created,
-    http::Status,
+    Data,
+    data::{self, FromData, ToByteUnit},
+    error::ErrorKind,
+    Request,
+    Response,
+    fairing::{Fairing, Info, Kind},
+    request::{FromRequest, Outcome},
+    response::{self, Responder, status::Created},
+    http::{ContentType, Header, Status},
+    mtls::Certificate,
     routes, // Import the `routes` macro
+    catchers,
 };
-use mongodb::Database;
 use bson::oid::ObjectId;
+use serde::de::DeserializeOwned;
+use std::future;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::net::TcpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::info;
 use crate::{
+    auth::Credentials,
+    compression,
+    content::{self, BodyFormat, BodyStream, ResponseFormat},
     db::mongodb::MongoRepo,
-    models::restaurant::Restaurant,
-    error::AppError,
+    models::lenient::ScanReport,
+    models::restaurant::{CreateRestaurantRequest, ImportSummary, RestaurantDoc},
+    error::{AppError, ErrorBody},
+    frameworks::{Framework, ServerContext},
+    health::{self, Liveness, Readiness},
+    limits::{self, BodyLimits, BodyRoute},
+    metrics::{self, Metrics},
+    openapi,
+    ratelimit::Admitted,
+    request::{Headers, RequestContext, REQUEST_ID_HEADER},
+    tls::{ClientCertificate, Tls},
 };
 
-#[rocket::get("/restaurants")]
-async fn list_restaurants(repo: &State<MongoRepo>) -> Result<Json<Vec<Restaurant>>, Status> {
-    match repo.get_restaurants(10).await {
-        Ok(restaurants) => Ok(Json(restaurants)),
-        Err(_) => Err(Status::InternalServerError),
+/// Gives every request a `RequestContext`, kept in Rocket's request-local
+/// cache, adds the shared response headers, compresses the response and logs
+/// and records it in the metrics once it is ready.
+struct RequestFairing(Metrics);
+
+fn request_context<'r>(req: &'r Request<'_>) -> &'r RequestContext {
+    req.local_cache(|| {
+        RequestContext::begin(
+            Framework::Rocket.name(),
+            req.method().as_str(),
+            req.uri().path().as_str(),
+            |name| req.headers().get_one(name),
+        )
+    })
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestFairing {
+    fn info(&self) -> Info {
+        Info { name: "Request context", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        request_context(req);
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let request = request_context(req);
+        res.set_header(Header::new(REQUEST_ID_HEADER, request.request_id.clone()));
+        if res.status() == Status::Unauthorized {
+            if let Some(ctx) = req.rocket().state::<ServerContext>() {
+                res.set_header(Header::new("WWW-Authenticate", ctx.auth.challenge()));
+            }
+        }
+        for (name, value) in &req.local_cache(|| RateLimitHeaders(Vec::new())).0 {
+            res.set_header(Header::new(*name, value.clone()));
+        }
+        if let Some(ctx) = req.rocket().state::<ServerContext>() {
+            // `preflight` adds the headers of preflights itself
+            if !ctx.cors.is_preflight(req.method().as_str(), |name| req.headers().get_one(name)) {
+                for (name, value) in ctx.cors.response_headers(|name| req.headers().get_one(name)) {
+                    res.adjoin_header(Header::new(name, value));
+                }
+            }
+            compress(ctx, req, res).await;
+        }
+        let admitted = req.local_cache(|| Admission(Mutex::new(None))).0.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(admitted) = admitted {
+            hold(res, admitted);
+        }
+        request.finish(&self.0, res.status().code);
+    }
+}
+
+/// Keeps the in-flight slot of a streamed response until its body is done.
+fn hold(res: &mut Response<'_>, admitted: Admitted) {
+    if res.body().is_some() && res.body().preset_size().is_none() {
+        let body = ReaderStream::new(res.body_mut().take());
+        res.set_streamed_body(StreamReader::new(admitted.hold(body)));
+    }
+}
+
+/// Compresses the response as the request's `Accept-Encoding` allows.
+async fn compress<'r>(ctx: &ServerContext, req: &'r Request<'_>, res: &mut Response<'r>) {
+    // Bodies of unknown length are streamed, and compressed as they go
+    if res.body().is_some() && res.body().preset_size().is_none() {
+        let content_type = res.headers().get_one("Content-Type");
+        let (headers, encoding) = ctx.compression.stream_encoding(|name| req.headers().get_one(name), content_type);
+        for (name, value) in headers {
+            res.adjoin_header(Header::new(name, value));
+        }
+        if let Some(encoding) = encoding {
+            let body = ReaderStream::new(res.body_mut().take());
+            res.set_streamed_body(StreamReader::new(compression::compress_stream(encoding, body)));
+        }
+        return;
+    }
+
+    let Ok(body) = res.body_mut().to_bytes().await else {
+        res.set_status(Status::InternalServerError);
+        return;
+    };
+    let content_type = res.headers().get_one("Content-Type");
+    let (headers, compressed) = ctx.compression.compress(|name| req.headers().get_one(name), content_type, &body);
+    for (name, value) in headers {
+        res.adjoin_header(Header::new(name, value));
     }
+    let body = compressed.unwrap_or(body);
+    res.set_sized_body(body.len(), Cursor::new(body));
+}
+
+/// The request's `Content-Encoding`, if any.
+struct ContentEncoding(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ContentEncoding {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ContentEncoding(req.headers().get_one("Content-Encoding").map(str::to_string)))
+    }
+}
+
+/// The request's query string, if any.
+struct RawQuery<'r>(Option<&'r str>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RawQuery<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RawQuery(req.uri().query().map(|query| query.as_str())))
+    }
+}
+
+/// The format to answer with, negotiated from the request's `Accept` and
+/// `?format=`. The handler answers 406 itself, so it gets problem details
+/// like the others.
+struct Accept(Result<ResponseFormat, AppError>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Accept {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let query = req.uri().query().map(|query| query.as_str());
+        Outcome::Success(Accept(ResponseFormat::negotiate(req.headers().get_one("Accept"), query)))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestContext {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request_context(req))
+    }
+}
+
+/// Request guard of the API routes, listed first: the client's token bucket
+/// and the in-flight cap.
+struct RateLimited;
+
+/// `RateLimit-*` and `Retry-After` headers, added by `RequestFairing`.
+struct RateLimitHeaders(Headers);
+
+/// The in-flight slot of an admitted request, which `RequestFairing` hands to
+/// a streamed body or drops with the response.
+struct Admission(Mutex<Option<Admitted>>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimited {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ctx = req.rocket().state::<ServerContext>().expect("ServerContext is managed");
+        let peer = req.remote().map(|addr| addr.ip());
+        let path = req.uri().path();
+        match ctx.rate_limiter.admit(req.method().as_str(), path.as_str(), peer, |name| req.headers().get_one(name)) {
+            Ok(admitted) => {
+                req.local_cache(|| RateLimitHeaders(admitted.headers().clone()));
+                req.local_cache(|| Admission(Mutex::new(Some(admitted))));
+                Outcome::Success(RateLimited)
+            }
+            Err(throttled) => {
+                req.local_cache(|| RateLimitHeaders(throttled.headers));
+                req.local_cache(|| GuardError(Some(throttled.error.to_string())));
+                Outcome::Error((Status::new(throttled.error.status()), ()))
+            }
+        }
+    }
+}
+
+/// Request guard of the API routes: a bearer token or API key allowed to
+/// use the route, when auth is enabled, and with mutual TLS, a client
+/// certificate on admin routes.
+struct Authorized;
+
+/// Why a request guard failed, for `default_catcher` to report.
+struct GuardError(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authorized {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ctx = req.rocket().state::<ServerContext>().expect("ServerContext is managed");
+        let client = ClientCertificate(req.guard::<Certificate<'_>>().await.succeeded().is_some());
+        let credentials = Credentials::from_headers(|name| req.headers().get_one(name));
+        let path = req.uri().path();
+        let authorized = match ctx.check_client_certificate(req.method().as_str(), path.as_str(), client) {
+            Ok(()) => ctx.auth.authorize(request_context(req), req.method().as_str(), path.as_str(), &credentials).await,
+            Err(e) => Err(e),
+        };
+        match authorized {
+            Ok(_) => Outcome::Success(Authorized),
+            Err(e) => {
+                let status = Status::new(e.status());
+                req.local_cache(|| GuardError(Some(e.to_string())));
+                Outcome::Error((status, ()))
+            }
+        }
+    }
+}
+
+#[rocket::get("/healthz")]
+fn healthz() -> Json<Liveness> {
+    Json(health::liveness())
+}
+
+#[rocket::get("/readyz")]
+async fn readyz(ctx: &State<ServerContext>) -> (Status, Json<Readiness>) {
+    let readiness = ctx.readiness().await;
+    let status = if readiness.is_ready() {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(readiness))
+}
+
+#[rocket::get("/metrics")]
+fn metrics_handler(ctx: &State<ServerContext>) -> (ContentType, String) {
+    let content_type = ContentType::parse_flexible(metrics::CONTENT_TYPE).unwrap_or(ContentType::Plain);
+    (content_type, ctx.metrics.render())
+}
+
+#[rocket::get("/openapi.json")]
+fn openapi_json() -> (ContentType, &'static str) {
+    (ContentType::JSON, openapi::spec_json())
+}
+
+#[rocket::get("/docs")]
+fn docs() -> (ContentType, &'static str) {
+    (ContentType::HTML, openapi::SWAGGER_UI_HTML)
+}
+
+/// Answers CORS preflights on every path; other `OPTIONS` requests get 404
+/// as before.
+#[rocket::options("/<_..>")]
+fn preflight() -> Preflight {
+    Preflight
+}
+
+struct Preflight;
+
+impl<'r> Responder<'r, 'static> for Preflight {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let ctx = req.rocket().state::<ServerContext>().expect("ServerContext is managed");
+        match ctx.cors.preflight(req.method().as_str(), |name| req.headers().get_one(name)) {
+            Some(Ok(headers)) => {
+                let mut response = Response::build().status(Status::NoContent).finalize();
+                for (name, value) in headers {
+                    response.adjoin_header(Header::new(name, value));
+                }
+                Ok(response)
+            }
+            Some(Err(e)) => error_response(e).respond_to(req),
+            None => Err(Status::NotFound),
+        }
+    }
+}
+
+/// Error responses of the API handlers.
+type ApiError = (Status, (ContentType, Json<ErrorBody>));
+
+fn error_response(e: AppError) -> ApiError {
+    let content_type = ContentType::parse_flexible(e.content_type()).unwrap_or(ContentType::JSON);
+    (Status::new(e.status()), (content_type, Json(e.body())))
+}
+
+/// A restaurant response, already encoded in its negotiated format.
+struct Negotiated {
+    status: Status,
+    format: ResponseFormat,
+    body: Vec<u8>,
+}
+
+impl<'r> Responder<'r, 'static> for Negotiated {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let content_type = ContentType::parse_flexible(self.format.media_type()).unwrap_or(ContentType::Binary);
+        Response::build()
+            .status(self.status)
+            .header(content_type)
+            .raw_header("Vary", "Accept")
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
+
+/// A restaurant list, sent in `format` as it is encoded.
+struct Streamed {
+    format: ResponseFormat,
+    body: BodyStream,
+}
+
+impl<'r> Responder<'r, 'static> for Streamed {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let content_type = ContentType::parse_flexible(self.format.media_type()).unwrap_or(ContentType::Binary);
+        Response::build()
+            .header(content_type)
+            .raw_header("Vary", "Accept")
+            .streamed_body(StreamReader::new(self.body))
+            .ok()
+    }
+}
+
+/// A request body and its `Content-Type`, read by the handler within the
+/// route's size limit.
+struct RequestBody<'r> {
+    content_type: Option<&'r str>,
+    data: Data<'r>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for RequestBody<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        data::Outcome::Success(RequestBody { content_type: req.headers().get_one("Content-Type"), data })
+    }
+}
+
+/// Reads the body of `route` in the format its `Content-Type` names, within
+/// the route's size limit. Used instead of `Json` so every framework
+/// rejects the same bodies the same way.
+async fn decode_body<T: DeserializeOwned>(limits: &BodyLimits, route: BodyRoute, body: RequestBody<'_>) -> Result<T, ApiError> {
+    let format = limits::body_format(route, body.content_type).map_err(error_response)?;
+    let body = body
+        .data
+        .open(limits.max_bytes(route).bytes())
+        .into_bytes()
+        .await
+        .map_err(|e| error_response(AppError::BadRequest(e.to_string())))?;
+    if !body.is_complete() {
+        return Err(error_response(limits.too_large(route)));
+    }
+    limits.decode(format, &body).map_err(error_response)
+}
+
+/// Answers errors raised by Rocket itself (unknown routes, unparsable
+/// bodies) with the same JSON body as the handlers.
+#[rocket::catch(default)]
+fn default_catcher(status: Status, req: &Request<'_>) -> ApiError {
+    let error = match &req.local_cache(|| GuardError(None)).0 {
+        Some(error) => error.clone(),
+        None => status.reason().unwrap_or("Error").to_string(),
+    };
+    (status, (ContentType::JSON, Json(ErrorBody { error, ..Default::default() })))
+}
+
+#[rocket::get("/restaurants")]
+async fn list_restaurants(
+    _limit: RateLimited,
+    _auth: Authorized,
+    repo: &State<MongoRepo>,
+    ctx: &State<ServerContext>,
+    request: &RequestContext,
+    accept: Accept,
+    query: RawQuery<'_>,
+) -> Result<Streamed, ApiError> {
+    let format = accept.0.map_err(error_response)?;
+    let limit = ctx.limits.list_limit(query.0).map_err(error_response)?;
+
+    let body = content::stream_restaurants(repo, request, limit, format).await.map_err(error_response)?;
+    Ok(Streamed { format, body })
 }
 
 #[rocket::get("/restaurants/<id>")]
-async fn get_restaurant(repo: &State<MongoRepo>, id: &str) -> Result<Json<Restaurant>, Status> {
+async fn get_restaurant(
+    _limit: RateLimited,
+    _auth: Authorized,
+    repo: &State<MongoRepo>,
+    request: &RequestContext,
+    accept: Accept,
+    id: &str,
+) -> Result<Negotiated, ApiError> {
+    let format = accept.0.map_err(error_response)?;
     let object_id = match ObjectId::parse_str(id) {
         Ok(id) => id,
-        Err(_) => return Err(Status::BadRequest),
+        Err(e) => return Err(error_response(e.into())),
     };
 
-    match repo.get_restaurant_by_id(object_id).await {
-        Ok(restaurant) => Ok(Json(restaurant)),
-        Err(AppError::NotFound) => Err(Status::NotFound),
-        Err(_) => Err(Status::InternalServerError),
-    }
+    let body = content::read_restaurant(repo, request, object_id, format).await.map_err(error_response)?;
+    Ok(Negotiated { status: Status::Ok, format, body })
 }
 
-#[rocket::post("/restaurants", data = "<restaurant>")]
+#[rocket::post("/restaurants", data = "<body>")]
 async fn create_restaurant(
+    _limit: RateLimited,
+    _auth: Authorized,
+    ctx: &State<ServerContext>,
+    repo: &State<MongoRepo>,
+    request: &RequestContext,
+    accept: Accept,
+    body: RequestBody<'_>,
+) -> Result<Created<Negotiated>, ApiError> {
+    let format = accept.0.map_err(error_response)?;
+    let restaurant: CreateRestaurantRequest = decode_body(&ctx.limits, BodyRoute::Create, body).await?;
+
+    let body = repo
+        .create_restaurant(request, restaurant.into())
+        .await
+        .and_then(|created| format.encode_one(&created.into()))
+        .map_err(error_response)?;
+    Ok(Created::new("/").body(Negotiated { status: Status::Created, format, body }))
+}
+
+#[rocket::post("/restaurants/import", data = "<body>")]
+async fn import_restaurants(
+    _limit: RateLimited,
+    _auth: Authorized,
+    ctx: &State<ServerContext>,
     repo: &State<MongoRepo>,
-    restaurant: Json<Restaurant>,
-) -> Result<Created<Json<Restaurant>>, Status> {
-    match repo.create_restaurant(restaurant.into_inner()).await {
-        Ok(created) => Ok(Created::new("/").body(Json(created))),
-        Err(_) => Err(Status::InternalServerError),
+    request: &RequestContext,
+    content_encoding: ContentEncoding,
+    body: RequestBody<'_>,
+) -> Result<(Status, Json<ImportSummary>), ApiError> {
+    let format = BodyFormat::from_content_type(body.content_type).map_err(error_response)?;
+    let compression = &ctx.compression;
+    let body = body
+        .data
+        .open(compression.max_import_bytes().bytes())
+        .into_bytes()
+        .await
+        .map_err(|e| error_response(AppError::BadRequest(e.to_string())))?;
+    if !body.is_complete() {
+        return Err(error_response(compression.import_too_large()));
+    }
+    let restaurants = compression
+        .decompress(content_encoding.0.as_deref(), &body)
+        .and_then(|body| ctx.limits.decode_many::<CreateRestaurantRequest>(format, &body))
+        .map_err(error_response)?;
+
+    match repo.import_restaurants(request, restaurants.into_iter().map(RestaurantDoc::from).collect()).await {
+        Ok(summary) => Ok((Status::Created, Json(summary))),
+        Err(e) => Err(error_response(e)),
     }
 }
 
-#[rocket::put("/restaurants/<id>", data = "<update>")]
+#[rocket::get("/restaurants/scan")]
+async fn scan_restaurants(
+    _limit: RateLimited,
+    _auth: Authorized,
+    repo: &State<MongoRepo>,
+    request: &RequestContext,
+) -> Result<Json<ScanReport>, ApiError> {
+    repo.scan_restaurants(request).await.map(Json).map_err(error_response)
+}
+
+#[rocket::put("/restaurants/<id>", data = "<body>")]
+#[allow(clippy::too_many_arguments)]
 async fn update_restaurant(
+    _limit: RateLimited,
+    _auth: Authorized,
+    ctx: &State<ServerContext>,
     repo: &State<MongoRepo>,
+    request: &RequestContext,
+    accept: Accept,
     id: &str,
-    update: Json<bson::Document>,
-) -> Result<Json<Restaurant>, Status> {
+    body: RequestBody<'_>,
+) -> Result<Negotiated, ApiError> {
+    let format = accept.0.map_err(error_response)?;
     let object_id = match ObjectId::parse_str(id) {
         Ok(id) => id,
-        Err(_) => return Err(Status::BadRequest),
+        Err(e) => return Err(error_response(e.into())),
     };
+    let update = decode_body(&ctx.limits, BodyRoute::Update, body).await?;
 
-    match repo.update_restaurant(object_id, update.into_inner()).await {
-        Ok(updated) => Ok(Json(updated)),
-        Err(AppError::NotFound) => Err(Status::NotFound),
-        Err(_) => Err(Status::InternalServerError),
-    }
+    let body = repo
+        .update_restaurant(request, object_id, update)
+        .await
+        .and_then(|updated| format.encode_one(&updated.into()))
+        .map_err(error_response)?;
+    Ok(Negotiated { status: Status::Ok, format, body })
 }
 
 #[rocket::delete("/restaurants/<id>")]
-async fn delete_restaurant(repo: &State<MongoRepo>, id: &str) -> Status {
+async fn delete_restaurant(
+    _limit: RateLimited,
+    _auth: Authorized,
+    repo: &State<MongoRepo>,
+    request: &RequestContext,
+    id: &str,
+) -> Result<Status, ApiError> {
     let object_id = match ObjectId::parse_str(id) {
         Ok(id) => id,
-        Err(_) => return Status::BadRequest,
+        Err(e) => return Err(error_response(e.into())),
     };
 
-    match repo.delete_restaurant(object_id).await {
-        Ok(_) => Status::NoContent,
-        Err(AppError::NotFound) => Status::NotFound,
-        Err(_) => Status::InternalServerError,
+    match repo.delete_restaurant(request, object_id).await {
+        Ok(_) => Ok(Status::NoContent),
+        Err(e) => Err(error_response(e)),
     }
 }
 
-pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
-    let repo = MongoRepo::new(&db);
+/// How often a relaunch tries to bind before giving up, and how long it
+/// waits in between, while the instance it replaces lets go of the port.
+const BIND_ATTEMPTS: u32 = 40;
+const BIND_RETRY: Duration = Duration::from_millis(25);
+
+pub async fn start(ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = ctx.config.server.rocket;
     
-    println!("Starting Rocket server at http://127.0.0.1:8082");
+    info!("Starting Rocket server at {}://{}", ctx.scheme(), addr);
     
-    let config = rocket::Config::figment()
-        .merge(("port", 8082));
+    // Rocket reads its certificate once, at launch, and 0.5 has no custom
+    // listener or certificate resolver to swap it through, so it is
+    // relaunched when the certificate changes. The old instance stops
+    // listening as soon as it is told to stop, so the new one binds the port
+    // right away while the old one drains its requests. Connections made in
+    // between, or still queued on the old listener, are refused or reset.
+    let mut reloaded = ctx.tls.as_ref().map(Tls::reloaded);
+    let mut stop = CancellationToken::new();
+    let mut running = tokio::spawn(serve(ctx.clone(), stop.clone()));
+    let mut draining = Vec::new();
+    loop {
+        tokio::select! {
+            _ = ctx.shutdown.cancelled() => break,
+            _ = certificate_changed(&mut reloaded) => {
+                info!("Relaunching Rocket server with the reloaded TLS certificate");
+                stop.cancel();
+                stop = CancellationToken::new();
+                let relaunched = tokio::spawn(serve(ctx.clone(), stop.clone()));
+                draining.retain(|instance: &JoinHandle<_>| !instance.is_finished());
+                draining.push(std::mem::replace(&mut running, relaunched));
+            }
+            served = &mut running => return Ok(served??),
+        }
+    }
+    stop.cancel();
+    for instance in draining.into_iter().chain([running]) {
+        instance.await??;
+    }
+    Ok(())
+}
+
+async fn certificate_changed(reloaded: &mut Option<watch::Receiver<u64>>) {
+    match reloaded {
+        Some(reloaded) => {
+            if reloaded.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Serves one instance until `stop` is cancelled.
+async fn serve(ctx: ServerContext, stop: CancellationToken) -> Result<(), rocket::Error> {
+    let mut attempts = 1;
+    loop {
+        let rocket = build(&ctx).ignite().await?;
+        port_released(ctx.config.server.rocket).await;
+        let handle = rocket.shutdown();
+        let notify = {
+            let stop = stop.clone();
+            tokio::spawn(async move {
+                stop.cancelled().await;
+                handle.notify();
+            })
+        };
+        let launched = rocket.launch().await;
+        notify.abort();
+        match launched {
+            Err(e) if matches!(e.kind(), ErrorKind::Bind(_)) && attempts < BIND_ATTEMPTS && !stop.is_cancelled() => {
+                attempts += 1;
+                tokio::time::sleep(BIND_RETRY).await;
+            }
+            launched => return launched.map(drop),
+        }
+    }
+}
+
+/// Waits, for as long as the bind retries would, until `addr` can be bound.
+/// Probing with a socket that never listens is cheap enough to repeat
+/// without a pause, so an ignited instance launches the moment the one it
+/// replaces lets go of the port, instead of refusing connections until
+/// its next retry.
+async fn port_released(addr: SocketAddr) {
+    let started = Instant::now();
+    while started.elapsed() < BIND_RETRY * BIND_ATTEMPTS {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() };
+        let free = socket.and_then(|socket| {
+            socket.set_reuseaddr(true)?;
+            socket.bind(addr)
+        });
+        if free.is_ok() {
+            return;
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+fn build(ctx: &ServerContext) -> rocket::Rocket<rocket::Build> {
+    let addr = ctx.config.server.rocket;
+    let mut config = rocket::Config::figment()
+        .merge(("address", addr.ip()))
+        .merge(("port", addr.port()))
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.grace", ctx.config.shutdown.drain_timeout_secs))
+        .merge(("shutdown.mercy", 0));
+    if let Some(tls) = &ctx.tls {
+        config = config.merge(("tls.certs", tls.cert_file())).merge(("tls.key", tls.key_file()));
+        if let Some(ca_file) = tls.client_ca_file() {
+            // Optional, so that only admin routes need a client certificate
+            config = config.merge(("tls.mutual.ca_certs", ca_file)).merge(("tls.mutual.mandatory", false));
+        }
+    }
     
     rocket::custom(config)
-        .manage(repo)
+        .manage(ctx.repo())
+        .manage(ctx.clone())
+        .attach(RequestFairing(ctx.metrics.clone()))
+        .mount("/", routes![healthz, readyz, metrics_handler, openapi_json, docs, preflight])
+        .register("/", catchers![default_catcher])
         .mount("/api", routes![
             list_restaurants,
             get_restaurant,
@@ -96,8 +669,5 @@ pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
             update_restaurant,
             delete_restaurant,
         ])
-        .launch()
-        .await?;
-    
-    Ok(())
+        .mount("/admin", routes![import_restaurants, scan_restaurants])
 }
\ No newline at end of file
@@ -0,0 +1,227 @@
+//! Runs every framework at once for tests that compare their behavior.
+
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, ServerName};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tokio_util::sync::CancellationToken;
+
+use super::{Framework, ServerContext};
+use crate::{
+    auth::Authenticator,
+    compression::Compression,
+    config::{AuthConfig, Config, RateLimitConfig},
+    cors::CorsPolicy,
+    db,
+    health::TopologyWatcher,
+    limits::BodyLimits,
+    metrics::Metrics,
+    ratelimit::RateLimiter,
+    tls::Tls,
+};
+
+/// Defaults with every framework on a free port and a MongoDB URI nothing
+/// listens on, so handlers that reach the database fail fast with 500.
+pub fn config() -> Config {
+    let mut config = Config::default();
+    config.mongodb.uri = "mongodb://127.0.0.1:1".to_string();
+    config.mongodb.server_selection_timeout_secs = 1;
+    config.health.ping_timeout_ms = 200;
+    config.shutdown.drain_timeout_secs = 1;
+    config.server.actix = free_port();
+    config.server.axum = free_port();
+    config.server.rocket = free_port();
+    config.server.warp = free_port();
+    config.server.tide = free_port();
+    config.server.grpc = free_port();
+    config
+}
+
+/// An `Authenticator` over a database nothing listens on, for tests of what
+/// it decides without MongoDB: tokens, cached keys and missing credentials.
+pub async fn authenticator(auth: &AuthConfig) -> Authenticator {
+    let client = mongodb::Client::with_uri_str(config().mongodb.uri).await.unwrap();
+    Authenticator::new(&client.database("test"), auth).unwrap()
+}
+
+/// A `RateLimiter` on its own, with an `authenticator`.
+pub async fn rate_limiter(rate_limit: &RateLimitConfig, auth: &AuthConfig) -> RateLimiter {
+    RateLimiter::new(rate_limit, &authenticator(auth).await)
+}
+
+fn free_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+pub fn addr(config: &Config, framework: Framework) -> SocketAddr {
+    match framework {
+        Framework::Actix => config.server.actix,
+        Framework::Axum => config.server.axum,
+        Framework::Rocket => config.server.rocket,
+        Framework::Warp => config.server.warp,
+        Framework::Tide => config.server.tide,
+        Framework::Grpc => config.server.grpc,
+    }
+}
+
+/// Serves every framework while `probe` runs, then shuts them down and
+/// returns what `probe` returned.
+pub async fn with_every_framework<T, F>(config: Config, probe: impl FnOnce(Arc<Config>) -> F) -> T
+where
+    F: Future<Output = T>,
+{
+    with_every_framework_and_shutdown(config, |config, _| probe(config)).await
+}
+
+/// Like `with_every_framework`, but `probe` also gets the shutdown token, to
+/// start shutting down while it still has requests in flight. Returns once
+/// every server has stopped.
+pub async fn with_every_framework_and_shutdown<T, F>(
+    config: Config,
+    probe: impl FnOnce(Arc<Config>, CancellationToken) -> F,
+) -> T
+where
+    F: Future<Output = T>,
+{
+    let topology = TopologyWatcher::default();
+    let metrics = Metrics::new();
+    let client = db::mongodb::connect(&config.mongodb, &topology, &metrics).await.unwrap();
+    let db = client.database(&config.mongodb.database);
+    let config = Arc::new(config);
+    let auth = Authenticator::new(&db, &config.auth).unwrap();
+    let ctx = ServerContext {
+        cors: CorsPolicy::new(&config.cors),
+        compression: Compression::new(&config.compression),
+        limits: BodyLimits::new(&config.limits),
+        rate_limiter: RateLimiter::new(&config.rate_limit, &auth),
+        auth,
+        tls: Tls::load(&config.tls).unwrap(),
+        db,
+        config: config.clone(),
+        topology,
+        metrics,
+        shutdown: CancellationToken::new(),
+    };
+
+    let probe = async {
+        for framework in Framework::ALL {
+            wait_until_listening(addr(&config, framework)).await;
+        }
+        let result = probe(config.clone(), ctx.shutdown.clone()).await;
+        ctx.shutdown.cancel();
+        result
+    };
+    let (served, result) = tokio::join!(super::serve(ctx.clone(), &Framework::ALL), probe);
+    served.unwrap();
+    result
+}
+
+async fn wait_until_listening(addr: SocketAddr) {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("nothing listening on {}", addr);
+}
+
+/// Status, headers and body of a response; header names are lower case.
+pub struct RawResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// As received, so still chunked if the server chose to.
+    pub body: String,
+}
+
+impl RawResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends a bare HTTP/1.1 request with the given extra headers, and a `{}`
+/// JSON body unless the method is `OPTIONS`.
+pub async fn send(addr: SocketAddr, method: &str, path: &str, headers: &[(&str, &str)]) -> RawResponse {
+    if method == "OPTIONS" {
+        return exchange(addr, method, path, headers, None).await;
+    }
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Type", "application/json"));
+    exchange(addr, method, path, &headers, Some(b"{}")).await
+}
+
+/// Sends a bare HTTP/1.1 request with the given headers and body.
+pub async fn send_body(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: impl AsRef<[u8]>,
+) -> RawResponse {
+    exchange(addr, method, path, headers, Some(body.as_ref())).await
+}
+
+/// Sends a bare HTTP/1.1 request without a body over TLS, whose client
+/// configuration should only offer `http/1.1`.
+pub async fn send_tls(addr: SocketAddr, tls: Arc<ClientConfig>, method: &str, path: &str) -> RawResponse {
+    let stream = connect_tls(addr, tls).await.unwrap();
+    exchange_on(stream, addr, method, path, &[], None).await
+}
+
+/// Connects to `addr` and completes a TLS handshake with it as `localhost`.
+pub async fn connect_tls(addr: SocketAddr, tls: Arc<ClientConfig>) -> std::io::Result<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(addr).await?;
+    let localhost = ServerName::try_from("localhost").unwrap();
+    TlsConnector::from(tls).connect(localhost, stream).await
+}
+
+async fn exchange(addr: SocketAddr, method: &str, path: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> RawResponse {
+    exchange_on(TcpStream::connect(addr).await.unwrap(), addr, method, path, headers, body).await
+}
+
+async fn exchange_on(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> RawResponse {
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, addr);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let request = match body {
+        Some(body) => {
+            request.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+            [request.as_bytes(), body].concat()
+        }
+        None => (request + "\r\n").into_bytes(),
+    };
+
+    stream.write_all(&request).await.unwrap();
+    let mut response = Vec::new();
+    // Servers may close a TLS connection without a close_notify alert
+    if let Err(e) = stream.read_to_end(&mut response).await {
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof, "{} {}: {}", method, path, e);
+    }
+    let response = String::from_utf8_lossy(&response);
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let mut lines = head.lines();
+    let status = lines.next().and_then(|line| line.split(' ').nth(1)).and_then(|code| code.parse().ok());
+    let Some(status) = status else {
+        panic!("no status line in response to {} {}: {:?}", method, path, response);
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    RawResponse { status, headers, body: body.to_string() }
+}
@@ -1,33 +1,307 @@
-use tide::{Request, Response, StatusCode};
-use mongodb::Database;
+use tide::{
+    Middleware, Next, Request, Response, Server, StatusCode,
+    listener::{ListenInfo, Listener, ToListener},
+};
+use async_std::{io::{self, ReadExt}, net::TcpListener, stream::StreamExt, task};
 use bson::oid::ObjectId;
-use serde_json::Value;
+use bytes::Bytes;
+use futures::{AsyncBufRead, AsyncRead, Stream, TryStreamExt};
+use futures_rustls::TlsAcceptor;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
 use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tracing::{Instrument, debug, info, warn};
 
 use crate::{
+    auth::Credentials,
+    compression,
+    content::{self, BodyFormat, ResponseFormat},
     db::mongodb::MongoRepo,
-    models::restaurant::Restaurant,
+    models::restaurant::{CreateRestaurantRequest, RestaurantDoc, UpdateRestaurantRequest},
     error::AppError,
+    frameworks::{Framework, ServerContext},
+    health,
+    limits::{self, BodyRoute},
+    metrics::{self, Metrics},
+    openapi,
+    request::{RequestContext, REQUEST_ID_HEADER},
+    tls::{self, ClientCertificate, Tls},
 };
 
 #[derive(Clone)]
 struct State {
     repo: Arc<MongoRepo>,
+    server: ServerContext,
     runtime: Handle,
 }
 
-pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
+/// Counts requests being handled, streamed bodies included, so shutdown can
+/// wait for them to finish.
+#[derive(Clone, Default)]
+struct InFlight {
+    count: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+impl InFlight {
+    /// Counts a request until the returned guard is dropped.
+    fn enter(&self) -> InFlightRequest {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightRequest(self.clone())
+    }
+
+    async fn drained(&self) {
+        loop {
+            // Registered before the check so a request finishing in between is not missed
+            let idle = self.idle.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<State> for InFlight {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let request = self.enter();
+        let mut response = next.run(req).await;
+        hold(&mut response, request);
+        Ok(response)
+    }
+}
+
+/// A request counted by `InFlight`.
+struct InFlightRequest(InFlight);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Keeps `guard` until the body of a streamed response is done; other
+/// responses drop it right away.
+fn hold(response: &mut Response, guard: impl Send + Sync + Unpin + 'static) {
+    if response.len().is_none() {
+        let body = response.take_body();
+        response.set_body(tide::Body::from_reader(Holding { body, _guard: guard }, None));
+    }
+}
+
+/// A response body and what it holds until it is dropped.
+struct Holding<G> {
+    body: tide::Body,
+    _guard: G,
+}
+
+impl<G: Unpin> AsyncRead for Holding<G> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl<G: Unpin> AsyncBufRead for Holding<G> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+/// Gives every request a `RequestContext`, stored as a request extension,
+/// and logs and records it in the metrics once the response is ready.
+struct RequestMiddleware(Metrics);
+
+#[tide::utils::async_trait]
+impl Middleware<State> for RequestMiddleware {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let request = RequestContext::begin(
+            Framework::Tide.name(),
+            req.method().as_ref(),
+            req.url().path(),
+            |name| req.header(name).map(|values| values.last().as_str()),
+        );
+        req.set_ext(request.clone());
+
+        let mut response = next.run(req).instrument(request.span.clone()).await;
+        response.insert_header(REQUEST_ID_HEADER, request.request_id.as_str());
+        request.finish(&self.0, response.status() as u16);
+        Ok(response)
+    }
+}
+
+/// Compresses responses as the request's `Accept-Encoding` allows.
+struct CompressionMiddleware;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for CompressionMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let compression = req.state().server.compression.clone();
+        let accept_encoding = req.header("accept-encoding").map(|values| values.last().to_string());
+        let header = |name: &str| (name == "accept-encoding").then_some(accept_encoding.as_deref()).flatten();
+        let mut response = next.run(req).await;
+        let content_type = response.header("content-type").map(|values| values.last().to_string());
+
+        // Bodies of unknown length are streamed, and compressed as they go
+        if response.len().is_none() {
+            let (headers, encoding) = compression.stream_encoding(header, content_type.as_deref());
+            for (name, value) in headers {
+                response.append_header(name, value);
+            }
+            if let Some(encoding) = encoding {
+                let (body, pump) = channel_body(compression::compress_stream(encoding, body_chunks(response.take_body())));
+                task::spawn(pump);
+                response.set_body(body);
+            }
+            return Ok(response);
+        }
+
+        let body = response.take_body().into_bytes().await?;
+        let (headers, compressed) = compression.compress(header, content_type.as_deref(), &body);
+        for (name, value) in headers {
+            response.append_header(name, value);
+        }
+        response.set_body(compressed.unwrap_or(body));
+        Ok(response)
+    }
+}
+
+/// Answers CORS preflights and adds the CORS headers to other responses.
+struct CorsMiddleware;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for CorsMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let cors = req.state().server.cors.clone();
+        let header = |name: &str| req.header(name).map(|values| values.last().as_str());
+        let (mut response, headers) = match cors.preflight(req.method().as_ref(), header) {
+            Some(Ok(headers)) => (Response::new(StatusCode::NoContent), headers),
+            Some(Err(e)) => (error_response(e)?, Vec::new()),
+            None => {
+                let headers = cors.response_headers(header);
+                (next.run(req).await, headers)
+            }
+        };
+        for (name, value) in headers {
+            response.append_header(name, value);
+        }
+        Ok(response)
+    }
+}
+
+/// Applies the client's token bucket and the in-flight cap, adding the
+/// `RateLimit-*` headers to the response.
+struct RateLimitMiddleware;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for RateLimitMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let limiter = req.state().server.rate_limiter.clone();
+        let peer = req.peer_addr().and_then(|addr| addr.parse::<SocketAddr>().ok()).map(|addr| addr.ip());
+        let admission = limiter.admit(req.method().as_ref(), req.url().path(), peer, |name| {
+            req.header(name).map(|values| values.last().as_str())
+        });
+        let (mut response, headers) = match admission {
+            Ok(admitted) => {
+                let headers = admitted.headers().clone();
+                let mut response = next.run(req).await;
+                hold(&mut response, admitted);
+                (response, headers)
+            }
+            Err(throttled) => (error_response(throttled.error)?, throttled.headers),
+        };
+        for (name, value) in headers {
+            response.insert_header(name, value);
+        }
+        Ok(response)
+    }
+}
+
+/// Rejects requests without a bearer token or API key allowed to use their
+/// route, and with mutual TLS, admin requests without a client certificate.
+struct AuthMiddleware;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for AuthMiddleware {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let auth = req.state().server.auth.clone();
+        let runtime = req.state().runtime.clone();
+        let request = request_context(&req);
+        let method = req.method().to_string();
+        let path = req.url().path().to_string();
+        let client = req.ext::<ClientCertificate>().copied().unwrap_or(ClientCertificate(false));
+        let credentials = Credentials::from_headers(|name| req.header(name).map(|values| values.last().as_str()));
+        let span = request.span.clone();
+
+        let authorized = match req.state().server.check_client_certificate(&method, &path, client) {
+            Ok(()) => runtime
+                .spawn({
+                    let auth = auth.clone();
+                    async move { auth.authorize(&request, &method, &path, &credentials).await }.instrument(span)
+                })
+                .await
+                .unwrap_or_else(|e| Err(AppError::from(e))),
+            Err(e) => Err(e),
+        };
+
+        match authorized {
+            Ok(_) => Ok(next.run(req).await),
+            Err(e) => {
+                let mut response = error_response(e)?;
+                if response.status() == StatusCode::Unauthorized {
+                    response.insert_header("WWW-Authenticate", auth.challenge());
+                }
+                Ok(response)
+            }
+        }
+    }
+}
+
+/// The context set by `RequestMiddleware`, to be moved into tokio tasks.
+fn request_context(req: &Request<State>) -> RequestContext {
+    req.ext::<RequestContext>()
+        .cloned()
+        .expect("RequestMiddleware runs before every handler")
+}
+
+pub async fn start(ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
     // Get a handle to the Tokio runtime for MongoDB operations
     let runtime = Handle::current();
     
     let state = State {
-        repo: Arc::new(MongoRepo::new(&db)),
+        repo: Arc::new(ctx.repo()),
+        server: ctx.clone(),
         runtime,
     };
     
+    let in_flight = InFlight::default();
     let mut app = tide::with_state(state);
+    app.with(in_flight.clone());
+    app.with(RequestMiddleware(ctx.metrics.clone()));
+    app.with(CompressionMiddleware);
+    app.with(CorsMiddleware);
+    app.with(RateLimitMiddleware);
+    app.with(AuthMiddleware);
     
+    app.at("/healthz").get(healthz);
+    app.at("/readyz").get(readyz);
+    app.at("/metrics").get(metrics_handler);
+    app.at("/openapi.json").get(openapi_json);
+    app.at("/docs").get(docs);
+
     app.at("/api/restaurants")
         .post(create_restaurant)
         .get(list_restaurants);
@@ -37,144 +311,436 @@ pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
         .put(update_restaurant)
         .delete(delete_restaurant);
 
-    println!("Starting Tide server at http://127.0.0.1:8084");
+    app.at("/admin/restaurants/import").post(import_restaurants);
+    app.at("/admin/restaurants/scan").get(scan_restaurants);
+
+    let addr = ctx.config.server.tide;
+    info!("Starting Tide server at {}://{}", ctx.scheme(), addr);
     
-    app.listen("127.0.0.1:8084").await?;
+    let listen = async {
+        match &ctx.tls {
+            Some(tls) => app.listen(TlsListener::new(addr, tls)).await,
+            None => app.listen(addr.to_string()).await,
+        }
+    };
+    // Tide has no graceful shutdown of its own: dropping the listener future
+    // stops it from accepting new connections, while requests already being
+    // handled keep running on async-std until they finish.
+    tokio::select! {
+        result = listen => result?,
+        _ = ctx.shutdown.cancelled() => {}
+    }
+    if tokio::time::timeout(ctx.drain_timeout(), in_flight.drained()).await.is_err() {
+        warn!("Tide: requests still in flight after {:?}", ctx.drain_timeout());
+    }
     
     Ok(())
 }
 
+/// Serves HTTPS, which tide's own listeners do not. HTTP/1.1 only: tide has
+/// no HTTP/2 server.
+struct TlsListener<State> {
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    listener: Option<TcpListener>,
+    server: Option<Server<State>>,
+}
+
+impl<State> TlsListener<State> {
+    fn new(addr: SocketAddr, tls: &Tls) -> Self {
+        Self {
+            addr,
+            acceptor: TlsAcceptor::from(Arc::new(tls.server_config(tls::ALPN_HTTP1))),
+            listener: None,
+            server: None,
+        }
+    }
+}
+
+impl<State> fmt::Display for TlsListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}", self.addr)
+    }
+}
+
+impl<State> fmt::Debug for TlsListener<State> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsListener").field("addr", &self.addr).finish()
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for TlsListener<State> {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self> {
+        Ok(self)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for TlsListener<State> {
+    async fn bind(&mut self, server: Server<State>) -> io::Result<()> {
+        self.listener = Some(TcpListener::bind(self.addr).await?);
+        self.server = Some(server);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let listener = self.listener.take().expect("`bind` runs before `accept`");
+        let server = self.server.take().expect("`bind` runs before `accept`");
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(error = %e, "Tide: could not accept a connection");
+                    continue;
+                }
+            };
+            let acceptor = self.acceptor.clone();
+            let server = server.clone();
+            task::spawn(async move {
+                let local_addr = stream.local_addr().ok();
+                let peer_addr = stream.peer_addr().ok();
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => return debug!(peer = ?peer_addr, error = %e, "TLS handshake failed"),
+                };
+                let client = ClientCertificate(stream.get_ref().1.peer_certificates().is_some());
+                // async-h1 needs a stream it can clone for reading and writing
+                let stream = async_dup::Arc::new(async_dup::Mutex::new(stream));
+                let served = async_h1::accept(stream, |mut req| {
+                    req.set_local_addr(local_addr);
+                    req.set_peer_addr(peer_addr);
+                    req.ext_mut().insert(client);
+                    server.respond(req)
+                });
+                if let Err(e) = served.await {
+                    debug!(peer = ?peer_addr, error = %e, "Connection closed with an error");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        vec![ListenInfo::new(self.to_string(), "tcp".to_string(), true)]
+    }
+}
+
+async fn healthz(_req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(tide::Body::from_json(&health::liveness())?)
+        .build())
+}
+
+async fn readyz(req: Request<State>) -> tide::Result {
+    let server = req.state().server.clone();
+    let runtime = req.state().runtime.clone();
+
+    let readiness = runtime
+        .spawn(async move { server.readiness().await })
+        .await?;
+
+    let status = if readiness.is_ready() {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    };
+    Ok(Response::builder(status)
+        .body(tide::Body::from_json(&readiness)?)
+        .build())
+}
+
+async fn metrics_handler(req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(metrics::CONTENT_TYPE)
+        .body(req.state().server.metrics.render())
+        .build())
+}
+
+async fn openapi_json(_req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(tide::http::mime::JSON)
+        .body(openapi::spec_json())
+        .build())
+}
+
+async fn docs(_req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(tide::http::mime::HTML)
+        .body(openapi::SWAGGER_UI_HTML)
+        .build())
+}
+
+fn error_response(e: AppError) -> tide::Result {
+    let status = StatusCode::try_from(e.status()).unwrap_or(StatusCode::InternalServerError);
+    Ok(Response::builder(status)
+        .body(tide::Body::from_json(&e.body())?)
+        .content_type(e.content_type())
+        .build())
+}
+
+/// Reads the body of `route` in the format its `Content-Type` names, within
+/// the route's size limit. Used instead of `body_json`, whose errors would
+/// not reach the client as ours do.
+async fn decode_body<T: DeserializeOwned>(req: &mut Request<State>, route: BodyRoute) -> Result<T, AppError> {
+    let limits = req.state().server.limits.clone();
+    let format = limits::body_format(route, req.header("Content-Type").map(|values| values.last().as_str()))?;
+    let body = read_body(req, limits.max_bytes(route), || limits.too_large(route)).await?;
+    limits.decode(format, &body)
+}
+
+/// The format to answer with, negotiated from `Accept` and `?format=`.
+fn response_format(req: &Request<State>) -> Result<ResponseFormat, AppError> {
+    ResponseFormat::negotiate(req.header("Accept").map(|values| values.last().as_str()), req.url().query())
+}
+
+/// A restaurant response, already encoded in `format`.
+fn negotiated(status: StatusCode, format: ResponseFormat, body: Result<Vec<u8>, AppError>) -> tide::Result {
+    match body {
+        Ok(body) => Ok(Response::builder(status)
+            .body(body)
+            .content_type(format.media_type())
+            .header("Vary", "Accept")
+            .build()),
+        Err(e) => error_response(e),
+    }
+}
+
+/// A body read from `chunks`, and the future that sends them to it through
+/// a channel of one chunk: Tide bodies must be `Sync`, and the cursor
+/// behind a list must be polled on the Tokio runtime, so the caller spawns
+/// it where it belongs. It waits for the client to take each chunk, and
+/// stops, dropping `chunks`, once the client has gone away.
+fn channel_body(
+    chunks: impl Stream<Item = io::Result<Bytes>> + Send + 'static,
+) -> (tide::Body, impl Future<Output = ()> + Send + 'static) {
+    let (sender, receiver) = async_std::channel::bounded(1);
+    let pump = async move {
+        let mut chunks = Box::pin(chunks);
+        while let Some(chunk) = chunks.next().await {
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    };
+    (tide::Body::from_reader(receiver.into_async_read(), None), pump)
+}
+
+/// A response body as a stream of chunks, as it is read.
+fn body_chunks(body: tide::Body) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    futures::stream::unfold(Some(body), |body| async move {
+        let mut body = body?;
+        let mut chunk = vec![0; 8 * 1024];
+        match body.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(chunk), Some(body)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+/// Collects a request body, failing with `too_large` past `max_bytes`.
+async fn read_body(
+    req: &mut Request<State>,
+    max_bytes: usize,
+    too_large: impl FnOnce() -> AppError,
+) -> Result<Vec<u8>, AppError> {
+    let mut body = Vec::new();
+    req.take_body()
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut body)
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    if body.len() > max_bytes {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
 async fn create_restaurant(mut req: Request<State>) -> tide::Result {
-    let restaurant: Restaurant = req.body_json().await?;
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let restaurant: CreateRestaurantRequest = match decode_body(&mut req, BodyRoute::Create).await {
+        Ok(restaurant) => restaurant,
+        Err(e) => return error_response(e),
+    };
     let repo = req.state().repo.clone();
     let runtime = req.state().runtime.clone();
+    let request = request_context(&req);
+    let span = request.span.clone();
     
     let result = runtime
-        .spawn(async move { repo.create_restaurant(restaurant).await })
+        .spawn(async move { repo.create_restaurant(&request, restaurant.into()).await }.instrument(span))
         .await
         .unwrap_or_else(|e| Err(AppError::from(e)));
     
+    negotiated(StatusCode::Created, format, result.and_then(|created| format.encode_one(&created.into())))
+}
+
+async fn import_restaurants(mut req: Request<State>) -> tide::Result {
+    let compression = req.state().server.compression.clone();
+    let limits = req.state().server.limits.clone();
+    let format = match BodyFormat::from_content_type(req.header("Content-Type").map(|values| values.last().as_str())) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let content_encoding = req.header("Content-Encoding").map(|values| values.last().to_string());
+    let restaurants = match read_body(&mut req, compression.max_import_bytes(), || compression.import_too_large())
+        .await
+        .and_then(|body| compression.decompress(content_encoding.as_deref(), &body))
+        .and_then(|body| limits.decode_many::<CreateRestaurantRequest>(format, &body))
+    {
+        Ok(restaurants) => restaurants,
+        Err(e) => return error_response(e),
+    };
+    let repo = req.state().repo.clone();
+    let runtime = req.state().runtime.clone();
+    let request = request_context(&req);
+    let span = request.span.clone();
+
+    let result = runtime
+        .spawn(async move { repo.import_restaurants(&request, restaurants.into_iter().map(RestaurantDoc::from).collect()).await }.instrument(span))
+        .await
+        .unwrap_or_else(|e| Err(AppError::from(e)));
+
     match result {
-        Ok(created) => Ok(Response::builder(StatusCode::Created)
-            .body(tide::Body::from_json(&created)?)
-            .build()),
-        Err(e) => Ok(Response::builder(StatusCode::InternalServerError)
-            .body(e.to_string())
+        Ok(summary) => Ok(Response::builder(StatusCode::Created)
+            .body(tide::Body::from_json(&summary)?)
             .build()),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn scan_restaurants(req: Request<State>) -> tide::Result {
+    let repo = req.state().repo.clone();
+    let runtime = req.state().runtime.clone();
+    let request = request_context(&req);
+    let span = request.span.clone();
+
+    let result = runtime
+        .spawn(async move { repo.scan_restaurants(&request).await }.instrument(span))
+        .await
+        .unwrap_or_else(|e| Err(AppError::from(e)));
+
+    match result {
+        Ok(report) => Ok(Response::builder(StatusCode::Ok).body(tide::Body::from_json(&report)?).build()),
+        Err(e) => error_response(e),
     }
 }
 
 async fn list_restaurants(req: Request<State>) -> tide::Result {
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
+    let limit = match req.state().server.limits.list_limit(req.url().query()) {
+        Ok(limit) => limit,
+        Err(e) => return error_response(e),
+    };
     let repo = req.state().repo.clone();
     let runtime = req.state().runtime.clone();
+    let request = request_context(&req);
+    let span = request.span.clone();
     
     let result = runtime
-        .spawn(async move { repo.get_restaurants(10).await })
+        .spawn(async move { content::stream_restaurants(&repo, &request, limit, format).await }.instrument(span))
         .await
         .unwrap_or_else(|e| Err(AppError::from(e)));
     
     match result {
-        Ok(restaurants) => Ok(Response::builder(StatusCode::Ok)
-            .body(tide::Body::from_json(&restaurants)?)
-            .build()),
-        Err(e) => Ok(Response::builder(StatusCode::InternalServerError)
-            .body(e.to_string())
-            .build()),
+        Ok(chunks) => {
+            let (body, pump) = channel_body(chunks);
+            runtime.spawn(pump);
+            Ok(Response::builder(StatusCode::Ok)
+                .body(body)
+                .content_type(format.media_type())
+                .header("Vary", "Accept")
+                .build())
+        }
+        Err(e) => error_response(e),
     }
 }
 
 async fn get_restaurant(req: Request<State>) -> tide::Result {
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
     let id = req.param("id")?;
     let object_id = match ObjectId::parse_str(id) {
         Ok(id) => id,
-        Err(_) => return Ok(Response::builder(StatusCode::BadRequest)
-            .body("Invalid ID format")
-            .build()),
+        Err(e) => return error_response(e.into()),
     };
 
     let repo = req.state().repo.clone();
     let runtime = req.state().runtime.clone();
+    let request = request_context(&req);
+    let span = request.span.clone();
     
     let result = runtime
-        .spawn(async move { repo.get_restaurant_by_id(object_id).await })
+        .spawn(async move { content::read_restaurant(&repo, &request, object_id, format).await }.instrument(span))
         .await
         .unwrap_or_else(|e| Err(AppError::from(e)));
 
-    match result {
-        Ok(restaurant) => Ok(Response::builder(StatusCode::Ok)
-            .body(tide::Body::from_json(&restaurant)?)
-            .build()),
-        Err(AppError::NotFound) => Ok(Response::builder(StatusCode::NotFound)
-            .body("Restaurant not found")
-            .build()),
-        Err(e) => Ok(Response::builder(StatusCode::InternalServerError)
-            .body(e.to_string())
-            .build()),
-    }
+    negotiated(StatusCode::Ok, format, result)
 }
 
 async fn update_restaurant(mut req: Request<State>) -> tide::Result {
+    let format = match response_format(&req) {
+        Ok(format) => format,
+        Err(e) => return error_response(e),
+    };
     let id = req.param("id")?;
     let object_id = match ObjectId::parse_str(id) {
         Ok(id) => id,
-        Err(_) => return Ok(Response::builder(StatusCode::BadRequest)
-            .body("Invalid ID format")
-            .build()),
+        Err(e) => return error_response(e.into()),
     };
 
-    let update: Value = req.body_json().await?;
-    let update_doc = match bson::to_document(&update) {
-        Ok(doc) => doc,
-        Err(_) => return Ok(Response::builder(StatusCode::BadRequest)
-            .body("Invalid update document")
-            .build()),
+    let update: UpdateRestaurantRequest = match decode_body(&mut req, BodyRoute::Update).await {
+        Ok(update) => update,
+        Err(e) => return error_response(e),
     };
 
     let repo = req.state().repo.clone();
     let runtime = req.state().runtime.clone();
+    let request = request_context(&req);
+    let span = request.span.clone();
     
     let result = runtime
-        .spawn(async move { repo.update_restaurant(object_id, update_doc).await })
+        .spawn(async move { repo.update_restaurant(&request, object_id, update).await }.instrument(span))
         .await
         .unwrap_or_else(|e| Err(AppError::from(e)));
 
-    match result {
-        Ok(updated) => Ok(Response::builder(StatusCode::Ok)
-            .body(tide::Body::from_json(&updated)?)
-            .build()),
-        Err(AppError::NotFound) => Ok(Response::builder(StatusCode::NotFound)
-            .body("Restaurant not found")
-            .build()),
-        Err(e) => Ok(Response::builder(StatusCode::InternalServerError)
-            .body(e.to_string())
-            .build()),
-    }
+    negotiated(StatusCode::Ok, format, result.and_then(|updated| format.encode_one(&updated.into())))
 }
 
 async fn delete_restaurant(req: Request<State>) -> tide::Result {
     let id = req.param("id")?;
     let object_id = match ObjectId::parse_str(id) {
         Ok(id) => id,
-        Err(_) => return Ok(Response::builder(StatusCode::BadRequest)
-            .body("Invalid ID format")
-            .build()),
+        Err(e) => return error_response(e.into()),
     };
 
     let repo = req.state().repo.clone();
     let runtime = req.state().runtime.clone();
+    let request = request_context(&req);
+    let span = request.span.clone();
     
     let result = runtime
-        .spawn(async move { repo.delete_restaurant(object_id).await })
+        .spawn(async move { repo.delete_restaurant(&request, object_id).await }.instrument(span))
         .await
         .unwrap_or_else(|e| Err(AppError::from(e)));
 
     match result {
         Ok(_) => Ok(Response::builder(StatusCode::NoContent).build()),
-        Err(AppError::NotFound) => Ok(Response::builder(StatusCode::NotFound)
-            .body("Restaurant not found")
-            .build()),
-        Err(e) => Ok(Response::builder(StatusCode::InternalServerError)
-            .body(e.to_string())
-            .build()),
+        Err(e) => error_response(e),
     }
 }
\ No newline at end of file
@@ -1,150 +1,631 @@
 use warp::{
     self,
+    Buf,
     Filter,
+    Stream,
     Reply,
     Rejection,
+    reject::Reject,
     reply::{json, with_status},
-    http::StatusCode,
+    http::{HeaderMap, Method, StatusCode, HeaderValue, header},
+    path::FullPath,
+    hyper::{self, Body, server::{accept, conn::AddrStream}, service::{make_service_fn, service_fn, Service}},
 };
-use mongodb::Database;
 use bson::oid::ObjectId;
-use serde_json::Value;
+use futures::{pin_mut, TryStreamExt};
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{Instrument, debug, info, warn};
 
 use crate::{
+    auth::Credentials,
+    compression,
+    content::{self, BodyFormat, ResponseFormat},
     db::mongodb::MongoRepo,
-    models::restaurant::Restaurant,
+    models::restaurant::{CreateRestaurantRequest, RestaurantDoc, UpdateRestaurantRequest},
     error::AppError,
+    frameworks::{Framework, ServerContext},
+    health,
+    limits::{self, BodyLimits, BodyRoute},
+    metrics,
+    openapi,
+    ratelimit::Admitted,
+    request::{Headers, RequestContext, REQUEST_ID_HEADER},
+    tls::{self, ClientCertificate},
 };
 
-pub async fn start(db: Database) -> Result<(), Box<dyn std::error::Error>> {
-    let repo = Arc::new(MongoRepo::new(&db));
+pub async fn start(ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+    let repo = Arc::new(ctx.repo());
     
     let repo_filter = warp::any().map(move || repo.clone());
+    let request_filter = warp::ext::get::<RequestContext>();
+    let auth = authorized(ctx.clone());
+    let server_ctx = ctx.clone();
+    let ctx_filter = warp::any().map(move || server_ctx.clone());
+
+    // Every route matches its path before its method: warp answers with the
+    // rejection of the route that got furthest, so a path no route serves is
+    // a 404 instead of the 405 of some other route's method.
+
+    // Health checks
+    let healthz = warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| json(&health::liveness()));
+
+    let readyz = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(ctx_filter.clone())
+        .and_then(readyz_handler);
+
+    let openapi_route = warp::path("openapi.json")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| warp::reply::with_header(openapi::spec_json(), "content-type", "application/json"));
+
+    let docs = warp::path("docs")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| warp::reply::html(openapi::SWAGGER_UI_HTML));
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(ctx_filter.clone())
+        .map(|ctx: ServerContext| {
+            warp::reply::with_header(ctx.metrics.render(), "content-type", metrics::CONTENT_TYPE)
+        });
     
     // CRUD Routes
-    let create_restaurant = warp::post()
-        .and(warp::path("api"))
+    let create_restaurant = warp::path("api")
         .and(warp::path("restaurants"))
         .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
         .and(repo_filter.clone())
-        .and(warp::body::json())
+        .and(request_filter)
+        .and(response_format())
+        .and(ctx_filter.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::stream())
         .and_then(create_restaurant_handler);
 
-    let list_restaurants = warp::get()
-        .and(warp::path("api"))
+    let list_restaurants = warp::path("api")
         .and(warp::path("restaurants"))
         .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
         .and(repo_filter.clone())
+        .and(request_filter)
+        .and(response_format())
+        .and(ctx_filter.clone())
+        .and(raw_query())
         .and_then(list_restaurants_handler);
 
-    let get_restaurant = warp::get()
-        .and(warp::path("api"))
+    let get_restaurant = warp::path("api")
         .and(warp::path("restaurants"))
         .and(warp::path::param::<String>())
         .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
         .and(repo_filter.clone())
+        .and(request_filter)
+        .and(response_format())
         .and_then(get_restaurant_handler);
 
-    let update_restaurant = warp::put()
-        .and(warp::path("api"))
+    let update_restaurant = warp::path("api")
         .and(warp::path("restaurants"))
         .and(warp::path::param::<String>())
         .and(warp::path::end())
+        .and(warp::put())
+        .and(auth.clone())
         .and(repo_filter.clone())
-        .and(warp::body::json())
+        .and(request_filter)
+        .and(response_format())
+        .and(ctx_filter.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::stream())
         .and_then(update_restaurant_handler);
 
-    let delete_restaurant = warp::delete()
-        .and(warp::path("api"))
+    let delete_restaurant = warp::path("api")
         .and(warp::path("restaurants"))
         .and(warp::path::param::<String>())
         .and(warp::path::end())
+        .and(warp::delete())
+        .and(auth.clone())
         .and(repo_filter.clone())
+        .and(request_filter)
         .and_then(delete_restaurant_handler);
 
-    let routes = create_restaurant
+    let import_restaurants = warp::path("admin")
+        .and(warp::path("restaurants"))
+        .and(warp::path("import"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(auth.clone())
+        .and(repo_filter.clone())
+        .and(request_filter)
+        .and(ctx_filter)
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::stream())
+        .and_then(import_restaurants_handler);
+
+    let scan_restaurants = warp::path("admin")
+        .and(warp::path("restaurants"))
+        .and(warp::path("scan"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth.clone())
+        .and(repo_filter.clone())
+        .and(request_filter)
+        .and_then(scan_restaurants_handler);
+
+    let routes = healthz
+        .or(readyz)
+        .or(metrics_route)
+        .or(openapi_route)
+        .or(docs)
+        .or(create_restaurant)
         .or(list_restaurants)
         .or(get_restaurant)
         .or(update_restaurant)
-        .or(delete_restaurant);
+        .or(delete_restaurant)
+        .or(import_restaurants)
+        .or(scan_restaurants)
+        .recover(recover_auth);
 
-    println!("Starting Warp server at http://127.0.0.1:8083");
-    
-    warp::serve(routes)
-        .run(([127, 0, 0, 1], 8083))
-        .await;
+    // Warp filters cannot see the final response of a rejected request, so
+    // the request context and rate limit are handled by wrapping the
+    // filter's hyper service.
+    let service = warp::service(routes);
+    let server_ctx = ctx.clone();
+    let connection = move |peer: IpAddr, client: ClientCertificate| {
+        let service = service.clone();
+        let ctx = server_ctx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                with_request_context(service.clone(), ctx.clone(), peer, client, req)
+            }))
+        }
+    };
+
+    let addr = ctx.config.server.warp;
+    info!("Starting Warp server at {}://{}", ctx.scheme(), addr);
+    match &ctx.tls {
+        Some(tls) => {
+            let listener = TcpListener::bind(addr).await?;
+            let acceptor = TlsAcceptor::from(Arc::new(tls.server_config(tls::ALPN_H2)));
+            let (sender, mut connections) = mpsc::channel(64);
+            tokio::spawn(accept_tls(listener, acceptor, sender, ctx.clone()));
+            let incoming = accept::poll_fn(move |cx| connections.poll_recv(cx).map(|conn| conn.map(Ok::<_, io::Error>)));
+            let make_service = make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                let (tcp, session) = conn.get_ref();
+                let peer = tcp.peer_addr().map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
+                connection(peer, ClientCertificate(session.peer_certificates().is_some()))
+            });
+            hyper::Server::builder(incoming)
+                .serve(make_service)
+                .with_graceful_shutdown(ctx.shutdown.cancelled_owned())
+                .await?;
+        }
+        None => {
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                connection(conn.remote_addr().ip(), ClientCertificate(false))
+            });
+            hyper::Server::try_bind(&addr)?
+                .serve(make_service)
+                .with_graceful_shutdown(ctx.shutdown.cancelled_owned())
+                .await?;
+        }
+    }
     
     Ok(())
 }
 
+/// Accepts TCP connections and hands them to hyper once their TLS handshake
+/// is done, so a slow handshake does not hold up the others.
+async fn accept_tls(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    connections: mpsc::Sender<TlsStream<TcpStream>>,
+    ctx: ServerContext,
+) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "Warp: could not accept a connection");
+                    continue;
+                }
+            },
+            _ = ctx.shutdown.cancelled() => return,
+        };
+        let acceptor = acceptor.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    let _ = connections.send(stream).await;
+                }
+                Err(e) => debug!(%peer, error = %e, "TLS handshake failed"),
+            }
+        });
+    }
+}
+
+async fn with_request_context<S>(
+    mut service: S,
+    ctx: ServerContext,
+    peer: IpAddr,
+    client: ClientCertificate,
+    mut req: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<Body>, Error = Infallible>,
+{
+    let request = RequestContext::begin(
+        Framework::Warp.name(),
+        req.method().as_str(),
+        req.uri().path(),
+        |name| req.headers().get(name).and_then(|v| v.to_str().ok()),
+    );
+    req.extensions_mut().insert(request.clone());
+    req.extensions_mut().insert(client);
+    let accept_encoding = req.headers().get(header::ACCEPT_ENCODING).cloned();
+
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let (mut response, headers) = match ctx.cors.preflight(req.method().as_str(), header) {
+        Some(Ok(headers)) => (StatusCode::NO_CONTENT.into_response(), headers),
+        Some(Err(e)) => (error_response(e).into_response(), Vec::new()),
+        None => {
+            let mut headers = ctx.cors.response_headers(header);
+            let admission = ctx.rate_limiter.admit(req.method().as_str(), req.uri().path(), Some(peer), header);
+            let response = match admission {
+                Ok(admitted) => {
+                    headers.extend(admitted.headers().iter().cloned());
+                    hold(service.call(req).instrument(request.span.clone()).await?, admitted)
+                }
+                Err(throttled) => {
+                    headers.extend(throttled.headers);
+                    error_response(throttled.error).into_response()
+                }
+            };
+            (response, headers)
+        }
+    };
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    let mut response = compress(&ctx, accept_encoding, response).await;
+    if let Ok(value) = HeaderValue::from_str(&request.request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    request.finish(&ctx.metrics, response.status().as_u16());
+    Ok(response)
+}
+
+/// Keeps the in-flight slot of a streamed response until its body is done.
+fn hold(response: hyper::Response<Body>, admitted: Admitted) -> hyper::Response<Body> {
+    if hyper::body::HttpBody::size_hint(response.body()).exact().is_some() {
+        return response;
+    }
+    response.map(|body| Body::wrap_stream(admitted.hold(body)))
+}
+
+/// Compresses a response as the request's `Accept-Encoding` allows.
+async fn compress(
+    ctx: &ServerContext,
+    accept_encoding: Option<HeaderValue>,
+    response: hyper::Response<Body>,
+) -> hyper::Response<Body> {
+    let header = |name: &str| (name == "accept-encoding").then(|| accept_encoding.as_ref()?.to_str().ok()).flatten();
+    let (mut parts, body) = response.into_parts();
+    let content_type = parts.headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string);
+
+    // Bodies of unknown length are streamed, and compressed as they go
+    if hyper::body::HttpBody::size_hint(&body).exact().is_none() {
+        let (headers, encoding) = ctx.compression.stream_encoding(header, content_type.as_deref());
+        append_headers(&mut parts.headers, headers);
+        let body = match encoding {
+            Some(encoding) => Body::wrap_stream(compression::compress_stream(encoding, body.map_err(io::Error::other))),
+            None => body,
+        };
+        return hyper::Response::from_parts(parts, body);
+    }
+
+    let Ok(body) = hyper::body::to_bytes(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let (headers, compressed) = ctx.compression.compress(header, content_type.as_deref(), &body);
+    append_headers(&mut parts.headers, headers);
+    match compressed {
+        Some(compressed) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            hyper::Response::from_parts(parts, Body::from(compressed))
+        }
+        None => hyper::Response::from_parts(parts, Body::from(body)),
+    }
+}
+
+fn append_headers(map: &mut HeaderMap, headers: Headers) {
+    for (name, value) in headers {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            map.append(name, value);
+        }
+    }
+}
+
+/// Auth failure of the `authorized` filter, with the `WWW-Authenticate` challenge.
+#[derive(Debug)]
+struct AuthRejection(AppError, &'static str);
+
+impl Reject for AuthRejection {}
+
+/// Passes requests with a bearer token or API key allowed to use their
+/// route, and with mutual TLS, a client certificate on admin routes.
+fn authorized(ctx: ServerContext) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::get::<RequestContext>())
+        .and(warp::ext::get::<ClientCertificate>())
+        .and_then(move |method: Method, path: FullPath, headers: HeaderMap, request: RequestContext, client| {
+            let auth = ctx.auth.clone();
+            let checked = ctx.check_client_certificate(method.as_str(), path.as_str(), client);
+            async move {
+                let credentials = Credentials::from_headers(|name| headers.get(name).and_then(|v| v.to_str().ok()));
+                let authorized = match checked {
+                    Ok(()) => auth.authorize(&request, method.as_str(), path.as_str(), &credentials).await,
+                    Err(e) => Err(e),
+                };
+                match authorized {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(warp::reject::custom(AuthRejection(e, auth.challenge()))),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Answers `AuthRejection`s; other rejections keep warp's default handling.
+async fn recover_auth(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    let Some(AuthRejection(e, challenge)) = rejection.find() else {
+        return Err(rejection);
+    };
+    let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = with_status(json(&e.body()), status).into_response();
+    if status == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+    }
+    Ok(response)
+}
+
+async fn readyz_handler(ctx: ServerContext) -> Result<impl Reply, Rejection> {
+    let readiness = ctx.readiness().await;
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(with_status(json(&readiness), status))
+}
+
+fn error_response(e: AppError) -> warp::reply::Response {
+    let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = with_status(json(&e.body()), status).into_response();
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(e.content_type()));
+    response
+}
+
+/// Reads the body of `route` in the format its `Content-Type` names, within
+/// the route's size limit. Used instead of `warp::body::json` so every
+/// framework rejects the same bodies the same way.
+async fn decode_body<T: DeserializeOwned>(
+    limits: &BodyLimits,
+    route: BodyRoute,
+    content_type: Option<String>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<T, AppError> {
+    let format = limits::body_format(route, content_type.as_deref())?;
+    let body = read_body(body, limits.max_bytes(route), || limits.too_large(route)).await?;
+    limits.decode(format, &body)
+}
+
+/// The query string, empty when there is none, which `warp::query::raw`
+/// rejects.
+fn raw_query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::query::raw().or(warp::any().map(String::new)).unify()
+}
+
+/// The format to answer with, negotiated from `Accept` and `?format=`. The
+/// handlers answer a failure themselves, so it gets problem details.
+fn response_format() -> impl Filter<Extract = (Result<ResponseFormat, AppError>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept")
+        .and(raw_query())
+        .map(|accept: Option<String>, query: String| ResponseFormat::negotiate(accept.as_deref(), Some(&query)))
+}
+
+/// A restaurant response, already encoded in `format`.
+fn negotiated(status: StatusCode, format: ResponseFormat, body: Result<Vec<u8>, AppError>) -> warp::reply::Response {
+    match body {
+        Ok(body) => {
+            let mut response = with_status(body, status).into_response();
+            response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(format.media_type()));
+            response.headers_mut().insert(header::VARY, HeaderValue::from_static("Accept"));
+            response
+        }
+        Err(e) => error_response(e),
+    }
+}
+
 async fn create_restaurant_handler(
     repo: Arc<MongoRepo>,
-    restaurant: Restaurant,
+    request: RequestContext,
+    format: Result<ResponseFormat, AppError>,
+    ctx: ServerContext,
+    content_type: Option<String>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<impl Reply, Rejection> {
+    let format = match format {
+        Ok(format) => format,
+        Err(e) => return Ok(error_response(e)),
+    };
+    let restaurant = match decode_body::<CreateRestaurantRequest>(&ctx.limits, BodyRoute::Create, content_type, body).await {
+        Ok(restaurant) => restaurant,
+        Err(e) => return Ok(error_response(e)),
+    };
+
+    let created = repo.create_restaurant(&request, restaurant.into()).await;
+    Ok(negotiated(StatusCode::CREATED, format, created.and_then(|created| format.encode_one(&created.into()))))
+}
+
+async fn import_restaurants_handler(
+    repo: Arc<MongoRepo>,
+    request: RequestContext,
+    ctx: ServerContext,
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
 ) -> Result<impl Reply, Rejection> {
-    match repo.create_restaurant(restaurant).await {
-        Ok(created) => Ok(with_status(json(&created), StatusCode::CREATED)),
-        Err(e) => Ok(with_status(json(&e.to_string()), StatusCode::INTERNAL_SERVER_ERROR)),
+    let format = match BodyFormat::from_content_type(content_type.as_deref()) {
+        Ok(format) => format,
+        Err(e) => return Ok(error_response(e)),
+    };
+    let compression = &ctx.compression;
+    let restaurants = match read_body(body, compression.max_import_bytes(), || compression.import_too_large())
+        .await
+        .and_then(|body| compression.decompress(content_encoding.as_deref(), &body))
+        .and_then(|body| ctx.limits.decode_many::<CreateRestaurantRequest>(format, &body))
+    {
+        Ok(restaurants) => restaurants,
+        Err(e) => return Ok(error_response(e)),
+    };
+
+    match repo.import_restaurants(&request, restaurants.into_iter().map(RestaurantDoc::from).collect()).await {
+        Ok(summary) => Ok(with_status(json(&summary), StatusCode::CREATED).into_response()),
+        Err(e) => Ok(error_response(e)),
+    }
+}
+
+async fn scan_restaurants_handler(repo: Arc<MongoRepo>, request: RequestContext) -> Result<impl Reply, Rejection> {
+    match repo.scan_restaurants(&request).await {
+        Ok(report) => Ok(json(&report).into_response()),
+        Err(e) => Ok(error_response(e)),
     }
 }
 
-async fn list_restaurants_handler(repo: Arc<MongoRepo>) -> Result<impl Reply, Rejection> {
-    match repo.get_restaurants(10).await {
-        Ok(restaurants) => Ok(with_status(json(&restaurants), StatusCode::OK)),
-        Err(e) => Ok(with_status(json(&e.to_string()), StatusCode::INTERNAL_SERVER_ERROR)),
+/// Collects a request body, failing with `too_large` past `max_bytes`.
+async fn read_body(
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    max_bytes: usize,
+    too_large: impl FnOnce() -> AppError,
+) -> Result<Vec<u8>, AppError> {
+    pin_mut!(body);
+    let mut collected = Vec::new();
+    while let Some(mut chunk) = body.try_next().await.map_err(|e| AppError::BadRequest(e.to_string()))? {
+        if collected.len() + chunk.remaining() > max_bytes {
+            return Err(too_large());
+        }
+        collected.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(collected)
+}
+
+async fn list_restaurants_handler(
+    repo: Arc<MongoRepo>,
+    request: RequestContext,
+    format: Result<ResponseFormat, AppError>,
+    ctx: ServerContext,
+    query: String,
+) -> Result<impl Reply, Rejection> {
+    let format = match format {
+        Ok(format) => format,
+        Err(e) => return Ok(error_response(e)),
+    };
+    let limit = match ctx.limits.list_limit(Some(&query)) {
+        Ok(limit) => limit,
+        Err(e) => return Ok(error_response(e)),
+    };
+
+    match content::stream_restaurants(&repo, &request, limit, format).await {
+        Ok(body) => {
+            let mut response = warp::reply::Response::new(Body::wrap_stream(body));
+            response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(format.media_type()));
+            response.headers_mut().insert(header::VARY, HeaderValue::from_static("Accept"));
+            Ok(response)
+        }
+        Err(e) => Ok(error_response(e)),
     }
 }
 
 async fn get_restaurant_handler(
     id: String,
     repo: Arc<MongoRepo>,
+    request: RequestContext,
+    format: Result<ResponseFormat, AppError>,
 ) -> Result<impl Reply, Rejection> {
+    let format = match format {
+        Ok(format) => format,
+        Err(e) => return Ok(error_response(e)),
+    };
     let object_id = match ObjectId::parse_str(&id) {
         Ok(id) => id,
-        Err(_) => return Ok(with_status(json(&"Invalid ID format"), StatusCode::BAD_REQUEST)),
+        Err(e) => return Ok(error_response(e.into())),
     };
 
-    match repo.get_restaurant_by_id(object_id).await {
-        Ok(restaurant) => Ok(with_status(json(&restaurant), StatusCode::OK)),
-        Err(AppError::NotFound) => Ok(with_status(json(&"Not found"), StatusCode::NOT_FOUND)),
-        Err(e) => Ok(with_status(json(&e.to_string()), StatusCode::INTERNAL_SERVER_ERROR)),
-    }
+    Ok(negotiated(StatusCode::OK, format, content::read_restaurant(&repo, &request, object_id, format).await))
 }
 
 async fn update_restaurant_handler(
     id: String,
     repo: Arc<MongoRepo>,
-    update: Value,
+    request: RequestContext,
+    format: Result<ResponseFormat, AppError>,
+    ctx: ServerContext,
+    content_type: Option<String>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
 ) -> Result<impl Reply, Rejection> {
+    let format = match format {
+        Ok(format) => format,
+        Err(e) => return Ok(error_response(e)),
+    };
     let object_id = match ObjectId::parse_str(&id) {
         Ok(id) => id,
-        Err(_) => return Ok(with_status(json(&"Invalid ID format"), StatusCode::BAD_REQUEST)),
+        Err(e) => return Ok(error_response(e.into())),
     };
 
-    let update_doc = match bson::to_document(&update) {
-        Ok(doc) => doc,
-        Err(_) => return Ok(with_status(json(&"Invalid update document"), StatusCode::BAD_REQUEST)),
+    let update = match decode_body::<UpdateRestaurantRequest>(&ctx.limits, BodyRoute::Update, content_type, body).await {
+        Ok(update) => update,
+        Err(e) => return Ok(error_response(e)),
     };
 
-    match repo.update_restaurant(object_id, update_doc).await {
-        Ok(updated) => Ok(with_status(json(&updated), StatusCode::OK)),
-        Err(AppError::NotFound) => Ok(with_status(json(&"Not found"), StatusCode::NOT_FOUND)),
-        Err(e) => Ok(with_status(json(&e.to_string()), StatusCode::INTERNAL_SERVER_ERROR)),
-    }
+    let updated = repo.update_restaurant(&request, object_id, update).await;
+    Ok(negotiated(StatusCode::OK, format, updated.and_then(|updated| format.encode_one(&updated.into()))))
 }
 
 async fn delete_restaurant_handler(
     id: String,
     repo: Arc<MongoRepo>,
+    request: RequestContext,
 ) -> Result<impl Reply, Rejection> {
     let object_id = match ObjectId::parse_str(&id) {
         Ok(id) => id,
-        Err(_) => return Ok(with_status(json(&"Invalid ID format"), StatusCode::BAD_REQUEST)),
+        Err(e) => return Ok(error_response(e.into())),
     };
 
-    match repo.delete_restaurant(object_id).await {
-        Ok(_) => Ok(with_status(json(&""), StatusCode::NO_CONTENT)),
-        Err(AppError::NotFound) => Ok(with_status(json(&"Not found"), StatusCode::NOT_FOUND)),
-        Err(e) => Ok(with_status(json(&e.to_string()), StatusCode::INTERNAL_SERVER_ERROR)),
+    match repo.delete_restaurant(&request, object_id).await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => Ok(error_response(e)),
     }
 }
\ No newline at end of file
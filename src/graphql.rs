@@ -0,0 +1,608 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use async_graphql::{
+    connection::{Connection, Edge},
+    dataloader::{DataLoader, Loader},
+    http::{GraphiQLSource, WebSocket, WebSocketProtocols as Protocols, WsMessage},
+    parser::{
+        self,
+        types::{DocumentOperations, OperationType},
+        Pos,
+    },
+    BatchRequest, BatchResponse, Context, Data, Enum, Error, ErrorExtensions, InputObject, Object, Result, Schema,
+    Subscription, ID,
+};
+use bson::{doc, oid::ObjectId, DateTime, Document};
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::{
+    auth::{Authenticator, Credentials, Operation},
+    db::mongodb::MongoRepo,
+    error::AppError,
+    models::restaurant::{Address, ChangeKind, Grade, RestaurantChange, RestaurantDoc, UpdateRestaurantRequest},
+    ratelimit::{Budget, RouteClass},
+    request::RequestContext,
+};
+
+/// Queries and mutations, over HTTP POST.
+pub const ENDPOINT: &str = "/graphql";
+/// Subscriptions, over a web socket.
+pub const WS_ENDPOINT: &str = "/graphql/ws";
+/// The GraphiQL IDE.
+pub const GRAPHIQL: &str = "/graphiql";
+pub const ROUTES: &[&str] = &[ENDPOINT, WS_ENDPOINT, GRAPHIQL];
+
+/// Pages of `restaurants` hold at most this many.
+const MAX_PAGE_SIZE: i32 = 100;
+/// Deeper queries are refused before they run.
+const MAX_QUERY_DEPTH: usize = 12;
+
+pub type RestaurantSchema = Schema<Query, Mutation, Subscription>;
+
+/// The GraphQL API over the restaurants collection, served the same way by
+/// every framework that mounts it. Cheap to clone.
+#[derive(Clone)]
+pub struct GraphQl {
+    schema: RestaurantSchema,
+    repo: Arc<MongoRepo>,
+    auth: Authenticator,
+}
+
+impl GraphQl {
+    pub fn new(repo: Arc<MongoRepo>, auth: Authenticator) -> Self {
+        let schema = Schema::build(Query, Mutation, Subscription)
+            .data(repo.clone())
+            .data(auth.clone())
+            .limit_depth(MAX_QUERY_DEPTH)
+            .finish();
+        Self { schema, repo, auth }
+    }
+
+    /// Runs the queries of a `POST /graphql` body. Its `Read` grant was
+    /// checked by the auth middleware; mutations check their own with
+    /// `credentials`. Each operation takes a token from `budget`, so a batch
+    /// costs as much as its operations sent one by one; those over the limit
+    /// fail with status 429 and the others still run.
+    pub async fn execute(&self, request: &RequestContext, credentials: Credentials, budget: Budget, batch: BatchRequest) -> BatchResponse {
+        let data = RequestData::new(self.repo.clone(), request, credentials);
+        let run = |single: async_graphql::Request| {
+            let charged = budget.charge(operation_class(&single));
+            let single = data.attach(single);
+            async move {
+                match charged {
+                    Ok(()) => self.schema.execute(single).await,
+                    Err(e) => async_graphql::Response::from_errors(vec![to_error(&e).into_server_error(Pos::default())]),
+                }
+            }
+        };
+        match batch {
+            BatchRequest::Single(single) => BatchResponse::Single(run(single).await),
+            BatchRequest::Batch(batch) => BatchResponse::Batch(stream::iter(batch).then(run).collect().await),
+        }
+    }
+
+    /// Serves a subscription web socket: `messages` are the text frames from
+    /// the client, the returned stream the frames to send back. Credentials
+    /// come in the `connection_init` payload, as `Authorization` or
+    /// `X-API-Key`, since browsers cannot set headers on web sockets.
+    pub fn websocket<S>(&self, request: RequestContext, protocol: Protocols, messages: S) -> impl Stream<Item = WsMessage> + Send
+    where
+        S: Stream + Unpin + Send + 'static,
+        S::Item: AsRef<[u8]> + Send,
+    {
+        let repo = self.repo.clone();
+        let auth = self.auth.clone();
+        WebSocket::new(self.schema.clone(), messages, protocol).on_connection_init(move |payload| async move {
+            let credentials = Credentials::from_headers(|name| {
+                payload.as_object()?.iter().find(|(key, _)| key.eq_ignore_ascii_case(name))?.1.as_str()
+            });
+            auth.authorize_operation(&request, Operation::Read, &credentials).await.map_err(|e| to_error(&e))?;
+            let mut data = Data::default();
+            RequestData::new(repo, &request, credentials).insert_into(&mut data);
+            Ok(data)
+        })
+    }
+}
+
+/// The first subprotocol of a web socket request's `Sec-WebSocket-Protocol`
+/// that async-graphql speaks: `graphql-transport-ws` or the older
+/// `graphql-ws`.
+pub fn negotiate_protocol(sec_websocket_protocol: Option<&str>) -> Result<Protocols, AppError> {
+    sec_websocket_protocol
+        .into_iter()
+        .flat_map(|protocols| protocols.split(','))
+        .find_map(|protocol| protocol.trim().parse().ok())
+        .ok_or_else(|| AppError::BadRequest("use the graphql-transport-ws or graphql-ws subprotocol".to_string()))
+}
+
+/// The GraphiQL page, pointed at both endpoints.
+pub fn graphiql_html() -> &'static str {
+    static HTML: OnceLock<String> = OnceLock::new();
+    HTML.get_or_init(|| {
+        GraphiQLSource::build()
+            .endpoint(ENDPOINT)
+            .subscription_endpoint(WS_ENDPOINT)
+            .title("Restaurants GraphQL")
+            .finish()
+    })
+}
+
+/// Mutations are charged as writes; queries, and operations that do not
+/// parse or cannot be picked out, as reads.
+fn operation_class(single: &async_graphql::Request) -> RouteClass {
+    let Ok(document) = parser::parse_query(&single.query) else {
+        return RouteClass::Reads;
+    };
+    let operation = match (&document.operations, single.operation_name.as_deref()) {
+        (DocumentOperations::Single(operation), _) => Some(operation),
+        (DocumentOperations::Multiple(operations), Some(name)) => operations.get(name),
+        (DocumentOperations::Multiple(operations), None) => operations.values().next().filter(|_| operations.len() == 1),
+    };
+    match operation.map(|operation| operation.node.ty) {
+        Some(OperationType::Mutation) => RouteClass::Writes,
+        _ => RouteClass::Reads,
+    }
+}
+
+/// What resolvers get from the request on top of the schema's data.
+struct RequestData {
+    request: RequestContext,
+    credentials: Credentials,
+    loader: Arc<DataLoader<RestaurantLoader>>,
+}
+
+impl RequestData {
+    fn new(repo: Arc<MongoRepo>, request: &RequestContext, credentials: Credentials) -> Self {
+        // A loader per request: batching within it, no cache shared across clients
+        let loader = RestaurantLoader { repo, request: request.clone() };
+        Self {
+            request: request.clone(),
+            credentials,
+            loader: Arc::new(DataLoader::new(loader, tokio::spawn)),
+        }
+    }
+
+    fn attach(&self, single: async_graphql::Request) -> async_graphql::Request {
+        single.data(self.request.clone()).data(self.credentials.clone()).data(self.loader.clone())
+    }
+
+    fn insert_into(self, data: &mut Data) {
+        data.insert(self.request);
+        data.insert(self.credentials);
+        data.insert(self.loader);
+    }
+}
+
+/// Batches the restaurant lookups of one request into a single `$in` find.
+struct RestaurantLoader {
+    repo: Arc<MongoRepo>,
+    request: RequestContext,
+}
+
+impl Loader<ObjectId> for RestaurantLoader {
+    type Value = RestaurantDoc;
+    type Error = Arc<AppError>;
+
+    async fn load(&self, ids: &[ObjectId]) -> std::result::Result<HashMap<ObjectId, RestaurantDoc>, Self::Error> {
+        let restaurants = self.repo.get_restaurants_by_ids(&self.request, ids).await?;
+        Ok(restaurants.into_iter().filter_map(|restaurant| Some((restaurant.id?, restaurant))).collect())
+    }
+}
+
+/// A GraphQL error with the status the REST API would answer with as its
+/// `status` extension.
+fn to_error(e: &AppError) -> Error {
+    Error::new(e.to_string()).extend_with(|_, extensions| {
+        extensions.set("status", e.status());
+        if let AppError::Invalid(errors) = e {
+            let errors = errors.iter().map(|error| async_graphql::Value::from_json(serde_json::json!(error)).unwrap_or_default());
+            extensions.set("errors", async_graphql::Value::List(errors.collect()));
+        }
+    })
+}
+
+fn parse_id(id: &ID) -> Result<ObjectId> {
+    ObjectId::parse_str(id.as_str()).map_err(|e| to_error(&AppError::from(e)))
+}
+
+async fn authorize(ctx: &Context<'_>, operation: Operation) -> Result<()> {
+    let auth = ctx.data::<Authenticator>()?;
+    auth.authorize_operation(ctx.data()?, operation, ctx.data()?).await.map_err(|e| to_error(&e))?;
+    Ok(())
+}
+
+#[Object(name = "Restaurant")]
+impl RestaurantDoc {
+    /// Hex `ObjectId`.
+    async fn id(&self) -> Option<ID> {
+        self.id.map(|id| ID(id.to_hex()))
+    }
+
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn borough(&self) -> &str {
+        &self.borough
+    }
+
+    async fn cuisine(&self) -> &str {
+        &self.cuisine
+    }
+
+    async fn restaurant_id(&self) -> &str {
+        &self.restaurant_id
+    }
+
+    async fn address(&self) -> Option<&Address> {
+        self.address.as_ref()
+    }
+
+    async fn grades(&self) -> &[Grade] {
+        &self.grades
+    }
+
+    /// Authenticated subject that created the restaurant.
+    async fn created_by(&self) -> Option<&str> {
+        self.created_by.as_deref()
+    }
+
+    /// Authenticated subject of the last update.
+    async fn updated_by(&self) -> Option<&str> {
+        self.updated_by.as_deref()
+    }
+}
+
+#[Object]
+impl Address {
+    async fn building(&self) -> &str {
+        &self.building
+    }
+
+    /// `[longitude, latitude]`
+    async fn coord(&self) -> &[f64] {
+        &self.coord
+    }
+
+    async fn street(&self) -> &str {
+        &self.street
+    }
+
+    async fn zipcode(&self) -> &str {
+        &self.zipcode
+    }
+}
+
+#[Object]
+impl Grade {
+    /// RFC 3339 date of the inspection.
+    async fn date(&self) -> Result<String> {
+        Ok(self.date.try_to_rfc3339_string()?)
+    }
+
+    async fn grade(&self) -> &str {
+        &self.grade
+    }
+
+    async fn score(&self) -> i32 {
+        self.score
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[graphql(remote = "ChangeKind")]
+enum RestaurantChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[Object]
+impl RestaurantChange {
+    async fn kind(&self) -> RestaurantChangeKind {
+        self.kind.into()
+    }
+
+    async fn id(&self) -> ID {
+        ID(self.id.to_hex())
+    }
+
+    /// The restaurant after the change; null once deleted.
+    async fn restaurant(&self) -> Option<&RestaurantDoc> {
+        self.restaurant.as_ref()
+    }
+}
+
+#[derive(InputObject)]
+struct AddressInput {
+    #[graphql(default)]
+    building: String,
+    /// `[longitude, latitude]`
+    #[graphql(default)]
+    coord: Vec<f64>,
+    #[graphql(default)]
+    street: String,
+    #[graphql(default)]
+    zipcode: String,
+}
+
+impl From<AddressInput> for Address {
+    fn from(input: AddressInput) -> Self {
+        Address { building: input.building, coord: input.coord, street: input.street, zipcode: input.zipcode }
+    }
+}
+
+#[derive(InputObject)]
+struct GradeInput {
+    /// RFC 3339 date of the inspection.
+    date: String,
+    grade: String,
+    score: i32,
+}
+
+impl GradeInput {
+    fn into_grade(self) -> Result<Grade> {
+        let date = DateTime::parse_rfc3339_str(&self.date)
+            .map_err(|e| to_error(&AppError::BadRequest(format!("invalid grade date {}: {}", self.date, e))))?;
+        Ok(Grade { date, grade: self.grade, score: self.score })
+    }
+}
+
+fn into_grades(grades: Vec<GradeInput>) -> Result<Vec<Grade>> {
+    grades.into_iter().map(GradeInput::into_grade).collect()
+}
+
+#[derive(InputObject)]
+struct RestaurantInput {
+    name: String,
+    #[graphql(default)]
+    borough: String,
+    #[graphql(default)]
+    cuisine: String,
+    #[graphql(default)]
+    restaurant_id: String,
+    address: Option<AddressInput>,
+    #[graphql(default)]
+    grades: Vec<GradeInput>,
+}
+
+impl RestaurantInput {
+    fn into_restaurant(self) -> Result<RestaurantDoc> {
+        Ok(RestaurantDoc {
+            name: self.name,
+            borough: self.borough,
+            cuisine: self.cuisine,
+            restaurant_id: self.restaurant_id,
+            address: self.address.map(Address::from),
+            grades: into_grades(self.grades)?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Fields left out are left unchanged; `grades` replaces the whole list.
+#[derive(InputObject)]
+struct RestaurantUpdate {
+    name: Option<String>,
+    borough: Option<String>,
+    cuisine: Option<String>,
+    restaurant_id: Option<String>,
+    address: Option<AddressInput>,
+    grades: Option<Vec<GradeInput>>,
+}
+
+impl RestaurantUpdate {
+    fn into_request(self) -> Result<UpdateRestaurantRequest> {
+        Ok(UpdateRestaurantRequest {
+            name: self.name,
+            borough: self.borough,
+            cuisine: self.cuisine,
+            restaurant_id: self.restaurant_id,
+            address: self.address.map(|address| Some(Address::from(address))),
+            grades: self.grades.map(into_grades).transpose()?,
+        })
+    }
+}
+
+/// Exact matches; fields left out match everything.
+#[derive(InputObject, Default)]
+struct RestaurantFilter {
+    name: Option<String>,
+    borough: Option<String>,
+    cuisine: Option<String>,
+    zipcode: Option<String>,
+}
+
+impl RestaurantFilter {
+    fn into_document(self) -> Document {
+        let mut filter = Document::new();
+        let fields = [
+            ("name", self.name),
+            ("borough", self.borough),
+            ("cuisine", self.cuisine),
+            ("address.zipcode", self.zipcode),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                filter.insert(key, value);
+            }
+        }
+        filter
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// A restaurant by its ID, or null if there is none.
+    async fn restaurant(&self, ctx: &Context<'_>, id: ID) -> Result<Option<RestaurantDoc>> {
+        let loader = ctx.data::<Arc<DataLoader<RestaurantLoader>>>()?;
+        loader.load_one(parse_id(&id)?).await.map_err(|e| to_error(&e))
+    }
+
+    /// Restaurants by their IDs, in the same order, with null for unknown
+    /// IDs. Looked up with one query, as are `restaurant` fields of the same
+    /// request.
+    async fn restaurants_by_ids(&self, ctx: &Context<'_>, ids: Vec<ID>) -> Result<Vec<Option<RestaurantDoc>>> {
+        let ids = ids.iter().map(parse_id).collect::<Result<Vec<_>>>()?;
+        let loader = ctx.data::<Arc<DataLoader<RestaurantLoader>>>()?;
+        let mut found = loader.load_many(ids.iter().copied()).await.map_err(|e| to_error(&e))?;
+        Ok(ids.iter().map(|id| found.remove(id)).collect())
+    }
+
+    /// Restaurants matching `filter` in ID order, `first` (at most 100) at a
+    /// time. Pass a page's `endCursor` as `after` for the next one.
+    async fn restaurants(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<RestaurantFilter>,
+        #[graphql(default = 10)] first: i32,
+        after: Option<String>,
+    ) -> Result<Connection<String, RestaurantDoc>> {
+        if !(1..=MAX_PAGE_SIZE).contains(&first) {
+            return Err(to_error(&AppError::BadRequest(format!("first must be between 1 and {}", MAX_PAGE_SIZE))));
+        }
+        let after = after.map(|cursor| parse_id(&ID(cursor))).transpose()?;
+        let repo = ctx.data::<Arc<MongoRepo>>()?;
+        let filter = filter.unwrap_or_default().into_document();
+        // One more than asked for tells whether there is a next page
+        let mut page = repo
+            .find_restaurants(ctx.data()?, filter, after, i64::from(first) + 1)
+            .await
+            .map_err(|e| to_error(&e))?;
+        let has_next_page = page.len() > first as usize;
+        page.truncate(first as usize);
+
+        let mut connection = Connection::new(after.is_some(), has_next_page);
+        connection.edges.extend(page.into_iter().filter_map(|restaurant| {
+            Some(Edge::new(restaurant.id?.to_hex(), restaurant))
+        }));
+        Ok(connection)
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    async fn create_restaurant(&self, ctx: &Context<'_>, input: RestaurantInput) -> Result<RestaurantDoc> {
+        authorize(ctx, Operation::Write).await?;
+        let repo = ctx.data::<Arc<MongoRepo>>()?;
+        repo.create_restaurant(ctx.data()?, input.into_restaurant()?).await.map_err(|e| to_error(&e))
+    }
+
+    async fn update_restaurant(&self, ctx: &Context<'_>, id: ID, input: RestaurantUpdate) -> Result<RestaurantDoc> {
+        authorize(ctx, Operation::Write).await?;
+        let repo = ctx.data::<Arc<MongoRepo>>()?;
+        repo.update_restaurant(ctx.data()?, parse_id(&id)?, input.into_request()?).await.map_err(|e| to_error(&e))
+    }
+
+    /// True once deleted; unknown IDs are an error with status 404.
+    async fn delete_restaurant(&self, ctx: &Context<'_>, id: ID) -> Result<bool> {
+        authorize(ctx, Operation::Delete).await?;
+        let repo = ctx.data::<Arc<MongoRepo>>()?;
+        repo.delete_restaurant(ctx.data()?, parse_id(&id)?).await.map_err(|e| to_error(&e))?;
+        Ok(true)
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// Every restaurant created, updated or deleted from now on, by anyone.
+    /// Needs MongoDB change streams, so a replica set or sharded cluster.
+    async fn restaurant_changed(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = Result<RestaurantChange>>> {
+        let repo = ctx.data::<Arc<MongoRepo>>()?;
+        let changes = repo.watch(ctx.data()?).await.map_err(|e| to_error(&e))?;
+        Ok(changes.map(|change| change.map_err(|e| to_error(&e))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::BucketConfig,
+        frameworks::{testing, Framework},
+    };
+
+    /// The frameworks that mount GraphQL must answer the same way; the
+    /// others leave its routes unknown.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn graphql_is_served_by_axum_and_actix() {
+        let failures = testing::with_every_framework(testing::config(), |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mounted = matches!(framework, Framework::Axum | Framework::Actix);
+                let json = [("Content-Type", "application/json")];
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+
+                let typename = testing::send_body(addr, "POST", ENDPOINT, &json, r#"{"query": "{ __typename }"}"#).await;
+                let answered = typename.status == 200 && typename.body.contains(r#""__typename":"Query""#);
+                check("answers { __typename }", answered == mounted);
+                if !mounted {
+                    check("leaves /graphql unknown", matches!(typename.status, 404 | 405));
+                    continue;
+                }
+                let invalid = testing::send_body(addr, "POST", ENDPOINT, &json, r#"{"query": "{ restaurant(id: \"x\") { name } }"}"#).await;
+                check("reports invalid IDs with status 400", invalid.body.contains(r#""status":400"#));
+                let form = testing::send_body(addr, "POST", ENDPOINT, &[("Content-Type", "text/plain")], "{}").await;
+                check("requires JSON bodies", form.status == 415);
+                let graphiql = testing::send(addr, "GET", GRAPHIQL, &[]).await;
+                check("serves GraphiQL", graphiql.status == 200 && graphiql.body.contains(WS_ENDPOINT));
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "GraphQL differs:\n{}", failures.join("\n"));
+    }
+
+    /// Each operation of a batch takes its own token, mutations from the
+    /// writes bucket.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_operation_of_a_batch_is_charged() {
+        let mut config = testing::config();
+        config.rate_limit.enabled = true;
+        config.rate_limit.trust_forwarded_for = true;
+        config.rate_limit.reads = BucketConfig { burst: 2, per_second: 0.001 };
+        config.rate_limit.writes = BucketConfig { burst: 1, per_second: 0.001 };
+        let failures = testing::with_every_framework(config, |config| async move {
+            let mut failures = Vec::new();
+            for (index, framework) in [Framework::Axum, Framework::Actix].into_iter().enumerate() {
+                let addr = testing::addr(&config, framework);
+                // A client of its own for each framework, since they share the buckets
+                let client = format!("192.0.2.{}", index + 1);
+                let headers = [("Content-Type", "application/json"), ("X-Forwarded-For", client.as_str())];
+                let statuses = |body: &str| -> Vec<Option<u64>> {
+                    let responses: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+                    let responses = responses.as_array().cloned().unwrap_or_default();
+                    responses.iter().map(|response| response["errors"][0]["extensions"]["status"].as_u64()).collect()
+                };
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+
+                let mutations = r#"[{"query": "mutation { deleteRestaurant(id: \"x\") }"}, {"query": "mutation { deleteRestaurant(id: \"x\") }"}]"#;
+                let mutations = testing::send_body(addr, "POST", ENDPOINT, &headers, mutations).await;
+                check("charges mutations to the writes bucket", statuses(&mutations.body) == [Some(400), Some(429)]);
+                let queries = r#"[{"query": "{ __typename }"}, {"query": "{ __typename }"}, {"query": "{ __typename }"}]"#;
+                let queries = testing::send_body(addr, "POST", ENDPOINT, &headers, queries).await;
+                check("answers the batch with 200", queries.status == 200);
+                check("charges each query of the batch", statuses(&queries.body) == [None, None, Some(429)]);
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "GraphQL rate limiting differs:\n{}", failures.join("\n"));
+    }
+}
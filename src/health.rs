@@ -0,0 +1,214 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use mongodb::{
+    Database,
+    bson::doc,
+    event::{EventHandler, sdam::{SdamEvent, TopologyDescription}},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{config::HealthConfig, error::AppError};
+
+/// Version of the `mongodb` crate this binary was built with, see `build.rs`.
+pub const DRIVER_VERSION: &str = env!("MONGODB_DRIVER_VERSION");
+
+/// Latest topology the driver has discovered, fed by SDAM events.
+#[derive(Clone, Default)]
+pub struct TopologyWatcher {
+    latest: Arc<RwLock<Option<TopologySummary>>>,
+}
+
+impl TopologyWatcher {
+    pub fn event_handler(&self) -> EventHandler<SdamEvent> {
+        let latest = self.latest.clone();
+        EventHandler::callback(move |event| {
+            if let SdamEvent::TopologyDescriptionChanged(event) = event {
+                let summary = TopologySummary::from(&event.new_description);
+                *latest.write().unwrap_or_else(|e| e.into_inner()) = Some(summary);
+            }
+        })
+    }
+
+    pub fn current(&self) -> Option<TopologySummary> {
+        self.latest.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TopologySummary {
+    #[serde(rename = "type")]
+    pub topology_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set_name: Option<String>,
+    pub servers: Vec<ServerSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ServerSummary {
+    pub address: String,
+    #[serde(rename = "type")]
+    pub server_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub round_trip_ms: Option<f64>,
+}
+
+impl From<&TopologyDescription> for TopologySummary {
+    fn from(description: &TopologyDescription) -> Self {
+        let mut servers: Vec<ServerSummary> = description
+            .servers()
+            .into_iter()
+            .map(|(address, info)| ServerSummary {
+                address: address.to_string(),
+                server_type: format!("{:?}", info.server_type()),
+                round_trip_ms: info.average_round_trip_time().map(as_millis),
+            })
+            .collect();
+        servers.sort_by(|a, b| a.address.cmp(&b.address));
+
+        Self {
+            topology_type: format!("{:?}", description.topology_type()),
+            set_name: description.set_name().cloned(),
+            servers,
+        }
+    }
+}
+
+/// Body of `/healthz`: the process is up and serving HTTP.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Liveness {
+    pub status: &'static str,
+}
+
+pub fn liveness() -> Liveness {
+    Liveness { status: "ok" }
+}
+
+/// Body of `/readyz`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub mongodb: MongoHealth,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MongoHealth {
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ping_ms: Option<f64>,
+    pub driver_version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topology: Option<TopologySummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Readiness {
+    /// Whether the instance should receive traffic; `/readyz` answers 503 otherwise.
+    pub fn is_ready(&self) -> bool {
+        self.status == "ready"
+    }
+}
+
+/// Pings MongoDB and reports whether this instance can serve requests. While
+/// shutting down it reports not ready, so load balancers stop routing to it.
+pub async fn readiness(
+    db: &Database,
+    topology: &TopologyWatcher,
+    config: &HealthConfig,
+    shutting_down: bool,
+) -> Readiness {
+    let ping = ping(db, Duration::from_millis(config.ping_timeout_ms)).await;
+    let status = match (&ping, shutting_down) {
+        (_, true) => "shutting_down",
+        (Ok(_), false) => "ready",
+        (Err(_), false) => "unavailable",
+    };
+
+    Readiness {
+        status,
+        mongodb: MongoHealth {
+            reachable: ping.is_ok(),
+            ping_ms: ping.as_ref().ok().copied().map(as_millis),
+            driver_version: DRIVER_VERSION,
+            topology: topology.current(),
+            error: ping.err().map(|e| e.to_string()),
+        },
+    }
+}
+
+/// Runs the `ping` command and returns its round trip time.
+pub async fn ping(db: &Database, timeout: Duration) -> Result<Duration, AppError> {
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, db.run_command(doc! { "ping": 1 })).await {
+        Ok(result) => {
+            result?;
+            Ok(started.elapsed())
+        }
+        Err(_) => Err(AppError::Unavailable(format!("ping timed out after {:?}", timeout))),
+    }
+}
+
+/// Pings MongoDB until it answers, backing off exponentially between
+/// attempts, and gives up with the last error after `startup_ping_attempts`.
+pub async fn wait_for_mongodb(db: &Database, config: &HealthConfig) -> Result<Duration, AppError> {
+    let timeout = Duration::from_millis(config.ping_timeout_ms);
+    let mut backoff = Duration::from_millis(config.startup_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        match ping(db, timeout).await {
+            Ok(latency) => return Ok(latency),
+            Err(e) if attempt >= config.startup_ping_attempts => return Err(e),
+            Err(e) => {
+                tracing::warn!(
+                    attempt,
+                    max_attempts = config.startup_ping_attempts,
+                    error = %e,
+                    retry_in = ?backoff,
+                    "MongoDB ping failed"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(10));
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frameworks::{testing, Framework};
+
+    /// `testing::config` points at a MongoDB nothing listens on, so every
+    /// framework must stay live but report itself not ready.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_is_live_but_not_ready_without_mongodb() {
+        let failures = testing::with_every_framework(testing::config(), |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                let live = testing::send(addr, "GET", "/healthz", &[]).await;
+                check("/healthz answers 200", live.status == 200);
+                check("/healthz reports ok", live.body.contains(r#""status":"ok""#));
+
+                let ready = testing::send(addr, "GET", "/readyz", &[]).await;
+                check("/readyz answers 503", ready.status == 503);
+                check("/readyz reports unavailable", ready.body.contains(r#""status":"unavailable""#));
+                check("/readyz reports MongoDB unreachable", ready.body.contains(r#""reachable":false"#));
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "health checks differ:\n{}", failures.join("\n"));
+    }
+}
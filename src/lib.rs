@@ -0,0 +1,20 @@
+pub mod auth;
+pub mod compression;
+pub mod content;
+pub mod models;
+pub mod db;
+pub mod frameworks;
+pub mod error;
+pub mod config;
+pub mod cors;
+pub mod graphql;
+pub mod health;
+pub mod limits;
+pub mod metrics;
+pub mod migrations;
+pub mod openapi;
+pub mod ratelimit;
+pub mod request;
+pub mod schema;
+pub mod telemetry;
+pub mod tls;
@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+
+use crate::{
+    config::LimitsConfig,
+    content::{self, BodyFormat},
+    error::AppError,
+    request,
+};
+
+/// Restaurants listed when the request does not say how many.
+const DEFAULT_LIST_LIMIT: i64 = 10;
+
+/// The routes that take a body, each with its own size limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyRoute {
+    Create,
+    Update,
+    GraphQL,
+}
+
+/// Size and nesting limits of request bodies, and the length of lists, the
+/// same in every framework. Cheap to clone.
+#[derive(Clone)]
+pub struct BodyLimits {
+    config: Arc<LimitsConfig>,
+}
+
+impl BodyLimits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self { config: Arc::new(config.clone()) }
+    }
+
+    /// Upper bound of the body of `route`.
+    pub fn max_bytes(&self, route: BodyRoute) -> usize {
+        match route {
+            BodyRoute::Create => self.config.create_body_bytes,
+            BodyRoute::Update => self.config.update_body_bytes,
+            BodyRoute::GraphQL => self.config.graphql_body_bytes,
+        }
+    }
+
+    /// The 413 for a body of `route` over its limit.
+    pub fn too_large(&self, route: BodyRoute) -> AppError {
+        AppError::PayloadTooLarge(format!("body exceeds {} bytes", self.max_bytes(route)))
+    }
+
+    /// The `limit` query parameter of `GET /api/restaurants`, 10 when there
+    /// is none. Fails with 400 unless it is between 1 and
+    /// `max_list_results`.
+    pub fn list_limit(&self, query: Option<&str>) -> Result<i64, AppError> {
+        let max = self.config.max_list_results;
+        match request::query_param(query, "limit") {
+            None => Ok(DEFAULT_LIST_LIMIT.min(max)),
+            Some(limit) => limit
+                .parse()
+                .ok()
+                .filter(|limit| (1..=max).contains(limit))
+                .ok_or_else(|| AppError::BadRequest(format!("limit must be between 1 and {}, got {:?}", max, limit))),
+        }
+    }
+
+    /// Decodes a body sent in `format`. Fails with 422 when it nests deeper
+    /// than `max_json_depth` or does not have the shape of `T`, and with 400
+    /// when it is not in `format` at all.
+    pub fn decode<T: DeserializeOwned>(&self, format: BodyFormat, body: &[u8]) -> Result<T, AppError> {
+        match format {
+            BodyFormat::Json => self.parse(body),
+            BodyFormat::Bson => content::from_bson(body, self.config.max_json_depth),
+            BodyFormat::MessagePack => content::from_msgpack(body, self.config.max_json_depth),
+        }
+    }
+
+    /// Decodes a bulk import body: a JSON or MessagePack array, or BSON
+    /// documents one after the other.
+    pub fn decode_many<T: DeserializeOwned>(&self, format: BodyFormat, body: &[u8]) -> Result<Vec<T>, AppError> {
+        match format {
+            BodyFormat::Bson => content::from_bson_sequence(body, self.config.max_json_depth),
+            BodyFormat::Json | BodyFormat::MessagePack => self.decode(format, body),
+        }
+    }
+
+    /// Parses a JSON body, failing like `decode`.
+    fn parse<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, AppError> {
+        let max_depth = self.config.max_json_depth;
+        if nests_deeper_than(body, max_depth) {
+            return Err(AppError::Unprocessable(format!("body nests deeper than {} levels", max_depth)));
+        }
+        serde_json::from_slice(body).map_err(|e| match e.classify() {
+            Category::Data => AppError::Unprocessable(e.to_string()),
+            Category::Syntax | Category::Eof | Category::Io => {
+                AppError::BadRequest(format!("body is not valid JSON: {}", e))
+            }
+        })
+    }
+}
+
+/// The format of a body of `route` sent with `content_type`: GraphQL takes
+/// JSON only, the restaurant routes also BSON and MessagePack. Fails with
+/// 415 for anything else.
+pub fn body_format(route: BodyRoute, content_type: Option<&str>) -> Result<BodyFormat, AppError> {
+    match route {
+        BodyRoute::GraphQL => require_json(content_type).map(|()| BodyFormat::Json),
+        BodyRoute::Create | BodyRoute::Update => BodyFormat::from_content_type(content_type),
+    }
+}
+
+/// Fails with 415 unless `content_type` is `application/json`, parameters
+/// such as `charset` aside.
+pub fn require_json(content_type: Option<&str>) -> Result<(), AppError> {
+    let essence = content_type.and_then(|value| value.split(';').next()).map(str::trim).unwrap_or_default();
+    if essence.eq_ignore_ascii_case("application/json") {
+        return Ok(());
+    }
+    Err(AppError::UnsupportedMediaType(if essence.is_empty() {
+        "Content-Type application/json is required".to_string()
+    } else {
+        format!("content type {} is not supported, use application/json", essence)
+    }))
+}
+
+/// Whether objects and arrays in `body` nest deeper than `max`. Counted on
+/// the raw bytes so a hostile body never reaches the parser's recursion.
+fn nests_deeper_than(body: &[u8], max: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in body {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frameworks::{testing, Framework};
+
+    #[test]
+    fn nesting_is_counted_outside_strings() {
+        assert!(!nests_deeper_than(br#"{"grades": [{"score": 1}]}"#, 3));
+        assert!(nests_deeper_than(br#"{"grades": [{"score": [1]}]}"#, 3));
+        assert!(!nests_deeper_than(br#"{"name": "[[[[\"{{{{"}"#, 1));
+        assert!(nests_deeper_than(&[b'['; 10_000], 64));
+    }
+
+    #[test]
+    fn only_application_json_is_accepted() {
+        assert!(require_json(Some("application/json")).is_ok());
+        assert!(require_json(Some("Application/JSON; charset=utf-8")).is_ok());
+        for content_type in [None, Some("text/plain"), Some("application/x-www-form-urlencoded"), Some("")] {
+            assert_eq!(require_json(content_type).unwrap_err().status(), 415);
+        }
+    }
+
+    #[test]
+    fn list_limits_are_bounded() {
+        let limits = BodyLimits::new(&LimitsConfig { max_list_results: 500, ..Default::default() });
+        assert_eq!(limits.list_limit(None).unwrap(), 10);
+        assert_eq!(limits.list_limit(Some("cuisine=Bakery&limit=500")).unwrap(), 500);
+        for query in ["limit=0", "limit=501", "limit=-1", "limit=ten", "limit="] {
+            assert_eq!(limits.list_limit(Some(query)).unwrap_err().status(), 400, "{}", query);
+        }
+    }
+
+    /// Every framework must reject the same bodies with the same status and
+    /// problem details, before touching MongoDB.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_rejects_the_same_bodies() {
+        let mut config = testing::config();
+        config.limits.create_body_bytes = 256;
+        config.limits.max_json_depth = 4;
+        let deep = format!(r#"{{"address": {}1{}}}"#, "[".repeat(8), "]".repeat(8));
+        let large = format!(r#"{{"name": "{}"}}"#, "x".repeat(300));
+        let failures = testing::with_every_framework(config, |config| async move {
+            let cases = [
+                ("POST", "/api/restaurants", "text/plain", r#"{"name": "Nordic Delicacies"}"#, 415),
+                ("POST", "/api/restaurants", "application/json", large.as_str(), 413),
+                ("POST", "/api/restaurants", "application/json", deep.as_str(), 422),
+                ("POST", "/api/restaurants", "application/json", r#"{"name": 5}"#, 422),
+                ("POST", "/api/restaurants", "application/json", r#"{"name": "#, 400),
+                ("PUT", "/api/restaurants/5eb3d668b31de5d588f42a7e", "application/json", "[1, 2]", 422),
+                ("PUT", "/api/restaurants/5eb3d668b31de5d588f42a7e", "application/json", "{,}", 400),
+                ("POST", "/admin/restaurants/import", "text/csv", "name\nNordic Delicacies", 415),
+                ("POST", "/admin/restaurants/import", "application/json", deep.as_str(), 422),
+            ];
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                for (method, path, content_type, body, status) in cases {
+                    let response = testing::send_body(addr, method, path, &[("Content-Type", content_type)], body).await;
+                    if response.status != status {
+                        failures.push(format!("{}: {} {} gave {}, not {}", framework, method, path, response.status, status));
+                    } else if status != 400 && response.header("content-type") != Some("application/problem+json") {
+                        failures.push(format!("{}: {} is not problem details", framework, status));
+                    }
+                }
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "body checks differ:\n{}", failures.join("\n"));
+    }
+}
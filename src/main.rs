@@ -1,25 +1,350 @@
-mod models;
-mod db;
-mod frameworks;
-mod error;
-
-use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use dotenv::dotenv;
-use mongodb::Client;
-use tokio;
+use futures::TryStreamExt;
+
+use mongodb_driver_web_frameworks::{
+    auth::{ApiKeys, Authenticator, Scope},
+    compression::Compression,
+    config::{Config, LogFormat, Overrides},
+    cors::CorsPolicy,
+    db::{self, mongodb::MongoRepo},
+    frameworks::{self, Framework, ServerContext},
+    health::{self, TopologyWatcher},
+    limits::BodyLimits,
+    metrics::Metrics,
+    migrations::{Migrator, Rollback, RunOptions},
+    ratelimit::RateLimiter,
+    request::RequestContext,
+    schema::Inference,
+    telemetry,
+    tls::Tls,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+#[derive(Parser)]
+#[command(about = "MongoDB restaurants CRUD across several Rust web frameworks")]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Command line overrides, applied on top of the config file and environment
+#[derive(Args)]
+struct ConfigArgs {
+    /// TOML configuration file [default: config.toml, if present]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// MongoDB connection string
+    #[arg(long, global = true)]
+    mongodb_uri: Option<String>,
+
+    /// Database holding the restaurants collection
+    #[arg(long, global = true)]
+    database: Option<String>,
+
+    /// Restaurants collection name
+    #[arg(long, global = true)]
+    collection: Option<String>,
+
+    /// Log output format
+    #[arg(long, value_enum, global = true)]
+    log_format: Option<LogFormatArg>,
+
+    /// Override any setting, e.g. `--set mongodb.max_pool_size=20`
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_key_value, global = true)]
+    set: Vec<(String, String)>,
+}
+
+impl From<ConfigArgs> for Overrides {
+    fn from(args: ConfigArgs) -> Self {
+        Overrides {
+            config_file: args.config,
+            mongodb_uri: args.mongodb_uri,
+            database: args.database,
+            collection: args.collection,
+            log_format: args.log_format.map(|format| match format {
+                LogFormatArg::Text => LogFormat::Text,
+                LogFormatArg::Json => LogFormat::Json,
+            }),
+            set: args.set,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormatArg {
+    Text,
+    Json,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start one or more web frameworks side by side, each on its own port
+    Serve {
+        /// Comma separated list of frameworks, or `all`
+        #[arg(long = "framework", value_enum, value_delimiter = ',', default_value = "all")]
+        frameworks: Vec<FrameworkArg>,
+    },
+
+    /// Manage the API keys required when `auth.enabled` is set
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+
+    /// Sample the restaurants and compare the fields found with the model
+    Schema {
+        /// How many documents to sample with `$sample`
+        #[arg(long, default_value_t = 1000)]
+        sample: u32,
+
+        /// Report format
+        #[arg(long, value_enum, default_value = "table")]
+        output: OutputArg,
+    },
+
+    /// Apply or undo the versioned changes to the stored restaurants
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommand,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputArg {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Create a key; it is printed once, only its hash is stored
+    Mint {
+        /// Unique name of the active key, e.g. the client using it
+        #[arg(long)]
+        name: String,
+
+        /// Comma separated scopes
+        #[arg(long = "scope", value_enum, value_delimiter = ',', required = true)]
+        scopes: Vec<ScopeArg>,
+    },
+
+    /// Revoke the active key with this ID or name
+    Revoke {
+        id_or_name: String,
+    },
+
+    /// List every key, revoked ones included
+    List,
+}
+
+#[derive(Subcommand)]
+enum MigrateCommand {
+    /// Apply every pending migration, in order
+    Up {
+        /// Stop after this migration
+        #[arg(long)]
+        to: Option<String>,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
+
+    /// Undo the last applied migration
+    Down {
+        /// Undo every migration applied after this one, keeping it
+        #[arg(long, conflicts_with = "all")]
+        to: Option<String>,
+
+        /// Undo every applied migration
+        #[arg(long)]
+        all: bool,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
+
+    /// List every migration and whether it is applied
+    Status,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Count the documents each migration would change, without writing
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Documents changed by each `update_many`
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: u32,
+}
+
+impl From<RunArgs> for RunOptions {
+    fn from(args: RunArgs) -> Self {
+        RunOptions { dry_run: args.dry_run, batch_size: args.batch_size }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ScopeArg {
+    #[value(name = "restaurants:read")]
+    RestaurantsRead,
+    #[value(name = "restaurants:write")]
+    RestaurantsWrite,
+    Admin,
+}
+
+impl From<ScopeArg> for Scope {
+    fn from(arg: ScopeArg) -> Self {
+        match arg {
+            ScopeArg::RestaurantsRead => Scope::RestaurantsRead,
+            ScopeArg::RestaurantsWrite => Scope::RestaurantsWrite,
+            ScopeArg::Admin => Scope::Admin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum FrameworkArg {
+    All,
+    Actix,
+    Axum,
+    Rocket,
+    Warp,
+    Tide,
+    Grpc,
+}
+
+fn selected_frameworks(args: &[FrameworkArg]) -> Vec<Framework> {
+    let mut selected = Vec::new();
+    for arg in args {
+        let frameworks: &[Framework] = match arg {
+            FrameworkArg::All => &Framework::ALL,
+            FrameworkArg::Actix => &[Framework::Actix],
+            FrameworkArg::Axum => &[Framework::Axum],
+            FrameworkArg::Rocket => &[Framework::Rocket],
+            FrameworkArg::Warp => &[Framework::Warp],
+            FrameworkArg::Tide => &[Framework::Tide],
+            FrameworkArg::Grpc => &[Framework::Grpc],
+        };
+        for framework in frameworks {
+            if !selected.contains(framework) {
+                selected.push(*framework);
+            }
+        }
+    }
+    selected
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
-    env_logger::init();
-
-    let mongodb_uri = env::var("MONGODB_URI")
-        .unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
-    
-    let client = Client::with_uri_str(&mongodb_uri).await?;
-    let db = client.database("sample_restaurants");
-    
-    println!("Connected to MongoDB!");
+    let cli = Cli::parse();
+
+    let config = match Config::load(&cli.config.into()) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let telemetry = match telemetry::init(&config.logging, &config.tracing) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("could not set up tracing: {}", e);
+            std::process::exit(2);
+        }
+    };
+    info!("Effective configuration:\n{}", config.to_redacted_toml());
+
+    let result = run(cli.command, config).await;
+    telemetry.shutdown();
+    result
+}
+
+async fn run(command: Option<Command>, config: Arc<Config>) -> Result<(), Box<dyn std::error::Error>> {
+    let topology = TopologyWatcher::default();
+    let metrics = Metrics::new();
+    let client = db::mongodb::connect(&config.mongodb, &topology, &metrics).await?;
+    let db = client.database(&config.mongodb.database);
+    let auth = match Authenticator::new(&db, &config.auth) {
+        Ok(auth) => auth,
+        Err(e) => {
+            error!(error = %e, "Could not set up authentication");
+            std::process::exit(2);
+        }
+    };
+    let tls = match Tls::load(&config.tls) {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!(error = %e, "Could not load the TLS certificate");
+            std::process::exit(2);
+        }
+    };
+
+    match health::wait_for_mongodb(&db, &config.health).await {
+        Ok(latency) => info!(?latency, "Connected to MongoDB!"),
+        Err(e) => {
+            error!(
+                uri = %config.redacted().mongodb.uri,
+                attempts = config.health.startup_ping_attempts,
+                error = %e,
+                "Could not reach MongoDB"
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let ctx = ServerContext {
+        db: db.clone(),
+        config: config.clone(),
+        topology,
+        metrics,
+        cors: CorsPolicy::new(&config.cors),
+        compression: Compression::new(&config.compression),
+        limits: BodyLimits::new(&config.limits),
+        rate_limiter: RateLimiter::new(&config.rate_limit, &auth),
+        auth,
+        tls,
+        shutdown: CancellationToken::new(),
+    };
+
+    match command {
+        Some(Command::Serve { frameworks }) => {
+            return frameworks::serve(ctx, &selected_frameworks(&frameworks)).await;
+        }
+        Some(Command::Keys { command }) => {
+            let result = manage_keys(ctx.auth.api_keys(), command).await;
+            client.shutdown().await;
+            return result;
+        }
+        Some(Command::Schema { sample, output }) => {
+            let result = report_schema(&MongoRepo::new(&db, &config.mongodb.collection), sample, output).await;
+            client.shutdown().await;
+            return result;
+        }
+        Some(Command::Migrate { command }) => {
+            let result = migrate(&Migrator::new(&db, &config.mongodb.collection), command).await;
+            client.shutdown().await;
+            return result;
+        }
+        None => {}
+    }
+
     println!("Available web frameworks:");
     println!("1. None (MongoDB driver only)");
     println!("2. Actix Web");
@@ -27,21 +352,109 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("4. Rocket");
     println!("5. Warp");
     println!("6. Tide");
-    
-    println!("\nEnter your choice (1-6):");
-    
+    println!("7. gRPC");
+    println!("8. All of the above side by side");
+
+    println!("\nEnter your choice (1-8):");
+
     let mut choice = String::new();
     std::io::stdin().read_line(&mut choice)?;
-    
+
     match choice.trim().parse::<u8>()? {
-        1 => frameworks::none::start(db).await?,
-        2 => frameworks::actix::start(db).await?,
-        3 => frameworks::axum::start(db).await?,
-        4 => frameworks::rocket::start(db).await?,
-        5 => frameworks::warp::start(db).await?,
-        6 => frameworks::tide::start(db).await?,
+        1 => {
+            frameworks::none::start(MongoRepo::new(&db, &config.mongodb.collection)).await?;
+            client.shutdown().await;
+        }
+        2 => frameworks::serve(ctx, &[Framework::Actix]).await?,
+        3 => frameworks::serve(ctx, &[Framework::Axum]).await?,
+        4 => frameworks::serve(ctx, &[Framework::Rocket]).await?,
+        5 => frameworks::serve(ctx, &[Framework::Warp]).await?,
+        6 => frameworks::serve(ctx, &[Framework::Tide]).await?,
+        7 => frameworks::serve(ctx, &[Framework::Grpc]).await?,
+        8 => frameworks::serve(ctx, &Framework::ALL).await?,
         _ => println!("Invalid choice!")
     }
 
     Ok(())
 }
+
+async fn manage_keys(api_keys: &ApiKeys, command: KeysCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        KeysCommand::Mint { name, scopes } => {
+            let scopes = scopes.into_iter().map(Scope::from).collect();
+            let (key, api_key) = api_keys.mint(&name, scopes).await?;
+            println!("Created API key {} ({})", api_key.id, api_key.name);
+            println!("{}", key);
+            println!("Store it now, it cannot be shown again.");
+        }
+        KeysCommand::Revoke { id_or_name } => match api_keys.revoke(&id_or_name).await? {
+            0 => return Err(format!("no active API key with ID or name {:?}", id_or_name).into()),
+            revoked => println!("Revoked {} API key(s)", revoked),
+        },
+        KeysCommand::List => {
+            println!("{:<24}  {:<20}  {:<11}  {:<10}  SCOPES", "ID", "NAME", "PREFIX", "STATUS");
+            for api_key in api_keys.list().await? {
+                let status = if api_key.revoked_at.is_some() { "revoked" } else { "active" };
+                let scopes: Vec<&str> = api_key.scopes.iter().map(|scope| scope.as_str()).collect();
+                println!(
+                    "{:<24}  {:<20}  {:<11}  {:<10}  {}",
+                    api_key.id,
+                    api_key.name,
+                    api_key.prefix,
+                    status,
+                    scopes.join(",")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn report_schema(repo: &MongoRepo, sample: u32, output: OutputArg) -> Result<(), Box<dyn std::error::Error>> {
+    let mut documents = repo.sample_restaurants(&RequestContext::cli("schema"), sample).await?;
+    let mut inference = Inference::default();
+    while let Some(document) = documents.try_next().await? {
+        inference.add(&document);
+    }
+    let report = inference.report();
+    match output {
+        OutputArg::Table => print!("{}", report.table()),
+        OutputArg::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+async fn migrate(migrator: &Migrator, command: MigrateCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let request = RequestContext::cli("migrate");
+    let (outcomes, dry_run) = match command {
+        MigrateCommand::Up { to, run } => {
+            let options = RunOptions::from(run);
+            (migrator.up(&request, to.as_deref(), &options).await?, options.dry_run)
+        }
+        MigrateCommand::Down { to, all, run } => {
+            let rollback = match (&to, all) {
+                (Some(to), _) => Rollback::To(to),
+                (None, true) => Rollback::All,
+                (None, false) => Rollback::Last,
+            };
+            let options = RunOptions::from(run);
+            (migrator.down(&request, rollback, &options).await?, options.dry_run)
+        }
+        MigrateCommand::Status => {
+            println!("{:<32}  {:<10}  {:<24}  DESCRIPTION", "ID", "STATE", "APPLIED_AT");
+            for status in migrator.status(&request).await? {
+                let applied_at = status.applied_at.and_then(|at| at.try_to_rfc3339_string().ok()).unwrap_or_else(|| "-".to_string());
+                println!("{:<32}  {:<10}  {:<24}  {}", status.id, status.state, applied_at, status.description);
+            }
+            return Ok(());
+        }
+    };
+    if outcomes.is_empty() {
+        println!("Nothing to migrate");
+    }
+    for outcome in outcomes {
+        let verb = if dry_run { "would change" } else { "changed" };
+        println!("{}  {} {} document(s)", outcome.id, verb, outcome.documents);
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use mongodb::event::{
+    EventHandler,
+    cmap::{CmapEvent, ConnectionCheckoutFailedReason},
+    command::CommandEvent,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{frameworks::grpc, graphql};
+
+/// Content type of the Prometheus text exposition format served on `/metrics`.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Route templates used as the `route` label, in one syntax for every
+/// framework so their series line up. Paths matching none of them are
+/// recorded as `unmatched`, which keeps label cardinality bounded. The
+/// GraphQL routes and gRPC methods, served by some frameworks only, come on
+/// top.
+pub const ROUTES: &[&str] = &[
+    "/api/restaurants",
+    "/api/restaurants/{id}",
+    "/admin/restaurants/import",
+    "/admin/restaurants/scan",
+    "/healthz",
+    "/readyz",
+    "/metrics",
+    "/openapi.json",
+    "/docs",
+];
+
+/// Prometheus metrics for HTTP requests, MongoDB commands and the connection
+/// pool. Cheap to clone; every clone records into the same registry.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    mongo_commands: HistogramVec,
+    mongo_command_errors: IntCounterVec,
+    pool_connections: IntGaugeVec,
+    pool_checked_out: IntGaugeVec,
+    pool_checkout_failures: IntCounterVec,
+    pool_cleared: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["framework", "method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+            &["framework", "method", "route", "status"],
+        )
+        .unwrap();
+        let mongo_commands = HistogramVec::new(
+            HistogramOpts::new("mongodb_command_duration_seconds", "MongoDB command latency")
+                .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+            &["command", "outcome"],
+        )
+        .unwrap();
+        let mongo_command_errors = IntCounterVec::new(
+            Opts::new("mongodb_command_errors_total", "MongoDB commands that failed"),
+            &["command"],
+        )
+        .unwrap();
+        let pool_connections = IntGaugeVec::new(
+            Opts::new("mongodb_pool_connections", "Open connections in the MongoDB pool"),
+            &["address"],
+        )
+        .unwrap();
+        let pool_checked_out = IntGaugeVec::new(
+            Opts::new("mongodb_pool_checked_out_connections", "MongoDB connections currently in use"),
+            &["address"],
+        )
+        .unwrap();
+        let pool_checkout_failures = IntCounterVec::new(
+            Opts::new("mongodb_pool_checkout_failures_total", "Failed MongoDB connection checkouts"),
+            &["address", "reason"],
+        )
+        .unwrap();
+        let pool_cleared = IntCounterVec::new(
+            Opts::new("mongodb_pool_cleared_total", "Times the MongoDB pool was cleared"),
+            &["address"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(mongo_commands.clone())).unwrap();
+        registry.register(Box::new(mongo_command_errors.clone())).unwrap();
+        registry.register(Box::new(pool_connections.clone())).unwrap();
+        registry.register(Box::new(pool_checked_out.clone())).unwrap();
+        registry.register(Box::new(pool_checkout_failures.clone())).unwrap();
+        registry.register(Box::new(pool_cleared.clone())).unwrap();
+
+        Self {
+            inner: Arc::new(Inner {
+                registry,
+                http_requests,
+                http_duration,
+                mongo_commands,
+                mongo_command_errors,
+                pool_connections,
+                pool_checked_out,
+                pool_checkout_failures,
+                pool_cleared,
+            }),
+        }
+    }
+
+    /// Records one handled request. `path` is the raw request path.
+    pub fn observe_request(&self, framework: &str, method: &str, path: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [framework, method, route_template(path), status.as_str()];
+        self.inner.http_requests.with_label_values(&labels).inc();
+        self.inner.http_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    }
+
+    /// Feeds `mongodb_command_*` from the driver's command monitoring events.
+    pub fn command_event_handler(&self) -> EventHandler<CommandEvent> {
+        let metrics = self.clone();
+        EventHandler::callback(move |event| match event {
+            CommandEvent::Succeeded(event) => {
+                metrics.inner.mongo_commands
+                    .with_label_values(&[&event.command_name, "success"])
+                    .observe(event.duration.as_secs_f64());
+            }
+            CommandEvent::Failed(event) => {
+                metrics.inner.mongo_commands
+                    .with_label_values(&[&event.command_name, "failure"])
+                    .observe(event.duration.as_secs_f64());
+                metrics.inner.mongo_command_errors.with_label_values(&[&event.command_name]).inc();
+            }
+            CommandEvent::Started(_) => {}
+            _ => {}
+        })
+    }
+
+    /// Feeds the `mongodb_pool_*` gauges and counters from CMAP events.
+    pub fn cmap_event_handler(&self) -> EventHandler<CmapEvent> {
+        let metrics = self.clone();
+        EventHandler::callback(move |event| {
+            let inner = &metrics.inner;
+            match event {
+                CmapEvent::ConnectionCreated(event) => {
+                    inner.pool_connections.with_label_values(&[&event.address.to_string()]).inc();
+                }
+                CmapEvent::ConnectionClosed(event) => {
+                    inner.pool_connections.with_label_values(&[&event.address.to_string()]).dec();
+                }
+                CmapEvent::ConnectionCheckedOut(event) => {
+                    inner.pool_checked_out.with_label_values(&[&event.address.to_string()]).inc();
+                }
+                CmapEvent::ConnectionCheckedIn(event) => {
+                    inner.pool_checked_out.with_label_values(&[&event.address.to_string()]).dec();
+                }
+                CmapEvent::ConnectionCheckoutFailed(event) => {
+                    let reason = match event.reason {
+                        ConnectionCheckoutFailedReason::Timeout => "timeout",
+                        ConnectionCheckoutFailedReason::ConnectionError => "connection_error",
+                        _ => "other",
+                    };
+                    inner.pool_checkout_failures
+                        .with_label_values(&[&event.address.to_string(), reason])
+                        .inc();
+                }
+                CmapEvent::PoolCleared(event) => {
+                    inner.pool_cleared.with_label_values(&[&event.address.to_string()]).inc();
+                }
+                _ => {}
+            }
+        })
+    }
+
+    /// Everything recorded so far in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.inner.registry.gather(), &mut buffer)
+            .expect("metrics are valid UTF-8");
+        String::from_utf8(buffer).expect("metrics are valid UTF-8")
+    }
+}
+
+/// Maps a request path onto the route template it was served by.
+pub fn route_template(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    ROUTES
+        .iter()
+        .chain(graphql::ROUTES)
+        .chain(grpc::ROUTES)
+        .find(|route| {
+            let pattern: Vec<&str> = route.split('/').collect();
+            pattern.len() == segments.len()
+                && pattern.iter().zip(&segments).all(|(p, s)| p.starts_with('{') || p == s)
+        })
+        .copied()
+        .unwrap_or("unmatched")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frameworks::{testing, Framework};
+
+    /// Every framework must count its requests under the same labels, with
+    /// routes as templates and unknown paths as `unmatched`.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_counts_requests_by_framework_route_and_status() {
+        let failures = testing::with_every_framework(testing::config(), |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                let invalid = testing::send(addr, "GET", "/api/restaurants/not-an-object-id", &[]).await;
+                check("GET of an invalid id answers 400", invalid.status == 400);
+                let missing = testing::send(addr, "GET", "/no/such/route", &[]).await;
+                check("GET of an unknown path answers 404", missing.status == 404);
+
+                let metrics = testing::send(addr, "GET", "/metrics", &[]).await;
+                check("/metrics answers 200", metrics.status == 200);
+                for (route, status) in [("/api/restaurants/{id}", 400), ("unmatched", 404)] {
+                    let series = format!(
+                        r#"http_requests_total{{framework="{}",method="GET",route="{}",status="{}"}} 1"#,
+                        framework, route, status,
+                    );
+                    check(&format!("/metrics counts {}", series), metrics.body.contains(&series));
+                }
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "request metrics differ:\n{}", failures.join("\n"));
+    }
+}
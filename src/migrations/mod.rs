@@ -0,0 +1,443 @@
+//! Versioned changes to the stored restaurants, defined in Rust, applied in
+//! order with `migrate up` and undone with `migrate down`. Every migration
+//! run or in progress has a document in the `migrations` collection, and
+//! only one run at a time may hold the lock document.
+//!
+//! Steps change the documents in batches of `_id`s, and the last `_id` of
+//! each batch is recorded, so a run that was interrupted resumes after it.
+
+mod restaurants;
+
+use std::collections::HashMap;
+
+use bson::{doc, Bson, DateTime, Document};
+use futures::TryStreamExt;
+use mongodb::{Collection, Database, options::UpdateModifications};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{db::mongodb::is_duplicate_key, error::AppError, request::RequestContext};
+
+/// Collection recording the migrations, next to the restaurants.
+pub const COLLECTION: &str = "migrations";
+
+/// Collection holding the lock document while a run writes.
+pub const LOCK_COLLECTION: &str = "migrations_lock";
+
+const LOCK_ID: &str = "migrate";
+
+/// A run refreshes its lock after every batch; a lock older than this was
+/// left by a run that died, and is taken over.
+const LOCK_LEASE_MILLIS: i64 = 5 * 60 * 1000;
+
+/// Every migration, ordered by ID.
+pub const MIGRATIONS: &[Migration] = &[restaurants::TIMESTAMPS];
+
+pub struct Migration {
+    /// Its position in `MIGRATIONS`, numbered from `0001`, then a name; so
+    /// IDs are unique and sort after the IDs of every older migration.
+    pub id: &'static str,
+    pub description: &'static str,
+    pub up: Step,
+    pub down: Step,
+}
+
+/// One direction of a migration.
+pub struct Step {
+    /// The documents still to change. Documents must stop matching once
+    /// changed, so running a step again only changes what it missed.
+    pub filter: fn() -> Document,
+    /// Applied to each batch with `update_many`.
+    pub update: fn() -> UpdateModifications,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+impl Direction {
+    fn step(self, migration: &Migration) -> &Step {
+        match self {
+            Direction::Up => &migration.up,
+            Direction::Down => &migration.down,
+        }
+    }
+
+    /// State of a migration while its step in this direction runs.
+    fn state(self) -> State {
+        match self {
+            Direction::Up => State::Applying,
+            Direction::Down => State::Reverting,
+        }
+    }
+}
+
+/// Migrations undone by `migrate down`.
+#[derive(Debug, Clone, Copy)]
+pub enum Rollback<'a> {
+    /// The last one applied.
+    Last,
+    /// Every one applied after this one, which stays applied.
+    To(&'a str),
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Applying,
+    Applied,
+    Reverting,
+}
+
+impl State {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            State::Applying => "applying",
+            State::Applied => "applied",
+            State::Reverting => "reverting",
+        }
+    }
+}
+
+/// A document of the `migrations` collection. Reverted migrations have none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationRecord {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub description: String,
+    pub state: State,
+    pub started_at: DateTime,
+    #[serde(default)]
+    pub applied_at: Option<DateTime>,
+    /// `_id` of the last document of the last batch the step in progress
+    /// wrote.
+    #[serde(default)]
+    pub resume_after: Option<Bson>,
+    /// Documents changed by the step in progress, or the last one run.
+    #[serde(default)]
+    pub modified: u64,
+}
+
+/// A line of `migrate status`.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub id: String,
+    /// `pending`, a [`State`], or `unknown` for a migration recorded by
+    /// another build that this one does not define.
+    pub state: &'static str,
+    pub applied_at: Option<DateTime>,
+    pub description: String,
+}
+
+pub struct RunOptions {
+    /// Count the documents each step would change, and write nothing.
+    pub dry_run: bool,
+    /// Documents changed by each `update_many`.
+    pub batch_size: u32,
+}
+
+/// What a run did, or would do, to one migration.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub id: &'static str,
+    /// Documents changed, or that would be with `dry_run`.
+    pub documents: u64,
+}
+
+/// Applies and undoes [`MIGRATIONS`] on the restaurants collection.
+pub struct Migrator {
+    restaurants: Collection<Document>,
+    records: Collection<MigrationRecord>,
+    lock: Collection<Document>,
+}
+
+impl Migrator {
+    pub fn new(db: &Database, collection: &str) -> Self {
+        Self {
+            restaurants: db.collection(collection),
+            records: db.collection(COLLECTION),
+            lock: db.collection(LOCK_COLLECTION),
+        }
+    }
+
+    /// Every migration defined, in order, then those only recorded.
+    pub async fn status(&self, request: &RequestContext) -> Result<Vec<Status>, AppError> {
+        let mut records = self.records(request).await?;
+        let mut lines: Vec<Status> = MIGRATIONS
+            .iter()
+            .map(|migration| {
+                let record = records.remove(migration.id);
+                Status {
+                    id: migration.id.to_string(),
+                    state: record.as_ref().map_or("pending", |record| record.state.as_str()),
+                    applied_at: record.and_then(|record| record.applied_at),
+                    description: migration.description.to_string(),
+                }
+            })
+            .collect();
+        let mut unknown: Vec<MigrationRecord> = records.into_values().collect();
+        unknown.sort_by(|a, b| a.id.cmp(&b.id));
+        lines.extend(unknown.into_iter().map(|record| Status {
+            id: record.id,
+            state: "unknown",
+            applied_at: record.applied_at,
+            description: record.description,
+        }));
+        Ok(lines)
+    }
+
+    /// Applies every migration not applied yet, up to and including `to`.
+    pub async fn up(&self, request: &RequestContext, to: Option<&str>, options: &RunOptions) -> Result<Vec<Outcome>, AppError> {
+        let records = self.records(request).await?;
+        let plan = pending(&states(&records), to)?;
+        self.run(request, Direction::Up, plan, &records, options).await
+    }
+
+    /// Undoes the applied migrations `rollback` names, newest first.
+    pub async fn down(&self, request: &RequestContext, rollback: Rollback<'_>, options: &RunOptions) -> Result<Vec<Outcome>, AppError> {
+        let records = self.records(request).await?;
+        let plan = applied(&states(&records), rollback)?;
+        self.run(request, Direction::Down, plan, &records, options).await
+    }
+
+    async fn records(&self, request: &RequestContext) -> Result<HashMap<String, MigrationRecord>, AppError> {
+        let cursor = self.records.find(doc! {}).comment(request.comment()).await?;
+        let records: Vec<MigrationRecord> = cursor.try_collect().await?;
+        Ok(records.into_iter().map(|record| (record.id.clone(), record)).collect())
+    }
+
+    async fn run(
+        &self,
+        request: &RequestContext,
+        direction: Direction,
+        plan: Vec<&'static Migration>,
+        records: &HashMap<String, MigrationRecord>,
+        options: &RunOptions,
+    ) -> Result<Vec<Outcome>, AppError> {
+        let mut outcomes = Vec::new();
+        if options.dry_run {
+            for migration in plan {
+                let filter = (direction.step(migration).filter)();
+                let documents = self.restaurants.count_documents(filter).comment(request.comment()).await?;
+                outcomes.push(Outcome { id: migration.id, documents });
+            }
+            return Ok(outcomes);
+        }
+        if plan.is_empty() {
+            return Ok(outcomes);
+        }
+
+        let owner = self.acquire(request).await?;
+        let mut result = Ok(());
+        for migration in plan {
+            match self.migrate(request, &owner, migration, direction, records.get(migration.id), options.batch_size).await {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        // Released even after a failure, the records say where to resume
+        self.release(request, &owner).await?;
+        result.map(|()| outcomes)
+    }
+
+    /// Runs the step of `migration` in `direction` to the end, resuming
+    /// after the last batch recorded if it was interrupted.
+    async fn migrate(
+        &self,
+        request: &RequestContext,
+        owner: &str,
+        migration: &'static Migration,
+        direction: Direction,
+        record: Option<&MigrationRecord>,
+        batch_size: u32,
+    ) -> Result<Outcome, AppError> {
+        let step = direction.step(migration);
+        let interrupted = record.filter(|record| record.state == direction.state());
+        let mut record = MigrationRecord {
+            id: migration.id.to_string(),
+            description: migration.description.to_string(),
+            state: direction.state(),
+            started_at: interrupted.map_or_else(DateTime::now, |record| record.started_at),
+            applied_at: None,
+            resume_after: interrupted.and_then(|record| record.resume_after.clone()),
+            modified: interrupted.map_or(0, |record| record.modified),
+        };
+        match &record.resume_after {
+            Some(after) => info!(migration = migration.id, ?direction, %after, "Resuming migration"),
+            None => info!(migration = migration.id, ?direction, "Starting migration"),
+        }
+        self.records
+            .replace_one(doc! { "_id": migration.id }, &record)
+            .upsert(true)
+            .comment(request.comment())
+            .await?;
+
+        loop {
+            let mut filter = (step.filter)();
+            if let Some(after) = &record.resume_after {
+                filter = doc! { "$and": [filter, { "_id": { "$gt": after.clone() } }] };
+            }
+            let batch: Vec<Document> = self.restaurants
+                .find(filter)
+                .projection(doc! { "_id": 1 })
+                .sort(doc! { "_id": 1 })
+                .limit(i64::from(batch_size))
+                .comment(request.comment())
+                .await?
+                .try_collect()
+                .await?;
+            let ids: Vec<Bson> = batch.into_iter().filter_map(|document| document.get("_id").cloned()).collect();
+            let Some(last) = ids.last().cloned() else {
+                break;
+            };
+
+            // Only what still matches, in case another writer got there first
+            let filter = doc! { "$and": [(step.filter)(), { "_id": { "$in": ids } }] };
+            let result = self.restaurants.update_many(filter, (step.update)()).comment(request.comment()).await?;
+            record.modified += result.modified_count;
+            let checkpoint = doc! { "$set": { "resume_after": &last, "modified": record.modified as i64 } };
+            self.records.update_one(doc! { "_id": migration.id }, checkpoint).comment(request.comment()).await?;
+            record.resume_after = Some(last);
+            self.refresh(request, owner).await?;
+            info!(migration = migration.id, modified = record.modified, "Migrated a batch");
+        }
+
+        match direction {
+            Direction::Up => {
+                let update = doc! {
+                    "$set": { "state": State::Applied.as_str(), "applied_at": DateTime::now() },
+                    "$unset": { "resume_after": "" },
+                };
+                self.records.update_one(doc! { "_id": migration.id }, update).comment(request.comment()).await?;
+            }
+            Direction::Down => {
+                self.records.delete_one(doc! { "_id": migration.id }).comment(request.comment()).await?;
+            }
+        }
+        info!(migration = migration.id, ?direction, modified = record.modified, "Finished migration");
+        Ok(Outcome { id: migration.id, documents: record.modified })
+    }
+
+    /// Takes the lock, or fails naming the run holding it. A lock whose
+    /// lease ran out is taken over.
+    async fn acquire(&self, request: &RequestContext) -> Result<String, AppError> {
+        let owner = request.request_id.clone();
+        let now = DateTime::now();
+        let filter = doc! { "_id": LOCK_ID, "expires_at": { "$lt": now } };
+        let update = doc! { "$set": { "owner": &owner, "locked_at": now, "expires_at": lease_end() } };
+        match self.lock.update_one(filter, update).upsert(true).comment(request.comment()).await {
+            Ok(_) => Ok(owner),
+            // The lock exists and has not expired, so the upsert clashed with it
+            Err(e) if is_duplicate_key(&e) => {
+                let holder = self.lock.find_one(doc! { "_id": LOCK_ID }).comment(request.comment()).await?.unwrap_or_default();
+                Err(AppError::Unavailable(format!(
+                    "another migration run holds the lock: {} since {}",
+                    holder.get_str("owner").unwrap_or("unknown"),
+                    holder.get_datetime("locked_at").ok().and_then(|at| at.try_to_rfc3339_string().ok()).unwrap_or_else(|| "unknown".to_string()),
+                )))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Extends the lease of the lock, failing if another run took it over.
+    async fn refresh(&self, request: &RequestContext, owner: &str) -> Result<(), AppError> {
+        let update = doc! { "$set": { "expires_at": lease_end() } };
+        let result = self.lock.update_one(doc! { "_id": LOCK_ID, "owner": owner }, update).comment(request.comment()).await?;
+        if result.matched_count == 0 {
+            return Err(AppError::Unavailable("the migration lock expired and was taken over by another run".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn release(&self, request: &RequestContext, owner: &str) -> Result<(), AppError> {
+        self.lock.delete_one(doc! { "_id": LOCK_ID, "owner": owner }).comment(request.comment()).await?;
+        Ok(())
+    }
+}
+
+fn states(records: &HashMap<String, MigrationRecord>) -> HashMap<String, State> {
+    records.iter().map(|(id, record)| (id.clone(), record.state)).collect()
+}
+
+/// The migrations `migrate up` runs, in order: every one not applied, up
+/// to and including `to`. Interrupted ones are run again.
+fn pending(states: &HashMap<String, State>, to: Option<&str>) -> Result<Vec<&'static Migration>, AppError> {
+    let last = match to {
+        Some(to) => position(to)?,
+        None => MIGRATIONS.len().saturating_sub(1),
+    };
+    Ok(MIGRATIONS
+        .iter()
+        .take(last + 1)
+        .filter(|migration| states.get(migration.id) != Some(&State::Applied))
+        .collect())
+}
+
+/// The migrations `migrate down` runs, newest first: those recorded, in
+/// any state, that `rollback` names.
+fn applied(states: &HashMap<String, State>, rollback: Rollback<'_>) -> Result<Vec<&'static Migration>, AppError> {
+    let keep = match rollback {
+        Rollback::To(to) => position(to)? + 1,
+        Rollback::Last | Rollback::All => 0,
+    };
+    let recorded = MIGRATIONS[keep..].iter().rev().filter(|migration| states.contains_key(migration.id));
+    Ok(match rollback {
+        Rollback::Last => recorded.take(1).collect(),
+        Rollback::To(_) | Rollback::All => recorded.collect(),
+    })
+}
+
+fn position(id: &str) -> Result<usize, AppError> {
+    MIGRATIONS
+        .iter()
+        .position(|migration| migration.id == id)
+        .ok_or_else(|| AppError::BadRequest(format!("no migration with ID {:?}", id)))
+}
+
+fn lease_end() -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + LOCK_LEASE_MILLIS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(plan: Result<Vec<&'static Migration>, AppError>) -> Vec<&'static str> {
+        plan.unwrap().into_iter().map(|migration| migration.id).collect()
+    }
+
+    #[test]
+    fn migration_ids_are_numbered_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            let number = format!("{:04}_", i + 1);
+            assert!(migration.id.starts_with(&number), "{} must start with {}", migration.id, number);
+        }
+    }
+
+    #[test]
+    fn runs_go_through_the_migrations_their_records_call_for() {
+        let first = MIGRATIONS[0].id;
+        let nothing = HashMap::new();
+        assert_eq!(ids(pending(&nothing, None)), [first]);
+        assert_eq!(ids(pending(&nothing, Some(first))), [first]);
+        assert!(ids(applied(&nothing, Rollback::Last)).is_empty());
+
+        let interrupted = HashMap::from([(first.to_string(), State::Applying)]);
+        assert_eq!(ids(pending(&interrupted, None)), [first]);
+        assert_eq!(ids(applied(&interrupted, Rollback::All)), [first]);
+
+        let done = HashMap::from([(first.to_string(), State::Applied)]);
+        assert!(ids(pending(&done, None)).is_empty());
+        assert_eq!(ids(applied(&done, Rollback::Last)), [first]);
+        assert!(ids(applied(&done, Rollback::To(first))).is_empty());
+
+        assert!(matches!(pending(&nothing, Some("9999_missing")), Err(AppError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,116 @@
+//! Migrations of the restaurants collection, oldest first. Once a migration
+//! has been applied somewhere, change it with a new one rather than editing
+//! it.
+
+use bson::doc;
+
+use super::{Migration, Step};
+
+/// Gives every restaurant the `created_at` its ObjectId records, and an
+/// `updated_at` starting at the same time unless it already has one.
+/// Neither field is in the model; updates keep `created_at` and set
+/// `updated_at` again. `timestamps_added` lists the fields the migration
+/// added, and updates take `updated_at` off it, so undoing the migration
+/// leaves timestamps written by anything else alone.
+pub const TIMESTAMPS: Migration = Migration {
+    id: "0001_restaurant_timestamps",
+    description: "Add created_at and updated_at, taken from the ObjectId",
+    up: Step {
+        filter: || doc! { "created_at": { "$exists": false }, "_id": { "$type": "objectId" } },
+        update: || {
+            let created_at = doc! { "$toDate": "$_id" };
+            let has_updated_at = doc! { "$ne": [{ "$type": "$updated_at" }, "missing"] };
+            vec![doc! { "$set": {
+                "created_at": created_at.clone(),
+                "updated_at": { "$cond": [has_updated_at.clone(), "$updated_at", created_at] },
+                "timestamps_added": { "$cond": [has_updated_at, ["created_at"], ["created_at", "updated_at"]] },
+            } }]
+            .into()
+        },
+    },
+    down: Step {
+        filter: || doc! { "timestamps_added": { "$exists": true } },
+        update: || {
+            let added = |field: &str| doc! { "$cond": [{ "$in": [field, "$timestamps_added"] }, "$$REMOVE", format!("${}", field)] };
+            vec![
+                doc! { "$set": { "created_at": added("created_at"), "updated_at": added("updated_at") } },
+                doc! { "$unset": "timestamps_added" },
+            ]
+            .into()
+        },
+    },
+};
+
+#[cfg(test)]
+mod tests {
+    use bson::{bson, oid::ObjectId, Bson, DateTime, Document};
+    use mongodb::Collection;
+
+    use super::*;
+    use crate::{
+        config::MongoConfig, db::mongodb::MongoRepo, models::restaurant::UpdateRestaurantRequest,
+        request::RequestContext,
+    };
+
+    async fn run(restaurants: &Collection<Document>, step: &Step) -> u64 {
+        restaurants.update_many((step.filter)(), (step.update)()).await.unwrap().modified_count
+    }
+
+    async fn read(restaurants: &Collection<Document>, id: impl Into<Bson>) -> Document {
+        restaurants.find_one(doc! { "_id": id.into() }).await.unwrap().unwrap()
+    }
+
+    /// Runs both steps of `TIMESTAMPS` on a throwaway database, with an
+    /// update in between, the way `migrate` runs them on one batch.
+    #[tokio::test]
+    #[ignore = "needs MongoDB"]
+    async fn timestamps_are_added_kept_by_updates_and_removed() {
+        let uri = std::env::var("MONGODB_URI").unwrap_or_else(|_| MongoConfig::default().uri);
+        let client = mongodb::Client::with_uri_str(uri).await.unwrap();
+        let db = client.database(&format!("migration_test_{}", ObjectId::new()));
+        let restaurants = db.collection::<Document>("restaurants");
+
+        let (fresh, stamped) = (ObjectId::new(), ObjectId::new());
+        let earlier = DateTime::from_millis(1_577_836_800_000);
+        restaurants
+            .insert_many([
+                doc! { "_id": fresh, "name": "Fresh" },
+                doc! { "_id": stamped, "name": "Stamped", "updated_at": earlier },
+                doc! { "_id": "not-an-object-id", "name": "Odd" },
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(run(&restaurants, &TIMESTAMPS.up).await, 2);
+        let doc = read(&restaurants, fresh).await;
+        assert_eq!(doc.get_datetime("created_at"), Ok(&fresh.timestamp()));
+        assert_eq!(doc.get_datetime("updated_at"), Ok(&fresh.timestamp()));
+        assert_eq!(doc.get("timestamps_added"), Some(&bson!(["created_at", "updated_at"])));
+        let doc = read(&restaurants, stamped).await;
+        assert_eq!(doc.get_datetime("created_at"), Ok(&stamped.timestamp()));
+        assert_eq!(doc.get_datetime("updated_at"), Ok(&earlier));
+        assert_eq!(doc.get("timestamps_added"), Some(&bson!(["created_at"])));
+        assert!(!read(&restaurants, "not-an-object-id").await.contains_key("created_at"));
+        assert_eq!(run(&restaurants, &TIMESTAMPS.up).await, 0);
+
+        let before = DateTime::now();
+        let update = UpdateRestaurantRequest { cuisine: Some("Thai".to_string()), ..Default::default() };
+        MongoRepo::new(&db, "restaurants")
+            .update_restaurant(&RequestContext::cli("test"), fresh, update)
+            .await
+            .unwrap();
+        let doc = read(&restaurants, fresh).await;
+        let updated_at = *doc.get_datetime("updated_at").unwrap();
+        assert!(updated_at >= before, "{} is older than the update", updated_at);
+        assert_eq!(doc.get("timestamps_added"), Some(&bson!(["created_at"])));
+
+        assert_eq!(run(&restaurants, &TIMESTAMPS.down).await, 2);
+        for (id, updated) in [(fresh, updated_at), (stamped, earlier)] {
+            let doc = read(&restaurants, id).await;
+            assert!(!doc.contains_key("created_at") && !doc.contains_key("timestamps_added"), "{}", doc);
+            assert_eq!(doc.get_datetime("updated_at"), Ok(&updated));
+        }
+
+        db.drop().await.unwrap();
+    }
+}
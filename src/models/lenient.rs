@@ -0,0 +1,210 @@
+//! Reading stored documents that do not match the model, such as a grade
+//! with a string `score` or a `null` date. Decoded strictly, one of them
+//! fails a whole list; decoded leniently, each field that does not match is
+//! left out and reported, and the rest of the document is still answered.
+
+use bson::{raw::RawDocument, Bson, DateTime, Document};
+use serde::{de::DeserializeOwned, Serialize};
+use utoipa::ToSchema;
+
+use crate::error::FieldError;
+
+use super::{
+    restaurant::{Address, Grade, RestaurantDoc},
+    validation::{self, Validate, Violations},
+};
+
+/// A stored document, decoded as far as it matches the model.
+#[derive(Debug, Clone)]
+pub struct Lenient {
+    /// `None` when the document was skipped.
+    pub restaurant: Option<RestaurantDoc>,
+    /// What did not match, `None` when everything did.
+    pub warning: Option<DocumentWarning>,
+}
+
+/// A stored document that does not match the model.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct DocumentWarning {
+    /// `_id` of the document, hex for an ObjectId.
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Whether the document was left out, rather than answered with the
+    /// fields that matched. Only documents without an ObjectId `_id` are.
+    pub skipped: bool,
+    /// Every field that does not match, and why.
+    pub errors: Vec<FieldError>,
+}
+
+/// The documents of the collection that do not match the model.
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ScanReport {
+    /// Documents read.
+    pub scanned: u64,
+    /// Every document that cannot be decoded as it is or breaks a rule new
+    /// restaurants must follow, with all the ways it does.
+    pub mismatched: Vec<DocumentWarning>,
+}
+
+impl ScanReport {
+    /// Adds `document` to the report, and to the mismatches if the decoded
+    /// fields or the restaurant they make are wrong.
+    pub fn scan(&mut self, document: &RawDocument) {
+        self.scanned += 1;
+        let decoded = decode(document);
+        let mut warning = decoded.warning.unwrap_or_else(|| DocumentWarning {
+            id: decoded.restaurant.as_ref().and_then(|restaurant| restaurant.id).map(|id| id.to_hex()),
+            skipped: false,
+            errors: Vec::new(),
+        });
+        if let Some(restaurant) = &decoded.restaurant {
+            let mut violations = Violations::default();
+            restaurant.check("", &mut violations);
+            // A field left out for not matching is not also reported as empty
+            let reported: Vec<String> = warning.errors.iter().map(|e| e.field.clone()).collect();
+            warning.errors.extend(violations.into_vec().into_iter().filter(|v| !reported.contains(&v.field)));
+        }
+        if !warning.errors.is_empty() {
+            self.mismatched.push(warning);
+        }
+    }
+}
+
+/// Decodes `document`, leaving out the fields of it, of its address and the
+/// grades that do not match the model.
+pub fn decode(document: &RawDocument) -> Lenient {
+    if let Ok(restaurant) = bson::from_slice::<RestaurantDoc>(document.as_bytes()) {
+        return Lenient { restaurant: Some(restaurant), warning: None };
+    }
+    let mut errors = Vec::new();
+    let mut document: Document = match bson::from_slice(document.as_bytes()) {
+        Ok(document) => document,
+        Err(e) => return skipped(None, vec![error("", e)]),
+    };
+    let id = document.get("_id").map(|id| match id {
+        Bson::ObjectId(id) => id.to_hex(),
+        id => id.to_string(),
+    });
+
+    if let Some(Bson::Document(address)) = document.get_mut("address") {
+        leave_out_mismatched::<Address>(address, &Document::new(), "address", &mut errors);
+    }
+    if let Some(Bson::Array(grades)) = document.get_mut("grades") {
+        let template = bson::to_document(&Grade { date: DateTime::MIN, grade: String::new(), score: 0 }).unwrap_or_default();
+        let mut index = 0;
+        grades.retain(|grade| {
+            let path = format!("grades[{}]", index);
+            index += 1;
+            let Err(e) = bson::from_bson::<Grade>(grade.clone()) else {
+                return true;
+            };
+            let found = errors.len();
+            if let Bson::Document(grade) = grade {
+                leave_out_mismatched::<Grade>(&mut grade.clone(), &template, &path, &mut errors);
+            }
+            // A missing field, or a grade that is not a document at all
+            if errors.len() == found {
+                errors.push(error(&path, e));
+            }
+            false
+        });
+    }
+    leave_out_mismatched::<RestaurantDoc>(&mut document, &Document::new(), "", &mut errors);
+    if !document.contains_key("_id") && !errors.iter().any(|e| e.field == "_id") {
+        errors.push(FieldError { field: "_id".to_string(), message: "is missing".to_string() });
+    }
+
+    match bson::from_document::<RestaurantDoc>(document) {
+        Ok(restaurant) if restaurant.id.is_some() => {
+            let warning = DocumentWarning { id, skipped: false, errors };
+            Lenient { restaurant: Some(restaurant), warning: Some(warning) }
+        }
+        Ok(_) => skipped(id, errors),
+        Err(e) => {
+            errors.push(error("", e));
+            skipped(id, errors)
+        }
+    }
+}
+
+/// Removes the fields of `document` that do not decode as part of a `T`,
+/// each tried on its own in place of the same field of `template`.
+fn leave_out_mismatched<T: DeserializeOwned>(document: &mut Document, template: &Document, path: &str, errors: &mut Vec<FieldError>) {
+    let keys: Vec<String> = document.keys().cloned().collect();
+    for key in keys {
+        let mut probe = template.clone();
+        probe.insert(key.clone(), document.get(&key).cloned().unwrap_or(Bson::Null));
+        if let Err(e) = bson::from_document::<T>(probe) {
+            errors.push(error(&validation::field(path, &key), e));
+            document.remove(&key);
+        }
+    }
+}
+
+fn skipped(id: Option<String>, errors: Vec<FieldError>) -> Lenient {
+    Lenient { restaurant: None, warning: Some(DocumentWarning { id, skipped: true, errors }) }
+}
+
+fn error(field: &str, e: impl std::fmt::Display) -> FieldError {
+    FieldError { field: field.to_string(), message: e.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, oid::ObjectId, RawDocumentBuf};
+
+    use super::*;
+
+    fn raw(document: Document) -> RawDocumentBuf {
+        RawDocumentBuf::from_document(&document).unwrap()
+    }
+
+    fn fields(warning: &DocumentWarning) -> Vec<&str> {
+        warning.errors.iter().map(|e| e.field.as_str()).collect()
+    }
+
+    #[test]
+    fn mismatched_fields_are_left_out_and_reported() {
+        let id = ObjectId::parse_str("5eb3d668b31de5d588f42a7e").unwrap();
+        let date = DateTime::from_millis(1_393_804_800_000);
+        let decoded = decode(&raw(doc! {
+            "_id": id,
+            "name": "Dirty",
+            "cuisine": 7,
+            "address": { "street": "Morris Park Ave", "zipcode": 10462 },
+            "grades": [
+                { "date": date, "grade": "A", "score": 2 },
+                { "date": null, "grade": "B", "score": "seven" },
+                { "date": date, "grade": "C" },
+            ],
+            "legacy_id": 40356018,
+        }));
+        let restaurant = decoded.restaurant.unwrap();
+        assert_eq!((restaurant.name.as_str(), restaurant.cuisine.as_str()), ("Dirty", ""));
+        assert_eq!(restaurant.address.unwrap().street, "Morris Park Ave");
+        assert_eq!(restaurant.grades.len(), 1);
+        assert_eq!(restaurant.extra, doc! { "legacy_id": 40356018 });
+        let warning = decoded.warning.unwrap();
+        assert_eq!((warning.id.as_deref(), warning.skipped), (Some("5eb3d668b31de5d588f42a7e"), false));
+        assert_eq!(fields(&warning), ["address.zipcode", "grades[1].date", "grades[1].score", "grades[2]", "cuisine"]);
+
+        let clean = decode(&raw(doc! { "_id": id, "name": "Clean" }));
+        assert!(clean.restaurant.is_some() && clean.warning.is_none());
+
+        let skipped = decode(&raw(doc! { "_id": 12, "name": "Numbered" }));
+        let warning = skipped.warning.unwrap();
+        assert!(skipped.restaurant.is_none() && warning.skipped);
+        assert_eq!((warning.id.as_deref(), fields(&warning)), (Some("12"), vec!["_id"]));
+    }
+
+    #[test]
+    fn scans_report_mismatches_and_broken_rules() {
+        let mut report = ScanReport::default();
+        report.scan(&raw(doc! { "_id": ObjectId::new(), "name": "Clean", "borough": "Queens" }));
+        report.scan(&raw(doc! { "_id": ObjectId::new(), "name": "Atlantean", "borough": "Atlantis" }));
+        report.scan(&raw(doc! { "_id": ObjectId::new(), "name": 5 }));
+        assert_eq!(report.scanned, 3);
+        let found: Vec<_> = report.mismatched.iter().map(fields).collect();
+        assert_eq!(found, [vec!["borough"], vec!["name"]]);
+    }
+}
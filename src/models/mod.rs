@@ -1 +1,4 @@
-pub mod restaurant;
\ No newline at end of file
+pub mod lenient;
+pub mod restaurant;
+pub mod serde_helpers;
+pub mod validation;
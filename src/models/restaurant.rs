@@ -1,11 +1,20 @@
+use std::str::FromStr;
+
 use serde::{Serialize, Deserialize};
-use bson::oid::ObjectId;
+use bson::{oid::ObjectId, Document};
 use mongodb::bson::DateTime;
+use utoipa::ToSchema;
+
+use crate::openapi::{DateTimeString, ObjectIdString};
+
+use super::serde_helpers;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// A restaurant as stored in the collection. Fields the model does not
+/// know are kept in `extra`, so a document read and written back keeps them.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 #[serde(default)]
-pub struct Restaurant {
-    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+pub struct RestaurantDoc {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none", deserialize_with = "serde_helpers::optional_object_id")]
     pub id: Option<ObjectId>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub address: Option<Address>,
@@ -19,13 +28,235 @@ pub struct Restaurant {
     pub name: String,
     #[serde(default)]
     pub restaurant_id: String,
+    /// Authenticated subject that created the document, set by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// Authenticated subject of the last update, set by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_by: Option<String>,
+    /// Fields of the stored document the model does not know.
+    #[serde(flatten)]
+    pub extra: Document,
+}
+
+/// The body of `POST /api/restaurants`, and of each restaurant imported.
+/// The server sets `_id`, `created_by` and `updated_by`, so they and any
+/// other unknown field are refused.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateRestaurantRequest {
+    pub name: String,
+    #[serde(default)]
+    #[schema(value_type = Option<Borough>)]
+    pub borough: String,
+    #[serde(default)]
+    pub cuisine: String,
+    #[serde(default)]
+    pub restaurant_id: String,
+    #[serde(default)]
+    pub address: Option<Address>,
+    #[serde(default)]
+    pub grades: Vec<Grade>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+impl From<CreateRestaurantRequest> for RestaurantDoc {
+    fn from(request: CreateRestaurantRequest) -> Self {
+        RestaurantDoc {
+            name: request.name,
+            borough: request.borough,
+            cuisine: request.cuisine,
+            restaurant_id: request.restaurant_id,
+            address: request.address,
+            grades: request.grades,
+            ..Default::default()
+        }
+    }
+}
+
+/// The body of `PUT /api/restaurants/{id}`: the fields to change, the others
+/// left as they are. `grades` replaces the whole list and a `null` `address`
+/// removes it. Server fields and unknown ones are refused, as in creates.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateRestaurantRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Borough>)]
+    pub borough: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cuisine: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restaurant_id: Option<String>,
+    #[serde(default, deserialize_with = "serde_helpers::nullable", skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Address>, nullable)]
+    pub address: Option<Option<Address>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grades: Option<Vec<Grade>>,
+}
+
+impl UpdateRestaurantRequest {
+    /// True when the update changes no field.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.borough.is_none()
+            && self.cuisine.is_none()
+            && self.restaurant_id.is_none()
+            && self.address.is_none()
+            && self.grades.is_none()
+    }
+
+    /// The fields to `$set`, grade dates as BSON dates.
+    pub fn to_document(&self) -> Result<Document, bson::ser::Error> {
+        bson::to_document(self)
+    }
+}
+
+/// A restaurant as the REST API answers with it: the fields of the model,
+/// without whatever else the stored document holds.
+#[derive(Debug, Clone, Serialize, Default, ToSchema)]
+#[schema(as = Restaurant)]
+pub struct RestaurantResponse {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<ObjectIdString>)]
+    pub id: Option<ObjectId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    pub borough: String,
+    pub cuisine: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub grades: Vec<Grade>,
+    pub name: String,
+    pub restaurant_id: String,
+    /// Authenticated subject that created the restaurant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// Authenticated subject of the last update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_by: Option<String>,
+}
+
+impl From<RestaurantDoc> for RestaurantResponse {
+    fn from(doc: RestaurantDoc) -> Self {
+        RestaurantResponse {
+            id: doc.id,
+            address: doc.address,
+            borough: doc.borough,
+            cuisine: doc.cuisine,
+            grades: doc.grades,
+            name: doc.name,
+            restaurant_id: doc.restaurant_id,
+            created_by: doc.created_by,
+            updated_by: doc.updated_by,
+        }
+    }
+}
+
+/// The boroughs of New York City, and `Missing`, which the dataset has for
+/// restaurants without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Borough {
+    Bronx,
+    Brooklyn,
+    Manhattan,
+    Queens,
+    #[serde(rename = "Staten Island")]
+    StatenIsland,
+    Missing,
+}
+
+impl Borough {
+    pub const ALL: [Borough; 6] =
+        [Borough::Bronx, Borough::Brooklyn, Borough::Manhattan, Borough::Queens, Borough::StatenIsland, Borough::Missing];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Borough::Bronx => "Bronx",
+            Borough::Brooklyn => "Brooklyn",
+            Borough::Manhattan => "Manhattan",
+            Borough::Queens => "Queens",
+            Borough::StatenIsland => "Staten Island",
+            Borough::Missing => "Missing",
+        }
+    }
+}
+
+impl FromStr for Borough {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Borough::ALL.into_iter().find(|borough| borough.as_str() == name).ok_or(())
+    }
+}
+
+/// The grades of a health inspection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum GradeLetter {
+    A,
+    B,
+    C,
+    /// Grade pending
+    P,
+    /// Grade pending, issued on re-opening after being closed
+    Z,
+    #[serde(rename = "Not Yet Graded")]
+    NotYetGraded,
+}
+
+impl GradeLetter {
+    pub const ALL: [GradeLetter; 6] =
+        [GradeLetter::A, GradeLetter::B, GradeLetter::C, GradeLetter::P, GradeLetter::Z, GradeLetter::NotYetGraded];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            GradeLetter::A => "A",
+            GradeLetter::B => "B",
+            GradeLetter::C => "C",
+            GradeLetter::P => "P",
+            GradeLetter::Z => "Z",
+            GradeLetter::NotYetGraded => "Not Yet Graded",
+        }
+    }
+}
+
+impl FromStr for GradeLetter {
+    type Err = ();
+
+    fn from_str(letter: &str) -> Result<Self, ()> {
+        GradeLetter::ALL.into_iter().find(|grade| grade.as_str() == letter).ok_or(())
+    }
+}
+
+/// A change to the restaurants collection, from its change stream.
+#[derive(Debug, Clone)]
+pub struct RestaurantChange {
+    pub kind: ChangeKind,
+    pub id: ObjectId,
+    /// The restaurant after the change; `None` once deleted, or when it was
+    /// deleted again before the change was read.
+    pub restaurant: Option<RestaurantDoc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+/// Outcome of a bulk import.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ImportSummary {
+    /// Number of restaurants inserted.
+    pub inserted: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, ToSchema)]
 #[serde(default)]
 pub struct Address {
     #[serde(default, skip_serializing_if = "String::is_empty")]
     pub building: String,
+    /// `[longitude, latitude]`
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub coord: Vec<f64>,
     #[serde(default, skip_serializing_if = "String::is_empty")]
@@ -34,9 +265,82 @@ pub struct Address {
     pub zipcode: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, ToSchema)]
 pub struct Grade {    
+    #[serde(deserialize_with = "serde_helpers::date_time")]
+    #[schema(value_type = DateTimeString)]
     pub date: DateTime,
+    #[schema(value_type = GradeLetter)]
     pub grade: String,
     pub score: i32,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::doc;
+
+    use super::*;
+
+    #[test]
+    fn unknown_stored_fields_survive_a_round_trip() {
+        let stored = doc! {
+            "_id": ObjectId::parse_str("5eb3d668b31de5d588f42a7e").unwrap(),
+            "name": "Morris Park Bake Shop",
+            "grades": [{ "date": DateTime::from_millis(1_393_804_800_000), "grade": "A", "score": 2 }],
+            "inspector": { "name": "J. Doe", "visits": 3 },
+            "legacy_id": 40356018,
+        };
+        // As the driver reads it, from the raw bytes
+        let restaurant: RestaurantDoc = bson::from_slice(&bson::to_vec(&stored).unwrap()).unwrap();
+        assert_eq!(restaurant.id.unwrap().to_hex(), "5eb3d668b31de5d588f42a7e");
+        assert_eq!(restaurant.grades[0].date, DateTime::from_millis(1_393_804_800_000));
+        assert_eq!(restaurant.extra, doc! { "inspector": { "name": "J. Doe", "visits": 3 }, "legacy_id": 40356018 });
+        let written = bson::to_document(&restaurant).unwrap();
+        assert_eq!(written.get("inspector"), stored.get("inspector"));
+        assert_eq!(written.get("legacy_id"), stored.get("legacy_id"));
+        assert_eq!(bson::from_document::<RestaurantDoc>(written).unwrap(), restaurant);
+
+        let response = bson::to_document(&RestaurantResponse::from(restaurant)).unwrap();
+        assert!(!response.contains_key("inspector") && !response.contains_key("legacy_id"));
+    }
+
+    #[test]
+    fn create_requests_leave_server_fields_to_the_server() {
+        let request: CreateRestaurantRequest = serde_json::from_str(r#"{"name": "Nordic Delicacies", "borough": "Brooklyn"}"#).unwrap();
+        let restaurant = RestaurantDoc::from(request);
+        assert_eq!((restaurant.id, restaurant.borough.as_str()), (None, "Brooklyn"));
+        for body in [
+            r#"{"name": "Nordic Delicacies", "_id": "5eb3d668b31de5d588f42a7e"}"#,
+            r#"{"name": "Nordic Delicacies", "created_by": "mallory"}"#,
+            r#"{"name": "Nordic Delicacies", "stars": 5}"#,
+            r#"{"borough": "Brooklyn"}"#,
+        ] {
+            assert!(serde_json::from_str::<CreateRestaurantRequest>(body).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn update_requests_set_only_the_fields_sent() {
+        let request: UpdateRestaurantRequest =
+            serde_json::from_str(r#"{"cuisine": "Thai", "address": null, "grades": [{"date": "2014-03-03T00:00:00Z", "grade": "A", "score": 2}]}"#)
+                .unwrap();
+        assert_eq!(
+            request.to_document().unwrap(),
+            doc! {
+                "cuisine": "Thai",
+                "address": null,
+                "grades": [{ "date": DateTime::from_millis(1_393_804_800_000), "grade": "A", "score": 2 }],
+            }
+        );
+        assert!(serde_json::from_str::<UpdateRestaurantRequest>("{}").unwrap().is_empty());
+        for body in [
+            r#"{"_id": "5eb3d668b31de5d588f42a7e"}"#,
+            r#"{"updated_by": "mallory"}"#,
+            r#"{"created_at": "2014-03-03T00:00:00Z"}"#,
+            r#"{"timestamps_added": true}"#,
+            r#"{"address.zipcode": "10462"}"#,
+        ] {
+            assert!(serde_json::from_str::<UpdateRestaurantRequest>(body).is_err(), "{}", body);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Deserializers for the bson types in restaurants, reading the forms API
+//! clients send as well as the ones MongoDB stores: hex strings or Extended
+//! JSON `{"$oid": ...}` for IDs, RFC 3339 strings or Extended JSON
+//! `{"$date": ...}` for dates, and the BSON types themselves.
+
+use bson::{oid::ObjectId, Bson, DateTime};
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// An optional `ObjectId`; `null` is `None`.
+pub fn optional_object_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ObjectId>, D::Error> {
+    match Bson::deserialize(deserializer)? {
+        Bson::Null => Ok(None),
+        Bson::ObjectId(id) => Ok(Some(id)),
+        Bson::String(hex) => ObjectId::parse_str(&hex)
+            .map(Some)
+            .map_err(|_| D::Error::custom(format!("invalid ObjectId {:?}, expected 24 hex digits", hex))),
+        other => Err(D::Error::custom(format!("expected an ObjectId, got {}", other))),
+    }
+}
+
+/// A date.
+pub fn date_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime, D::Error> {
+    match Bson::deserialize(deserializer)? {
+        Bson::DateTime(date) => Ok(date),
+        Bson::String(text) => DateTime::parse_rfc3339_str(&text)
+            .map_err(|_| D::Error::custom(format!("invalid date {:?}, expected RFC 3339", text))),
+        other => Err(D::Error::custom(format!("expected a date, got {}", other))),
+    }
+}
+
+/// A field that may be `null`: left out is `None`, `null` is `Some(None)`.
+pub fn nullable<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
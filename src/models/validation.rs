@@ -0,0 +1,202 @@
+//! Rules restaurants follow before they are stored. `MongoRepo` checks what
+//! it writes, so every web framework, GraphQL, gRPC and the CLI refuse the
+//! same restaurants with the same errors, all of them at once.
+
+use std::ops::RangeInclusive;
+
+use crate::{
+    error::{AppError, FieldError},
+    models::restaurant::{Address, Borough, Grade, GradeLetter, RestaurantDoc, UpdateRestaurantRequest},
+};
+
+const LONGITUDE: RangeInclusive<f64> = -180.0..=180.0;
+const LATITUDE: RangeInclusive<f64> = -90.0..=90.0;
+
+/// The rules broken so far, collected rather than stopping at the first.
+#[derive(Debug, Default)]
+pub struct Violations(Vec<FieldError>);
+
+impl Violations {
+    pub fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(FieldError { field: field.into(), message: message.into() });
+    }
+
+    /// Fails with 422 listing every rule broken, if any was.
+    pub fn into_result(self) -> Result<(), AppError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Invalid(self.0))
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<FieldError> {
+        self.0
+    }
+}
+
+pub trait Validate {
+    /// Adds the rules `self` breaks to `violations`, naming its fields under
+    /// `path`.
+    fn check(&self, path: &str, violations: &mut Violations);
+
+    /// Fails with 422 listing every rule `self` breaks.
+    fn validate(&self) -> Result<(), AppError> {
+        let mut violations = Violations::default();
+        self.check("", &mut violations);
+        violations.into_result()
+    }
+}
+
+impl Validate for RestaurantDoc {
+    fn check(&self, path: &str, violations: &mut Violations) {
+        check_name(&self.name, &field(path, "name"), violations);
+        check_borough(&self.borough, &field(path, "borough"), violations);
+        if let Some(address) = &self.address {
+            address.check(&field(path, "address"), violations);
+        }
+        check_grades(&self.grades, &field(path, "grades"), violations);
+    }
+}
+
+/// Only the fields the update sets are checked.
+impl Validate for UpdateRestaurantRequest {
+    fn check(&self, path: &str, violations: &mut Violations) {
+        if let Some(name) = &self.name {
+            check_name(name, &field(path, "name"), violations);
+        }
+        if let Some(borough) = &self.borough {
+            check_borough(borough, &field(path, "borough"), violations);
+        }
+        if let Some(Some(address)) = &self.address {
+            address.check(&field(path, "address"), violations);
+        }
+        if let Some(grades) = &self.grades {
+            check_grades(grades, &field(path, "grades"), violations);
+        }
+    }
+}
+
+/// The zipcode and coordinates may be left empty, but not be wrong.
+impl Validate for Address {
+    fn check(&self, path: &str, violations: &mut Violations) {
+        let five_digits = self.zipcode.len() == 5 && self.zipcode.bytes().all(|b| b.is_ascii_digit());
+        if !self.zipcode.is_empty() && !five_digits {
+            violations.add(field(path, "zipcode"), "must be 5 digits");
+        }
+        match self.coord[..] {
+            [] => {}
+            [longitude, latitude] => {
+                if !LONGITUDE.contains(&longitude) {
+                    violations.add(format!("{}[0]", field(path, "coord")), "must be a longitude between -180 and 180");
+                }
+                if !LATITUDE.contains(&latitude) {
+                    violations.add(format!("{}[1]", field(path, "coord")), "must be a latitude between -90 and 90");
+                }
+            }
+            _ => violations.add(field(path, "coord"), "must be [longitude, latitude]"),
+        }
+    }
+}
+
+impl Validate for Grade {
+    fn check(&self, path: &str, violations: &mut Violations) {
+        if self.grade.parse::<GradeLetter>().is_err() {
+            violations.add(field(path, "grade"), format!("must be one of {}", one_of(GradeLetter::ALL.map(GradeLetter::as_str))));
+        }
+        if self.score < 0 {
+            violations.add(field(path, "score"), "must not be negative");
+        }
+    }
+}
+
+fn check_name(name: &str, path: &str, violations: &mut Violations) {
+    if name.trim().is_empty() {
+        violations.add(path, "must not be empty");
+    }
+}
+
+/// The borough may be left empty, but not be any other name.
+fn check_borough(borough: &str, path: &str, violations: &mut Violations) {
+    if !borough.is_empty() && borough.parse::<Borough>().is_err() {
+        violations.add(path, format!("must be one of {}", one_of(Borough::ALL.map(Borough::as_str))));
+    }
+}
+
+fn check_grades(grades: &[Grade], path: &str, violations: &mut Violations) {
+    for (index, grade) in grades.iter().enumerate() {
+        grade.check(&format!("{}[{}]", path, index), violations);
+    }
+}
+
+pub(super) fn field(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn one_of<const N: usize>(names: [&str; N]) -> String {
+    names.map(|name| format!("{:?}", name)).join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::DateTime;
+
+    use super::*;
+
+    fn violations(result: Result<(), AppError>) -> Vec<(String, String)> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(AppError::Invalid(errors)) => errors.into_iter().map(|e| (e.field, e.message)).collect(),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn grade(grade: &str, score: i32) -> Grade {
+        Grade { date: DateTime::from_millis(1_393_804_800_000), grade: grade.to_string(), score }
+    }
+
+    #[test]
+    fn every_broken_rule_is_reported() {
+        let restaurant = RestaurantDoc {
+            name: " ".to_string(),
+            borough: "Atlantis".to_string(),
+            address: Some(Address { zipcode: "1046".to_string(), coord: vec![-273.9, 40.8], ..Default::default() }),
+            grades: vec![grade("A", 2), grade("F", -1)],
+            ..Default::default()
+        };
+        let fields: Vec<_> = violations(restaurant.validate()).into_iter().map(|(field, _)| field).collect();
+        assert_eq!(fields, ["name", "borough", "address.zipcode", "address.coord[0]", "grades[1].grade", "grades[1].score"]);
+
+        let valid = RestaurantDoc {
+            name: "Morris Park Bake Shop".to_string(),
+            borough: "Staten Island".to_string(),
+            address: Some(Address { zipcode: "10462".to_string(), coord: vec![-73.856077, 40.848447], ..Default::default() }),
+            grades: vec![grade("Not Yet Graded", 0)],
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+        let unknown = Address { coord: vec![], ..Default::default() };
+        assert!(violations(RestaurantDoc { name: "X".to_string(), address: Some(unknown), ..Default::default() }.validate()).is_empty());
+        let three = Address { coord: vec![1.0, 2.0, 3.0], ..Default::default() };
+        assert_eq!(violations(RestaurantDoc { name: "X".to_string(), address: Some(three), ..Default::default() }.validate())[0].0, "address.coord");
+    }
+
+    #[test]
+    fn updates_check_only_the_fields_they_set() {
+        let update = UpdateRestaurantRequest {
+            name: Some(String::new()),
+            borough: Some("Brooklyn".to_string()),
+            address: Some(Some(Address { zipcode: "ABCDE".to_string(), ..Default::default() })),
+            grades: Some(vec![grade("B", -3)]),
+            ..Default::default()
+        };
+        let fields: Vec<_> = violations(update.validate()).into_iter().map(|(field, _)| field).collect();
+        assert_eq!(fields, ["name", "address.zipcode", "grades[0].score"]);
+        let clear = UpdateRestaurantRequest { cuisine: Some("Thai".to_string()), address: Some(None), ..Default::default() };
+        assert!(clear.validate().is_ok());
+    }
+}
@@ -0,0 +1,538 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use utoipa::{
+    Modify, OpenApi, PartialSchema, ToSchema,
+    openapi::{
+        KnownFormat, ObjectBuilder, RefOr, Schema, SchemaFormat, Type,
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    },
+};
+
+use crate::{
+    error::{ErrorBody, FieldError},
+    health::{Liveness, Readiness},
+    models::{
+        lenient::{DocumentWarning, ScanReport},
+        restaurant::{Address, CreateRestaurantRequest, Grade, ImportSummary, RestaurantResponse, UpdateRestaurantRequest},
+    },
+};
+
+/// Page served on `/docs`, rendering `/openapi.json` with Swagger UI.
+pub const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Restaurants API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+/// Paths and schemas of the API every framework serves. The operations are
+/// declared on the stubs in `paths` rather than on any framework's handlers,
+/// so the one document describes all of them.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Restaurants API",
+        description = "CRUD over the `sample_restaurants` dataset, served the same way by every web framework."
+    ),
+    paths(
+        paths::list_restaurants,
+        paths::create_restaurant,
+        paths::get_restaurant,
+        paths::update_restaurant,
+        paths::delete_restaurant,
+        paths::import_restaurants,
+        paths::scan_restaurants,
+        paths::healthz,
+        paths::readyz,
+        paths::metrics,
+        paths::openapi_json,
+        paths::docs,
+    ),
+    components(schemas(
+        RestaurantResponse,
+        CreateRestaurantRequest,
+        UpdateRestaurantRequest,
+        Address,
+        Grade,
+        ImportSummary,
+        ErrorBody,
+        FieldError,
+        LenientList,
+        DocumentWarning,
+        ScanReport,
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "restaurants", description = "The restaurants collection"),
+        (name = "admin", description = "Bulk operations, with the `admin` scope or role"),
+        (name = "operations", description = "Health, metrics and documentation"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The `api_key` and `bearer` security schemes the restaurant operations
+/// refer to. The bearer requirements list the roles that are allowed.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-API-Key",
+                "Required when `auth.enabled` is set; minted with the `keys mint` command",
+            ))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some("RS256 or HS256 token with `viewer`, `editor` or `admin` roles"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// The OpenAPI document served on `/openapi.json`.
+pub fn spec_json() -> &'static str {
+    static SPEC: OnceLock<String> = OnceLock::new();
+    SPEC.get_or_init(|| ApiDoc::openapi().to_pretty_json().expect("the OpenAPI document serializes"))
+}
+
+/// The body of `GET /api/restaurants?lenient=true`, in JSON or MessagePack.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+struct LenientList {
+    /// The restaurants that could be read, with the fields that match.
+    restaurants: Vec<RestaurantResponse>,
+    /// The documents that do not match the model.
+    warnings: Vec<DocumentWarning>,
+}
+
+/// `ObjectId` as the API writes it to JSON: a hex string.
+pub struct ObjectIdString;
+
+impl PartialSchema for ObjectIdString {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .pattern(Some("^[0-9a-f]{24}$"))
+            .description(Some("Hex ObjectId; Extended JSON `{\"$oid\": \"...\"}` is accepted too"))
+            .into()
+    }
+}
+
+impl ToSchema for ObjectIdString {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("ObjectId")
+    }
+}
+
+/// BSON `DateTime` as the API writes it to JSON: an RFC 3339 string.
+pub struct DateTimeString;
+
+impl PartialSchema for DateTimeString {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::DateTime)))
+            .description(Some("RFC 3339 date; Extended JSON `{\"$date\": ...}` is accepted too"))
+            .into()
+    }
+}
+
+impl ToSchema for DateTimeString {
+    fn name() -> Cow<'static, str> {
+        Cow::Borrowed("DateTime")
+    }
+}
+
+/// Operation declarations only; each framework has its own handlers.
+#[allow(dead_code)]
+mod paths {
+    use super::*;
+
+    /// List restaurants
+    ///
+    /// Answered in the format `Accept` prefers: JSON, BSON documents one
+    /// after the other, MessagePack, NDJSON or CSV with the address flattened
+    /// and the grades summarized. The body is streamed from the cursor as the
+    /// client reads it, so the connection is cut short if MongoDB fails
+    /// midway; MessagePack is collected first.
+    ///
+    /// A document that does not match the model also cuts the body short,
+    /// unless `lenient=true` is asked for. Then the body is a `LenientList`
+    /// in JSON or MessagePack: each mismatched field is left out, a document
+    /// without an ObjectId `_id` is skipped, and both are listed in
+    /// `warnings`.
+    #[utoipa::path(
+        get,
+        path = "/api/restaurants",
+        tag = "restaurants",
+        security(("api_key" = ["restaurants:read"]), ("bearer" = ["viewer", "editor", "admin"])),
+        params(
+            ("limit" = Option<i64>, Query, description = "How many restaurants, 10 by default and at most `limits.max_list_results`"),
+            ("format" = Option<String>, Query, description = "`extended` for canonical Extended JSON in JSON, NDJSON and MessagePack; IDs are hex strings and dates RFC 3339 otherwise"),
+            ("lenient" = Option<bool>, Query, description = "`true` to answer documents that do not match the model as far as they do, with `warnings`"),
+        ),
+        responses(
+            (status = 200, description = "Up to `limit` restaurants", content(
+                (Vec<RestaurantResponse> = "application/json"),
+                (RestaurantResponse = "application/bson"),
+                (Vec<RestaurantResponse> = "application/msgpack"),
+                (RestaurantResponse = "application/x-ndjson"),
+                (String = "text/csv"),
+            )),
+            (status = 400, description = "`limit` is not a number between 1 and `limits.max_list_results`, `format` is not `extended` or `lenient` is not a boolean", body = ErrorBody),
+            (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+            (status = 403, description = "The credentials do not allow this operation", body = ErrorBody),
+            (status = 406, description = "`Accept` allows none of the formats restaurants are answered in, or with `lenient=true`, neither JSON nor MessagePack", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 429, description = "Rate limit exceeded; retry after `Retry-After` seconds", body = ErrorBody),
+            (status = 500, description = "Database error", body = ErrorBody),
+            (status = 503, description = "Too many requests in flight", body = ErrorBody),
+        )
+    )]
+    fn list_restaurants() {}
+
+    /// Create a restaurant
+    ///
+    /// The body may be JSON, BSON or MessagePack; the response is negotiated
+    /// like the list's. `_id`, `created_by` and `updated_by` are the
+    /// server's to set, and are refused like any other unknown field.
+    #[utoipa::path(
+        post,
+        path = "/api/restaurants",
+        tag = "restaurants",
+        security(("api_key" = ["restaurants:write"]), ("bearer" = ["editor", "admin"])),
+        params(("format" = Option<String>, Query, description = "`extended` for canonical Extended JSON in JSON, NDJSON and MessagePack; IDs are hex strings and dates RFC 3339 otherwise")),
+        request_body(content(
+            (CreateRestaurantRequest = "application/json"),
+            (CreateRestaurantRequest = "application/bson"),
+            (CreateRestaurantRequest = "application/msgpack"),
+        )),
+        responses(
+            (status = 201, description = "The stored restaurant, with its `_id`", content(
+                (RestaurantResponse = "application/json"),
+                (RestaurantResponse = "application/bson"),
+                (RestaurantResponse = "application/msgpack"),
+                (RestaurantResponse = "application/x-ndjson"),
+                (String = "text/csv"),
+            )),
+            (status = 400, description = "Body is not in the format of its `Content-Type`, or `format` is not `extended`", body = ErrorBody),
+            (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+            (status = 403, description = "The credentials do not allow this operation", body = ErrorBody),
+            (status = 406, description = "`Accept` allows none of the formats restaurants are answered in", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 413, description = "Body larger than `limits.create_body_bytes`", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 415, description = "`Content-Type` is not JSON, BSON or MessagePack", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 422, description = "Body is not a restaurant, has fields a restaurant does not, breaks a validation rule (every one is listed in `errors`), or nests deeper than `limits.max_json_depth`", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 429, description = "Rate limit exceeded; retry after `Retry-After` seconds", body = ErrorBody),
+            (status = 500, description = "Database error", body = ErrorBody),
+            (status = 503, description = "Too many requests in flight", body = ErrorBody),
+        )
+    )]
+    fn create_restaurant() {}
+
+    /// Get a restaurant by its `_id`
+    ///
+    /// Negotiated like the list; BSON is the document byte for byte as stored.
+    #[utoipa::path(
+        get,
+        path = "/api/restaurants/{id}",
+        tag = "restaurants",
+        security(("api_key" = ["restaurants:read"]), ("bearer" = ["viewer", "editor", "admin"])),
+        params(
+            ("id" = String, Path, description = "Hex `ObjectId` of the restaurant"),
+            ("format" = Option<String>, Query, description = "`extended` for canonical Extended JSON in JSON, NDJSON and MessagePack; IDs are hex strings and dates RFC 3339 otherwise"),
+        ),
+        responses(
+            (status = 200, description = "The restaurant", content(
+                (RestaurantResponse = "application/json"),
+                (RestaurantResponse = "application/bson"),
+                (RestaurantResponse = "application/msgpack"),
+                (RestaurantResponse = "application/x-ndjson"),
+                (String = "text/csv"),
+            )),
+            (status = 400, description = "`id` is not an ObjectId, or `format` is not `extended`", body = ErrorBody),
+            (status = 404, description = "No such restaurant", body = ErrorBody),
+            (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+            (status = 403, description = "The credentials do not allow this operation", body = ErrorBody),
+            (status = 406, description = "`Accept` allows none of the formats restaurants are answered in", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 429, description = "Rate limit exceeded; retry after `Retry-After` seconds", body = ErrorBody),
+            (status = 500, description = "Database error", body = ErrorBody),
+            (status = 503, description = "Too many requests in flight", body = ErrorBody),
+        )
+    )]
+    fn get_restaurant() {}
+
+    /// Set fields of a restaurant
+    ///
+    /// The body may be JSON, BSON or MessagePack; the response is negotiated
+    /// like the list's. Fields left out are left unchanged; server fields
+    /// and unknown ones are refused.
+    #[utoipa::path(
+        put,
+        path = "/api/restaurants/{id}",
+        tag = "restaurants",
+        security(("api_key" = ["restaurants:write"]), ("bearer" = ["editor", "admin"])),
+        params(
+            ("id" = String, Path, description = "Hex `ObjectId` of the restaurant"),
+            ("format" = Option<String>, Query, description = "`extended` for canonical Extended JSON in JSON, NDJSON and MessagePack; IDs are hex strings and dates RFC 3339 otherwise"),
+        ),
+        request_body(description = "Fields to set, e.g. `{\"cuisine\": \"Thai\"}`", content(
+            (UpdateRestaurantRequest = "application/json"),
+            (UpdateRestaurantRequest = "application/bson"),
+            (UpdateRestaurantRequest = "application/msgpack"),
+        )),
+        responses(
+            (status = 200, description = "The updated restaurant", content(
+                (RestaurantResponse = "application/json"),
+                (RestaurantResponse = "application/bson"),
+                (RestaurantResponse = "application/msgpack"),
+                (RestaurantResponse = "application/x-ndjson"),
+                (String = "text/csv"),
+            )),
+            (status = 400, description = "`id` is not an ObjectId, the body is not in the format of its `Content-Type` or `format` is not `extended`", body = ErrorBody),
+            (status = 404, description = "No such restaurant, or nothing changed", body = ErrorBody),
+            (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+            (status = 403, description = "The credentials do not allow this operation", body = ErrorBody),
+            (status = 406, description = "`Accept` allows none of the formats restaurants are answered in", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 413, description = "Body larger than `limits.update_body_bytes`", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 415, description = "`Content-Type` is not JSON, BSON or MessagePack", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 422, description = "Body sets no fields, has fields a restaurant does not, breaks a validation rule (every one is listed in `errors`), or nests deeper than `limits.max_json_depth`", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 429, description = "Rate limit exceeded; retry after `Retry-After` seconds", body = ErrorBody),
+            (status = 500, description = "Database error", body = ErrorBody),
+            (status = 503, description = "Too many requests in flight", body = ErrorBody),
+        )
+    )]
+    fn update_restaurant() {}
+
+    /// Delete a restaurant
+    #[utoipa::path(
+        delete,
+        path = "/api/restaurants/{id}",
+        tag = "restaurants",
+        security(("api_key" = ["admin"]), ("bearer" = ["admin"])),
+        params(("id" = String, Path, description = "Hex `ObjectId` of the restaurant")),
+        responses(
+            (status = 204, description = "Deleted"),
+            (status = 400, description = "`id` is not an ObjectId", body = ErrorBody),
+            (status = 404, description = "No such restaurant", body = ErrorBody),
+            (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+            (status = 403, description = "The credentials do not allow this operation", body = ErrorBody),
+            (status = 429, description = "Rate limit exceeded; retry after `Retry-After` seconds", body = ErrorBody),
+            (status = 500, description = "Database error", body = ErrorBody),
+            (status = 503, description = "Too many requests in flight", body = ErrorBody),
+        )
+    )]
+    fn delete_restaurant() {}
+
+    /// Insert many restaurants at once
+    ///
+    /// The body may be a JSON or MessagePack array, or BSON documents one
+    /// after the other, and compressed with `Content-Encoding: gzip`, `br` or
+    /// `zstd`.
+    #[utoipa::path(
+        post,
+        path = "/admin/restaurants/import",
+        tag = "admin",
+        security(("api_key" = ["admin"]), ("bearer" = ["admin"])),
+        request_body(content(
+            (Vec<CreateRestaurantRequest> = "application/json"),
+            (CreateRestaurantRequest = "application/bson"),
+            (Vec<CreateRestaurantRequest> = "application/msgpack"),
+        )),
+        responses(
+            (status = 201, description = "How many restaurants were inserted", body = ImportSummary),
+            (status = 400, description = "Body is not in the format of its `Content-Type`, or is empty", body = ErrorBody),
+            (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+            (status = 403, description = "The credentials do not allow this operation, or mutual TLS is on and no client certificate was presented", body = ErrorBody),
+            (status = 413, description = "Body larger than `compression.max_import_bytes`, before or after decompression", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 415, description = "`Content-Type` is not JSON, BSON or MessagePack, or unsupported `Content-Encoding`", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 422, description = "Body is not an array of restaurants, one breaks a validation rule (every one is listed in `errors`, under `[index]`), or nests deeper than `limits.max_json_depth`", body = ErrorBody, content_type = "application/problem+json"),
+            (status = 429, description = "Rate limit exceeded; retry after `Retry-After` seconds", body = ErrorBody),
+            (status = 500, description = "Database error", body = ErrorBody),
+            (status = 503, description = "Too many requests in flight", body = ErrorBody),
+        )
+    )]
+    fn import_restaurants() {}
+
+    /// Find the restaurants that do not match the model
+    ///
+    /// Reads the whole collection and lists every document with fields that
+    /// cannot be decoded, or that breaks a rule new restaurants must follow,
+    /// with each way it does.
+    #[utoipa::path(
+        get,
+        path = "/admin/restaurants/scan",
+        tag = "admin",
+        security(("api_key" = ["admin"]), ("bearer" = ["admin"])),
+        responses(
+            (status = 200, description = "The documents that do not match", body = ScanReport),
+            (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+            (status = 403, description = "The credentials do not allow this operation, or mutual TLS is on and no client certificate was presented", body = ErrorBody),
+            (status = 429, description = "Rate limit exceeded; retry after `Retry-After` seconds", body = ErrorBody),
+            (status = 500, description = "Database error", body = ErrorBody),
+            (status = 503, description = "Too many requests in flight", body = ErrorBody),
+        )
+    )]
+    fn scan_restaurants() {}
+
+    /// Liveness probe
+    #[utoipa::path(
+        get,
+        path = "/healthz",
+        tag = "operations",
+        responses((status = 200, description = "The process is up", body = Liveness))
+    )]
+    fn healthz() {}
+
+    /// Readiness probe, pings MongoDB
+    #[utoipa::path(
+        get,
+        path = "/readyz",
+        tag = "operations",
+        responses(
+            (status = 200, description = "Ready to serve requests", body = Readiness),
+            (status = 503, description = "MongoDB is unreachable or the server is shutting down", body = Readiness),
+        )
+    )]
+    fn readyz() {}
+
+    /// Prometheus metrics
+    #[utoipa::path(
+        get,
+        path = "/metrics",
+        tag = "operations",
+        responses((status = 200, description = "Prometheus text format", body = String, content_type = "text/plain"))
+    )]
+    fn metrics() {}
+
+    /// This document
+    #[utoipa::path(
+        get,
+        path = "/openapi.json",
+        tag = "operations",
+        responses((status = 200, description = "OpenAPI 3.1 document", body = Object))
+    )]
+    fn openapi_json() {}
+
+    /// Swagger UI for this document
+    #[utoipa::path(
+        get,
+        path = "/docs",
+        tag = "operations",
+        responses((status = 200, description = "HTML page", body = String, content_type = "text/html"))
+    )]
+    fn docs() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use utoipa::openapi::path::HttpMethod;
+
+    use super::*;
+    use crate::{
+        frameworks::{testing, Framework},
+        metrics,
+    };
+
+    const METHODS: [&str; 5] = ["GET", "POST", "PUT", "PATCH", "DELETE"];
+
+    /// Stands in for `{id}`: not an ObjectId, so the handlers answer 400
+    /// without touching the database.
+    const ID: &str = "not-an-object-id";
+
+    /// Paths that no framework should serve, to catch stray routes. The
+    /// metric route templates are probed as well, so a route added there but
+    /// not here is caught too.
+    const UNDOCUMENTED_PATHS: [&str; 4] = ["/", "/api", "/api/restaurants/not-an-object-id/grades", "/openapi.yaml"];
+
+    /// `(method, concrete path)` for every operation in the document.
+    fn documented() -> BTreeSet<(String, String)> {
+        let mut operations = BTreeSet::new();
+        for (path, item) in ApiDoc::openapi().paths.paths {
+            let path = path.replace("{id}", ID);
+            let methods = [
+                (HttpMethod::Get, &item.get),
+                (HttpMethod::Post, &item.post),
+                (HttpMethod::Put, &item.put),
+                (HttpMethod::Patch, &item.patch),
+                (HttpMethod::Delete, &item.delete),
+            ];
+            for (method, operation) in methods {
+                if operation.is_some() {
+                    let method = serde_json::to_value(method).unwrap();
+                    operations.insert((method.as_str().unwrap().to_uppercase(), path.clone()));
+                }
+            }
+        }
+        operations
+    }
+
+    /// Every framework must serve exactly the operations in the document: a
+    /// documented operation must not answer 404/405, and every other method
+    /// on those paths, or on a few plausible extra paths, must.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_serves_exactly_the_documented_routes() {
+        let documented = documented();
+        let mut paths: BTreeSet<String> = documented.iter().map(|(_, path)| path.clone()).collect();
+        paths.extend(UNDOCUMENTED_PATHS.map(String::from));
+        paths.extend(metrics::ROUTES.iter().map(|route| route.replace("{id}", ID)));
+
+        let mismatches = testing::with_every_framework(testing::config(), |config| async move {
+            let mut mismatches = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                for path in &paths {
+                    for method in METHODS {
+                        let status = testing::send(addr, method, path, &[]).await.status;
+                        let served = status != 404 && status != 405;
+                        let expected = documented.contains(&(method.to_string(), path.clone()));
+                        if served != expected {
+                            let problem = if expected { "is documented but not served" } else { "is served but not documented" };
+                            mismatches.push(format!("{}: {} {} {} (status {})", framework, method, path, problem, status));
+                        }
+                    }
+                }
+            }
+            mismatches
+        })
+        .await;
+        assert!(mismatches.is_empty(), "routes differ from the OpenAPI document:\n{}", mismatches.join("\n"));
+    }
+
+    /// A documented path missing from the route templates would be recorded
+    /// as `unmatched`.
+    #[test]
+    fn every_documented_path_has_a_metric_route() {
+        let missing: Vec<String> =
+            ApiDoc::openapi().paths.paths.into_keys().filter(|path| !metrics::ROUTES.contains(&path.as_str())).collect();
+        assert!(missing.is_empty(), "not in metrics::ROUTES: {:?}", missing);
+    }
+
+    #[test]
+    fn spec_is_openapi_3_1_with_the_api_schemas() {
+        let spec: serde_json::Value = serde_json::from_str(spec_json()).unwrap();
+        assert_eq!(spec["openapi"], "3.1.0");
+        for schema in ["Restaurant", "Address", "Grade", "ErrorBody"] {
+            assert!(spec["components"]["schemas"][schema].is_object(), "missing schema {}", schema);
+        }
+    }
+}
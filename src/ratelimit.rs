@@ -0,0 +1,425 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{Stream, StreamExt};
+use lru::LruCache;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    auth::{self, Authenticator, Credentials, Operation},
+    config::{BucketConfig, RateLimitConfig},
+    error::AppError,
+    graphql,
+    request::Headers,
+};
+
+/// Buckets kept at most; past this the least recently used one is dropped.
+const MAX_BUCKETS: usize = 100_000;
+
+/// How often buckets that have refilled are dropped. A new bucket starts out
+/// full, so nothing is lost.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Route classes with a limit of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Reads,
+    Writes,
+    /// Admin routes and bulk operations, which run many MongoDB operations each.
+    Bulk,
+}
+
+impl RouteClass {
+    /// The class of an `/api`, `/admin` or GraphQL request; other routes are
+    /// not limited. Opening a subscription socket counts as a read.
+    pub fn of(method: &str, path: &str) -> Option<Self> {
+        if path == graphql::WS_ENDPOINT {
+            return Some(RouteClass::Reads);
+        }
+        auth::required_operation(method, path).map(|operation| match operation {
+            Operation::Read => RouteClass::Reads,
+            Operation::Write | Operation::Delete => RouteClass::Writes,
+            Operation::Admin => RouteClass::Bulk,
+        })
+    }
+}
+
+/// A request that may go ahead. Holds its slot of `max_in_flight` until dropped.
+pub struct Admitted {
+    headers: Headers,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Admitted {
+    /// `RateLimit-*` headers for the response.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// `body`, holding the slot until it is dropped. A streamed body still
+    /// reads from MongoDB after its handler has returned, so the slot goes
+    /// with the body rather than the handler.
+    pub fn hold<S: Stream>(self, body: S) -> impl Stream<Item = S::Item> {
+        body.map(move |chunk| {
+            let _slot = &self;
+            chunk
+        })
+    }
+}
+
+/// The buckets of one client, for requests whose operations are only known
+/// once their body is read: each operation of a GraphQL batch is charged
+/// on its own.
+pub struct Budget {
+    limiter: RateLimiter,
+    client: String,
+}
+
+impl Budget {
+    /// Takes a token of `class` for one operation.
+    pub fn charge(&self, class: RouteClass) -> Result<(), AppError> {
+        if !self.limiter.config.enabled {
+            return Ok(());
+        }
+        self.limiter.take_token(class, self.client.clone()).map(drop).map_err(|throttled| throttled.error)
+    }
+}
+
+/// A request turned away: 429 once the client's bucket is empty, 503 when
+/// `max_in_flight` requests are already running.
+pub struct Throttled {
+    pub error: AppError,
+    /// `Retry-After` and, for 429s, the `RateLimit-*` headers.
+    pub headers: Headers,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn is_full_at(&self, limit: &BucketConfig, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.per_second >= limit.burst as f64
+    }
+
+    fn refill(&mut self, limit: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Token buckets per client and route class, plus the global cap on
+/// requests in flight. Shared by every framework, so a client has one
+/// budget however it reaches the API. Cheap to clone.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    auth: Authenticator,
+    buckets: Arc<Mutex<LruCache<(RouteClass, String), Bucket>>>,
+    in_flight: Option<Arc<Semaphore>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, auth: &Authenticator) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            auth: auth.clone(),
+            buckets: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(MAX_BUCKETS).expect("MAX_BUCKETS is not zero")))),
+            in_flight: (config.max_in_flight > 0).then(|| Arc::new(Semaphore::new(config.max_in_flight))),
+        }
+    }
+
+    /// Admits a request or tells why not. `header` looks up a request header
+    /// by its lower case name. The client is the principal its credentials
+    /// authenticate as, when that is known before authentication runs, and
+    /// otherwise its IP address: credentials that do not authenticate, such
+    /// as a new random key on every request, never get a bucket of their own.
+    pub fn admit<'a>(
+        &self,
+        method: &str,
+        path: &str,
+        peer: Option<IpAddr>,
+        header: impl Fn(&str) -> Option<&'a str>,
+    ) -> Result<Admitted, Throttled> {
+        let Some(class) = RouteClass::of(method, path) else {
+            return Ok(Admitted { headers: Vec::new(), _permit: None });
+        };
+
+        // GraphQL charges each operation of its body, through a `Budget`
+        let headers = if self.config.enabled && path != graphql::ENDPOINT {
+            let client = self.client(peer, &header);
+            self.take_token(class, client)?
+        } else {
+            Vec::new()
+        };
+        Ok(Admitted { headers, _permit: self.acquire()? })
+    }
+
+    /// A slot of `max_in_flight` for a connection that outlives the request
+    /// opening it, such as a GraphQL subscription socket.
+    pub fn reserve(&self) -> Result<Admitted, Throttled> {
+        Ok(Admitted { headers: Vec::new(), _permit: self.acquire()? })
+    }
+
+    /// The buckets of the client making a request, looked up as in `admit`.
+    pub fn budget<'a>(&self, peer: Option<IpAddr>, header: impl Fn(&str) -> Option<&'a str>) -> Budget {
+        Budget { limiter: self.clone(), client: self.client(peer, &header) }
+    }
+
+    fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, Throttled> {
+        let Some(semaphore) = &self.in_flight else {
+            return Ok(None);
+        };
+        match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(Throttled {
+                error: AppError::Unavailable("too many requests in flight".to_string()),
+                headers: vec![("retry-after", "1".to_string())],
+            }),
+        }
+    }
+
+    fn client<'a>(&self, peer: Option<IpAddr>, header: &impl Fn(&str) -> Option<&'a str>) -> String {
+        if let Some(subject) = self.auth.identify(&Credentials::from_headers(header)) {
+            return format!("principal:{}", subject);
+        }
+        let forwarded = self
+            .config
+            .trust_forwarded_for
+            .then(|| header("x-forwarded-for"))
+            .flatten()
+            .and_then(|value| value.split(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+        match forwarded.or(peer) {
+            Some(ip) => format!("ip:{}", ip),
+            None => "ip:unknown".to_string(),
+        }
+    }
+
+    /// Drops the buckets that have refilled every `PRUNE_INTERVAL`, until
+    /// shutdown.
+    pub async fn prune(self, shutdown: CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(PRUNE_INTERVAL) => {}
+                _ = shutdown.cancelled() => return,
+            }
+            self.prune_full(Instant::now());
+        }
+    }
+
+    fn prune_full(&self, now: Instant) {
+        let mut buckets = self.buckets();
+        let full: Vec<(RouteClass, String)> = buckets
+            .iter()
+            .filter(|((class, _), bucket)| bucket.is_full_at(self.limit(*class), now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in full {
+            buckets.pop(&key);
+        }
+    }
+
+    fn buckets(&self) -> MutexGuard<'_, LruCache<(RouteClass, String), Bucket>> {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn limit(&self, class: RouteClass) -> &BucketConfig {
+        match class {
+            RouteClass::Reads => &self.config.reads,
+            RouteClass::Writes => &self.config.writes,
+            RouteClass::Bulk => &self.config.bulk,
+        }
+    }
+
+    fn take_token(&self, class: RouteClass, client: String) -> Result<Headers, Throttled> {
+        let limit = self.limit(class);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets();
+        let bucket = buckets.get_or_insert_mut((class, client), || Bucket { tokens: limit.burst as f64, updated: now });
+        bucket.refill(limit, now);
+
+        let admitted = bucket.tokens >= 1.0;
+        if admitted {
+            bucket.tokens -= 1.0;
+        }
+        let until_full = (limit.burst as f64 - bucket.tokens) / limit.per_second;
+        let mut headers = vec![
+            ("ratelimit-limit", limit.burst.to_string()),
+            ("ratelimit-remaining", (bucket.tokens.floor() as u64).to_string()),
+            ("ratelimit-reset", (until_full.ceil() as u64).to_string()),
+            ("ratelimit-policy", format!("{};w={}", limit.burst, (limit.burst as f64 / limit.per_second).ceil())),
+        ];
+        if admitted {
+            return Ok(headers);
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) / limit.per_second).ceil().max(1.0);
+        headers.push(("retry-after", (retry_after as u64).to_string()));
+        Err(Throttled {
+            error: AppError::TooManyRequests(format!("rate limit of {} requests exceeded", limit.burst)),
+            headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        config::AuthConfig,
+        frameworks::{testing, Framework},
+    };
+
+    /// Keys that never authenticated cannot buy a bucket of their own.
+    #[tokio::test]
+    async fn unauthenticated_keys_share_the_bucket_of_their_ip() {
+        let config = RateLimitConfig { enabled: true, reads: BucketConfig { burst: 2, per_second: 0.001 }, ..RateLimitConfig::default() };
+        let limiter = testing::rate_limiter(&config, &AuthConfig { enabled: true, ..AuthConfig::default() }).await;
+        let peer = Some(IpAddr::from([192, 0, 2, 1]));
+
+        let statuses: Vec<u16> = (0..3)
+            .map(|i| {
+                let key = format!("rk_random{}", i);
+                let header = |name: &str| (name == "x-api-key").then_some(key.as_str());
+                match limiter.admit("GET", "/api/restaurants", peer, header) {
+                    Ok(_) => 200,
+                    Err(throttled) => throttled.error.status(),
+                }
+            })
+            .collect();
+        assert_eq!(statuses, [200, 200, 429]);
+        let other = limiter.admit("GET", "/api/restaurants", Some(IpAddr::from([192, 0, 2, 2])), |_| None);
+        assert!(other.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pruning_drops_only_refilled_buckets() {
+        let config = RateLimitConfig { enabled: true, reads: BucketConfig { burst: 2, per_second: 1.0 }, ..RateLimitConfig::default() };
+        let limiter = testing::rate_limiter(&config, &AuthConfig::default()).await;
+        let drained = Some(IpAddr::from([192, 0, 2, 1]));
+        for _ in 0..2 {
+            assert!(limiter.admit("GET", "/api/restaurants", drained, |_| None).is_ok());
+        }
+        assert!(limiter.admit("GET", "/api/restaurants", Some(IpAddr::from([192, 0, 2, 2])), |_| None).is_ok());
+
+        limiter.prune_full(Instant::now() + Duration::from_millis(1500));
+        let buckets = limiter.buckets();
+        let kept: Vec<&str> = buckets.iter().map(|((_, client), _)| client.as_str()).collect();
+        assert_eq!(kept, ["ip:192.0.2.1"]);
+    }
+
+    #[tokio::test]
+    async fn held_bodies_and_sockets_keep_their_slot_until_dropped() {
+        let config = RateLimitConfig { max_in_flight: 1, ..RateLimitConfig::default() };
+        let limiter = testing::rate_limiter(&config, &AuthConfig::default()).await;
+        let admit = || limiter.admit("GET", "/api/restaurants", None, |_| None);
+
+        let mut body = admit().ok().unwrap().hold(futures::stream::iter([1, 2])).boxed();
+        assert_eq!(body.next().await, Some(1));
+        assert_eq!(admit().err().map(|throttled| throttled.error.status()), Some(503));
+        assert_eq!(body.next().await, Some(2));
+        drop(body);
+        assert!(admit().is_ok());
+
+        let socket = limiter.reserve().ok().unwrap();
+        assert_eq!(admit().err().map(|throttled| throttled.error.status()), Some(503));
+        drop(socket);
+        assert!(admit().is_ok());
+    }
+
+    /// An empty bucket answers 429 with `Retry-After` and the `RateLimit-*`
+    /// headers, on every framework.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_throttles_an_empty_bucket() {
+        let mut config = testing::config();
+        config.rate_limit.enabled = true;
+        config.rate_limit.trust_forwarded_for = true;
+        config.rate_limit.reads = BucketConfig { burst: 1, per_second: 0.001 };
+        let failures = testing::with_every_framework(config, |config| async move {
+            let mut failures = Vec::new();
+            for (index, framework) in Framework::HTTP.into_iter().enumerate() {
+                let addr = testing::addr(&config, framework);
+                // A client of its own for each framework, since they share the buckets
+                let client = format!("192.0.2.{}", index + 1);
+                let headers = [("X-Forwarded-For", client.as_str())];
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                let first = testing::send(addr, "GET", "/api/restaurants/not-an-object-id", &headers).await;
+                check("admits the first request", first.status == 400);
+                check("tells how many requests remain", first.header("ratelimit-remaining") == Some("0"));
+                let second = testing::send(addr, "GET", "/api/restaurants/not-an-object-id", &headers).await;
+                check("answers the second with 429", second.status == 429);
+                check("sends Retry-After", second.header("retry-after").is_some_and(|secs| secs.parse::<u64>().is_ok_and(|secs| secs > 0)));
+                for name in ["ratelimit-limit", "ratelimit-remaining", "ratelimit-reset", "ratelimit-policy"] {
+                    check(&format!("sends {}", name), second.header(name).is_some());
+                }
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "rate limiting differs:\n{}", failures.join("\n"));
+    }
+
+    /// With `max_in_flight` requests running, the next one answers 503 with
+    /// `Retry-After: 1` on every framework, and the slot comes back once
+    /// the running request ends.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_turns_requests_away_at_max_in_flight() {
+        let mut config = testing::config();
+        config.rate_limit.max_in_flight = 1;
+        let failures = testing::with_every_framework(config, |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                // An upload that never finishes holds the only slot. Rocket
+                // routes a request once it has the first 14 bytes of its body.
+                let mut upload = TcpStream::connect(addr).await.unwrap();
+                let head = "POST /api/restaurants HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 100\r\n\r\n{\"name\": \"Hanging";
+                upload.write_all(head.as_bytes()).await.unwrap();
+                let mut turned_away = None;
+                for _ in 0..50 {
+                    let response = testing::send(addr, "GET", "/api/restaurants/not-an-object-id", &[]).await;
+                    if response.status == 503 {
+                        turned_away = Some(response);
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                check("answers 503 while the slot is taken", turned_away.as_ref().is_some_and(|response| response.header("retry-after") == Some("1")));
+
+                drop(upload);
+                let mut freed = false;
+                for _ in 0..100 {
+                    if testing::send(addr, "GET", "/api/restaurants/not-an-object-id", &[]).await.status == 400 {
+                        freed = true;
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                check("gives the slot back once the upload is dropped", freed);
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "the in-flight cap differs:\n{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,299 @@
+use std::marker::PhantomData;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use opentelemetry::{global, propagation::Extractor};
+use tracing::{Span, field, info, info_span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+use crate::metrics::{self, Metrics};
+
+/// Header carrying the request ID, read from requests and echoed on responses.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Response headers added by the shared middleware, by lower case name.
+pub type Headers = Vec<(&'static str, String)>;
+
+/// Incoming IDs longer than this, or with characters other than visible
+/// ASCII, are replaced by a generated one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Per-request data created by each framework's middleware and handed to the
+/// handlers, which pass it on to `MongoRepo`. The request ID ends up in the
+/// `comment` of every MongoDB operation so profiler entries can be traced
+/// back to the HTTP request.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub span: Span,
+    framework: &'static str,
+    method: String,
+    path: String,
+    started: Instant,
+    subject: Arc<OnceLock<String>>,
+}
+
+impl RequestContext {
+    /// Starts a request. `header` looks up a request header by its lower case
+    /// name: a well-formed `X-Request-Id` is kept (one is generated otherwise)
+    /// and a W3C `traceparent` makes the request span part of the caller's trace.
+    pub fn begin<'a>(
+        framework: &'static str,
+        method: &str,
+        path: &str,
+        header: impl Fn(&str) -> Option<&'a str>,
+    ) -> Self {
+        let headers = HeaderLookup(header, PhantomData);
+        let request_id = match headers.get(REQUEST_ID_HEADER) {
+            Some(id) if is_valid_request_id(id) => id.to_string(),
+            _ => Uuid::new_v4().to_string(),
+        };
+        let route = metrics::route_template(path);
+        let span = info_span!(
+            "request",
+            otel.name = %format_args!("{} {}", method, route),
+            otel.kind = "server",
+            otel.status_code = field::Empty,
+            framework,
+            http.request.method = %method,
+            http.route = route,
+            http.response.status_code = field::Empty,
+            url.path = %path,
+            %request_id,
+            enduser.id = field::Empty,
+        );
+        let parent = global::get_text_map_propagator(|propagator| propagator.extract(&headers));
+        // Only fails when no OpenTelemetry layer is installed
+        let _ = span.set_parent(parent);
+
+        Self {
+            request_id,
+            span,
+            framework,
+            method: method.to_string(),
+            path: path.to_string(),
+            started: Instant::now(),
+            subject: Arc::default(),
+        }
+    }
+
+    /// Context for operations started from the interactive CLI.
+    pub fn cli(operation: &str) -> Self {
+        let request_id = Uuid::new_v4().to_string();
+        let span = info_span!("cli", otel.name = operation, %request_id);
+        Self {
+            request_id,
+            span,
+            framework: "cli",
+            method: "CLI".to_string(),
+            path: operation.to_string(),
+            started: Instant::now(),
+            subject: Arc::new(OnceLock::from("cli".to_string())),
+        }
+    }
+
+    /// Value for the `comment` option of MongoDB operations.
+    pub fn comment(&self) -> &str {
+        &self.request_id
+    }
+
+    /// Records who made the request once it is authenticated. Clones made
+    /// before share it, so it can be set after the handlers got their copy.
+    pub fn set_subject(&self, subject: &str) {
+        if self.subject.set(subject.to_string()).is_ok() {
+            self.span.record("enduser.id", subject);
+        }
+    }
+
+    /// The authenticated subject; `None` when auth is disabled or the route is open.
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.get().map(String::as_str)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Logs the outcome inside the request span and records it in the metrics.
+    pub fn finish(&self, metrics: &Metrics, status: u16) {
+        let elapsed = self.elapsed();
+        metrics.observe_request(self.framework, &self.method, &self.path, status, elapsed);
+        self.span.record("http.response.status_code", status);
+        if status >= 500 {
+            self.span.record("otel.status_code", "ERROR");
+        }
+        info!(
+            parent: &self.span,
+            status,
+            elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+            "request completed"
+        );
+    }
+}
+
+/// The value of the query parameter `name`, the last one when it is
+/// repeated. Values are not percent-decoded; none of the parameters the API
+/// reads need it.
+pub fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+        .next_back()
+}
+
+/// Lets the W3C trace context propagator read request headers of any framework.
+struct HeaderLookup<'a, F>(F, PhantomData<&'a str>);
+
+impl<'a, F: Fn(&str) -> Option<&'a str>> Extractor for HeaderLookup<'a, F> {
+    fn get(&self, key: &str) -> Option<&str> {
+        (self.0)(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        // Not used by the trace context propagator, which only asks for its own headers
+        Vec::new()
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider as _};
+    use opentelemetry_sdk::{
+        error::OTelSdkResult,
+        propagation::TraceContextPropagator,
+        trace::{SdkTracerProvider, SpanData, SpanExporter},
+    };
+    use tracing::Level;
+    use tracing_subscriber::{filter::Targets, prelude::*};
+
+    use super::*;
+    use crate::frameworks::{testing, Framework};
+
+    /// Keeps every span it is given, for tests to look through.
+    #[derive(Debug, Clone, Default)]
+    struct Collected(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collected {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    impl Collected {
+        /// Installs, once for the whole test binary, a global subscriber
+        /// exporting this crate's spans the way `telemetry::init` does.
+        fn install() -> &'static Collected {
+            static COLLECTED: OnceLock<Collected> = OnceLock::new();
+            COLLECTED.get_or_init(|| {
+                let collected = Collected::default();
+                let provider = SdkTracerProvider::builder().with_simple_exporter(collected.clone()).build();
+                global::set_text_map_propagator(TraceContextPropagator::new());
+                let subscriber = tracing_subscriber::registry().with(
+                    tracing_opentelemetry::layer()
+                        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+                        .with_filter(Targets::new().with_target(env!("CARGO_PKG_NAME"), Level::INFO)),
+                );
+                // Not `init`, whose `log` bridge clashes with the loggers the frameworks set up
+                tracing::subscriber::set_global_default(subscriber).unwrap();
+                collected
+            })
+        }
+
+        /// The exported span of the request with this ID, waiting a little
+        /// for it to end, since the frameworks drop it after responding.
+        async fn request(&self, request_id: &str) -> Option<SpanData> {
+            for _ in 0..50 {
+                let found = self.0.lock().unwrap().iter().find(|span| {
+                    span.attributes
+                        .iter()
+                        .any(|kv| kv.key.as_str() == "request_id" && kv.value.as_str() == request_id)
+                }).cloned();
+                if found.is_some() {
+                    return found;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            None
+        }
+    }
+
+    /// Every framework must echo a well-formed incoming ID, on successes and
+    /// errors alike, and generate one when it is missing or malformed.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_echoes_or_generates_the_request_id() {
+        let too_long = "x".repeat(MAX_REQUEST_ID_LEN + 1);
+        let failures = testing::with_every_framework(testing::config(), |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                for path in ["/healthz", "/api/restaurants/not-an-object-id"] {
+                    let echoed = testing::send(addr, "GET", path, &[("X-Request-Id", "client-id-42")]).await;
+                    check(&format!("GET {} echoes the request ID", path), echoed.header(REQUEST_ID_HEADER) == Some("client-id-42"));
+
+                    let generated = testing::send(addr, "GET", path, &[]).await;
+                    let id = generated.header(REQUEST_ID_HEADER).unwrap_or_default();
+                    check(&format!("GET {} generates a UUID request ID", path), Uuid::parse_str(id).is_ok());
+                }
+                let replaced = testing::send(addr, "GET", "/healthz", &[("X-Request-Id", &too_long)]).await;
+                let id = replaced.header(REQUEST_ID_HEADER).unwrap_or_default();
+                check("an overlong request ID is replaced", Uuid::parse_str(id).is_ok());
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "request IDs differ:\n{}", failures.join("\n"));
+    }
+
+    /// A `traceparent` from the caller makes every framework's request span a
+    /// child of the caller's span, in the caller's trace.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_continues_the_callers_trace() {
+        let collected = Collected::install();
+        let failures = testing::with_every_framework(testing::config(), |config| async move {
+            let mut failures = Vec::new();
+            for (i, framework) in Framework::HTTP.into_iter().enumerate() {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                let trace_id = format!("4bf92f3577b34da6a3ce929d0e0e47{:02x}", i);
+                let parent_id = format!("00f067aa0ba902{:02x}", i);
+                let request_id = format!("traced-{}", framework);
+                let traceparent = format!("00-{}-{}-01", trace_id, parent_id);
+                let response = testing::send(addr, "GET", "/healthz", &[
+                    ("traceparent", &traceparent),
+                    ("X-Request-Id", &request_id),
+                ])
+                .await;
+                check("/healthz answers 200", response.status == 200);
+
+                let Some(span) = collected.request(&request_id).await else {
+                    check("the request span is exported", false);
+                    continue;
+                };
+                check("the span is in the caller's trace", span.span_context.trace_id() == TraceId::from_hex(&trace_id).unwrap());
+                check("the span's parent is the caller's span", span.parent_span_id == SpanId::from_hex(&parent_id).unwrap());
+                check("the span is named after the route", span.name == "GET /healthz");
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "trace propagation differs:\n{}", failures.join("\n"));
+    }
+}
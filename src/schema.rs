@@ -0,0 +1,390 @@
+//! Schema inference for the `schema` command: the fields a sample of the
+//! collection has, their BSON types and how often they appear, compared
+//! with the fields `RestaurantDoc`, `Address` and `Grade` read and write.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use bson::{Bson, Document};
+use serde::Serialize;
+
+/// A field of the model. Array elements are named with `[]`, as in
+/// `grades[].score`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ModelField {
+    pub path: &'static str,
+    /// `$type` names of the BSON types the model decodes the field from, as
+    /// `serde_helpers` reads them: strings stand for hex IDs and RFC 3339
+    /// dates. The Extended JSON objects they also read are left out, as
+    /// MongoDB stores those as the types they stand for.
+    pub types: &'static [&'static str],
+    /// Whether every document the model writes has the field. Fields it
+    /// leaves out when empty or `None` are optional.
+    pub required: bool,
+}
+
+const fn field(path: &'static str, types: &'static [&'static str], required: bool) -> ModelField {
+    ModelField { path, types, required }
+}
+
+/// The fields of `RestaurantDoc` and of the `Address` and `Grade` in it.
+pub const MODEL: [ModelField; 18] = [
+    field("_id", &["objectId", "string", "null"], true),
+    field("address", &["object", "null"], false),
+    field("address.building", &["string"], false),
+    field("address.coord", &["array"], false),
+    field("address.coord[]", &["double", "int", "long"], false),
+    field("address.street", &["string"], false),
+    field("address.zipcode", &["string"], false),
+    field("borough", &["string"], true),
+    field("created_by", &["string", "null"], false),
+    field("cuisine", &["string"], true),
+    field("grades", &["array"], false),
+    field("grades[]", &["object"], false),
+    field("grades[].date", &["date", "string"], true),
+    field("grades[].grade", &["string"], true),
+    field("grades[].score", &["int", "long"], true),
+    field("name", &["string"], true),
+    field("restaurant_id", &["string"], true),
+    field("updated_by", &["string", "null"], false),
+];
+
+/// Field statistics of the documents added so far.
+#[derive(Debug, Default)]
+pub struct Inference {
+    sampled: u64,
+    /// How many documents each document path was seen holding, the root
+    /// being `""`: how many times its fields could have appeared.
+    containers: BTreeMap<String, u64>,
+    fields: BTreeMap<String, BTreeMap<&'static str, u64>>,
+}
+
+impl Inference {
+    pub fn add(&mut self, document: &Document) {
+        self.sampled += 1;
+        self.visit_document("", document);
+    }
+
+    fn visit_document(&mut self, path: &str, document: &Document) {
+        *self.containers.entry(path.to_string()).or_default() += 1;
+        for (key, value) in document {
+            let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            self.visit(&path, value);
+        }
+    }
+
+    fn visit(&mut self, path: &str, value: &Bson) {
+        *self.fields.entry(path.to_string()).or_default().entry(type_name(value)).or_default() += 1;
+        match value {
+            Bson::Document(document) => self.visit_document(path, document),
+            Bson::Array(values) => {
+                let elements = format!("{}[]", path);
+                for value in values {
+                    self.visit(&elements, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Every field found or in the model, and how the two disagree.
+    pub fn report(&self) -> SchemaReport {
+        let mut paths: Vec<&str> = self.fields.keys().map(String::as_str).chain(MODEL.iter().map(|field| field.path)).collect();
+        paths.sort_unstable();
+        paths.dedup();
+
+        let mut report = SchemaReport { sampled: self.sampled, fields: Vec::new(), drift: Vec::new() };
+        for path in paths {
+            let types = self.fields.get(path).cloned().unwrap_or_default();
+            let present = types.values().sum();
+            let model = MODEL.iter().find(|field| field.path == path).copied();
+            // Elements are counted one by one, so they have no frequency
+            let of = if path.ends_with("[]") { present } else { self.containers.get(parent(path)).copied().unwrap_or_default() };
+            let field = FieldReport { path: path.to_string(), present, of, types, model };
+            report.drift.extend(field.drift());
+            report.fields.push(field);
+        }
+        report
+    }
+}
+
+/// The fields of a sample, and how they differ from the model.
+#[derive(Debug, Serialize)]
+pub struct SchemaReport {
+    /// Documents sampled.
+    pub sampled: u64,
+    pub fields: Vec<FieldReport>,
+    pub drift: Vec<Drift>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldReport {
+    pub path: String,
+    /// Documents, or array elements, with the field.
+    pub present: u64,
+    /// Documents that could have had it: those its parent was a document in.
+    pub of: u64,
+    /// How many times each BSON type was found.
+    pub types: BTreeMap<&'static str, u64>,
+    /// `None` for fields the model does not have.
+    pub model: Option<ModelField>,
+}
+
+impl FieldReport {
+    fn drift(&self) -> Vec<Drift> {
+        let drift = |kind, detail| Drift { path: self.path.clone(), kind, detail };
+        let Some(model) = self.model else {
+            // Only the outermost field the model lacks, not each one inside it
+            let parent = parent(&self.path);
+            if parent.is_empty() || MODEL.iter().any(|field| field.path == parent) {
+                let detail = format!("{} in {}, but not in the model", self.type_list(|_| true), self.share());
+                return vec![drift(DriftKind::Extra, detail)];
+            }
+            return Vec::new();
+        };
+        let mut found = Vec::new();
+        let mismatched: u64 = self.types.iter().filter(|(name, _)| !model.types.contains(name)).map(|(_, count)| count).sum();
+        if mismatched > 0 {
+            let detail = format!(
+                "{} in {} of {}, but the model reads {}",
+                self.type_list(|name| !model.types.contains(&name)),
+                mismatched,
+                self.present,
+                model.types.join(" or ")
+            );
+            found.push(drift(DriftKind::TypeMismatch, detail));
+        }
+        if self.of > 0 && !self.path.ends_with("[]") {
+            if model.required && self.present < self.of {
+                let detail = format!("missing from {} of {}, but the model writes it on every one", self.of - self.present, self.of);
+                found.push(drift(DriftKind::Optionality, detail));
+            } else if !model.required && self.present == self.of {
+                let detail = format!("present in all {}, but optional in the model", self.of);
+                found.push(drift(DriftKind::Optionality, detail));
+            }
+        }
+        found
+    }
+
+    fn share(&self) -> String {
+        if self.path.ends_with("[]") {
+            format!("{} elements", self.present)
+        } else {
+            format!("{} of {}", self.present, self.of)
+        }
+    }
+
+    fn type_list(&self, include: impl Fn(&str) -> bool) -> String {
+        let types: Vec<&str> = self.types.keys().copied().filter(|name| include(name)).collect();
+        types.join(" or ")
+    }
+}
+
+/// A way the data differs from the model.
+#[derive(Debug, Serialize)]
+pub struct Drift {
+    pub path: String,
+    pub kind: DriftKind,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftKind {
+    /// In the data, not in the model.
+    Extra,
+    /// Of a type the model does not read.
+    TypeMismatch,
+    /// Required in the model but sometimes missing, or optional but always
+    /// present.
+    Optionality,
+}
+
+impl DriftKind {
+    const fn as_str(self) -> &'static str {
+        match self {
+            DriftKind::Extra => "extra",
+            DriftKind::TypeMismatch => "type mismatch",
+            DriftKind::Optionality => "optionality",
+        }
+    }
+}
+
+impl SchemaReport {
+    /// The report as a table of fields followed by the drift, for a terminal.
+    pub fn table(&self) -> String {
+        let width = self.fields.iter().map(|field| field.path.len()).max().unwrap_or_default().max(4);
+        let mut table = format!("Sampled {} documents\n\n", self.sampled);
+        let _ = writeln!(table, "{:<width$}  {:>13}  {:<30}  MODEL", "PATH", "PRESENT", "TYPES");
+        for field in &self.fields {
+            let present = if field.of > 0 && !field.path.ends_with("[]") {
+                format!("{}/{} {:>3}%", field.present, field.of, field.present * 100 / field.of)
+            } else {
+                field.present.to_string()
+            };
+            let types: Vec<String> = field.types.iter().map(|(name, count)| format!("{}:{}", name, count)).collect();
+            let model = match field.model {
+                Some(model) => format!("{}{}", model.types.join("|"), if model.required { ", required" } else { "" }),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(table, "{:<width$}  {:>13}  {:<30}  {}", field.path, present, types.join(" "), model);
+        }
+        if self.drift.is_empty() {
+            table.push_str("\nNo drift from the model\n");
+        } else {
+            let _ = writeln!(table, "\n{:<13}  {:<width$}  DETAIL", "DRIFT", "PATH");
+            for drift in &self.drift {
+                let _ = writeln!(table, "{:<13}  {:<width$}  {}", drift.kind.as_str(), drift.path, drift.detail);
+            }
+        }
+        table
+    }
+}
+
+/// The path of the document `path` is a field of, `""` for the root. The
+/// elements of an array have the array as their parent.
+fn parent(path: &str) -> &str {
+    match path.strip_suffix("[]") {
+        Some(array) => array,
+        None => path.rsplit_once('.').map_or("", |(parent, _)| parent),
+    }
+}
+
+/// The `$type` alias of `value`'s BSON type.
+fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Double(_) => "double",
+        Bson::String(_) => "string",
+        Bson::Document(_) => "object",
+        Bson::Array(_) => "array",
+        Bson::Binary(_) => "binData",
+        Bson::Undefined => "undefined",
+        Bson::ObjectId(_) => "objectId",
+        Bson::Boolean(_) => "bool",
+        Bson::DateTime(_) => "date",
+        Bson::Null => "null",
+        Bson::RegularExpression(_) => "regex",
+        Bson::DbPointer(_) => "dbPointer",
+        Bson::JavaScriptCode(_) => "javascript",
+        Bson::Symbol(_) => "symbol",
+        Bson::JavaScriptCodeWithScope(_) => "javascriptWithScope",
+        Bson::Int32(_) => "int",
+        Bson::Timestamp(_) => "timestamp",
+        Bson::Int64(_) => "long",
+        Bson::Decimal128(_) => "decimal",
+        Bson::MinKey => "minKey",
+        Bson::MaxKey => "maxKey",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, oid::ObjectId, DateTime};
+
+    use super::*;
+    use crate::models::restaurant::{Address, Grade, RestaurantDoc};
+
+    fn infer(documents: &[Document]) -> SchemaReport {
+        let mut inference = Inference::default();
+        for document in documents {
+            inference.add(document);
+        }
+        inference.report()
+    }
+
+    /// `MODEL` is written by hand, so it is checked against what the structs
+    /// write: every field of a full restaurant, and only the required ones
+    /// of an empty one.
+    #[test]
+    fn the_model_table_matches_the_structs() {
+        let full = RestaurantDoc {
+            id: Some(ObjectId::new()),
+            address: Some(Address {
+                building: "1007".to_string(),
+                coord: vec![-73.856077, 40.848447],
+                street: "Morris Park Ave".to_string(),
+                zipcode: "10462".to_string(),
+            }),
+            grades: vec![Grade { date: DateTime::now(), grade: "A".to_string(), score: 2 }],
+            created_by: Some("alice".to_string()),
+            updated_by: Some("bob".to_string()),
+            ..Default::default()
+        };
+        let report = infer(&[bson::to_document(&full).unwrap()]);
+        let found: Vec<_> = report.fields.iter().filter(|field| field.present > 0).map(|field| field.path.as_str()).collect();
+        let expected: Vec<_> = MODEL.iter().map(|field| field.path).collect();
+        assert_eq!(found, expected);
+        assert!(report.drift.iter().all(|drift| drift.kind == DriftKind::Optionality), "{:?}", report.drift);
+
+        let empty = RestaurantDoc { id: Some(ObjectId::new()), ..Default::default() };
+        let report = infer(&[bson::to_document(&empty).unwrap()]);
+        let written: Vec<_> = report.fields.iter().filter(|field| field.present > 0).map(|field| field.path.as_str()).collect();
+        let required: Vec<_> = MODEL.iter().filter(|field| field.required && !field.path.contains('[') && !field.path.contains('.')).map(|field| field.path).collect();
+        assert_eq!(written, required);
+    }
+
+    /// Every form the decoders read is in `MODEL`, so documents that read
+    /// fine are not reported as drift.
+    #[test]
+    fn documents_the_model_reads_do_not_drift() {
+        let restaurant = |id: Bson, optional: Document| {
+            let mut document = doc! { "_id": id, "name": "Morris Park Bake Shop", "borough": "Bronx", "cuisine": "Bakery", "restaurant_id": "30075445" };
+            document.extend(optional);
+            document
+        };
+        let documents = [
+            restaurant(ObjectId::new().into(), doc! {
+                "address": { "building": "1007", "coord": [-73.856077, 40], "street": "Morris Park Ave", "zipcode": "10462" },
+                "grades": [{ "date": DateTime::from_millis(1_393_804_800_000), "grade": "A", "score": 2 }],
+                "created_by": "alice",
+                "updated_by": "bob",
+            }),
+            restaurant("5eb3d668b31de5d588f42a7e".into(), doc! {
+                "address": { "coord": [-73_i64, 40.848447] },
+                "grades": [{ "date": "2014-03-03T00:00:00Z", "grade": "B", "score": 7_i64 }],
+                "created_by": null,
+                "updated_by": null,
+            }),
+            restaurant(Bson::Null, doc! { "address": null }),
+            restaurant(ObjectId::new().into(), doc! { "address": {} }),
+            restaurant(ObjectId::new().into(), doc! {}),
+        ];
+        for document in &documents {
+            bson::from_document::<RestaurantDoc>(document.clone()).unwrap();
+        }
+        let report = infer(&documents);
+        assert!(report.drift.is_empty(), "{:?}", report.drift);
+    }
+
+    #[test]
+    fn drift_is_reported_per_field() {
+        let date = DateTime::from_millis(1_393_804_800_000);
+        let report = infer(&[
+            doc! {
+                "_id": ObjectId::new(), "name": "Clean", "borough": "Queens", "cuisine": "Thai", "restaurant_id": "1",
+                "address": { "zipcode": "11373" },
+                "grades": [{ "date": date, "grade": "A", "score": 2 }],
+            },
+            doc! {
+                "_id": ObjectId::new(), "name": "Dirty", "borough": "Queens", "restaurant_id": 2,
+                "address": { "zipcode": "11373" },
+                "grades": [{ "date": null, "grade": "B", "score": "seven" }],
+                "inspector": { "name": "J. Doe" },
+            },
+        ]);
+        let drift: Vec<_> = report.drift.iter().map(|drift| (drift.path.as_str(), drift.kind)).collect();
+        assert_eq!(drift, [
+            ("address", DriftKind::Optionality),
+            ("address.zipcode", DriftKind::Optionality),
+            ("cuisine", DriftKind::Optionality),
+            ("grades", DriftKind::Optionality),
+            ("grades[].date", DriftKind::TypeMismatch),
+            ("grades[].score", DriftKind::TypeMismatch),
+            ("inspector", DriftKind::Extra),
+            ("restaurant_id", DriftKind::TypeMismatch),
+        ]);
+        let score = report.fields.iter().find(|field| field.path == "grades[].score").unwrap();
+        assert_eq!((score.present, score.of, score.types.get("string")), (2, 2, Some(&1)));
+        assert!(report.table().contains("grades[].score"));
+    }
+}
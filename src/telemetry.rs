@@ -0,0 +1,127 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use opentelemetry::{KeyValue, global, trace::TracerProvider as _};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_proto::{
+    tonic::collector::trace::v1::ExportTraceServiceRequest,
+    transform::{common::tonic::ResourceAttributesWithSchema, trace::tonic::group_spans_by_resource_and_scope},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::{OTelSdkError, OTelSdkResult},
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider, SpanData, SpanExporter},
+};
+use tracing::Level;
+use tracing_subscriber::{EnvFilter, filter::Targets, fmt, prelude::*};
+
+use crate::config::{LogFormat, LoggingConfig, TraceExporter, TracingConfig};
+
+/// Keeps the tracer provider alive; `shutdown` flushes spans not yet exported.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Could not flush traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global `tracing` subscriber and, unless `tracing.exporter` is
+/// `none`, an OpenTelemetry layer exporting this crate's spans. `RUST_LOG`,
+/// when set, takes precedence over `logging.filter`; it does not affect which
+/// spans are exported. Records from crates that use `log` (tide, rocket) are
+/// forwarded to the same subscriber.
+pub fn init(logging: &LoggingConfig, tracing: &TracingConfig) -> Result<Telemetry, Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(&logging.filter));
+    let fmt_layer = match logging.format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().with_current_span(true).with_span_list(false).boxed(),
+    };
+
+    let provider = tracer_provider(tracing)?;
+    let otel_layer = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+            .with_filter(Targets::new().with_target(env!("CARGO_PKG_NAME"), Level::INFO))
+    });
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(filter))
+        .with(otel_layer)
+        .init();
+
+    Ok(Telemetry { provider })
+}
+
+fn tracer_provider(config: &TracingConfig) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    let builder = SdkTracerProvider::builder()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+        .with_resource(
+            Resource::builder()
+                .with_attribute(KeyValue::new("service.name", config.service_name.clone()))
+                .build(),
+        );
+
+    let provider = match config.exporter {
+        TraceExporter::None => return Ok(None),
+        TraceExporter::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(&config.otlp_endpoint)
+                .build()?;
+            builder.with_batch_exporter(exporter).build()
+        }
+        TraceExporter::File => builder.with_batch_exporter(FileExporter::create(&config.file)?).build(),
+    };
+    Ok(Some(provider))
+}
+
+/// Appends each batch as one OTLP/JSON `ExportTraceServiceRequest` per line,
+/// the format the OpenTelemetry Collector's file exporter and receiver use.
+#[derive(Debug)]
+struct FileExporter {
+    file: Mutex<BufWriter<File>>,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl FileExporter {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(BufWriter::new(file)),
+            resource: ResourceAttributesWithSchema::default(),
+        })
+    }
+
+    fn write(&self, batch: Vec<SpanData>) -> Result<(), Box<dyn std::error::Error>> {
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(batch, &self.resource),
+        };
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_writer(&mut *file, &request)?;
+        file.write_all(b"\n")?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl SpanExporter for FileExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.write(batch).map_err(|e| OTelSdkError::InternalFailure(e.to_string()))
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
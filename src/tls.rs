@@ -0,0 +1,319 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use rustls_pemfile::Item;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    auth::{self, Operation},
+    config::TlsConfig,
+    error::AppError,
+};
+
+/// ALPN protocols of frameworks that speak HTTP/2 as well as HTTP/1.1.
+pub const ALPN_H2: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// ALPN protocols of frameworks that only speak HTTP/1.1.
+pub const ALPN_HTTP1: &[&[u8]] = &[b"http/1.1"];
+
+/// Whether the client of a connection presented a certificate signed by
+/// `tls.client_ca_file`; rustls rejects the handshake for any other.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientCertificate(pub bool);
+
+/// TLS shared by every framework: the certificate, swapped in place when its
+/// files change, and the client CAs of mutual TLS. Cheap to clone.
+#[derive(Clone)]
+pub struct Tls {
+    config: Arc<TlsConfig>,
+    certificate: Arc<CurrentCertificate>,
+    client_roots: Option<RootCertStore>,
+    reloaded: watch::Sender<u64>,
+}
+
+/// Resolves every handshake to the certificate currently loaded.
+struct CurrentCertificate(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for CurrentCertificate {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+}
+
+impl Tls {
+    /// `None` when TLS is disabled. Fails if the PEM files hold no usable
+    /// certificate and key.
+    pub fn load(config: &TlsConfig) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let certificate = load_certified_key(&config.cert_file, &config.key_file)?;
+        let client_roots = match &config.client_ca_file {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certificates(path)? {
+                    roots.add(&cert).map_err(|e| format!("tls.client_ca_file {}: {}", path.display(), e))?;
+                }
+                Some(roots)
+            }
+            None => None,
+        };
+        Ok(Some(Self {
+            config: Arc::new(config.clone()),
+            certificate: Arc::new(CurrentCertificate(RwLock::new(Arc::new(certificate)))),
+            client_roots,
+            reloaded: watch::Sender::new(0),
+        }))
+    }
+
+    /// rustls configuration for a listener offering `alpn`. Every listener
+    /// built from it picks up reloaded certificates.
+    pub fn server_config(&self, alpn: &[&[u8]]) -> ServerConfig {
+        let verifier = match &self.client_roots {
+            Some(roots) => AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()).boxed(),
+            None => NoClientAuth::boxed(),
+        };
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(self.certificate.clone());
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        config
+    }
+
+    pub fn cert_file(&self) -> &Path {
+        &self.config.cert_file
+    }
+
+    pub fn key_file(&self) -> &Path {
+        &self.config.key_file
+    }
+
+    pub fn client_ca_file(&self) -> Option<&Path> {
+        self.config.client_ca_file.as_deref()
+    }
+
+    /// Changes each time the certificate is reloaded, for listeners that
+    /// cannot swap it in place.
+    pub fn reloaded(&self) -> watch::Receiver<u64> {
+        self.reloaded.subscribe()
+    }
+
+    /// With mutual TLS, admin routes need a client certificate.
+    pub fn check_client_certificate(&self, method: &str, path: &str, client: ClientCertificate) -> Result<(), AppError> {
+        let admin = auth::required_operation(method, path) == Some(Operation::Admin);
+        if admin && self.client_roots.is_some() && !client.0 {
+            return Err(AppError::Forbidden("admin routes require a client certificate".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Reloads the certificate whenever its files change, until shutdown. A
+    /// pair that does not load, e.g. while only one file has been replaced,
+    /// keeps the current certificate in place until the files change again.
+    pub async fn watch(self, shutdown: CancellationToken) {
+        let interval = Duration::from_secs(self.config.reload_interval_secs);
+        let mut loaded = self.modified();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.cancelled() => return,
+            }
+            let modified = self.modified();
+            if modified == loaded {
+                continue;
+            }
+            loaded = modified;
+            match load_certified_key(&self.config.cert_file, &self.config.key_file) {
+                Ok(certificate) => {
+                    *self.certificate.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(certificate);
+                    self.reloaded.send_modify(|version| *version += 1);
+                    info!(cert_file = %self.config.cert_file.display(), "TLS certificate reloaded");
+                }
+                Err(e) => warn!(error = %e, "Could not reload the TLS certificate, keeping the current one"),
+            }
+        }
+    }
+
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.config.cert_file, &self.config.key_file]
+            .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, String> {
+    let file = File::open(path).map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| format!("{} is not PEM: {}", path.display(), e))
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certificates: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certificates.is_empty() {
+        return Err(format!("{} holds no certificate", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn load_certified_key(cert_file: &Path, key_file: &Path) -> Result<CertifiedKey, String> {
+    let chain = load_certificates(cert_file)?;
+    let key = read_pem(key_file)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("{} holds no private key", key_file.display()))?;
+    let key = sign::any_supported_type(&key).map_err(|e| format!("{}: {}", key_file.display(), e))?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    use rustls::ClientConfig;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::frameworks::{testing, Framework};
+
+    fn testdata(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/testdata").join(file)
+    }
+
+    fn tls_config(cert_file: PathBuf, key_file: PathBuf) -> TlsConfig {
+        TlsConfig {
+            enabled: true,
+            cert_file,
+            key_file,
+            client_ca_file: Some(testdata("ca.pem")),
+            reload_interval_secs: 1,
+        }
+    }
+
+    /// A client trusting the test CA, offering `alpn`, with the client
+    /// certificate or without.
+    fn client(alpn: &[&[u8]], with_certificate: bool) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&load_certificates(&testdata("ca.pem")).unwrap()[0]).unwrap();
+        let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+        let mut config = if with_certificate {
+            let key = read_pem(&testdata("client_key.pem")).unwrap().into_iter().find_map(|item| match item {
+                Item::PKCS8Key(der) => Some(PrivateKey(der)),
+                _ => None,
+            });
+            builder.with_client_auth_cert(load_certificates(&testdata("client.pem")).unwrap(), key.unwrap()).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Arc::new(config)
+    }
+
+    /// Every framework must serve HTTPS, negotiate HTTP/2 where it speaks
+    /// it, and with mutual TLS answer admin routes only to clients with a
+    /// certificate.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_framework_serves_https_and_checks_client_certificates() {
+        let mut config = testing::config();
+        config.tls = tls_config(testdata("server.pem"), testdata("server_key.pem"));
+        let failures = testing::with_every_framework(config, |config| async move {
+            let mut failures = Vec::new();
+            for framework in Framework::ALL {
+                let addr = testing::addr(&config, framework);
+                let expected: &[u8] = if framework == Framework::Tide { b"http/1.1" } else { b"h2" };
+                match testing::connect_tls(addr, client(ALPN_H2, false)).await {
+                    Ok(stream) => {
+                        let alpn = stream.get_ref().1.alpn_protocol();
+                        if alpn != Some(expected) {
+                            failures.push(format!("{}: negotiated {:?}", framework, alpn.map(String::from_utf8_lossy)));
+                        }
+                    }
+                    Err(e) => failures.push(format!("{}: handshake failed: {}", framework, e)),
+                }
+            }
+
+            for framework in Framework::HTTP {
+                let addr = testing::addr(&config, framework);
+                let mut check = |what: &str, ok: bool| {
+                    if !ok {
+                        failures.push(format!("{}: {}", framework, what));
+                    }
+                };
+                let healthz = testing::send_tls(addr, client(ALPN_HTTP1, false), "GET", "/healthz").await;
+                check("/healthz answers 200 without a client certificate", healthz.status == 200);
+                let anonymous = testing::send_tls(addr, client(ALPN_HTTP1, false), "GET", "/admin/restaurants/scan").await;
+                check("/admin answers 403 without a client certificate", anonymous.status == 403);
+                let admin = testing::send_tls(addr, client(ALPN_HTTP1, true), "GET", "/admin/restaurants/scan").await;
+                check("/admin lets clients with a certificate through", admin.status != 403);
+            }
+            failures
+        })
+        .await;
+        assert!(failures.is_empty(), "TLS behavior differs:\n{}", failures.join("\n"));
+    }
+
+    /// Rocket is relaunched to use a new certificate. Its port can only be
+    /// closed for the moment it takes to rebind, not while the old instance
+    /// still has a request to finish.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rocket_keeps_accepting_connections_while_it_reloads() {
+        let dir = std::env::temp_dir().join(format!("tls-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::copy(testdata("server.pem"), &cert_file).unwrap();
+        fs::copy(testdata("server_key.pem"), &key_file).unwrap();
+        let renewed = load_certificates(&testdata("server_renewed.pem")).unwrap().remove(0);
+
+        let mut config = testing::config();
+        config.tls = tls_config(cert_file.clone(), key_file);
+        config.shutdown.drain_timeout_secs = 3;
+        let (reloaded, failed) = testing::with_every_framework(config, |config| async move {
+            let addr = testing::addr(&config, Framework::Rocket);
+            // An upload that never finishes, for the old instance to drain
+            let mut upload = testing::connect_tls(addr, client(ALPN_HTTP1, false)).await.unwrap();
+            let head = "POST /api/restaurants HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 100\r\n\r\n{";
+            upload.write_all(head.as_bytes()).await.unwrap();
+            fs::copy(testdata("server_renewed.pem"), &cert_file).unwrap();
+            let started = Instant::now();
+            let mut failed = Vec::new();
+            while started.elapsed() < Duration::from_secs(5) {
+                match testing::connect_tls(addr, client(ALPN_HTTP1, false)).await {
+                    Ok(stream) => {
+                        if stream.get_ref().1.peer_certificates().and_then(|chain| chain.first()) == Some(&renewed) {
+                            return (true, failed);
+                        }
+                    }
+                    Err(e) => failed.push((started.elapsed(), e)),
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            (false, failed)
+        })
+        .await;
+        let _ = fs::remove_dir_all(&dir);
+        assert!(reloaded, "Rocket still serves the old certificate");
+        let outage = match (failed.first(), failed.last()) {
+            (Some((first, _)), Some((last, _))) => *last - *first,
+            _ => Duration::ZERO,
+        };
+        assert!(outage < Duration::from_millis(250), "connections failed while Rocket reloaded: {:?}", failed);
+    }
+}
@@ -0,0 +1,109 @@
+//! Peak heap while listing restaurants collected first, as lists used to be,
+//! and streamed. The binary has its own counting allocator, so nothing else
+//! allocates while it measures. Run it with
+//! `cargo test --release --test list_memory -- --ignored --nocapture`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use bson::{oid::ObjectId, DateTime};
+use futures::{future, stream::{self, BoxStream}, StreamExt, TryStreamExt};
+
+use mongodb_driver_web_frameworks::{
+    content::{MediaType, ResponseFormat, MEDIA_TYPES},
+    error::AppError,
+    models::restaurant::{Address, Grade, RestaurantResponse},
+};
+
+/// The restaurants are made up as they are read, like documents from a
+/// cursor, so only the encoding is measured.
+#[tokio::test]
+#[ignore = "benchmark"]
+async fn list_memory() {
+    const COUNT: usize = 100_000;
+    for format in MEDIA_TYPES.map(ResponseFormat::from) {
+        let started = Instant::now();
+        let (collected, collected_peak) = peak_heap(async {
+            let all: Vec<_> = restaurants(COUNT).try_collect().await.unwrap();
+            format.encode_many(&all).unwrap().len()
+        })
+        .await;
+        let collected_time = started.elapsed();
+        let started = Instant::now();
+        let (streamed, streamed_peak) = peak_heap(async {
+            let body = format.encode_stream(restaurants(COUNT));
+            body.try_fold(0, |length, chunk| future::ready(Ok(length + chunk.len()))).await.unwrap()
+        })
+        .await;
+        let streamed_time = started.elapsed();
+
+        assert_eq!(streamed, collected);
+        println!(
+            "{:<12} {} bytes: collected {:>7} KiB peak in {:?}, streamed {:>7} KiB peak in {:?}",
+            format!("{:?}", format.media),
+            streamed,
+            collected_peak / 1024,
+            collected_time,
+            streamed_peak / 1024,
+            streamed_time,
+        );
+        if format.media != MediaType::MessagePack {
+            assert!(streamed_peak * 10 < collected_peak, "{:?} streamed with {} bytes", format, streamed_peak);
+        }
+    }
+}
+
+fn restaurant(index: usize) -> RestaurantResponse {
+    let mut id = [0; 12];
+    id[4..].copy_from_slice(&(index as u64).to_be_bytes());
+    RestaurantResponse {
+        id: Some(ObjectId::from_bytes(id)),
+        restaurant_id: format!("{:08}", index),
+        name: format!("Restaurant {}", index),
+        borough: "Brooklyn".to_string(),
+        cuisine: "Bakery".to_string(),
+        address: Some(Address { coord: vec![-73.9, 40.7], street: "Flatbush Avenue".to_string(), ..Default::default() }),
+        grades: vec![Grade { date: DateTime::from_millis(1_393_804_800_000), grade: "A".to_string(), score: 2 }],
+        ..Default::default()
+    }
+}
+
+/// `count` restaurants, made up as they are read.
+fn restaurants(count: usize) -> BoxStream<'static, Result<RestaurantResponse, AppError>> {
+    stream::iter((0..count).map(|index| Ok(restaurant(index)))).boxed()
+}
+
+/// What `work` returns, and the most it had allocated at once.
+async fn peak_heap<T>(work: impl Future<Output = T>) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let result = work.await;
+    (result, PEAK.load(Ordering::SeqCst) - before)
+}
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, keeping count of the heap.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;